@@ -0,0 +1,13 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var(
+            "PROTOC",
+            protoc_bin_vendored::protoc_bin_path().expect("No bundled protoc for this platform"),
+        );
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/notification.proto"], &["proto"])
+            .expect("proto/notification.proto could not be compiled");
+    }
+}
@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use chrono::Utc;
+use hyper::{header, Body, Client, Request};
+use hyper_rustls::HttpsConnectorBuilder;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use web_push_native::jwt_simple::{
+    self,
+    prelude::{Claims, ECDSAP256KeyPairLike, ES256KeyPair},
+};
+
+use crate::{
+    error::AppError,
+    notification::{Notification, PushOptions, Urgency},
+    push::{self, DeliveryError, ProviderKind, PushAttempt, PushClient, PushProvider},
+    store::Subscription,
+};
+
+/// Apple rejects provider tokens older than an hour and throttles ones refreshed
+/// more often than every 20 minutes.
+const TOKEN_LIFETIME: Duration = Duration::from_mins(50);
+
+/// Delivers to Apple devices through APNs with token-based (`.p8` key) auth.
+pub struct ApnsProvider {
+    client: PushClient,
+    key_pair: ES256KeyPair,
+    team_id: String,
+    /// Bundle id sent as `apns-topic`.
+    topic: String,
+    host: &'static str,
+    /// Provider token and the moment it should be replaced.
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl ApnsProvider {
+    /// Reads the key at `APNS_KEY_FILE` along with `APNS_KEY_ID`, `APNS_TEAM_ID` and
+    /// `APNS_TOPIC`, if set. `APNS_SANDBOX=true` targets the development environment.
+    pub async fn from_env() -> std::io::Result<Option<Self>> {
+        let Ok(path) = std::env::var("APNS_KEY_FILE") else {
+            return Ok(None);
+        };
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{name} is required together with APNS_KEY_FILE"),
+                )
+            })
+        };
+        let (key_id, team_id, topic) = (
+            var("APNS_KEY_ID")?,
+            var("APNS_TEAM_ID")?,
+            var("APNS_TOPIC")?,
+        );
+        let pem = tokio::fs::read_to_string(path).await?;
+        let key_pair = ES256KeyPair::from_pem(&pem)
+            .map_err(|error| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+            })?
+            .with_key_id(&key_id);
+        let sandbox = std::env::var("APNS_SANDBOX").is_ok_and(|value| value == "true");
+
+        // APNs only speaks HTTP/2.
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_only()
+            .enable_http2()
+            .build();
+        Ok(Some(Self {
+            client: Client::builder().http2_only(true).build(https),
+            key_pair,
+            team_id,
+            topic,
+            host: if sandbox {
+                "api.sandbox.push.apple.com"
+            } else {
+                "api.push.apple.com"
+            },
+            token: Mutex::new(None),
+        }))
+    }
+
+    fn request(
+        &self,
+        device_token: &str,
+        provider_token: &str,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<Request<Body>, AppError> {
+        let (payload, alert) = payload(data);
+        let priority = match options.urgency {
+            Some(Urgency::VeryLow | Urgency::Low) => "5",
+            // Background pushes must not use priority 10.
+            _ if !alert => "5",
+            _ => "10",
+        };
+        let mut request = Request::post(format!("https://{}/3/device/{device_token}", self.host))
+            .header(header::AUTHORIZATION, format!("bearer {provider_token}"))
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", if alert { "alert" } else { "background" })
+            .header("apns-priority", priority);
+        if let Some(ttl) = options.push_ttl() {
+            let expiration = if ttl == 0 {
+                0
+            } else {
+                Utc::now().timestamp() + i64::from(ttl)
+            };
+            request = request.header("apns-expiration", expiration);
+        }
+        if let Some(topic) = &options.topic {
+            request = request.header("apns-collapse-id", topic);
+        }
+        request
+            .body(Body::from(payload.to_string()))
+            .map_err(|error| AppError::invalid_registration("apns_token", error))
+    }
+
+    async fn provider_token(&self) -> Result<String, PushAttempt> {
+        let mut token = self.token.lock().await;
+        if let Some((token, refresh_at)) = &*token {
+            if Instant::now() < *refresh_at {
+                return Ok(token.clone());
+            }
+        }
+        let claims =
+            Claims::create(jwt_simple::prelude::Duration::from_hours(1)).with_issuer(&self.team_id);
+        let signed = self.key_pair.sign(claims).map_err(|error| {
+            PushAttempt::rejected(format!("APNs token signing failed: {error}"))
+        })?;
+        *token = Some((signed.clone(), Instant::now() + TOKEN_LIFETIME));
+        Ok(signed)
+    }
+}
+
+#[async_trait]
+impl PushProvider for ApnsProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Apns
+    }
+
+    fn origin(&self, _subscription: &Subscription) -> Option<String> {
+        Some(format!("https://{}", self.host))
+    }
+
+    async fn send(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<PushAttempt, AppError> {
+        let Some(device_token) = &subscription.apns_token else {
+            return Err(AppError::invalid_registration("apns_token", "missing"));
+        };
+        let provider_token = match self.provider_token().await {
+            Ok(provider_token) => provider_token,
+            Err(attempt) => return Ok(attempt),
+        };
+        let request = self.request(device_token, &provider_token, data, options)?;
+        let response = push::dispatch(&self.client, self.kind(), request).await;
+        if matches!(&response, Ok(response) if response.status() == StatusCode::FORBIDDEN) {
+            // Most likely an expired or revoked provider token, sign a new one next time.
+            *self.token.lock().await = None;
+            return Ok(PushAttempt::Retryable {
+                reason: DeliveryError::Unauthorized,
+                error: "APNs rejected the provider token".to_owned(),
+                retry_after: None,
+                status: Some(StatusCode::FORBIDDEN),
+            });
+        }
+        Ok(PushAttempt::from_response(response))
+    }
+
+    async fn preview(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<Request<Body>, String> {
+        let Some(device_token) = &subscription.apns_token else {
+            return Err("No APNs device token".to_owned());
+        };
+        let provider_token = self
+            .provider_token()
+            .await
+            .map_err(PushAttempt::into_error)?;
+        self.request(device_token, &provider_token, data, options)
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Builds the APNs payload and whether it shows an alert. Notifications without
+/// a title are delivered silently for the app to handle.
+fn payload(data: &str) -> (Value, bool) {
+    match serde_json::from_str::<Notification>(data) {
+        Ok(notification) if !notification.title.is_empty() => {
+            let mut aps = json!({
+                "alert": { "title": notification.title, "body": notification.body },
+            });
+            if let Some(tag) = notification.tag {
+                aps["thread-id"] = json!(tag);
+            }
+            (json!({ "aps": aps, "payload": data }), true)
+        }
+        _ => (
+            json!({
+                "aps": { "content-available": 1 },
+                "payload": data,
+            }),
+            false,
+        ),
+    }
+}
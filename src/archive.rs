@@ -0,0 +1,267 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use metrics::counter;
+use serde::Serialize;
+use serde_json::{from_str, Value};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    Row, SqlitePool,
+};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    at_rest::{self, PayloadCipher},
+    error::AppError,
+    store::StoreError,
+    AppState,
+};
+
+/// Most messages written in one transaction.
+const MAX_BATCH: usize = 256;
+/// How often messages past the retention period are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_hours(1);
+
+/// A message the user was sent, as listed by `/history/archive`.
+#[derive(Serialize, ToSchema)]
+pub struct ArchivedMessage {
+    pub message_id: Uuid,
+    pub sent_at: DateTime<Utc>,
+    #[schema(value_type = Notification)]
+    pub data: Value,
+}
+
+/// A page of `/history/archive`, newest first.
+#[derive(Serialize, ToSchema)]
+pub struct ArchivePage {
+    pub messages: Vec<ArchivedMessage>,
+    /// Passed as `cursor` for the next, older page. Unset after the last one.
+    pub next_cursor: Option<String>,
+}
+
+struct Archived {
+    message_id: Uuid,
+    user_id: String,
+    sent_at: DateTime<Utc>,
+    data: String,
+}
+
+/// Every message accepted for a user, kept in a database for as long as the
+/// retention period allows, where the in-memory history only holds the latest.
+pub struct MessageArchive {
+    pool: SqlitePool,
+    /// Messages are written by a background task, so recording never waits on
+    /// the disk.
+    writer: mpsc::UnboundedSender<Archived>,
+    /// Seals the content before it is written.
+    cipher: Box<dyn PayloadCipher>,
+    retention: Option<Duration>,
+}
+
+impl MessageArchive {
+    /// Enabled by `HISTORY_DATABASE_URL`, a `SQLite` database opened in WAL mode.
+    /// Messages are pruned after `HISTORY_RETENTION_DAYS`, 30 by default, `0`
+    /// keeping them forever. Content is encrypted when
+    /// `PAYLOAD_ENCRYPTION_SECRET` is set.
+    pub async fn from_env() -> Result<Option<Self>, StoreError> {
+        let Ok(url) = std::env::var("HISTORY_DATABASE_URL") else {
+            return Ok(None);
+        };
+        let days = std::env::var("HISTORY_RETENTION_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .unwrap_or(30_u64);
+        let archive =
+            Self::connect(&url, (days > 0).then(|| Duration::from_secs(days * 86_400))).await?;
+        info!("Archiving message history to {url}");
+        Ok(Some(archive))
+    }
+
+    async fn connect(url: &str, retention: Option<Duration>) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS message_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                data TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS message_history_user_created
+                ON message_history (user_id, created_at)",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS message_history_created ON message_history (created_at)",
+        )
+        .execute(&pool)
+        .await?;
+        let (writer, messages) = mpsc::unbounded_channel();
+        tokio::spawn(write(pool.clone(), messages));
+        Ok(Self {
+            pool,
+            writer,
+            cipher: at_rest::cipher_from_env(),
+            retention,
+        })
+    }
+
+    pub fn record(&self, user_id: &str, message_id: Uuid, data: &str) {
+        // Only fails once the writer stopped with the runtime.
+        let _ = self.writer.send(Archived {
+            message_id,
+            user_id: user_id.to_owned(),
+            sent_at: Utc::now(),
+            data: self.cipher.seal(data),
+        });
+    }
+
+    /// Up to `limit` messages of `user_id` older than `cursor`, newest first.
+    pub async fn page(
+        &self,
+        user_id: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ArchivePage, AppError> {
+        let (before, before_id) = match cursor {
+            Some(cursor) => parse_cursor(cursor)
+                .ok_or_else(|| AppError::InvalidCursor(format!("`{cursor}` isn't a cursor")))?,
+            None => (i64::MAX, i64::MAX),
+        };
+        let rows = sqlx::query(
+            "SELECT id, message_id, created_at, data FROM message_history
+                WHERE user_id = ? AND (created_at, id) < (?, ?)
+                ORDER BY created_at DESC, id DESC
+                LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(before)
+        .bind(before_id)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(StoreError::from)?;
+        let mut messages = Vec::with_capacity(rows.len());
+        let mut last = None;
+        for row in rows {
+            let id: i64 = row.try_get("id").map_err(StoreError::from)?;
+            let created_at: i64 = row.try_get("created_at").map_err(StoreError::from)?;
+            let message_id: String = row.try_get("message_id").map_err(StoreError::from)?;
+            let data = self
+                .cipher
+                .open(&row.try_get::<String, _>("data").map_err(StoreError::from)?)?;
+            messages.push(ArchivedMessage {
+                message_id: message_id
+                    .parse()
+                    .map_err(|_| StoreError::Corrupt(format!("message id `{message_id}`")))?,
+                sent_at: Utc
+                    .timestamp_millis_opt(created_at)
+                    .single()
+                    .unwrap_or_default(),
+                data: from_str(&data).unwrap_or(Value::String(data)),
+            });
+            last = Some((created_at, id));
+        }
+        let next_cursor = if messages.len() < limit {
+            None
+        } else {
+            last.map(|(created_at, id)| format!("{created_at}.{id}"))
+        };
+        Ok(ArchivePage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    /// Drops the archived messages of `user_id`, returning how many there were.
+    pub async fn erase(&self, user_id: &str) -> Result<usize, StoreError> {
+        let erased = sqlx::query("DELETE FROM message_history WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(usize::try_from(erased).unwrap_or(usize::MAX))
+    }
+
+    async fn prune(&self, retention: Duration) -> Result<u64, StoreError> {
+        let cutoff = Utc::now()
+            .timestamp_millis()
+            .saturating_sub(i64::try_from(retention.as_millis()).unwrap_or(i64::MAX));
+        Ok(
+            sqlx::query("DELETE FROM message_history WHERE created_at < ?")
+                .bind(cutoff)
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+}
+
+/// Reads the `<created_at>.<id>` a page left off at.
+fn parse_cursor(cursor: &str) -> Option<(i64, i64)> {
+    let (created_at, id) = cursor.split_once('.')?;
+    Some((created_at.parse().ok()?, id.parse().ok()?))
+}
+
+/// Writes archived messages as they come, a batch per transaction.
+async fn write(pool: SqlitePool, mut messages: mpsc::UnboundedReceiver<Archived>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    while messages.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        if let Err(error) = insert(&pool, &batch).await {
+            error!("{} message(s) could not be archived: {error}", batch.len());
+        } else {
+            counter!("messages_archived_total", batch.len() as u64);
+        }
+        batch.clear();
+    }
+}
+
+async fn insert(pool: &SqlitePool, batch: &[Archived]) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    for message in batch {
+        sqlx::query(
+            "INSERT INTO message_history (message_id, user_id, created_at, data)
+                VALUES (?, ?, ?, ?)",
+        )
+        .bind(message.message_id.to_string())
+        .bind(&message.user_id)
+        .bind(message.sent_at.timestamp_millis())
+        .bind(&message.data)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+}
+
+/// Prunes messages past the retention period, if there is one.
+pub async fn run(state: AppState) {
+    let Some(archive) = &state.archive else {
+        return;
+    };
+    let Some(retention) = archive.retention else {
+        return;
+    };
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match archive.prune(retention).await {
+            Ok(0) => {}
+            Ok(pruned) => {
+                info!("Pruned {pruned} archived message(s)");
+                counter!("archived_messages_pruned_total", pruned);
+            }
+            Err(error) => error!("Archived messages could not be pruned: {error}"),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::{path::Path, sync::LazyLock};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, MethodRouter},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::AppState;
+
+/// For files asked for by the current version, which never change under that
+/// URL.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// The files the version is derived from, as they are named on disk.
+const FILES: [&str; 3] = ["index.html", "index.js", "service_worker.js"];
+
+/// The built-in demo page and scripts, versioned by their content.
+pub static BUILT_IN: LazyLock<BuiltIn> = LazyLock::new(BuiltIn::new);
+
+#[derive(Deserialize)]
+pub struct AssetQuery {
+    /// The version the page or service worker was built with.
+    v: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AssetVersion {
+    /// Changes whenever the page or one of its scripts does.
+    pub version: String,
+}
+
+/// A file served with an `ETag` of its content. The tag is weak as the body may
+/// go out compressed.
+pub struct Asset {
+    content_type: &'static str,
+    body: Vec<u8>,
+    etag: HeaderValue,
+}
+
+impl Asset {
+    fn new(content_type: &'static str, body: Vec<u8>) -> Self {
+        let digest = hex::encode(Sha256::digest(&body));
+        let etag = HeaderValue::from_str(&format!("W/\"{}\"", &digest[..16]))
+            .expect("Hex digests are valid header values");
+        Self {
+            content_type,
+            body,
+            etag,
+        }
+    }
+
+    /// The file, or a 304 when the browser's copy is still current. Only a
+    /// request for the current version may be cached for good, the rest is
+    /// revalidated on every use.
+    fn respond(&'static self, headers: &HeaderMap, current: bool) -> Response {
+        let cache_control = if current { IMMUTABLE } else { "no-cache" };
+        let headers_out = [
+            (header::ETAG, self.etag.clone()),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(cache_control),
+            ),
+        ];
+        if self.matches(headers) {
+            return (StatusCode::NOT_MODIFIED, headers_out).into_response();
+        }
+        (
+            headers_out,
+            [(header::CONTENT_TYPE, self.content_type)],
+            self.body.as_slice(),
+        )
+            .into_response()
+    }
+
+    /// Whether `If-None-Match` lists this file's tag, compared weakly as
+    /// RFC 9110 asks for.
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        let own = self.etag.to_str().unwrap_or_default();
+        let own = own.trim_start_matches("W/");
+        headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == own)
+    }
+}
+
+pub struct BuiltIn {
+    pub version: String,
+    index: Asset,
+    index_js: Asset,
+    service_worker: Asset,
+}
+
+impl BuiltIn {
+    fn new() -> Self {
+        let index = include_str!("index.html");
+        let index_js = include_bytes!("index.js");
+        let service_worker = include_bytes!("service_worker.js");
+        let version = version_of([index.as_bytes(), index_js, service_worker]);
+        // The page hands the version on to the service worker it registers.
+        let index = index.replace(
+            "src=\"/index.js\"",
+            &format!("src=\"/index.js?v={version}\""),
+        );
+        Self {
+            index: Asset::new("text/html; charset=utf-8", index.into_bytes()),
+            index_js: Asset::new("application/javascript", index_js.to_vec()),
+            service_worker: Asset::new("application/javascript", service_worker.to_vec()),
+            version,
+        }
+    }
+
+    pub const fn index(&self) -> &Asset {
+        &self.index
+    }
+
+    pub const fn index_js(&self) -> &Asset {
+        &self.index_js
+    }
+
+    pub const fn service_worker(&self) -> &Asset {
+        &self.service_worker
+    }
+}
+
+/// Serves the built-in file `file` picks.
+pub fn serve(file: fn(&'static BuiltIn) -> &'static Asset) -> MethodRouter<AppState> {
+    get(
+        move |Query(query): Query<AssetQuery>, headers: HeaderMap| async move {
+            let current = query.v.as_deref() == Some(BUILT_IN.version.as_str());
+            file(&BUILT_IN).respond(&headers, current)
+        },
+    )
+}
+
+fn version_of<'a>(files: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(Sha256::digest(file));
+    }
+    hex::encode(hasher.finalize())[..12].to_owned()
+}
+
+/// The version of the files in `dir` as they are now, missing ones counting as
+/// empty, so edits are picked up without a restart.
+async fn version_on_disk(dir: &Path) -> String {
+    let mut files = Vec::with_capacity(FILES.len());
+    for name in FILES {
+        files.push(tokio::fs::read(dir.join(name)).await.unwrap_or_default());
+    }
+    version_of(files.iter().map(Vec::as_slice))
+}
+
+/// The version of the demo page and its scripts. The service worker polls it
+/// and updates itself once it no longer matches the version it was registered
+/// with.
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "subscriber",
+    security(()),
+    responses((status = 200, description = "The current version", body = AssetVersion))
+)]
+pub async fn version(State(state): State<AppState>) -> impl IntoResponse {
+    let version = match &state.config.static_dir {
+        Some(dir) => version_on_disk(dir).await,
+        None => BUILT_IN.version.clone(),
+    };
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(AssetVersion { version }),
+    )
+}
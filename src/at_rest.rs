@@ -0,0 +1,119 @@
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use base64ct::{Base64, Encoding};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::store::StoreError;
+
+const NONCE_LEN: usize = 12;
+/// Marks sealed payloads, so entries written before encryption was turned on
+/// still read as they are.
+const SEALED_PREFIX: &str = "aes256gcm:";
+
+/// How message content is protected while it is stored for later delivery.
+pub trait PayloadCipher: Send + Sync {
+    fn seal(&self, payload: &str) -> String;
+    /// The payload `stored` was sealed from.
+    fn open(&self, stored: &str) -> Result<String, StoreError>;
+}
+
+/// Stores payloads as they are.
+pub struct Plaintext;
+
+impl PayloadCipher for Plaintext {
+    fn seal(&self, payload: &str) -> String {
+        payload.to_owned()
+    }
+
+    fn open(&self, stored: &str) -> Result<String, StoreError> {
+        if stored.starts_with(SEALED_PREFIX) {
+            return Err(StoreError::Corrupt(
+                "payload is encrypted, but `PAYLOAD_ENCRYPTION_SECRET` isn't set".to_owned(),
+            ));
+        }
+        Ok(stored.to_owned())
+    }
+}
+
+/// AES-256-GCM with a fresh nonce per payload, which is stored in front of the
+/// ciphertext.
+pub struct AesGcmCipher(Aes256Gcm);
+
+impl AesGcmCipher {
+    pub fn new(secret: &str) -> Self {
+        let key = Sha256::digest(secret.as_bytes());
+        Self(Aes256Gcm::new(&key))
+    }
+}
+
+impl PayloadCipher for AesGcmCipher {
+    fn seal(&self, payload: &str) -> String {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), payload.as_bytes())
+            .expect("Payloads fit AES-GCM");
+        let sealed = [&nonce[..], &ciphertext].concat();
+        format!("{SEALED_PREFIX}{}", Base64::encode_string(&sealed))
+    }
+
+    fn open(&self, stored: &str) -> Result<String, StoreError> {
+        let Some(sealed) = stored.strip_prefix(SEALED_PREFIX) else {
+            return Ok(stored.to_owned());
+        };
+        let corrupt = |reason: &str| StoreError::Corrupt(format!("sealed payload {reason}"));
+        let sealed = Base64::decode_vec(sealed).map_err(|_| corrupt("isn't base64"))?;
+        if sealed.len() < NONCE_LEN {
+            return Err(corrupt("is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| corrupt("was tampered with or sealed with another secret"))?;
+        String::from_utf8(payload).map_err(|_| corrupt("isn't UTF-8"))
+    }
+}
+
+/// Encrypts with a key derived from `PAYLOAD_ENCRYPTION_SECRET` when it is set.
+/// Changing the secret leaves what was sealed with the old one unreadable.
+pub fn cipher_from_env() -> Box<dyn PayloadCipher> {
+    match std::env::var("PAYLOAD_ENCRYPTION_SECRET") {
+        Ok(secret) if !secret.is_empty() => {
+            info!("Encrypting stored message payloads");
+            Box::new(AesGcmCipher::new(&secret))
+        }
+        _ => Box::new(Plaintext),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_a_fresh_nonce() {
+        let cipher = AesGcmCipher::new("secret");
+        let sealed = cipher.seal("{\"title\":\"Hi\"}");
+        assert!(sealed.starts_with(SEALED_PREFIX));
+        assert!(!sealed.contains("Hi"));
+        assert_ne!(sealed, cipher.seal("{\"title\":\"Hi\"}"));
+        assert_eq!(cipher.open(&sealed).unwrap(), "{\"title\":\"Hi\"}");
+        // Written before encryption was turned on.
+        assert_eq!(cipher.open("plain").unwrap(), "plain");
+    }
+
+    #[test]
+    fn detects_tampering_and_other_secrets() {
+        let cipher = AesGcmCipher::new("secret");
+        let sealed = cipher.seal("payload");
+        let mut bytes = Base64::decode_vec(&sealed[SEALED_PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{SEALED_PREFIX}{}", Base64::encode_string(&bytes));
+        assert!(cipher.open(&tampered).is_err());
+        assert!(AesGcmCipher::new("other").open(&sealed).is_err());
+        assert!(cipher.open(&format!("{SEALED_PREFIX}AAAA")).is_err());
+        assert!(Plaintext.open(&sealed).is_err());
+    }
+}
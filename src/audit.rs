@@ -0,0 +1,330 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc, oneshot},
+};
+use tracing::{error, info};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::push::DeliveryError;
+
+/// Entries kept in memory when no file is configured.
+const DEFAULT_CAPACITY: usize = 10_000;
+/// Most entries `/admin/audit` returns at once.
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+/// Who a message came from: a fingerprint of the API key that sent it, so the
+/// log doesn't hand keys out, or the part of the server that did. `None` when
+/// authentication is disabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Sender(pub Option<String>);
+
+impl Sender {
+    /// Identifies the request's API key, if it has one.
+    pub fn api_key(key: Option<&str>) -> Self {
+        Self(key.map(|key| {
+            let digest = hex::encode(Sha256::digest(key.as_bytes()));
+            format!("key:{}", &digest[..12])
+        }))
+    }
+
+    pub fn internal(source: &str) -> Self {
+        Self(Some(source.to_owned()))
+    }
+}
+
+/// One thing that happened to a message on its way to a user: a push attempt,
+/// a real-time delivery or an email.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub user_id: String,
+    pub message_id: Uuid,
+    pub sender: Option<String>,
+    /// `server` when the message is accepted, then a push provider, `sse`,
+    /// `websocket`, `cluster`, `queue`, `realtime`, `email` or `digest`.
+    pub channel: String,
+    pub outcome: String,
+    /// What the push service responded with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<u16>,
+    /// Why a push attempt failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<DeliveryError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        user_id: &str,
+        message_id: Uuid,
+        sender: &Sender,
+        channel: &str,
+        outcome: &str,
+    ) -> Self {
+        Self {
+            at: Utc::now(),
+            user_id: user_id.to_owned(),
+            message_id,
+            sender: sender.0.clone(),
+            channel: channel.to_owned(),
+            outcome: outcome.to_owned(),
+            status_code: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    pub fn with_status(mut self, status: Option<StatusCode>) -> Self {
+        self.status_code = status.map(|status| status.as_u16());
+        self
+    }
+
+    pub const fn with_reason(mut self, reason: Option<DeliveryError>) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    pub fn with_error(mut self, error: Option<&str>) -> Self {
+        self.error = error.map(ToOwned::to_owned);
+        self
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TailQuery {
+    /// Only the events of this user.
+    pub user_id: Option<String>,
+    /// Only the events of users subscribed to this topic.
+    pub topic: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    pub user_id: String,
+    /// Only the entries of this message.
+    pub message_id: Option<Uuid>,
+    /// How many of the latest entries to return, 100 by default and at most 1000.
+    pub limit: Option<usize>,
+}
+
+enum Sink {
+    Memory {
+        capacity: usize,
+        entries: Mutex<VecDeque<AuditEntry>>,
+    },
+    /// Entries are appended as JSON lines by a background task, so recording
+    /// never waits on the disk.
+    File {
+        path: PathBuf,
+        writer: mpsc::UnboundedSender<Write>,
+    },
+}
+
+/// What the task owning the audit log file is asked to do.
+enum Write {
+    Append(AuditEntry),
+    /// Rewrites the file without the entries of `user_id`, answering how many
+    /// there were.
+    Erase {
+        user_id: String,
+        done: oneshot::Sender<std::io::Result<usize>>,
+    },
+}
+
+/// Append-only record of every send attempt, for finding out why a
+/// notification never arrived. Entries are also broadcast to `/admin/tail`.
+pub struct AuditLog {
+    sink: Sink,
+    tail: broadcast::Sender<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Appends to the JSON lines file at `AUDIT_LOG_PATH` when set, which keeps
+    /// everything and survives restarts. Otherwise the latest
+    /// `AUDIT_LOG_CAPACITY` entries (10000 by default) are kept in memory.
+    pub fn from_env() -> Self {
+        if let Ok(path) = std::env::var("AUDIT_LOG_PATH") {
+            let path = PathBuf::from(path);
+            let (writer, entries) = mpsc::unbounded_channel();
+            tokio::spawn(append(path.clone(), entries));
+            info!("Writing the audit log to {}", path.display());
+            return Self::new(Sink::File { path, writer });
+        }
+        let capacity = std::env::var("AUDIT_LOG_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+        Self::new(Sink::Memory {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            tail: broadcast::channel(1024).0,
+        }
+    }
+
+    /// Entries as they are recorded, missing those a lagging receiver fell
+    /// behind on.
+    pub fn subscribe(&self) -> broadcast::Receiver<AuditEntry> {
+        self.tail.subscribe()
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        // Fails only while nobody is tailing.
+        let _ = self.tail.send(entry.clone());
+        match &self.sink {
+            Sink::Memory { capacity, entries } => {
+                if *capacity == 0 {
+                    return;
+                }
+                let mut entries = entries.lock().expect("Audit log was poisoned");
+                if entries.len() == *capacity {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+            Sink::File { writer, .. } => {
+                // Only fails once the writer gave up, which it already logged.
+                let _ = writer.send(Write::Append(entry));
+            }
+        }
+    }
+
+    /// The latest `limit` entries of `user_id`, oldest first.
+    pub async fn query(
+        &self,
+        user_id: &str,
+        message_id: Option<Uuid>,
+        limit: usize,
+    ) -> std::io::Result<Vec<AuditEntry>> {
+        let matches = |entry: &AuditEntry| {
+            entry.user_id == user_id && message_id.is_none_or(|id| entry.message_id == id)
+        };
+        let mut found = match &self.sink {
+            Sink::Memory { entries, .. } => entries
+                .lock()
+                .expect("Audit log was poisoned")
+                .iter()
+                .filter(|entry| matches(entry))
+                .cloned()
+                .collect::<Vec<_>>(),
+            Sink::File { path, .. } => {
+                let content = match tokio::fs::read_to_string(path).await {
+                    Ok(content) => content,
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(error) => return Err(error),
+                };
+                content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                    .filter(matches)
+                    .collect()
+            }
+        };
+        found.drain(..found.len().saturating_sub(limit));
+        Ok(found)
+    }
+
+    /// Drops every entry of `user_id`, returning how many there were.
+    pub async fn erase(&self, user_id: &str) -> std::io::Result<usize> {
+        match &self.sink {
+            Sink::Memory { entries, .. } => {
+                let mut entries = entries.lock().expect("Audit log was poisoned");
+                let before = entries.len();
+                entries.retain(|entry| entry.user_id != user_id);
+                Ok(before - entries.len())
+            }
+            Sink::File { writer, .. } => {
+                // Left to the writer, so nothing is appended while the file is
+                // rewritten.
+                let (done, erased) = oneshot::channel();
+                let stopped = || std::io::Error::other("the audit log writer stopped");
+                writer
+                    .send(Write::Erase {
+                        user_id: user_id.to_owned(),
+                        done,
+                    })
+                    .map_err(|_| stopped())?;
+                erased.await.map_err(|_| stopped())?
+            }
+        }
+    }
+}
+
+async fn open(path: &Path) -> std::io::Result<File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+async fn append(path: PathBuf, mut writes: mpsc::UnboundedReceiver<Write>) {
+    let mut file = match open(&path).await {
+        Ok(file) => file,
+        Err(error) => {
+            error!("Audit log {} could not be opened: {error}", path.display());
+            return;
+        }
+    };
+    while let Some(write) = writes.recv().await {
+        match write {
+            Write::Append(entry) => {
+                let mut line = serde_json::to_vec(&entry).expect("Audit entries serialize");
+                line.push(b'\n');
+                if let Err(error) = file.write_all(&line).await {
+                    error!("Audit log {} could not be written: {error}", path.display());
+                }
+            }
+            Write::Erase { user_id, done } => {
+                let erased = erase_from(&path, &mut file, &user_id).await;
+                // The request may have given up waiting.
+                let _ = done.send(erased);
+            }
+        }
+    }
+}
+
+/// Replaces the file at `path` by a copy without the entries of `user_id` and
+/// reopens `file` on it. Lines that don't parse are kept.
+async fn erase_from(path: &Path, file: &mut File, user_id: &str) -> std::io::Result<usize> {
+    file.flush().await?;
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut kept = String::with_capacity(content.len());
+    let mut erased = 0;
+    for line in content.lines() {
+        if serde_json::from_str::<AuditEntry>(line).is_ok_and(|entry| entry.user_id == user_id) {
+            erased += 1;
+        } else {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if erased > 0 {
+        // Renamed into place, so a crash leaves either copy and never half of one.
+        let rewritten = path.with_extension("erasing");
+        tokio::fs::write(&rewritten, kept).await?;
+        tokio::fs::rename(&rewritten, path).await?;
+        *file = open(path).await?;
+    }
+    Ok(erased)
+}
@@ -0,0 +1,565 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Query, State},
+    http::{header, Request, Uri},
+    middleware::Next,
+    response::Response,
+};
+use base64ct::{Base64, Encoding};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{audit::Sender, error::AppError, AppState};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// May push notifications to users.
+    Publisher,
+    /// May register and open real-time streams.
+    Subscriber,
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "publisher" => Ok(Self::Publisher),
+            "subscriber" => Ok(Self::Subscriber),
+            _ => Err(format!("Unknown scope `{scope}`")),
+        }
+    }
+}
+
+/// The application a request acts for. Every user id, topic, group and template
+/// name it uses is namespaced as `<tenant>/<id>`, so tenants can't see or reach
+/// each other's. Keys without a tenant act for the default one, whose ids stay
+/// unprefixed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tenant(Option<String>);
+
+impl Tenant {
+    /// The namespaced form of `id`. Ids containing the separator are refused since
+    /// they would address another tenant's namespace.
+    pub fn scope(&self, id: &str) -> Result<String, AppError> {
+        if id.contains('/') {
+            return Err(AppError::CrossTenant);
+        }
+        Ok(self
+            .0
+            .as_ref()
+            .map_or_else(|| id.to_owned(), |tenant| format!("{tenant}/{id}")))
+    }
+
+    /// `id` without the namespace, or `None` if it belongs to another tenant.
+    pub fn unscope<'a>(&self, id: &'a str) -> Option<&'a str> {
+        match &self.0 {
+            Some(tenant) => id.strip_prefix(tenant.as_str())?.strip_prefix('/'),
+            None => (!id.contains('/')).then_some(id),
+        }
+    }
+
+    pub fn owns(&self, id: &str) -> bool {
+        self.unscope(id).is_some()
+    }
+
+    /// `id` without whichever tenant's namespace it is in.
+    pub fn local_part(id: &str) -> &str {
+        id.rsplit_once('/').map_or(id, |(_, id)| id)
+    }
+
+    /// The tenant whose namespace `id` is in, `""` for the default tenant.
+    pub fn name_of(id: &str) -> &str {
+        id.rsplit_once('/').map_or("", |(tenant, _)| tenant)
+    }
+}
+
+/// An entry of `API_KEYS_FILE`: either just the scopes or the scopes and a tenant.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyEntry {
+    Scopes(HashSet<Scope>),
+    Tenant {
+        scopes: HashSet<Scope>,
+        tenant: Option<String>,
+    },
+}
+
+/// Who a request acts as once its credentials were checked.
+#[derive(Debug, Clone, Default)]
+pub struct Principal {
+    pub scopes: HashSet<Scope>,
+    pub tenant: Tenant,
+    /// What the audit log records the request's messages as coming from.
+    pub sender: Sender,
+}
+
+/// Checks the credentials publishers and subscribers present, so deployments can
+/// change how they are issued without touching the handlers.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// The principal `credentials` name, the request's bearer token or
+    /// `access_token` parameter. [`AppError::Unauthorized`] when they are
+    /// missing or invalid.
+    async fn authenticate(&self, credentials: Option<&str>) -> Result<Principal, AppError>;
+
+    /// The principal, as long as it was granted `scope`.
+    async fn authorize(
+        &self,
+        credentials: Option<&str>,
+        scope: Scope,
+    ) -> Result<Principal, AppError> {
+        let principal = self.authenticate(credentials).await?;
+        if principal.scopes.contains(&scope) {
+            Ok(principal)
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+}
+
+/// Picks the authenticator `AUTH_PROVIDER` names: `api_key`, the default, `jwt` or
+/// `none`. Without any API keys configured `api_key` lets every request through,
+/// as `none` does.
+pub async fn authenticator_from_env() -> std::io::Result<Box<dyn Authenticator>> {
+    let provider = std::env::var("AUTH_PROVIDER").unwrap_or_else(|_| "api_key".to_owned());
+    match provider.as_str() {
+        "api_key" => {
+            let keys = ApiKeys::from_env().await?;
+            if keys.keys.is_empty() {
+                warn!("No API keys configured, authentication is disabled.");
+                return Ok(Box::new(NoAuth));
+            }
+            Ok(Box::new(keys))
+        }
+        "jwt" => {
+            info!("Authenticating with JSON web tokens");
+            Ok(Box::new(JwtAuthenticator::from_env().await?))
+        }
+        "none" => {
+            warn!("`AUTH_PROVIDER` is `none`, authentication is disabled.");
+            Ok(Box::new(NoAuth))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown `AUTH_PROVIDER` `{provider}`"),
+        )),
+    }
+}
+
+fn invalid_tenant(tenant: Option<&String>) -> bool {
+    tenant.is_some_and(|tenant| tenant.is_empty() || tenant.contains('/'))
+}
+
+/// Lets everyone in with every scope, for the default tenant.
+pub struct NoAuth;
+
+#[async_trait]
+impl Authenticator for NoAuth {
+    async fn authenticate(&self, _credentials: Option<&str>) -> Result<Principal, AppError> {
+        Ok(Principal {
+            scopes: HashSet::from([Scope::Publisher, Scope::Subscriber]),
+            ..Principal::default()
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct ApiKey {
+    scopes: HashSet<Scope>,
+    tenant: Tenant,
+}
+
+/// API keys with the scopes each of them grants and the tenant it acts for.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: HashMap<String, ApiKey>,
+}
+
+impl ApiKeys {
+    /// Collects keys from the JSON file at `API_KEYS_FILE` (`{"<key>": ["publisher", ...]}`,
+    /// or `{"<key>": {"scopes": [...], "tenant": "<tenant>"}}`) and the comma-separated
+    /// `PUBLISHER_API_KEYS` / `SUBSCRIBER_API_KEYS` variables, which are for the
+    /// default tenant.
+    pub async fn from_env() -> std::io::Result<Self> {
+        let mut keys = match std::env::var("API_KEYS_FILE") {
+            Ok(path) => {
+                let content = tokio::fs::read_to_string(path).await?;
+                serde_json::from_str::<HashMap<String, KeyEntry>>(&content)?
+                    .into_iter()
+                    .map(|(key, entry)| {
+                        let (scopes, tenant) = match entry {
+                            KeyEntry::Scopes(scopes) => (scopes, None),
+                            KeyEntry::Tenant { scopes, tenant } => (scopes, tenant),
+                        };
+                        if invalid_tenant(tenant.as_ref()) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                "Tenant names must be non-empty and can't contain `/`",
+                            ));
+                        }
+                        let tenant = Tenant(tenant);
+                        Ok((key, ApiKey { scopes, tenant }))
+                    })
+                    .collect::<std::io::Result<_>>()?
+            }
+            Err(_) => HashMap::new(),
+        };
+        for (variable, scope) in [
+            ("PUBLISHER_API_KEYS", Scope::Publisher),
+            ("SUBSCRIBER_API_KEYS", Scope::Subscriber),
+        ] {
+            let Ok(value) = std::env::var(variable) else {
+                continue;
+            };
+            for key in value
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+            {
+                keys.entry(key.to_owned()).or_default().scopes.insert(scope);
+            }
+        }
+        Ok(Self { keys })
+    }
+}
+
+#[async_trait]
+impl Authenticator for ApiKeys {
+    async fn authenticate(&self, credentials: Option<&str>) -> Result<Principal, AppError> {
+        let (key, entry) = credentials
+            .and_then(|key| self.keys.get_key_value(key))
+            .ok_or(AppError::Unauthorized)?;
+        Ok(Principal {
+            scopes: entry.scopes.clone(),
+            tenant: entry.tenant.clone(),
+            sender: Sender::api_key(Some(key)),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct AccessClaims {
+    sub: String,
+    /// Space-separated, as in OAuth. Unknown scopes are ignored.
+    #[serde(default)]
+    scope: String,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+/// Bearer tokens issued by an identity provider, naming their scopes in `scope`
+/// and optionally the `tenant` to act for.
+pub struct JwtAuthenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    /// Verifies HS256 tokens signed with `AUTH_JWT_SECRET`, or RS256 tokens against
+    /// the PEM file at `AUTH_JWT_PUBLIC_KEY_FILE`. `exp` is required, and so are
+    /// `iss` and `aud` if `AUTH_JWT_ISSUER` or `AUTH_JWT_AUDIENCE` are set.
+    pub async fn from_env() -> std::io::Result<Self> {
+        let invalid = |error: jsonwebtoken::errors::Error| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+        };
+        let (key, algorithm) = if let Ok(secret) = std::env::var("AUTH_JWT_SECRET") {
+            (
+                DecodingKey::from_secret(secret.as_bytes()),
+                Algorithm::HS256,
+            )
+        } else if let Ok(path) = std::env::var("AUTH_JWT_PUBLIC_KEY_FILE") {
+            let pem = tokio::fs::read(path).await?;
+            (
+                DecodingKey::from_rsa_pem(&pem).map_err(invalid)?,
+                Algorithm::RS256,
+            )
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "The `jwt` provider needs `AUTH_JWT_SECRET` or `AUTH_JWT_PUBLIC_KEY_FILE`",
+            ));
+        };
+        let validation = jwt_validation(algorithm, "AUTH_JWT_ISSUER", "AUTH_JWT_AUDIENCE");
+        Ok(Self { key, validation })
+    }
+}
+
+/// Validation for `algorithm` with the issuer and audience in the environment
+/// variables `issuer_var` and `audience_var`, if set.
+pub fn jwt_validation(algorithm: Algorithm, issuer_var: &str, audience_var: &str) -> Validation {
+    claim_validation(
+        algorithm,
+        std::env::var(issuer_var).ok(),
+        std::env::var(audience_var).ok(),
+    )
+}
+
+/// Requires `exp`, and `iss` and `aud` to be present and match when given.
+/// Without an audience `aud` isn't checked at all, as tokens naming one would
+/// otherwise be refused.
+pub fn claim_validation(
+    algorithm: Algorithm,
+    issuer: Option<String>,
+    audience: Option<String>,
+) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    if let Some(issuer) = issuer {
+        validation.set_issuer(&[issuer]);
+        validation.required_spec_claims.insert("iss".to_owned());
+    }
+    match audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            validation.required_spec_claims.insert("aud".to_owned());
+        }
+        None => validation.validate_aud = false,
+    }
+    validation
+}
+
+#[async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, credentials: Option<&str>) -> Result<Principal, AppError> {
+        let token = credentials.ok_or(AppError::Unauthorized)?;
+        let claims = jsonwebtoken::decode::<AccessClaims>(token, &self.key, &self.validation)
+            .map_err(|_| AppError::Unauthorized)?
+            .claims;
+        if invalid_tenant(claims.tenant.as_ref()) {
+            return Err(AppError::Unauthorized);
+        }
+        Ok(Principal {
+            scopes: claims
+                .scope
+                .split_whitespace()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            tenant: Tenant(claims.tenant),
+            sender: Sender::internal(&format!("jwt:{}", claims.sub)),
+        })
+    }
+}
+
+/// What an admin credential allows on `/admin/*`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// May look at users, connections, presence, stats and the audit log.
+    Viewer,
+    /// May also export and import registrations and reload the VAPID key.
+    Operator,
+}
+
+impl std::str::FromStr for AdminRole {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "viewer" => Ok(Self::Viewer),
+            "operator" => Ok(Self::Operator),
+            _ => Err(format!("Unknown admin role `{role}`")),
+        }
+    }
+}
+
+struct AdminUser {
+    password: [u8; 32],
+    role: AdminRole,
+}
+
+#[derive(Deserialize)]
+struct AdminClaims {
+    sub: String,
+    role: AdminRole,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+/// Credentials for `/admin/*`, separate from the API keys so publishers can't
+/// read the audit log or export registrations.
+pub struct AdminAuth {
+    users: HashMap<String, AdminUser>,
+    tokens: Option<(DecodingKey, Validation)>,
+}
+
+impl AdminAuth {
+    /// Basic auth users from `ADMIN_USERS`, comma-separated `<name>:<role>:<password>`,
+    /// and HS256 bearer tokens signed with `ADMIN_JWT_SECRET`, carrying `sub`,
+    /// `role`, `exp` and optionally the `tenant` to act for. Basic auth users act
+    /// for the default tenant.
+    ///
+    /// `None` when neither is set, leaving `/admin/*` to publisher keys.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let mut users = HashMap::new();
+        if let Ok(value) = std::env::var("ADMIN_USERS") {
+            for entry in value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+            {
+                let mut fields = entry.splitn(3, ':');
+                let (Some(name), Some(role), Some(password)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err("`ADMIN_USERS` entries are `<name>:<role>:<password>`".to_owned());
+                };
+                let user = AdminUser {
+                    password: Sha256::digest(password.as_bytes()).into(),
+                    role: role.parse()?,
+                };
+                users.insert(name.to_owned(), user);
+            }
+        }
+        let tokens = std::env::var("ADMIN_JWT_SECRET").ok().map(|secret| {
+            (
+                DecodingKey::from_secret(secret.as_bytes()),
+                Validation::new(Algorithm::HS256),
+            )
+        });
+        if users.is_empty() && tokens.is_none() {
+            return Ok(None);
+        }
+        info!("Admin credentials required on /admin");
+        Ok(Some(Self { users, tokens }))
+    }
+
+    /// Who the request's credentials name, if they are valid and grant `role`.
+    fn authorize<B>(
+        &self,
+        request: &Request<B>,
+        role: AdminRole,
+    ) -> Result<(Tenant, Sender), AppError> {
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        let (name, granted, tenant) =
+            match authorization.and_then(|value| value.strip_prefix("Basic ")) {
+                Some(credentials) => self.basic(credentials)?,
+                None => self.bearer(request_key(request).as_deref())?,
+            };
+        if granted < role {
+            return Err(AppError::AdminForbidden);
+        }
+        Ok((tenant, Sender::internal(&format!("admin:{name}"))))
+    }
+
+    fn basic(&self, credentials: &str) -> Result<(String, AdminRole, Tenant), AppError> {
+        let decoded =
+            Base64::decode_vec(credentials.trim()).map_err(|_| AppError::AdminUnauthorized)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| AppError::AdminUnauthorized)?;
+        let (name, password) = decoded.split_once(':').ok_or(AppError::AdminUnauthorized)?;
+        // Comparing digests keeps the time taken independent of the password.
+        let password: [u8; 32] = Sha256::digest(password.as_bytes()).into();
+        match self.users.get(name) {
+            Some(user) if user.password == password => {
+                Ok((name.to_owned(), user.role, Tenant::default()))
+            }
+            _ => Err(AppError::AdminUnauthorized),
+        }
+    }
+
+    fn bearer(&self, token: Option<&str>) -> Result<(String, AdminRole, Tenant), AppError> {
+        let ((key, validation), token) = self
+            .tokens
+            .as_ref()
+            .zip(token)
+            .ok_or(AppError::AdminUnauthorized)?;
+        let claims = jsonwebtoken::decode::<AdminClaims>(token, key, validation)
+            .map_err(|_| AppError::AdminUnauthorized)?
+            .claims;
+        if invalid_tenant(claims.tenant.as_ref()) {
+            return Err(AppError::AdminUnauthorized);
+        }
+        Ok((claims.sub, claims.role, Tenant(claims.tenant)))
+    }
+}
+
+pub async fn require_publisher<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    require(&state, Scope::Publisher, request, next).await
+}
+
+pub async fn require_subscriber<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    require(&state, Scope::Subscriber, request, next).await
+}
+
+pub async fn require_admin_viewer<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    require_admin(&state, AdminRole::Viewer, request, next).await
+}
+
+pub async fn require_admin_operator<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    require_admin(&state, AdminRole::Operator, request, next).await
+}
+
+/// Checks the admin credentials grant `role`, or without any configured that
+/// the request carries a publisher key.
+async fn require_admin<B>(
+    state: &AppState,
+    role: AdminRole,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    let Some(admin) = &state.admin_auth else {
+        return require(state, Scope::Publisher, request, next).await;
+    };
+    let (tenant, sender) = admin.authorize(&request, role)?;
+    request.extensions_mut().insert(tenant);
+    request.extensions_mut().insert(sender);
+    Ok(next.run(request).await)
+}
+
+/// Checks the credentials grant `scope` and hands the handler its [`Tenant`] and
+/// [`Sender`] as extensions, along with the whole [`Principal`] for handlers
+/// that allow more to some scopes.
+async fn require<B>(
+    state: &AppState,
+    scope: Scope,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    let key = request_key(&request);
+    let principal = state.authenticator.authorize(key.as_deref(), scope).await?;
+    request.extensions_mut().insert(principal.tenant.clone());
+    request.extensions_mut().insert(principal.sender.clone());
+    request.extensions_mut().insert(principal);
+    Ok(next.run(request).await)
+}
+
+/// Reads the bearer token, falling back to an `access_token` query parameter because
+/// browsers can't attach headers to `EventSource` or `WebSocket` connections.
+pub fn request_key<B>(request: &Request<B>) -> Option<String> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_owned)
+        .or_else(|| query_param(request.uri(), "access_token"))
+}
+
+/// The percent-decoded value of the `name` query parameter of `uri`.
+pub fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    let Query(mut params) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    params.remove(name)
+}
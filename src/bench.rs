@@ -0,0 +1,325 @@
+//! The `bench` command: registers synthetic users against a running server,
+//! keeps SSE connections open for them and sends at a steady rate, timing how
+//! long each message takes to arrive on every connection it reaches.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::http::{header, Method, StatusCode};
+use futures::{stream, StreamExt};
+use hyper::{client::HttpConnector, Body, Client, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde_json::{json, Value};
+
+use crate::config::Bench;
+
+/// Registrations and SSE connections made at once while setting up.
+const SETUP_CONCURRENCY: usize = 32;
+/// Prefix of the notification tag carrying the send's sequence number.
+const TAG_PREFIX: &str = "bench-";
+
+#[derive(Debug)]
+pub enum BenchError {
+    Request(hyper::Error),
+    Status(String, StatusCode),
+    Setup(&'static str),
+}
+
+impl Display for BenchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Request(error) => write!(f, "Request failed: {error}"),
+            Self::Status(what, status) => write!(f, "{what} failed with {status}"),
+            Self::Setup(reason) => write!(f, "Invalid options: {reason}"),
+        }
+    }
+}
+
+impl From<hyper::Error> for BenchError {
+    fn from(error: hyper::Error) -> Self {
+        Self::Request(error)
+    }
+}
+
+/// What a run measured.
+pub struct Report {
+    pub sent: u64,
+    pub failed_sends: u64,
+    /// Each message counts once for every connection it arrived on.
+    pub deliveries: usize,
+    /// Delivery latencies, sorted.
+    latencies: Vec<Duration>,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// The latency `percent` of deliveries stayed within.
+    #[must_use]
+    pub fn percentile(&self, percent: usize) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[last * percent.min(100) / 100])
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.sent as f64 / secs;
+        writeln!(
+            f,
+            "Sent {} messages in {secs:.1}s ({rate:.1}/s), {} failed",
+            self.sent, self.failed_sends
+        )?;
+        writeln!(f, "Delivered {} times over SSE", self.deliveries)?;
+        for (label, percent) in [("p50", 50), ("p90", 90), ("p99", 99), ("max", 100)] {
+            match self.percentile(percent) {
+                Some(latency) => writeln!(f, "{label}: {:.2}ms", latency.as_secs_f64() * 1000.0)?,
+                None => writeln!(f, "{label}: -")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When each send left, by sequence number, and the latencies seen so far.
+#[derive(Default)]
+struct Timings {
+    sent_at: Mutex<HashMap<u64, Instant>>,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl Timings {
+    fn sent(&self, seq: u64) {
+        self.sent_at.lock().unwrap().insert(seq, Instant::now());
+    }
+
+    fn arrived(&self, seq: u64) {
+        let Some(sent_at) = self.sent_at.lock().unwrap().get(&seq).copied() else {
+            return;
+        };
+        self.latencies.lock().unwrap().push(sent_at.elapsed());
+    }
+
+    fn sorted_latencies(&self) -> Vec<Duration> {
+        let mut latencies = std::mem::take(&mut *self.latencies.lock().unwrap());
+        latencies.sort_unstable();
+        latencies
+    }
+}
+
+struct Bencher {
+    client: Client<HttpsConnector<HttpConnector>>,
+    options: Bench,
+}
+
+impl Bencher {
+    fn request(&self, method: Method, path: &str, body: Option<&Value>) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("{}{path}", self.options.url.trim_end_matches('/')));
+        if let Some(key) = &self.options.api_key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        request.body(body).expect("Bench requests are valid")
+    }
+
+    async fn call(&self, what: &str, request: Request<Body>) -> Result<(), BenchError> {
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        // Read to the end so the connection can be reused.
+        hyper::body::to_bytes(response.into_body()).await?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(BenchError::Status(what.to_owned(), status))
+        }
+    }
+
+    fn user_id(&self, n: usize) -> String {
+        format!("{}{n}", self.options.user_prefix)
+    }
+
+    /// Registers a user reachable by email only, so nothing goes to real push services.
+    async fn register(&self, n: usize) -> Result<(), BenchError> {
+        let user_id = self.user_id(n);
+        let body = json!({ "user_id": user_id, "email": format!("{user_id}@example.invalid") });
+        let request = self.request(Method::POST, "/register", Some(&body));
+        self.call("Registration", request).await
+    }
+
+    async fn unregister(&self, n: usize) -> Result<(), BenchError> {
+        let request = self.request(
+            Method::DELETE,
+            &format!("/register/{}", self.user_id(n)),
+            None,
+        );
+        self.call("Unregistration", request).await
+    }
+
+    /// Opens an SSE connection and records every bench message arriving on it
+    /// until the server closes it.
+    async fn listen(self: Arc<Self>, n: usize, timings: Arc<Timings>) -> Result<(), BenchError> {
+        let path = format!("/sse?user_id={}", self.user_id(n));
+        let response = self
+            .client
+            .request(self.request(Method::GET, &path, None))
+            .await?;
+        if !response.status().is_success() {
+            return Err(BenchError::Status(
+                "SSE connection".to_owned(),
+                response.status(),
+            ));
+        }
+        tokio::spawn(async move {
+            let mut body = response.into_body();
+            let mut buffer = String::new();
+            while let Some(Ok(chunk)) = body.next().await {
+                buffer.push_str(&String::from_utf8_lossy(&chunk));
+                while let Some(end) = buffer.find("\n\n") {
+                    let event = buffer.drain(..end + 2).collect::<String>();
+                    for data in event.lines().filter_map(|line| line.strip_prefix("data:")) {
+                        for seq in sequence_numbers(data.trim()) {
+                            timings.arrived(seq);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn send(&self, n: usize, seq: u64) -> Result<(), BenchError> {
+        let body = json!({
+            "user_id": self.user_id(n),
+            "data": { "title": "Bench", "tag": format!("{TAG_PREFIX}{seq}") },
+        });
+        self.call("Send", self.request(Method::POST, "/send", Some(&body)))
+            .await
+    }
+}
+
+/// The sequence numbers in an SSE data line, several when the server batches.
+fn sequence_numbers(data: &str) -> Vec<u64> {
+    let Ok(Value::Array(messages)) = serde_json::from_str::<Value>(&format!("[{data}]")) else {
+        return Vec::new();
+    };
+    messages
+        .iter()
+        .filter_map(|message| {
+            message
+                .get("tag")?
+                .as_str()?
+                .strip_prefix(TAG_PREFIX)?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+/// Runs the benchmark described by `options` and unregisters its users again.
+///
+/// # Errors
+///
+/// Fails when the users can't be registered or their connections opened.
+pub async fn run(options: Bench) -> Result<Report, BenchError> {
+    if options.users == 0 || options.connections == 0 || options.rate == 0 {
+        return Err(BenchError::Setup(
+            "users, connections and rate must be positive",
+        ));
+    }
+    let https = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let bencher = Arc::new(Bencher {
+        client: Client::builder().build(https),
+        options,
+    });
+    let users = bencher.options.users;
+    let timings = Arc::new(Timings::default());
+
+    eprintln!("Registering {users} users");
+    stream::iter(0..users)
+        .map(|n| bencher.register(n))
+        .buffer_unordered(SETUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()?;
+
+    let connections = bencher.options.connections;
+    eprintln!("Opening {connections} SSE connections");
+    stream::iter(0..connections)
+        .map(|n| bencher.clone().listen(n % users, timings.clone()))
+        .buffer_unordered(SETUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<(), _>>()?;
+
+    // Only users with a connection can report a delivery.
+    let targets = users.min(connections);
+    let duration = Duration::from_secs(bencher.options.duration_secs);
+    eprintln!(
+        "Sending {}/s for {}s",
+        bencher.options.rate,
+        duration.as_secs()
+    );
+    let failed = Arc::new(AtomicU64::new(0));
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / bencher.options.rate);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let started = Instant::now();
+    let mut sends = Vec::new();
+    let mut seq = 0;
+    while started.elapsed() < duration {
+        interval.tick().await;
+        let (bencher, timings, failed) = (bencher.clone(), timings.clone(), failed.clone());
+        timings.sent(seq);
+        #[allow(clippy::cast_possible_truncation)]
+        let n = seq as usize % targets;
+        sends.push(tokio::spawn(async move {
+            if let Err(error) = bencher.send(n, seq).await {
+                eprintln!("{error}");
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+        seq += 1;
+    }
+    futures::future::join_all(sends).await;
+    let elapsed = started.elapsed();
+    tokio::time::sleep(Duration::from_secs(bencher.options.drain_secs)).await;
+
+    eprintln!("Unregistering {users} users");
+    for result in stream::iter(0..users)
+        .map(|n| bencher.unregister(n))
+        .buffer_unordered(SETUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+    {
+        if let Err(error) = result {
+            eprintln!("{error}");
+        }
+    }
+
+    let latencies = timings.sorted_latencies();
+    Ok(Report {
+        sent: seq,
+        failed_sends: failed.load(Ordering::Relaxed),
+        deliveries: latencies.len(),
+        latencies,
+        elapsed,
+    })
+}
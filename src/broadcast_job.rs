@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    audit::Sender, auth::Tenant, error::AppError, fan_out, notification::PushOptions, recipients,
+    AppState, DeliveryReport,
+};
+
+/// How long a finished broadcast can still be looked up.
+const RETENTION: Duration = Duration::from_hours(1);
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BroadcastOptions {
+    /// Answer `202 Accepted` at once and deliver in the background, reporting
+    /// progress at `/broadcast/{job_id}`.
+    #[serde(default)]
+    pub background: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastState {
+    Running,
+    Completed,
+    /// No further users are delivered to, those in flight still finish.
+    Cancelled,
+}
+
+/// How far a background broadcast got.
+#[derive(Serialize, ToSchema)]
+pub struct BroadcastProgress {
+    pub job_id: Uuid,
+    pub state: BroadcastState,
+    /// Users the broadcast was started for.
+    pub total: usize,
+    /// Users the message got through to, or still may, on some channel.
+    pub sent: usize,
+    /// Users no channel took the message for, or who unregistered meanwhile.
+    pub failed: usize,
+    pub remaining: usize,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+struct Job {
+    tenant: Tenant,
+    total: usize,
+    sent: AtomicUsize,
+    failed: AtomicUsize,
+    cancelled: AtomicBool,
+    started_at: DateTime<Utc>,
+    finished_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Job {
+    fn finished_at(&self) -> Option<DateTime<Utc>> {
+        *self.finished_at.lock().expect("Broadcast job was poisoned")
+    }
+
+    fn progress(&self, job_id: Uuid) -> BroadcastProgress {
+        let sent = self.sent.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+        let finished_at = self.finished_at();
+        let state = if self.cancelled.load(Ordering::Relaxed) {
+            BroadcastState::Cancelled
+        } else if finished_at.is_some() {
+            BroadcastState::Completed
+        } else {
+            BroadcastState::Running
+        };
+        BroadcastProgress {
+            job_id,
+            state,
+            total: self.total,
+            sent,
+            failed,
+            remaining: self.total.saturating_sub(sent + failed),
+            started_at: self.started_at,
+            finished_at,
+        }
+    }
+}
+
+/// Broadcasts delivered in the background, kept in memory until an hour after
+/// they finished.
+pub struct BroadcastJobs {
+    concurrency: usize,
+    jobs: Mutex<HashMap<Uuid, Arc<Job>>>,
+}
+
+impl BroadcastJobs {
+    /// Each broadcast delivers to `BROADCAST_CONCURRENCY` users at the same
+    /// time, 32 by default.
+    pub fn from_env() -> Self {
+        let concurrency = std::env::var("BROADCAST_CONCURRENCY")
+            .ok()
+            .and_then(|concurrency| concurrency.parse().ok())
+            .unwrap_or(32_usize)
+            .max(1);
+        Self {
+            concurrency,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, tenant: &Tenant, job_id: Uuid) -> Result<Arc<Job>, AppError> {
+        self.jobs
+            .lock()
+            .expect("Broadcast jobs were poisoned")
+            .get(&job_id)
+            .filter(|job| job.tenant == *tenant)
+            .cloned()
+            .ok_or(AppError::BroadcastNotFound)
+    }
+
+    pub fn progress(&self, tenant: &Tenant, job_id: Uuid) -> Result<BroadcastProgress, AppError> {
+        Ok(self.get(tenant, job_id)?.progress(job_id))
+    }
+
+    /// Stops the broadcast from starting on further users. Finished broadcasts
+    /// are left as they are.
+    pub fn cancel(&self, tenant: &Tenant, job_id: Uuid) -> Result<BroadcastProgress, AppError> {
+        let job = self.get(tenant, job_id)?;
+        if job.finished_at().is_none() {
+            job.cancelled.store(true, Ordering::Relaxed);
+            info!("Cancelled broadcast {job_id}");
+        }
+        Ok(job.progress(job_id))
+    }
+}
+
+/// Starts delivering `data` to `user_ids` in the background.
+pub fn start(
+    state: &AppState,
+    tenant: Tenant,
+    user_ids: Vec<String>,
+    data: String,
+    options: PushOptions,
+    sender: Sender,
+) -> BroadcastProgress {
+    let job_id = Uuid::new_v4();
+    let job = Arc::new(Job {
+        tenant,
+        total: user_ids.len(),
+        sent: AtomicUsize::new(0),
+        failed: AtomicUsize::new(0),
+        cancelled: AtomicBool::new(false),
+        started_at: Utc::now(),
+        finished_at: Mutex::new(None),
+    });
+    {
+        let mut jobs = state
+            .broadcasts
+            .jobs
+            .lock()
+            .expect("Broadcast jobs were poisoned");
+        let cutoff = Utc::now() - RETENTION;
+        jobs.retain(|_, job| job.finished_at().is_none_or(|at| at > cutoff));
+        jobs.insert(job_id, job.clone());
+    }
+    info!("Broadcasting {job_id} to {} user(s)", job.total);
+    tokio::spawn(run(
+        state.clone(),
+        job_id,
+        job.clone(),
+        user_ids,
+        data,
+        options,
+        sender,
+    ));
+    job.progress(job_id)
+}
+
+async fn run(
+    state: AppState,
+    job_id: Uuid,
+    job: Arc<Job>,
+    user_ids: Vec<String>,
+    data: String,
+    options: PushOptions,
+    sender: Sender,
+) {
+    let (state, job, data, options, sender) = (&state, &job, &data, &options, &sender);
+    futures::stream::iter(user_ids)
+        .take_while(|_| futures::future::ready(!job.cancelled.load(Ordering::Relaxed)))
+        .for_each_concurrent(state.broadcasts.concurrency, |user_id| async move {
+            let reader = state.channels.read().await;
+            let target = reader.get_key_value(&user_id);
+            let recipients = recipients(state, target.into_iter(), data, options, sender);
+            drop(reader);
+            let reached = fan_out(state, recipients)
+                .await
+                .iter()
+                .any(DeliveryReport::reached);
+            let counter = if reached { &job.sent } else { &job.failed };
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+        .await;
+    *job.finished_at.lock().expect("Broadcast job was poisoned") = Some(Utc::now());
+    let progress = job.progress(job_id);
+    info!(
+        "Broadcast {job_id} finished: {} sent, {} failed, {} left",
+        progress.sent, progress.failed, progress.remaining
+    );
+}
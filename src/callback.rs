@@ -0,0 +1,168 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use metrics::increment_counter;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, RwLock};
+use tracing::warn;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    audit::Sender,
+    auth::Tenant,
+    error::AppError,
+    status::{MessageState, MessageStatus, PushState, RealtimeState, Transition},
+    webhook, AppState,
+};
+
+#[derive(Deserialize, ToSchema)]
+pub struct CallbackRequest {
+    pub url: String,
+}
+
+/// A publisher's callback URL.
+#[derive(Serialize, Clone, ToSchema)]
+pub struct CallbackRegistration {
+    pub url: String,
+    /// Key of the HMAC-SHA256 in `X-Webhook-Signature`, only returned when the
+    /// callback is registered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+/// The body posted to a callback when one of the publisher's messages is
+/// delivered, fails or expires.
+#[derive(Serialize, ToSchema)]
+pub struct CallbackEvent {
+    pub event: Transition,
+    pub message_id: Uuid,
+    pub user_id: String,
+    pub state: MessageState,
+    pub push: PushState,
+    pub realtime: RealtimeState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl CallbackEvent {
+    fn new(transition: Transition, status: &MessageStatus) -> Self {
+        Self {
+            event: transition,
+            message_id: status.id,
+            user_id: Tenant::local_part(&status.user_id).to_owned(),
+            state: status.state,
+            push: status.push,
+            realtime: status.realtime,
+            error: status.error.clone(),
+            at: status.updated_at,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Callback {
+    url: String,
+    secret: String,
+}
+
+/// Callback URLs by the API key that registered them, kept in memory only.
+///
+/// Events are signed like subscriber webhooks, with `X-Webhook-Timestamp` and
+/// `X-Webhook-Signature: sha256=<hex>` over `<timestamp>.<body>`, but keyed
+/// with the secret handed out on registration.
+pub struct Callbacks {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    timeout: Duration,
+    registered: RwLock<HashMap<Sender, Callback>>,
+}
+
+impl Callbacks {
+    /// `CALLBACK_TIMEOUT_SECS` bounds each request, 10 seconds by default.
+    pub fn from_env() -> Self {
+        let timeout = std::env::var("CALLBACK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(10);
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            client: Client::builder().build(https),
+            timeout: Duration::from_secs(timeout),
+            registered: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Registers `url` for the messages `sender` sends, replacing its previous
+    /// callback and secret.
+    pub async fn set(&self, sender: Sender, url: String) -> Result<CallbackRegistration, AppError> {
+        webhook::validate_url(&url).map_err(AppError::InvalidCallback)?;
+        let secret = hex::encode(rand::random::<[u8; 32]>());
+        self.registered.write().await.insert(
+            sender,
+            Callback {
+                url: url.clone(),
+                secret: secret.clone(),
+            },
+        );
+        Ok(CallbackRegistration {
+            url,
+            secret: Some(secret),
+        })
+    }
+
+    pub async fn get(&self, sender: &Sender) -> Option<CallbackRegistration> {
+        self.registered
+            .read()
+            .await
+            .get(sender)
+            .map(|callback| CallbackRegistration {
+                url: callback.url.clone(),
+                secret: None,
+            })
+    }
+
+    pub async fn remove(&self, sender: &Sender) -> bool {
+        self.registered.write().await.remove(sender).is_some()
+    }
+}
+
+/// Tells the senders of messages about their transitions, for as long as the
+/// status store reports them.
+pub async fn run(
+    state: AppState,
+    mut transitions: mpsc::UnboundedReceiver<(Transition, MessageStatus)>,
+) {
+    while let Some((transition, status)) = transitions.recv().await {
+        let callbacks = &state.callbacks;
+        let Some(callback) = callbacks
+            .registered
+            .read()
+            .await
+            .get(&status.sender)
+            .cloned()
+        else {
+            continue;
+        };
+        let body = serde_json::to_string(&CallbackEvent::new(transition, &status))
+            .expect("Callback events serialize");
+        let (client, timeout) = (callbacks.client.clone(), callbacks.timeout);
+        tokio::spawn(async move {
+            let secret = callback.secret.as_bytes();
+            let outcome = match webhook::post(&client, timeout, &callback.url, secret, body).await {
+                Ok(()) => "delivered",
+                Err(error) => {
+                    warn!("Callback to {} failed: {error}", callback.url);
+                    "failed"
+                }
+            };
+            increment_counter!("callbacks_total", "outcome" => outcome);
+        });
+    }
+}
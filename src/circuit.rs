@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use metrics::increment_counter;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitConfig {
+    /// Consecutive failures opening the circuit of a push service, never when 0.
+    pub failure_threshold: u32,
+    /// How long an open circuit fails sends before letting a probe through.
+    pub open_for: Duration,
+}
+
+impl CircuitConfig {
+    /// Reads `CIRCUIT_FAILURE_THRESHOLD` and `CIRCUIT_OPEN_SECS`, falling back to
+    /// opening after 5 failures in a row for 30 seconds. A threshold of `0`
+    /// disables the breaker.
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<u64> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+        Self {
+            failure_threshold: var("CIRCUIT_FAILURE_THRESHOLD")
+                .map_or(5, |threshold| u32::try_from(threshold).unwrap_or(u32::MAX)),
+            open_for: Duration::from_secs(var("CIRCUIT_OPEN_SECS").unwrap_or(30).max(1)),
+        }
+    }
+}
+
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single send is testing whether the service recovered.
+    Probing,
+}
+
+/// Circuit breakers keyed by push service origin, so a dead service fails
+/// sends right away instead of holding push slots until requests time out.
+pub struct CircuitBreakers {
+    config: CircuitConfig,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitConfig) -> Self {
+        Self {
+            config,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a send to `origin` may go out, or how long until it's worth trying
+    /// again. Once the circuit has been open for long enough, the next send is let
+    /// through as a probe and the others keep failing until it reports back.
+    pub fn check(&self, origin: &str) -> Result<(), Duration> {
+        if self.config.failure_threshold == 0 {
+            return Ok(());
+        }
+        let mut circuits = self.circuits.lock().expect("Circuits were poisoned");
+        let Some(circuit) = circuits.get_mut(origin) else {
+            return Ok(());
+        };
+        match circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } => {
+                let now = Instant::now();
+                if now < *until {
+                    return Err(*until - now);
+                }
+                *circuit = Circuit::Probing;
+                Ok(())
+            }
+            Circuit::Probing => Err(self.config.open_for),
+        }
+    }
+
+    /// Notes how a send allowed by [`Self::check`] went: `Some(true)` when the
+    /// service answered, `Some(false)` when it failed or throttled, `None` when no
+    /// request was made.
+    pub fn record(&self, origin: &str, healthy: Option<bool>) {
+        if self.config.failure_threshold == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().expect("Circuits were poisoned");
+        match healthy {
+            Some(true) => {
+                if let Some(Circuit::Open { .. } | Circuit::Probing) = circuits.remove(origin) {
+                    info!("Push service {origin} recovered, closing its circuit");
+                }
+            }
+            Some(false) => {
+                let circuit = match circuits.get(origin) {
+                    None => self.after_failure(origin, 1),
+                    Some(Circuit::Closed { failures }) => self.after_failure(origin, failures + 1),
+                    // Sent before the circuit opened.
+                    Some(Circuit::Open { .. }) => return,
+                    Some(Circuit::Probing) => {
+                        warn!("Push service {origin} is still failing");
+                        self.open()
+                    }
+                };
+                circuits.insert(origin.to_owned(), circuit);
+            }
+            None => {
+                // The probe never reached the service, let the next send try.
+                if let Some(circuit @ Circuit::Probing) = circuits.get_mut(origin) {
+                    *circuit = Circuit::Open {
+                        until: Instant::now(),
+                    };
+                }
+            }
+        }
+    }
+
+    fn after_failure(&self, origin: &str, failures: u32) -> Circuit {
+        if failures < self.config.failure_threshold {
+            return Circuit::Closed { failures };
+        }
+        warn!(
+            "Push service {origin} failed {failures} times in a row, opening its circuit for {}s",
+            self.config.open_for.as_secs()
+        );
+        self.open()
+    }
+
+    fn open(&self) -> Circuit {
+        increment_counter!("push_circuit_opened_total");
+        Circuit::Open {
+            until: Instant::now() + self.config.open_for,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "https://push.example.com";
+
+    fn breakers(failure_threshold: u32) -> CircuitBreakers {
+        CircuitBreakers::new(CircuitConfig {
+            failure_threshold,
+            open_for: Duration::from_millis(50),
+        })
+    }
+
+    fn fail(breakers: &CircuitBreakers, times: u32) {
+        for _ in 0..times {
+            breakers.check(ORIGIN).unwrap();
+            breakers.record(ORIGIN, Some(false));
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breakers = breakers(3);
+        fail(&breakers, 2);
+        // An answer in between starts the count over.
+        breakers.record(ORIGIN, Some(true));
+        fail(&breakers, 2);
+        assert!(breakers.check(ORIGIN).is_ok());
+        breakers.record(ORIGIN, Some(false));
+        let wait = breakers.check(ORIGIN).unwrap_err();
+        assert!(wait <= Duration::from_millis(50));
+        assert!(breakers.check("https://other.example.com").is_ok());
+    }
+
+    #[test]
+    fn probe_closes_or_reopens_the_circuit() {
+        let breakers = breakers(1);
+        fail(&breakers, 1);
+        assert!(breakers.check(ORIGIN).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+
+        // One probe at a time, and a failing one opens the circuit again.
+        assert!(breakers.check(ORIGIN).is_ok());
+        assert!(breakers.check(ORIGIN).is_err());
+        breakers.record(ORIGIN, Some(false));
+        assert!(breakers.check(ORIGIN).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+
+        // A probe that never reached the service lets the next send try.
+        assert!(breakers.check(ORIGIN).is_ok());
+        breakers.record(ORIGIN, None);
+        assert!(breakers.check(ORIGIN).is_ok());
+        breakers.record(ORIGIN, Some(true));
+        assert!(breakers.check(ORIGIN).is_ok());
+        assert!(breakers.check(ORIGIN).is_ok());
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breakers = breakers(0);
+        fail(&breakers, 10);
+        assert!(breakers.check(ORIGIN).is_ok());
+    }
+}
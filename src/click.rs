@@ -0,0 +1,40 @@
+use serde_json::{from_str, Value};
+use uuid::Uuid;
+
+/// Points the URLs of messages sent with `track_clicks` at `/c/{message_id}`,
+/// which records the click before redirecting to where the URL led.
+pub struct ClickTracking {
+    /// Prepended to the tracking path, so clients that don't open URLs
+    /// relative to this server get an absolute one.
+    public_url: String,
+}
+
+impl ClickTracking {
+    /// Tracking URLs start with `PUBLIC_URL` when set, and are relative otherwise.
+    pub fn from_env() -> Self {
+        Self {
+            public_url: std::env::var("PUBLIC_URL")
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_owned(),
+        }
+    }
+
+    pub fn url(&self, message_id: Uuid) -> String {
+        format!("{}/c/{message_id}", self.public_url)
+    }
+
+    /// Swaps the `url` of the notification in `data` for the tracking URL,
+    /// returning the new data and the URL it replaced. `None` when there is no
+    /// URL to track.
+    pub fn rewrite(&self, message_id: Uuid, data: &str) -> Option<(String, String)> {
+        let Ok(Value::Object(mut fields)) = from_str::<Value>(data) else {
+            return None;
+        };
+        let Some(Value::String(target)) = fields.remove("url") else {
+            return None;
+        };
+        fields.insert("url".to_owned(), Value::String(self.url(message_id)));
+        Some((Value::Object(fields).to_string(), target))
+    }
+}
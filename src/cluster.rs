@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    journal::SendOutcome,
+    store::{StoreError, Subscription},
+};
+
+const REGISTRATIONS: &str = "notifications:registrations";
+
+/// Redis key holding, per instance, how many connections a user has open there.
+fn presence_key(user_id: &str) -> String {
+    format!("notifications:presence:{user_id}")
+}
+
+fn instance_channel(instance: Uuid) -> String {
+    format!("notifications:instance:{instance}")
+}
+
+/// Changes one instance announces so the others can keep their registry in sync.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    Registered {
+        user_id: String,
+        subscription: Box<Subscription>,
+    },
+    Removed {
+        user_id: String,
+    },
+    /// Everything kept about the user is to be erased.
+    Erased {
+        user_id: String,
+    },
+    /// A real-time message for a user connected to the receiving instance.
+    Deliver {
+        user_id: String,
+        message_id: Uuid,
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        collapse_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<DateTime<Utc>>,
+        /// Whether a connection on the sending instance took the message, so
+        /// the receiving one needn't queue it if its connections closed since.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        delivered: bool,
+    },
+    /// The message now retained for a topic.
+    Retained {
+        topic: String,
+        data: String,
+    },
+    /// How a send the receiving instance queued went on this one.
+    SendResult {
+        request: Uuid,
+        outcome: SendOutcome,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: Uuid,
+    #[serde(flatten)]
+    event: ClusterEvent,
+}
+
+/// Coordinates several instances sharing one Redis server: registrations are
+/// announced on a shared channel and real-time messages are forwarded to the
+/// instance that holds the user's connection.
+pub struct Cluster {
+    client: Client,
+    connection: MultiplexedConnection,
+    instance: Uuid,
+}
+
+impl Cluster {
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let client = Client::open(url)?;
+        let connection = client.get_multiplexed_tokio_connection().await?;
+        Ok(Self {
+            client,
+            connection,
+            instance: Uuid::new_v4(),
+        })
+    }
+
+    /// The id other instances address this one by.
+    pub const fn instance(&self) -> Uuid {
+        self.instance
+    }
+
+    pub async fn ping(&self) -> Result<(), StoreError> {
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// Events published by other instances, addressed to everyone or to this one.
+    pub async fn events(&self) -> Result<impl Stream<Item = ClusterEvent>, StoreError> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(REGISTRATIONS).await?;
+        pubsub.subscribe(instance_channel(self.instance)).await?;
+        let instance = self.instance;
+        Ok(pubsub
+            .into_on_message()
+            .filter_map(move |message| async move {
+                let payload = message.get_payload::<String>().ok()?;
+                match serde_json::from_str::<Envelope>(&payload) {
+                    Ok(envelope) => (envelope.origin != instance).then_some(envelope.event),
+                    Err(error) => {
+                        error!("Cluster event could not be decoded: {error}");
+                        None
+                    }
+                }
+            }))
+    }
+
+    pub async fn announce(&self, event: ClusterEvent) -> Result<(), StoreError> {
+        self.publish(REGISTRATIONS.to_owned(), event).await?;
+        Ok(())
+    }
+
+    /// Records one more connection for `user_id` on this instance.
+    pub async fn join(&self, user_id: &str) -> Result<(), StoreError> {
+        self.connection
+            .clone()
+            .hincr::<_, _, _, ()>(presence_key(user_id), self.instance.to_string(), 1)
+            .await?;
+        Ok(())
+    }
+
+    /// Records `count` fewer connections for `user_id` on this instance.
+    pub async fn leave(&self, user_id: &str, count: usize) -> Result<(), StoreError> {
+        let key = presence_key(user_id);
+        let field = self.instance.to_string();
+        let mut connection = self.connection.clone();
+        let remaining: i64 = connection
+            .hincr(&key, &field, -i64::try_from(count).unwrap_or(i64::MAX))
+            .await?;
+        if remaining <= 0 {
+            connection.hdel::<_, _, ()>(&key, &field).await?;
+        }
+        Ok(())
+    }
+
+    /// Connections `user_id` has open on the other instances. Counts left by
+    /// instances that went away without leaving, whose channel nobody listens
+    /// on any more, are dropped instead.
+    pub async fn remote_connections(&self, user_id: &str) -> Result<usize, StoreError> {
+        let key = presence_key(user_id);
+        let mut connection = self.connection.clone();
+        let counts: HashMap<String, i64> = connection.hgetall(&key).await?;
+        let others = counts
+            .into_iter()
+            .filter_map(|(field, count)| {
+                let instance = field.parse::<Uuid>().ok()?;
+                (instance != self.instance).then_some((field, instance, count))
+            })
+            .collect::<Vec<_>>();
+        if others.is_empty() {
+            return Ok(0);
+        }
+        let mut numsub = redis::cmd("PUBSUB");
+        numsub.arg("NUMSUB");
+        for (_, instance, _) in &others {
+            numsub.arg(instance_channel(*instance));
+        }
+        let listeners: Vec<(String, usize)> = numsub.query_async(&mut connection).await?;
+        let mut total = 0;
+        for ((field, _, count), (_, listeners)) in others.iter().zip(listeners) {
+            if listeners == 0 {
+                connection.hdel::<_, _, ()>(&key, field).await?;
+            } else {
+                total += usize::try_from(*count).unwrap_or(0);
+            }
+        }
+        Ok(total)
+    }
+
+    /// Forwards a message to every other instance the user is connected to,
+    /// `delivered` telling them whether a connection here already took it.
+    /// Returns whether any of them was listening.
+    pub async fn route(
+        &self,
+        user_id: &str,
+        message_id: Uuid,
+        data: &str,
+        collapse_key: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        delivered: bool,
+    ) -> Result<bool, StoreError> {
+        let key = presence_key(user_id);
+        let mut connection = self.connection.clone();
+        let instances: Vec<String> = connection.hkeys(&key).await?;
+        let mut routed = false;
+        for field in instances {
+            let Ok(instance) = field.parse::<Uuid>() else {
+                continue;
+            };
+            if instance == self.instance {
+                continue;
+            }
+            let event = ClusterEvent::Deliver {
+                user_id: user_id.to_owned(),
+                message_id,
+                data: data.to_owned(),
+                collapse_key: collapse_key.map(ToOwned::to_owned),
+                expires_at,
+                delivered,
+            };
+            if self.publish(instance_channel(instance), event).await? > 0 {
+                routed = true;
+            } else {
+                // Nobody is subscribed, so that instance is gone and its presence is stale.
+                connection.hdel::<_, _, ()>(&key, &field).await?;
+            }
+        }
+        Ok(routed)
+    }
+
+    /// Sends `event` to a single instance, returning whether it was listening.
+    pub async fn tell(&self, instance: Uuid, event: ClusterEvent) -> Result<bool, StoreError> {
+        Ok(self.publish(instance_channel(instance), event).await? > 0)
+    }
+
+    async fn publish(&self, channel: String, event: ClusterEvent) -> Result<usize, StoreError> {
+        let envelope = Envelope {
+            origin: self.instance,
+            event,
+        };
+        let payload = serde_json::to_string(&envelope)
+            .map_err(|error| StoreError::Corrupt(error.to_string()))?;
+        Ok(self.connection.clone().publish(channel, payload).await?)
+    }
+}
@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::FromRequest,
+    http::{header, Request},
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use base64ct::{Base64, Encoding as _};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+
+/// Serialization formats of request bodies and SSE data.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Self::Msgpack)
+            }
+            "application/cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(|error| error.to_string()),
+            Self::Msgpack => rmp_serde::from_slice(bytes).map_err(|error| error.to_string()),
+            Self::Cbor => ciborium::from_reader(bytes).map_err(|error| error.to_string()),
+        }
+    }
+
+    /// Re-encodes the JSON of an SSE event, as base64 for the binary formats since
+    /// event data is text.
+    pub fn sse_data(self, json: String) -> String {
+        if self == Self::Json {
+            return json;
+        }
+        let Ok(value) = serde_json::from_str::<Value>(&json) else {
+            return json;
+        };
+        let mut bytes = Vec::new();
+        let encoded = if self == Self::Msgpack {
+            rmp_serde::encode::write_named(&mut bytes, &value).is_ok()
+        } else {
+            ciborium::into_writer(&value, &mut bytes).is_ok()
+        };
+        if encoded {
+            Base64::encode_string(&bytes)
+        } else {
+            json
+        }
+    }
+}
+
+/// A request body in JSON, or in msgpack or CBOR when the `Content-Type` says
+/// so, for publishers where every byte counts.
+pub struct Payload<T>(pub T);
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for Payload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let encoding = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Encoding::from_content_type);
+        let Some(encoding) = encoding else {
+            return Json::<T>::from_request(request, state)
+                .await
+                .map(|Json(value)| Self(value))
+                .map_err(IntoResponse::into_response);
+        };
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        encoding
+            .decode(&bytes)
+            .map(Self)
+            .map_err(|error| AppError::InvalidBody(error).into_response())
+    }
+}
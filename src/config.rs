@@ -0,0 +1,615 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::Level;
+
+/// Command line arguments. Anything left out falls back to the config file, then
+/// to the built-in default.
+#[derive(Parser, Debug)]
+#[command(version, about = "Web Push, SSE and WebSocket notification server.")]
+struct Cli {
+    /// TOML file using the same keys as the long options, with underscores for dashes.
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "BIND_ADDRESS")]
+    bind: Option<IpAddr>,
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+    #[arg(long, env = "VAPID_KEY_PATH")]
+    vapid_file: Option<PathBuf>,
+    /// The VAPID key being rotated out. Registrations created under it are still
+    /// signed with it, new ones get the key of `--vapid-file`.
+    #[arg(long, env = "VAPID_SECONDARY_KEY_PATH")]
+    vapid_secondary_file: Option<PathBuf>,
+    /// Seconds between SSE `heartbeat` events.
+    #[arg(long)]
+    keep_alive_secs: Option<u64>,
+    /// Milliseconds SSE clients wait before reconnecting, sent as the `retry:`
+    /// field when set.
+    #[arg(long)]
+    sse_retry_ms: Option<u64>,
+    /// Compresses `/sse` streams with gzip or brotli for clients accepting it, on
+    /// by default.
+    #[arg(long, value_name = "BOOL")]
+    sse_compression: Option<bool>,
+    /// Compresses the demo frontend's files, on by default.
+    #[arg(long, value_name = "BOOL")]
+    asset_compression: Option<bool>,
+    /// Messages buffered per connection before `--overflow-policy` applies.
+    #[arg(long)]
+    channel_buffer: Option<usize>,
+    /// Percent of a connection's buffer filling up before it is reported as
+    /// lagging, 80 by default, 0 turns this off.
+    #[arg(long)]
+    lag_threshold_percent: Option<u8>,
+    /// What happens to messages for a connection whose buffer is full.
+    #[arg(long, value_enum)]
+    overflow_policy: Option<OverflowPolicy>,
+    /// SSE connections the server accepts in total.
+    #[arg(long)]
+    max_sse_connections: Option<usize>,
+    /// SSE connections a single user may hold.
+    #[arg(long)]
+    max_sse_connections_per_user: Option<usize>,
+    /// One of `trace`, `debug`, `info`, `warn` or `error`.
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// Minimum milliseconds between two SSE events, 0 disables throttling.
+    #[arg(long)]
+    sse_throttle_ms: Option<u64>,
+    /// Groups SSE messages arriving within this many milliseconds into one event.
+    #[arg(long)]
+    sse_batch_ms: Option<u64>,
+    /// Items of a `/send/batch` request delivered at the same time.
+    #[arg(long)]
+    batch_concurrency: Option<usize>,
+    /// Pushes sent at the same time, the rest wait by `priority`.
+    #[arg(long)]
+    push_concurrency: Option<usize>,
+    /// Pushes sent to one push service at the same time, unlimited when unset.
+    #[arg(long)]
+    push_origin_concurrency: Option<usize>,
+    /// `HOST=N` limits the pushes sent to one push service at the same time,
+    /// overriding `--push-origin-concurrency`, may be repeated.
+    #[arg(long = "push-origin-limit", value_parser = parse_origin_limit)]
+    push_origin_limits: Vec<(String, usize)>,
+    /// Seconds an `Idempotency-Key` of `/send` is remembered for.
+    #[arg(long)]
+    idempotency_window_secs: Option<u64>,
+    /// PEM certificate chain, serves HTTPS together with `--tls-key`.
+    #[arg(long, env = "TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`.
+    #[arg(long, env = "TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Serves the demo frontend from this directory instead of the copy built into
+    /// the binary, so it can be edited without rebuilding.
+    #[arg(long, env = "STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+    /// Serves `/demo/seed`, which anyone can call to fill the server with sample
+    /// users and a stream of synthetic notifications for the demo page.
+    #[arg(long)]
+    demo: bool,
+    /// Serves HTTPS with a Let's Encrypt certificate for this domain, may be repeated.
+    #[cfg(feature = "acme")]
+    #[arg(long = "acme-domain")]
+    acme_domains: Vec<String>,
+    /// Contact address for the ACME account.
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    acme_email: Option<String>,
+    /// Directory keeping the ACME account and certificates across restarts.
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    acme_cache: Option<PathBuf>,
+    /// Uses the Let's Encrypt production directory instead of staging.
+    #[cfg(feature = "acme")]
+    #[arg(long)]
+    acme_production: bool,
+    /// Also serves the publisher API over gRPC on this port.
+    #[cfg(feature = "grpc")]
+    #[arg(long, env = "GRPC_PORT")]
+    grpc_port: Option<u16>,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Generates a VAPID key file instead of starting the server.
+    GenVapid(GenVapid),
+    /// Load-tests a running server and reports how long SSE delivery takes.
+    Bench(Bench),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct GenVapid {
+    /// Contact for push services, a `mailto:` or `https:` URL.
+    #[arg(long, value_parser = parse_subject)]
+    pub subject: String,
+    /// Where to write the key file, printed to stdout when unset.
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+    /// Replaces an existing `--out` file.
+    #[arg(long)]
+    pub force: bool,
+    /// Also prints the public key to pass to `pushManager.subscribe` as
+    /// `applicationServerKey`.
+    #[arg(long)]
+    pub print_public_key: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct Bench {
+    /// Base URL of the server under test.
+    #[arg(long, default_value = "http://127.0.0.1:3000")]
+    pub url: String,
+    /// Bearer token sent with every request, when the server requires API keys.
+    #[arg(long, env = "BENCH_API_KEY")]
+    pub api_key: Option<String>,
+    /// Synthetic users to register, named `<prefix><n>`.
+    #[arg(long, default_value_t = 100)]
+    pub users: usize,
+    #[arg(long, default_value = "bench-")]
+    pub user_prefix: String,
+    /// SSE connections to open, spread over the users.
+    #[arg(long, default_value_t = 100)]
+    pub connections: usize,
+    /// Sends per second.
+    #[arg(long, default_value_t = 50)]
+    pub rate: u32,
+    /// Seconds to keep sending for.
+    #[arg(long, default_value_t = 30)]
+    pub duration_secs: u64,
+    /// Seconds to wait for stragglers after the last send.
+    #[arg(long, default_value_t = 5)]
+    pub drain_secs: u64,
+}
+
+fn parse_subject(subject: &str) -> Result<String, String> {
+    if subject.starts_with("mailto:") || subject.starts_with("https://") {
+        Ok(subject.to_owned())
+    } else {
+        Err("expected a `mailto:` or `https://` URL".to_owned())
+    }
+}
+
+fn parse_origin_limit(limit: &str) -> Result<(String, usize), String> {
+    let (host, slots) = limit
+        .split_once('=')
+        .ok_or_else(|| "expected `HOST=N`".to_owned())?;
+    let slots = slots
+        .parse::<usize>()
+        .ok()
+        .filter(|slots| *slots > 0)
+        .ok_or_else(|| format!("`{slots}` isn't a positive number"))?;
+    Ok((host_of(host).to_owned(), slots))
+}
+
+/// `origin` without its scheme, so limits can be given either way.
+fn host_of(origin: &str) -> &str {
+    let host = origin.split_once("://").map_or(origin, |(_, host)| host);
+    host.trim_end_matches('/')
+}
+
+/// What the process was asked to do.
+#[derive(Debug)]
+pub enum Command {
+    Serve(Box<Config>),
+    GenVapid(GenVapid),
+    Bench(Bench),
+}
+
+impl Command {
+    /// Parses the command line, merged over the optional `--config` file when serving.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the config file can't be read or parsed, or options conflict.
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let mut cli = Cli::parse();
+        match cli.command.take() {
+            Some(CliCommand::GenVapid(options)) => Ok(Self::GenVapid(options)),
+            Some(CliCommand::Bench(options)) => Ok(Self::Bench(options)),
+            None => Config::from_cli(cli).map(|config| Self::Serve(Box::new(config))),
+        }
+    }
+}
+
+/// The `[cors]` table of the config file.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CorsConfig {
+    /// Origins allowed to call the API, `*` for any of them.
+    allowed_origins: Vec<String>,
+    #[serde(default)]
+    allow_credentials: bool,
+    /// Response headers scripts may read besides the CORS-safelisted ones.
+    #[serde(default)]
+    expose_headers: Vec<String>,
+    max_age_secs: Option<u64>,
+}
+
+impl TryFrom<CorsConfig> for CorsLayer {
+    type Error = ConfigError;
+
+    fn try_from(config: CorsConfig) -> Result<Self, Self::Error> {
+        let invalid = |error: &dyn Display| ConfigError::InvalidCors(error.to_string());
+        let origins = if config.allowed_origins.iter().any(|origin| origin == "*") {
+            if config.allow_credentials {
+                return Err(ConfigError::Conflict(
+                    "`cors.allow_credentials` can't be used with a `*` origin",
+                ));
+            }
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                config
+                    .allowed_origins
+                    .iter()
+                    .map(|origin| HeaderValue::from_str(origin).map_err(|error| invalid(&error)))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        };
+        let expose_headers = config
+            .expose_headers
+            .iter()
+            .map(|name| HeaderName::from_str(name).map_err(|error| invalid(&error)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut layer = Self::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("last-event-id"),
+            ])
+            .expose_headers(expose_headers)
+            .allow_credentials(config.allow_credentials);
+        if let Some(secs) = config.max_age_secs {
+            layer = layer.max_age(Duration::from_secs(secs));
+        }
+        Ok(layer)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    vapid_file: Option<PathBuf>,
+    vapid_secondary_file: Option<PathBuf>,
+    keep_alive_secs: Option<u64>,
+    sse_retry_ms: Option<u64>,
+    sse_compression: Option<bool>,
+    asset_compression: Option<bool>,
+    channel_buffer: Option<usize>,
+    lag_threshold_percent: Option<u8>,
+    overflow_policy: Option<OverflowPolicy>,
+    max_sse_connections: Option<usize>,
+    max_sse_connections_per_user: Option<usize>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    sse_throttle_ms: Option<u64>,
+    sse_batch_ms: Option<u64>,
+    batch_concurrency: Option<usize>,
+    push_concurrency: Option<usize>,
+    push_origin_concurrency: Option<usize>,
+    /// By host, as in `push_origin_limits = { "fcm.googleapis.com" = 50 }`.
+    push_origin_limits: Option<HashMap<String, usize>>,
+    idempotency_window_secs: Option<u64>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    static_dir: Option<PathBuf>,
+    demo: Option<bool>,
+    cors: Option<CorsConfig>,
+    #[cfg(feature = "acme")]
+    acme_domains: Option<Vec<String>>,
+    #[cfg(feature = "acme")]
+    acme_email: Option<String>,
+    #[cfg(feature = "acme")]
+    acme_cache: Option<PathBuf>,
+    #[cfg(feature = "acme")]
+    acme_production: Option<bool>,
+    #[cfg(feature = "grpc")]
+    grpc_port: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    InvalidLogLevel(String),
+    InvalidCors(String),
+    Conflict(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(path, error) => write!(f, "{} could not be read: {error}", path.display()),
+            Self::Parse(error) => write!(f, "Invalid configuration file: {error}"),
+            Self::InvalidLogLevel(level) => write!(f, "Unknown log level `{level}`"),
+            Self::InvalidCors(reason) => write!(f, "Invalid `[cors]` table: {reason}"),
+            Self::Conflict(reason) => write!(f, "Conflicting options: {reason}"),
+        }
+    }
+}
+
+/// How log lines are written to stdout.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans.
+    Json,
+}
+
+/// What a connection whose buffer is full does with another message.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Makes room by dropping the oldest buffered message.
+    #[default]
+    DropOldest,
+    /// Drops the new message.
+    DropNewest,
+    /// Closes the connection with an error, the client is expected to reconnect.
+    Disconnect,
+}
+
+/// How `/sse` paces the messages it sends to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SseDelivery {
+    /// Every message is sent as soon as it arrives.
+    Immediate,
+    /// At most one event per interval, the rest wait their turn.
+    Throttle(Duration),
+    /// Messages arriving within the window are sent together as one JSON array.
+    Batch(Duration),
+}
+
+/// Where the certificate for HTTPS comes from.
+#[derive(Debug, Clone)]
+pub enum Tls {
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    /// Obtained and renewed through ACME (TLS-ALPN-01) from Let's Encrypt.
+    #[cfg(feature = "acme")]
+    Acme {
+        domains: Vec<String>,
+        email: Option<String>,
+        cache: Option<PathBuf>,
+        production: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub vapid_file: PathBuf,
+    /// The key being rotated out, kept for the registrations created under it.
+    pub vapid_secondary_file: Option<PathBuf>,
+    pub keep_alive: Duration,
+    /// Clients pick their own reconnect delay when unset.
+    pub sse_retry: Option<Duration>,
+    /// Whether `/sse` responses are compressed when the client accepts it.
+    pub sse_compression: bool,
+    /// Whether the frontend's files are compressed when the client accepts it.
+    pub asset_compression: bool,
+    pub channel_buffer: usize,
+    /// Messages queued for a connection before it counts as lagging, never
+    /// when unset.
+    pub lag_threshold: Option<usize>,
+    pub overflow_policy: OverflowPolicy,
+    /// Unlimited when unset.
+    pub max_sse_connections: Option<usize>,
+    pub max_sse_connections_per_user: Option<usize>,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub sse_delivery: SseDelivery,
+    pub batch_concurrency: usize,
+    pub push_concurrency: usize,
+    /// Unlimited when unset.
+    pub push_origin_concurrency: Option<usize>,
+    /// By host, with the port if the push service's URLs have one.
+    pub push_origin_limits: HashMap<String, usize>,
+    pub idempotency_window: Duration,
+    /// Plain HTTP when unset.
+    pub tls: Option<Tls>,
+    /// The built-in frontend is served when unset.
+    pub static_dir: Option<PathBuf>,
+    /// Whether `/demo/seed` is served.
+    pub demo: bool,
+    /// No gRPC server when unset.
+    #[cfg(feature = "grpc")]
+    pub grpc_port: Option<u16>,
+    /// Built from the `[cors]` table, cross-origin requests are refused without it.
+    pub cors: Option<CorsLayer>,
+}
+
+impl SseDelivery {
+    /// Throttles or batches when either is non-zero, but not both.
+    const fn from_millis(throttle: u64, batch: u64) -> Result<Self, ConfigError> {
+        match (throttle, batch) {
+            (0, 0) => Ok(Self::Immediate),
+            (throttle, 0) => Ok(Self::Throttle(Duration::from_millis(throttle))),
+            (0, batch) => Ok(Self::Batch(Duration::from_millis(batch))),
+            _ => Err(ConfigError::Conflict(
+                "`sse_throttle_ms` and `sse_batch_ms` can't both be set",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults with the given VAPID key file, ignoring the real command line.
+    pub(crate) fn for_tests(vapid_file: PathBuf) -> Self {
+        let cli = Cli::parse_from(["axum-notification-test"]);
+        Self {
+            vapid_file,
+            ..Self::from_cli(cli).expect("The defaults are valid")
+        }
+    }
+}
+
+impl Config {
+    /// TLS from certificate files or, with the `acme` feature, ACME domains.
+    fn tls(cli: &mut Cli, file: &mut FileConfig) -> Result<Option<Tls>, ConfigError> {
+        let tls = match (
+            cli.tls_cert.take().or_else(|| file.tls_cert.take()),
+            cli.tls_key.take().or_else(|| file.tls_key.take()),
+        ) {
+            (Some(cert), Some(key)) => Some(Tls::Files { cert, key }),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Conflict(
+                    "`tls_cert` and `tls_key` have to be set together",
+                ))
+            }
+        };
+        #[cfg(feature = "acme")]
+        let tls = {
+            let domains = if cli.acme_domains.is_empty() {
+                file.acme_domains.take().unwrap_or_default()
+            } else {
+                std::mem::take(&mut cli.acme_domains)
+            };
+            match (tls, domains.is_empty()) {
+                (tls, true) => tls,
+                (None, false) => Some(Tls::Acme {
+                    domains,
+                    email: cli.acme_email.take().or_else(|| file.acme_email.take()),
+                    cache: cli.acme_cache.take().or_else(|| file.acme_cache.take()),
+                    production: cli.acme_production || file.acme_production.unwrap_or(false),
+                }),
+                (Some(_), false) => {
+                    return Err(ConfigError::Conflict(
+                        "`acme_domains` can't be combined with `tls_cert`",
+                    ))
+                }
+            }
+        };
+        Ok(tls)
+    }
+
+    /// Merges the command line over the optional `--config` file.
+    fn from_cli(mut cli: Cli) -> Result<Self, ConfigError> {
+        let mut file = match &cli.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|error| ConfigError::Read(path.clone(), error))?;
+                toml::from_str::<FileConfig>(&content).map_err(ConfigError::Parse)?
+            }
+            None => FileConfig::default(),
+        };
+        let tls = Self::tls(&mut cli, &mut file)?;
+        let log_level = cli.log_level.or(file.log_level);
+        let channel_buffer = cli.channel_buffer.or(file.channel_buffer).unwrap_or(100);
+        let sse_delivery = SseDelivery::from_millis(
+            cli.sse_throttle_ms.or(file.sse_throttle_ms).unwrap_or(0),
+            cli.sse_batch_ms.or(file.sse_batch_ms).unwrap_or(0),
+        )?;
+        Ok(Self {
+            bind: cli
+                .bind
+                .or(file.bind)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: cli.port.or(file.port).unwrap_or(13700),
+            vapid_file: cli
+                .vapid_file
+                .or(file.vapid_file)
+                .unwrap_or_else(|| "vapid.json".into()),
+            vapid_secondary_file: cli.vapid_secondary_file.or(file.vapid_secondary_file),
+            keep_alive: Duration::from_secs(
+                cli.keep_alive_secs.or(file.keep_alive_secs).unwrap_or(10),
+            ),
+            sse_retry: cli
+                .sse_retry_ms
+                .or(file.sse_retry_ms)
+                .map(Duration::from_millis),
+            sse_compression: cli.sse_compression.or(file.sse_compression).unwrap_or(true),
+            asset_compression: cli
+                .asset_compression
+                .or(file.asset_compression)
+                .unwrap_or(true),
+            channel_buffer,
+            lag_threshold: match cli
+                .lag_threshold_percent
+                .or(file.lag_threshold_percent)
+                .unwrap_or(80)
+            {
+                0 => None,
+                percent => Some((channel_buffer * usize::from(percent.min(100)) / 100).max(1)),
+            },
+            overflow_policy: cli
+                .overflow_policy
+                .or(file.overflow_policy)
+                .unwrap_or_default(),
+            max_sse_connections: cli.max_sse_connections.or(file.max_sse_connections),
+            max_sse_connections_per_user: cli
+                .max_sse_connections_per_user
+                .or(file.max_sse_connections_per_user),
+            log_level: match log_level {
+                Some(level) => {
+                    Level::from_str(&level).map_err(|_| ConfigError::InvalidLogLevel(level))?
+                }
+                None => Level::INFO,
+            },
+            log_format: cli.log_format.or(file.log_format).unwrap_or_default(),
+            sse_delivery,
+            batch_concurrency: cli
+                .batch_concurrency
+                .or(file.batch_concurrency)
+                .unwrap_or(16)
+                .max(1),
+            push_concurrency: cli
+                .push_concurrency
+                .or(file.push_concurrency)
+                .unwrap_or(64)
+                .max(1),
+            push_origin_concurrency: cli
+                .push_origin_concurrency
+                .or(file.push_origin_concurrency)
+                .map(|slots| slots.max(1)),
+            push_origin_limits: file
+                .push_origin_limits
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(host, slots)| (host_of(&host).to_owned(), slots.max(1)))
+                .chain(cli.push_origin_limits)
+                .collect(),
+            idempotency_window: Duration::from_secs(
+                cli.idempotency_window_secs
+                    .or(file.idempotency_window_secs)
+                    .unwrap_or(24 * 3600),
+            ),
+            tls,
+            static_dir: cli.static_dir.or(file.static_dir),
+            demo: cli.demo || file.demo.unwrap_or(false),
+            #[cfg(feature = "grpc")]
+            grpc_port: cli.grpc_port.or(file.grpc_port),
+            cors: file.cors.map(CorsLayer::try_from).transpose()?,
+        })
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    audit::Sender,
+    fan_out,
+    notification::{Notification, PushOptions},
+    recipients, AppState,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConsentConfig {
+    /// How long a registration's consent lasts, forever when unset.
+    pub ttl: Option<Duration>,
+    /// How long before the consent expires the user is asked to renew it.
+    pub notice: Duration,
+    /// Time between two looks for users to ask.
+    pub interval: Duration,
+}
+
+impl ConsentConfig {
+    /// Reads `CONSENT_TTL_SECS`, unset or `0` for consent that never expires,
+    /// `CONSENT_RENEWAL_NOTICE_SECS`, a week by default, and
+    /// `CONSENT_CHECK_INTERVAL_SECS`, hourly by default.
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<u64> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+        Self {
+            ttl: var("CONSENT_TTL_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            notice: Duration::from_secs(
+                var("CONSENT_RENEWAL_NOTICE_SECS").unwrap_or(7 * 24 * 3600),
+            ),
+            interval: Duration::from_secs(
+                var("CONSENT_CHECK_INTERVAL_SECS").unwrap_or(3600).max(1),
+            ),
+        }
+    }
+
+    /// When consent given at `now` runs out.
+    pub fn expiry(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let ttl = chrono::Duration::from_std(self.ttl?).ok()?;
+        now.checked_add_signed(ttl)
+    }
+}
+
+/// A notification, so push services show it, that also names the event and the
+/// expiry for clients reading it over SSE.
+fn renewal_notice(expires_at: DateTime<Utc>) -> String {
+    let mut notice = Notification::new(
+        "Keep getting notifications?",
+        "Your consent to notifications is about to expire, renew it to keep receiving them.",
+    );
+    notice.tag = Some("renewal_required".to_owned());
+    let mut data = serde_json::to_value(notice).expect("Notifications serialize");
+    data["event"] = json!("renewal_required");
+    data["expires_at"] = json!(expires_at);
+    data.to_string()
+}
+
+/// Asks users whose consent expires within the notice period to renew it, once
+/// per consent period.
+pub async fn run(state: AppState) {
+    let Ok(notice) = chrono::Duration::from_std(state.consent_config.notice) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(state.consent_config.interval);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let due = {
+            let mut channels = state.channels.write().await;
+            channels
+                .iter_mut()
+                .filter_map(|(user_id, reg)| {
+                    let expires_at = reg.subscription.expires_at?;
+                    let due =
+                        !reg.renewal_requested && expires_at > now && expires_at - notice <= now;
+                    reg.renewal_requested |= due;
+                    due.then(|| (user_id.clone(), expires_at))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (user_id, expires_at) in &due {
+            let reader = state.channels.read().await;
+            let recipients = recipients(
+                &state,
+                reader.get_key_value(user_id).into_iter(),
+                &renewal_notice(*expires_at),
+                &PushOptions::default(),
+                &Sender::internal("consent"),
+            );
+            drop(reader);
+            fan_out(&state, recipients).await;
+        }
+        if !due.is_empty() {
+            info!("Asked {} user(s) to renew their consent.", due.len());
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use metrics::increment_counter;
+use redis::{aio::MultiplexedConnection, FromRedisValue, Value};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::store::StoreError;
+
+/// How long a Redis entry a worker took may go unacknowledged before another
+/// one takes it over, assuming the first went away with it.
+const REDIS_CLAIM_AFTER: Duration = Duration::from_mins(1);
+/// How often the entries an instance is still working on are claimed again,
+/// keeping them from looking abandoned however long they wait or take.
+const REDIS_KEEP_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a Redis read waits for new entries before checking for abandoned ones.
+const REDIS_BLOCK: Duration = Duration::from_secs(5);
+
+/// Whom to tell how a send went: the request waiting on an instance.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplyTo {
+    pub instance: Uuid,
+    pub request: Uuid,
+}
+
+/// A journaled send on its way to a worker.
+pub struct Job {
+    /// The queue's id for it, acknowledged once it was delivered.
+    pub id: String,
+    /// The journal entry, completed once it was delivered.
+    pub entry: String,
+    /// The sealed journal entry.
+    pub payload: String,
+    /// Unset for replayed sends, nobody waits for those.
+    pub reply_to: Option<ReplyTo>,
+}
+
+/// Hands accepted sends to the workers delivering them.
+#[async_trait]
+pub trait DeliveryQueue: Send + Sync {
+    async fn push(&self, job: Job) -> Result<(), StoreError>;
+    /// Waits for the next job for this instance's workers.
+    async fn pop(&self) -> Option<Job>;
+    /// Drops a job that was delivered.
+    async fn ack(&self, id: &str) -> Result<(), StoreError>;
+    /// Sets aside a job no worker can deliver, so it isn't handed out again.
+    async fn bury(&self, job: &Job) -> Result<(), StoreError>;
+    /// The id and payload of every job not yet delivered that this instance
+    /// can't rely on delivering soon.
+    async fn waiting(&self) -> Result<Vec<(String, String)>, StoreError>;
+}
+
+/// Delivers every send on the instance that accepted it.
+pub struct MemoryQueue {
+    sender: mpsc::UnboundedSender<Job>,
+    receiver: Mutex<mpsc::UnboundedReceiver<Job>>,
+}
+
+impl Default for MemoryQueue {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+#[async_trait]
+impl DeliveryQueue for MemoryQueue {
+    async fn push(&self, job: Job) -> Result<(), StoreError> {
+        self.sender
+            .send(job)
+            .expect("The queue holds its own receiver");
+        Ok(())
+    }
+
+    async fn pop(&self) -> Option<Job> {
+        self.receiver.lock().await.recv().await
+    }
+
+    async fn ack(&self, _id: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn bury(&self, _job: &Job) -> Result<(), StoreError> {
+        // Jobs are handed out once, and the journal keeps what this one was.
+        Ok(())
+    }
+
+    async fn waiting(&self) -> Result<Vec<(String, String)>, StoreError> {
+        // Whatever is in the channel is delivered moments from now.
+        Ok(Vec::new())
+    }
+}
+
+/// A Redis stream read through a consumer group, so every instance sharing
+/// the server takes sends off it as its workers free up, whichever instance
+/// accepted them. Entries stay in the stream until they were delivered, so it
+/// doubles as the journal.
+pub struct RedisQueue {
+    connection: MultiplexedConnection,
+    jobs: Mutex<mpsc::Receiver<Job>>,
+    /// Ids of the entries read but not acknowledged yet, buffered or delivering.
+    taken: Taken,
+}
+
+type Taken = Arc<std::sync::Mutex<HashSet<String>>>;
+
+impl RedisQueue {
+    const KEY: &'static str = "notifications:send-queue";
+    const GROUP: &'static str = "workers";
+    /// Where entries that couldn't be opened are moved, to be added back to
+    /// the queue once the right secret is configured.
+    const DEAD_KEY: &'static str = "notifications:send-queue:dead";
+
+    /// Joins the consumer group as `instance`, reading at most `workers` sends
+    /// ahead of the workers.
+    pub async fn connect(url: &str, instance: Uuid, workers: usize) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        let mut connection = client.get_multiplexed_tokio_connection().await?;
+        let created = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg(Self::KEY)
+            .arg(Self::GROUP)
+            .arg("0")
+            .arg("MKSTREAM")
+            .query_async::<_, ()>(&mut connection)
+            .await;
+        match created {
+            Err(error) if error.code() != Some("BUSYGROUP") => return Err(error.into()),
+            _ => {}
+        }
+        // Blocking reads would hold up every other command on a shared connection.
+        let reader = client.get_async_connection().await?;
+        let (jobs, receiver) = mpsc::channel(workers.max(1));
+        let taken = Taken::default();
+        tokio::spawn(read(reader, instance.to_string(), jobs, taken.clone()));
+        tokio::spawn(keep(
+            connection.clone(),
+            instance.to_string(),
+            taken.clone(),
+        ));
+        info!("Sharing sends through the Redis stream {}", Self::KEY);
+        Ok(Self {
+            connection,
+            jobs: Mutex::new(receiver),
+            taken,
+        })
+    }
+}
+
+#[async_trait]
+impl DeliveryQueue for RedisQueue {
+    async fn push(&self, job: Job) -> Result<(), StoreError> {
+        let mut command = redis::cmd("XADD");
+        command
+            .arg(Self::KEY)
+            .arg("*")
+            .arg("payload")
+            .arg(&job.payload)
+            .arg("entry")
+            .arg(&job.entry);
+        if let Some(reply_to) = job.reply_to {
+            command
+                .arg("instance")
+                .arg(reply_to.instance.to_string())
+                .arg("request")
+                .arg(reply_to.request.to_string());
+        }
+        command
+            .query_async::<_, String>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn pop(&self) -> Option<Job> {
+        self.jobs.lock().await.recv().await
+    }
+
+    async fn ack(&self, id: &str) -> Result<(), StoreError> {
+        redis::pipe()
+            .cmd("XACK")
+            .arg(Self::KEY)
+            .arg(Self::GROUP)
+            .arg(id)
+            .ignore()
+            .cmd("XDEL")
+            .arg(Self::KEY)
+            .arg(id)
+            .ignore()
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        self.taken
+            .lock()
+            .expect("Taken entries were poisoned")
+            .remove(id);
+        Ok(())
+    }
+
+    async fn bury(&self, job: &Job) -> Result<(), StoreError> {
+        redis::pipe()
+            .atomic()
+            .cmd("XADD")
+            .arg(Self::DEAD_KEY)
+            .arg("*")
+            .arg("payload")
+            .arg(&job.payload)
+            .arg("entry")
+            .arg(&job.entry)
+            .ignore()
+            .cmd("XACK")
+            .arg(Self::KEY)
+            .arg(Self::GROUP)
+            .arg(&job.id)
+            .ignore()
+            .cmd("XDEL")
+            .arg(Self::KEY)
+            .arg(&job.id)
+            .ignore()
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        self.taken
+            .lock()
+            .expect("Taken entries were poisoned")
+            .remove(&job.id);
+        Ok(())
+    }
+
+    async fn waiting(&self) -> Result<Vec<(String, String)>, StoreError> {
+        let entries: Entries = redis::cmd("XRANGE")
+            .arg(Self::KEY)
+            .arg("-")
+            .arg("+")
+            .query_async(&mut self.connection.clone())
+            .await?;
+        entries
+            .into_iter()
+            .map(|(id, fields)| {
+                let job = job(id, fields)?;
+                Ok((job.id, job.payload))
+            })
+            .collect()
+    }
+}
+
+/// Reads the fields of a stream entry back into the job that was pushed.
+fn job(id: String, fields: Vec<(String, String)>) -> Result<Job, StoreError> {
+    let mut payload = None;
+    let mut entry = String::new();
+    let mut instance = None;
+    let mut request = None;
+    for (name, value) in fields {
+        match name.as_str() {
+            "payload" => payload = Some(value),
+            "entry" => entry = value,
+            "instance" => instance = value.parse().ok(),
+            "request" => request = value.parse().ok(),
+            _ => {}
+        }
+    }
+    let payload =
+        payload.ok_or_else(|| StoreError::Corrupt(format!("queue entry {id} has no payload")))?;
+    Ok(Job {
+        id,
+        entry,
+        payload,
+        reply_to: instance
+            .zip(request)
+            .map(|(instance, request)| ReplyTo { instance, request }),
+    })
+}
+
+type Entries = Vec<(String, Vec<(String, String)>)>;
+
+/// Takes sends off the stream as the workers have room for them, along with
+/// the ones instances that went away left unacknowledged.
+async fn read(
+    mut connection: redis::aio::Connection,
+    consumer: String,
+    jobs: mpsc::Sender<Job>,
+    taken: Taken,
+) {
+    let mut claimed_at: Option<Instant> = None;
+    loop {
+        let Ok(permit) = jobs.reserve().await else {
+            return;
+        };
+        let count = jobs.capacity() + 1;
+        let entries = if claimed_at.is_none_or(|at| at.elapsed() >= REDIS_BLOCK) {
+            claimed_at = Some(Instant::now());
+            claim(&mut connection, &consumer, count).await
+        } else {
+            Ok(Vec::new())
+        };
+        let entries = match entries {
+            Ok(entries) if !entries.is_empty() => {
+                info!("Taking over {} abandoned send(s)", entries.len());
+                for _ in &entries {
+                    increment_counter!("send_replays_total");
+                }
+                Ok(entries)
+            }
+            Ok(_) => read_new(&mut connection, &consumer, count).await,
+            Err(error) => Err(error),
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(error) => {
+                error!("Send queue could not be read: {error}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let mut permit = Some(permit);
+        for (id, fields) in entries {
+            let job = match job(id, fields) {
+                Ok(job) => job,
+                Err(error) => {
+                    warn!("Skipping corrupt queue entry: {error}");
+                    continue;
+                }
+            };
+            taken
+                .lock()
+                .expect("Taken entries were poisoned")
+                .insert(job.id.clone());
+            match permit.take() {
+                Some(permit) => permit.send(job),
+                None if jobs.send(job).await.is_err() => return,
+                None => {}
+            }
+        }
+    }
+}
+
+async fn read_new(
+    connection: &mut redis::aio::Connection,
+    consumer: &str,
+    count: usize,
+) -> Result<Entries, redis::RedisError> {
+    let streams: Option<Vec<(String, Entries)>> = redis::cmd("XREADGROUP")
+        .arg("GROUP")
+        .arg(RedisQueue::GROUP)
+        .arg(consumer)
+        .arg("COUNT")
+        .arg(count)
+        .arg("BLOCK")
+        .arg(u64::try_from(REDIS_BLOCK.as_millis()).unwrap_or(u64::MAX))
+        .arg("STREAMS")
+        .arg(RedisQueue::KEY)
+        .arg(">")
+        .query_async(connection)
+        .await?;
+    Ok(streams
+        .into_iter()
+        .flatten()
+        .flat_map(|(_, entries)| entries)
+        .collect())
+}
+
+/// Entries other consumers took but left unacknowledged for too long.
+async fn claim(
+    connection: &mut redis::aio::Connection,
+    consumer: &str,
+    count: usize,
+) -> Result<Entries, redis::RedisError> {
+    let reply: Value = redis::cmd("XAUTOCLAIM")
+        .arg(RedisQueue::KEY)
+        .arg(RedisQueue::GROUP)
+        .arg(consumer)
+        .arg(u64::try_from(REDIS_CLAIM_AFTER.as_millis()).unwrap_or(u64::MAX))
+        .arg("0-0")
+        .arg("COUNT")
+        .arg(count)
+        .query_async(connection)
+        .await?;
+    // The next cursor, the claimed entries and, from Redis 7, the deleted ids.
+    match reply {
+        Value::Bulk(parts) if parts.len() >= 2 => Entries::from_redis_value(&parts[1]),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Claims the entries this instance took again every `REDIS_KEEP_INTERVAL`,
+/// which resets their idle time, so no other instance takes over sends that
+/// are only waiting for a worker or still being retried.
+async fn keep(mut connection: MultiplexedConnection, consumer: String, taken: Taken) {
+    let mut interval = tokio::time::interval(REDIS_KEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ids = taken
+            .lock()
+            .expect("Taken entries were poisoned")
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            continue;
+        }
+        let kept = redis::cmd("XCLAIM")
+            .arg(RedisQueue::KEY)
+            .arg(RedisQueue::GROUP)
+            .arg(&consumer)
+            .arg(0)
+            .arg(&ids)
+            .arg("JUSTID")
+            .query_async::<_, Vec<String>>(&mut connection)
+            .await;
+        if let Err(error) = kept {
+            warn!("Taken sends could not be kept: {error}");
+        }
+    }
+}
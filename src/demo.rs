@@ -0,0 +1,191 @@
+//! Fixtures for the bundled demo page, served with `--demo`: `/demo/seed` sets
+//! up a few users, topics and templates, then keeps notifications coming so
+//! SSE and push can be watched without sending anything by hand.
+
+use std::{collections::BTreeMap, sync::atomic::Ordering, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::Sender, auth::Tenant, deliver, error::AppError, notification::Notification,
+    save_registration, schedule::Target, subscribe_topic, template::NotificationTemplate, AppState,
+    UserRegistrationRequest,
+};
+
+/// The sample users, with the name and locale their notifications use.
+const USERS: [(&str, &str, &str); 3] = [
+    ("demo-alice", "Alice", "en"),
+    ("demo-bob", "Bob", "de"),
+    ("demo-carol", "Carol", "zh-TW"),
+];
+const NEWS: &str = "demo-news";
+const ALERTS: &str = "demo-alerts";
+/// Time between two synthetic notifications.
+const INTERVAL: Duration = Duration::from_secs(10);
+/// Services the synthetic alerts are about, in turn.
+const SERVICES: [&str; 3] = ["api", "database", "queue"];
+
+#[derive(Deserialize, ToSchema, Default)]
+pub struct DemoSeedRequest {
+    /// Also subscribed to the demo topics, usually the id typed into the demo
+    /// page. Registered first unless it already is, keeping its push subscription.
+    pub user_id: Option<String>,
+}
+
+/// What `/demo/seed` set up.
+#[derive(Serialize, ToSchema)]
+pub struct DemoSeed {
+    pub users: Vec<String>,
+    pub topics: Vec<String>,
+    pub templates: Vec<String>,
+    /// Seconds between two synthetic notifications.
+    pub interval_secs: u64,
+}
+
+fn templates() -> Vec<NotificationTemplate> {
+    let template = |name: &str, title: &str, body: &str| NotificationTemplate {
+        name: name.to_owned(),
+        template: Notification::new(title, body),
+    };
+    vec![
+        template(
+            "demo-welcome",
+            "Welcome, {{name}}",
+            "This is demo notification #{{seq}}.",
+        ),
+        template(
+            "demo-welcome.de",
+            "Willkommen, {{name}}",
+            "Das ist Demo-Benachrichtigung #{{seq}}.",
+        ),
+        template(
+            "demo-welcome.zh-TW",
+            "歡迎，{{name}}",
+            "這是第 {{seq}} 則示範通知。",
+        ),
+        template(
+            "demo-alert",
+            "{{service}} is {{state}}",
+            "Synthetic alert #{{seq}}.",
+        ),
+    ]
+}
+
+/// A registration reachable by an email address that doesn't exist, so the
+/// demo never sends anything to a real push service.
+fn registration(user_id: &str, metadata: BTreeMap<String, String>) -> UserRegistrationRequest {
+    UserRegistrationRequest {
+        user_id: user_id.to_owned(),
+        endpoint: None,
+        keys: None,
+        vapid_key: None,
+        fcm_token: None,
+        apns_token: None,
+        webhook_url: None,
+        email: Some(format!("{user_id}@example.invalid")),
+        metadata,
+        expires_at: None,
+    }
+}
+
+/// Registers the sample users and `user_id`, subscribes them to the demo topics
+/// and saves the demo templates, starting the synthetic notifications the
+/// first time.
+pub async fn seed(state: &AppState, user_id: Option<String>) -> Result<DemoSeed, AppError> {
+    let tenant = Tenant::default();
+    let mut users = Vec::new();
+    for (user_id, name, locale) in USERS {
+        let metadata = BTreeMap::from([
+            ("name".to_owned(), name.to_owned()),
+            ("locale".to_owned(), locale.to_owned()),
+        ]);
+        save_registration(state, &tenant, registration(user_id, metadata)).await?;
+        users.push(user_id.to_owned());
+    }
+    if let Some(user_id) = user_id {
+        let scoped = tenant.scope(&user_id)?;
+        if !state.channels.read().await.contains_key(&scoped) {
+            save_registration(state, &tenant, registration(&user_id, BTreeMap::new())).await?;
+        }
+        users.push(user_id);
+    }
+    for user_id in &users {
+        for topic in [NEWS, ALERTS] {
+            subscribe_topic(state, tenant.scope(user_id)?, topic.to_owned()).await?;
+        }
+    }
+    let templates = templates();
+    let names = templates
+        .iter()
+        .map(|template| template.name.clone())
+        .collect();
+    for template in templates {
+        state.templates.insert(template).await?;
+    }
+    if !state.demo_started.swap(true, Ordering::Relaxed) {
+        info!("Sending demo notifications every {}s", INTERVAL.as_secs());
+        tokio::spawn(run(state.clone()));
+    }
+    Ok(DemoSeed {
+        users,
+        topics: vec![NEWS.to_owned(), ALERTS.to_owned()],
+        templates: names,
+        interval_secs: INTERVAL.as_secs(),
+    })
+}
+
+/// Takes turns sending a headline to the news topic, an alert rendered from
+/// `demo-alert` to the alerts topic and a welcome in their own locale to one of
+/// the sample users.
+async fn run(state: AppState) {
+    let sender = Sender::internal("demo");
+    let mut interval = tokio::time::interval(INTERVAL);
+    for seq in 1_usize.. {
+        interval.tick().await;
+        let mut variables = Map::new();
+        variables.insert("seq".to_owned(), json!(seq));
+        let (target, data) = match seq % 3 {
+            1 => (
+                Target::Topic(NEWS.to_owned()),
+                Ok(Notification::new(
+                    format!("Demo headline #{seq}"),
+                    "Sent to everyone subscribed to demo-news.",
+                )),
+            ),
+            2 => {
+                let service = SERVICES[seq / 3 % SERVICES.len()];
+                let status = if seq % 2 == 0 { "degraded" } else { "healthy" };
+                variables.insert("service".to_owned(), Value::from(service));
+                variables.insert("state".to_owned(), Value::from(status));
+                let data = state.templates.render("demo-alert", None, &variables).await;
+                (Target::Topic(ALERTS.to_owned()), data)
+            }
+            _ => {
+                let (user_id, name, locale) = USERS[seq / 3 % USERS.len()];
+                variables.insert("name".to_owned(), Value::from(name));
+                let data = state
+                    .templates
+                    .render("demo-welcome", Some(locale), &variables)
+                    .await;
+                (Target::User(user_id.to_owned()), data)
+            }
+        };
+        match data {
+            Ok(data) => {
+                deliver(
+                    &state,
+                    &target,
+                    &data.to_json(),
+                    &data.push_options(),
+                    &sender,
+                )
+                .await;
+            }
+            // Templates deleted since seeding don't stop the rest.
+            Err(error) => warn!("Demo notification could not be rendered: {error}"),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Days, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use metrics::increment_counter;
+use serde_json::{json, Map, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditEntry, Sender},
+    error::AppError,
+    fan_out,
+    notification::{Notification, PushOptions},
+    recipients, AppState, SendData, Sent,
+};
+
+/// Time between two looks for digests that are due.
+const CHECK_INTERVAL: Duration = Duration::from_mins(1);
+/// Titles the built-in summary lists before counting the rest.
+const SUMMARY_TITLES: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct DigestConfig {
+    /// Local time digests go out at for users who didn't pick one.
+    pub at: NaiveTime,
+    /// Template the digest is rendered with, looked up in the recipient's tenant.
+    pub template: String,
+    /// Most messages a digest collects, the oldest make room past it.
+    pub max_messages: usize,
+}
+
+impl DigestConfig {
+    /// Reads `DIGEST_TIME` as `HH:MM`, 08:00 by default, `DIGEST_TEMPLATE`,
+    /// `digest` by default, and `DIGEST_MAX_MESSAGES`, 50 by default.
+    pub fn from_env() -> Self {
+        let at = std::env::var("DIGEST_TIME")
+            .ok()
+            .and_then(|at| NaiveTime::parse_from_str(&at, "%H:%M").ok())
+            .unwrap_or(NaiveTime::from_hms_opt(8, 0, 0).expect("08:00 is a time"));
+        let template = std::env::var("DIGEST_TEMPLATE")
+            .ok()
+            .filter(|template| !template.is_empty())
+            .unwrap_or_else(|| "digest".to_owned());
+        let max_messages = std::env::var("DIGEST_MAX_MESSAGES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(50);
+        Self {
+            at,
+            template,
+            max_messages,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Collected {
+    message_id: Uuid,
+    sent_at: DateTime<Utc>,
+    data: Notification,
+}
+
+/// Messages sent with `digest: true`, waiting for the user's next digest.
+#[derive(Debug, Default)]
+pub struct Digest {
+    messages: Mutex<VecDeque<Collected>>,
+}
+
+impl Digest {
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    fn push(&self, config: &DigestConfig, collected: Collected) {
+        let mut messages = self.messages.lock().unwrap();
+        while messages.len() >= config.max_messages.max(1) {
+            messages.pop_front();
+        }
+        messages.push_back(collected);
+    }
+
+    /// Every message, once the oldest one was collected before `slot`.
+    fn take_due(&self, slot: DateTime<Utc>) -> Vec<Collected> {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.front().is_some_and(|oldest| oldest.sent_at < slot) {
+            messages.drain(..).collect()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Keeps `send` for its recipient's next digest instead of sending it now.
+pub async fn collect(state: &AppState, send: SendData) -> Result<Sent, AppError> {
+    let reader = state.channels.read().await;
+    let reg = reader.get(&send.user_id).ok_or(AppError::UserNotFound)?;
+    let now = Utc::now();
+    if reg.subscription.is_expired(now) {
+        return Err(AppError::ConsentExpired);
+    }
+    let message_id = Uuid::new_v4();
+    reg.digest.push(
+        &state.digest_config,
+        Collected {
+            message_id,
+            sent_at: now,
+            data: send.data,
+        },
+    );
+    drop(reader);
+    state.audit.record(AuditEntry::new(
+        &send.user_id,
+        message_id,
+        &send.sender,
+        "digest",
+        "collected",
+    ));
+    increment_counter!("digest_messages_total");
+    Ok((
+        StatusCode::ACCEPTED,
+        message_id,
+        "Collected for the next digest.".to_owned(),
+    ))
+}
+
+/// The latest instant up to `now` at which clocks in `timezone` show `at`.
+/// Skipped by a daylight saving change, `at` falls an hour later that day.
+fn last_slot(now: DateTime<Utc>, timezone: Tz, at: NaiveTime) -> DateTime<Utc> {
+    let resolve = |local: NaiveDateTime| {
+        timezone.from_local_datetime(&local).earliest().or_else(|| {
+            timezone
+                .from_local_datetime(&(local + TimeDelta::hours(1)))
+                .earliest()
+        })
+    };
+    let today = now.with_timezone(&timezone).date_naive();
+    (0..=2)
+        .filter_map(|days| today.checked_sub_days(Days::new(days)))
+        .filter_map(|day| resolve(day.and_time(at)))
+        .map(|slot| slot.with_timezone(&Utc))
+        .find(|slot| *slot <= now)
+        .unwrap_or(now - TimeDelta::days(1))
+}
+
+/// `name` in the tenant namespace of `user_id`.
+fn in_tenant_of(user_id: &str, name: &str) -> String {
+    user_id
+        .rsplit_once('/')
+        .map_or_else(|| name.to_owned(), |(tenant, _)| format!("{tenant}/{name}"))
+}
+
+/// What the digest template is rendered with: `count` and `messages`, each
+/// with its `message_id`, `sent_at`, `title`, `body` and `url`.
+fn variables(messages: &[Collected]) -> Map<String, Value> {
+    let messages = messages
+        .iter()
+        .map(|collected| {
+            json!({
+                "message_id": collected.message_id,
+                "sent_at": collected.sent_at.to_rfc3339(),
+                "title": collected.data.title,
+                "body": collected.data.body.clone().unwrap_or_default(),
+                "url": collected.data.url.clone().unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut variables = Map::new();
+    variables.insert("count".to_owned(), messages.len().into());
+    variables.insert("messages".to_owned(), messages.into());
+    variables
+}
+
+/// Sent unless a digest template is registered: the count and the first few
+/// titles.
+fn summary(messages: &[Collected]) -> Notification {
+    let title = match messages.len() {
+        1 => "1 new notification".to_owned(),
+        count => format!("{count} new notifications"),
+    };
+    let titles = messages
+        .iter()
+        .map(|collected| collected.data.title.as_str())
+        .filter(|title| !title.is_empty())
+        .collect::<Vec<_>>();
+    let mut lines = titles
+        .iter()
+        .take(SUMMARY_TITLES)
+        .map(|title| (*title).to_owned())
+        .collect::<Vec<_>>();
+    if titles.len() > SUMMARY_TITLES {
+        lines.push(format!("and {} more", titles.len() - SUMMARY_TITLES));
+    }
+    Notification::new(title, lines.join("\n"))
+}
+
+async fn render(
+    state: &AppState,
+    user_id: &str,
+    locale: Option<&str>,
+    messages: &[Collected],
+) -> Notification {
+    let name = in_tenant_of(user_id, &state.digest_config.template);
+    match state
+        .templates
+        .render(&name, locale, &variables(messages))
+        .await
+    {
+        Ok(data) => data,
+        Err(AppError::TemplateNotFound) => summary(messages),
+        Err(error) => {
+            warn!("Digest template `{name}` could not be rendered, sending a summary: {error}");
+            summary(messages)
+        }
+    }
+}
+
+/// Sends every user whose digest time passed since their oldest collected
+/// message one notification rendered from the `DIGEST_TEMPLATE` template.
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let due = {
+            let reader = state.channels.read().await;
+            reader
+                .iter()
+                .filter_map(|(user_id, reg)| {
+                    let timezone = reg.preferences.timezone.unwrap_or(Tz::UTC);
+                    let at = reg.preferences.digest_at.unwrap_or(state.digest_config.at);
+                    let messages = reg.digest.take_due(last_slot(now, timezone, at));
+                    (!messages.is_empty()).then(|| (user_id.clone(), messages))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (user_id, messages) in &due {
+            let locale = {
+                let reader = state.channels.read().await;
+                let Some(reg) = reader.get(user_id) else {
+                    continue;
+                };
+                reg.subscription.metadata.get("locale").cloned()
+            };
+            let data = render(&state, user_id, locale.as_deref(), messages).await;
+            let reader = state.channels.read().await;
+            let recipients = recipients(
+                &state,
+                reader.get_key_value(user_id).into_iter(),
+                &data.to_json(),
+                &PushOptions::default(),
+                &Sender::internal("digest"),
+            );
+            drop(reader);
+            fan_out(&state, recipients).await;
+            increment_counter!("digests_sent_total");
+        }
+        if !due.is_empty() {
+            info!("Sent {} digest(s).", due.len());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::Tenant, cluster::ClusterEvent, error::AppError, remove_registration, schedule::Target,
+    web_push::VapidKey, AppState,
+};
+
+/// What was erased about a user.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+pub struct ErasedData {
+    /// Whether the user was registered. Their preferences and topic
+    /// subscriptions went with the registration.
+    pub registration: bool,
+    /// Messages that were waiting for the user to connect.
+    pub queued_messages: usize,
+    /// Messages remembered for `Last-Event-ID` and `/history`.
+    pub history: usize,
+    /// Messages kept in the archive behind `/history/archive`.
+    pub archived_messages: usize,
+    /// Messages collected for the user's next digest.
+    pub digest_messages: usize,
+    /// Messages held back by the user's cap.
+    pub held_messages: usize,
+    /// Delivery statuses of messages sent to the user.
+    pub statuses: usize,
+    pub audit_entries: usize,
+    /// Sends to the user journaled for delivery or replay.
+    pub journaled_sends: usize,
+    /// Schedules addressed to the user alone.
+    pub schedules: usize,
+    /// Groups the user was taken out of.
+    pub groups: usize,
+}
+
+impl ErasedData {
+    const fn add(&mut self, other: Self) {
+        self.registration |= other.registration;
+        self.queued_messages += other.queued_messages;
+        self.history += other.history;
+        self.archived_messages += other.archived_messages;
+        self.digest_messages += other.digest_messages;
+        self.held_messages += other.held_messages;
+        self.statuses += other.statuses;
+        self.audit_entries += other.audit_entries;
+        self.journaled_sends += other.journaled_sends;
+        self.schedules += other.schedules;
+        self.groups += other.groups;
+    }
+}
+
+/// Proof of an erasure to hand back to the user who asked for it.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct ErasureReceipt {
+    pub receipt_id: Uuid,
+    pub user_id: String,
+    pub erased_at: DateTime<Utc>,
+    pub erased: ErasedData,
+    /// An ES256 JWT carrying the other fields, signed with the VAPID key so it
+    /// can be checked against the public key in `/vapid.json`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature: String,
+}
+
+impl ErasureReceipt {
+    pub fn signed(vapid: &VapidKey, user_id: &str, erased: ErasedData) -> Result<Self, AppError> {
+        let mut receipt = Self {
+            receipt_id: Uuid::new_v4(),
+            user_id: Tenant::local_part(user_id).to_owned(),
+            erased_at: Utc::now(),
+            erased,
+            signature: String::new(),
+        };
+        receipt.signature = vapid.sign(receipt.clone())?;
+        Ok(receipt)
+    }
+}
+
+/// Erases everything kept about `user_id` in the stores and on this instance,
+/// and has the other instances of the cluster erase what they keep themselves.
+pub async fn erase(state: &AppState, user_id: &str) -> Result<ErasedData, AppError> {
+    let mut erased = ErasedData {
+        journaled_sends: state.send_queue.erase(user_id).await?,
+        ..ErasedData::default()
+    };
+    if let Some(reg) = remove_registration(state, user_id).await? {
+        erased.registration = true;
+        erased.queued_messages = reg.queue.len();
+        erased.history = reg.history.len();
+        erased.digest_messages = reg.digest.len();
+    }
+    erased.add(erase_local(state, user_id).await?);
+    if let Some(cluster) = &state.cluster {
+        let event = ClusterEvent::Erased {
+            user_id: user_id.to_owned(),
+        };
+        if let Err(error) = cluster.announce(event).await {
+            error!("Erasure could not be announced: {error}");
+        }
+    }
+    Ok(erased)
+}
+
+/// Erases what only this instance keeps about `user_id`, besides the
+/// registration.
+pub async fn erase_local(state: &AppState, user_id: &str) -> Result<ErasedData, AppError> {
+    let mut erased = ErasedData {
+        statuses: state.statuses.erase(user_id),
+        audit_entries: state
+            .audit
+            .erase(user_id)
+            .await
+            .map_err(AppError::AuditLog)?,
+        ..ErasedData::default()
+    };
+    if let Some(archive) = &state.archive {
+        erased.archived_messages = archive.erase(user_id).await?;
+    }
+    erased.held_messages = state.recipient_caps.erase(user_id);
+
+    let mut schedules = state.schedules.write().await;
+    let addressed = schedules
+        .iter()
+        .filter(|(_, job)| matches!(&job.target, Target::User(id) if id == user_id))
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in addressed {
+        if let Some(task) = schedules.remove(&id).and_then(|job| job.task) {
+            task.abort();
+        }
+        erased.schedules += 1;
+    }
+    drop(schedules);
+
+    let mut groups = state.groups.write().await;
+    groups.retain(|_, members| {
+        if members.remove(user_id) {
+            erased.groups += 1;
+        }
+        !members.is_empty()
+    });
+    Ok(erased)
+}
@@ -0,0 +1,276 @@
+use std::{fmt::Display, time::Duration};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{schema::Violation, store::StoreError};
+
+/// Errors surfaced by the HTTP handlers, rendered as a JSON error body.
+#[derive(Debug)]
+pub enum AppError {
+    Unauthorized,
+    Forbidden,
+    /// Missing or invalid credentials on `/admin/*`.
+    AdminUnauthorized,
+    /// The admin credentials are valid but their role is too low.
+    AdminForbidden,
+    /// The user token is missing, invalid or names another user.
+    InvalidUserToken(String),
+    /// An id addressed another tenant's namespace.
+    CrossTenant,
+    UserNotFound,
+    /// The user's consent to notifications ran out and wasn't renewed.
+    ConsentExpired,
+    InvalidRegistration {
+        field: &'static str,
+        reason: String,
+    },
+    InvalidVapidKey(String),
+    InvalidSchedule(String),
+    InvalidPushOptions(String),
+    ScheduleNotFound,
+    MessageNotFound,
+    InvalidTemplate(String),
+    TemplateNotFound,
+    InvalidSchema(String),
+    SchemaNotFound,
+    /// The notification doesn't conform to a registered schema.
+    SchemaViolation(Vec<Violation>),
+    GroupNotFound,
+    BroadcastNotFound,
+    CallbackNotFound,
+    InvalidCallback(String),
+    InvalidPreferences(String),
+    InvalidIdempotencyKey(String),
+    /// A msgpack, CBOR or import body that doesn't decode.
+    InvalidBody(String),
+    /// An encrypted export was asked for or sent without `EXPORT_SECRET` set.
+    ExportKeyMissing,
+    /// `/history/archive` was asked for without `HISTORY_DATABASE_URL` set.
+    ArchiveDisabled,
+    /// A pagination cursor that no page handed out.
+    InvalidCursor(String),
+    /// The payload doesn't fit a Web Push message.
+    PayloadTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// An SSE connection limit was reached, the user's own or the server's.
+    TooManyConnections {
+        per_user: bool,
+    },
+    /// Too many requests, another one is allowed after the given delay.
+    RateLimited(Duration),
+    /// No worker reported on a queued send in time.
+    SendTimedOut,
+    /// A send delivered by a worker on another instance failed there.
+    WorkerFailed(String),
+    Store(StoreError),
+    AuditLog(std::io::Error),
+}
+
+impl AppError {
+    pub fn invalid_registration(field: &'static str, reason: impl Display) -> Self {
+        Self::InvalidRegistration {
+            field,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub const fn status(&self) -> StatusCode {
+        match self {
+            Self::Unauthorized | Self::AdminUnauthorized | Self::InvalidUserToken(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            Self::Forbidden | Self::AdminForbidden | Self::CrossTenant => StatusCode::FORBIDDEN,
+            Self::UserNotFound
+            | Self::ScheduleNotFound
+            | Self::MessageNotFound
+            | Self::TemplateNotFound
+            | Self::SchemaNotFound
+            | Self::GroupNotFound
+            | Self::BroadcastNotFound
+            | Self::CallbackNotFound => StatusCode::NOT_FOUND,
+            Self::InvalidRegistration { .. }
+            | Self::InvalidSchedule(_)
+            | Self::InvalidPushOptions(_)
+            | Self::InvalidTemplate(_)
+            | Self::InvalidSchema(_)
+            | Self::InvalidCallback(_)
+            | Self::InvalidPreferences(_)
+            | Self::InvalidIdempotencyKey(_)
+            | Self::ExportKeyMissing
+            | Self::ArchiveDisabled
+            | Self::InvalidCursor(_) => StatusCode::BAD_REQUEST,
+            Self::ConsentExpired => StatusCode::GONE,
+            Self::InvalidBody(_) | Self::SchemaViolation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited(_) | Self::TooManyConnections { per_user: true } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::TooManyConnections { per_user: false } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::SendTimedOut => StatusCode::GATEWAY_TIMEOUT,
+            Self::InvalidVapidKey(_)
+            | Self::WorkerFailed(_)
+            | Self::Store(_)
+            | Self::AuditLog(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::AdminUnauthorized => "admin_unauthorized",
+            Self::AdminForbidden => "admin_forbidden",
+            Self::InvalidUserToken(_) => "invalid_user_token",
+            Self::CrossTenant => "cross_tenant",
+            Self::UserNotFound => "user_not_found",
+            Self::ConsentExpired => "consent_expired",
+            Self::InvalidRegistration { .. } => "invalid_registration",
+            Self::InvalidVapidKey(_) => "invalid_vapid_key",
+            Self::InvalidSchedule(_) => "invalid_schedule",
+            Self::InvalidPushOptions(_) => "invalid_push_options",
+            Self::ScheduleNotFound => "schedule_not_found",
+            Self::MessageNotFound => "message_not_found",
+            Self::InvalidTemplate(_) => "invalid_template",
+            Self::TemplateNotFound => "template_not_found",
+            Self::InvalidSchema(_) => "invalid_schema",
+            Self::SchemaNotFound => "schema_not_found",
+            Self::SchemaViolation(_) => "schema_violation",
+            Self::GroupNotFound => "group_not_found",
+            Self::BroadcastNotFound => "broadcast_not_found",
+            Self::CallbackNotFound => "callback_not_found",
+            Self::InvalidCallback(_) => "invalid_callback",
+            Self::InvalidPreferences(_) => "invalid_preferences",
+            Self::InvalidIdempotencyKey(_) => "invalid_idempotency_key",
+            Self::InvalidBody(_) => "invalid_body",
+            Self::ExportKeyMissing => "export_key_missing",
+            Self::ArchiveDisabled => "archive_disabled",
+            Self::InvalidCursor(_) => "invalid_cursor",
+            Self::PayloadTooLarge { .. } => "payload_too_large",
+            Self::TooManyConnections { .. } => "too_many_connections",
+            Self::RateLimited(_) => "rate_limited",
+            Self::SendTimedOut => "send_timed_out",
+            Self::WorkerFailed(_) => "worker_failed",
+            Self::Store(_) => "store_error",
+            Self::AuditLog(_) => "audit_log_error",
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "Missing or invalid credentials"),
+            Self::Forbidden => write!(f, "Credentials lack the required scope"),
+            Self::AdminUnauthorized => write!(f, "Missing or invalid admin credentials"),
+            Self::AdminForbidden => write!(f, "Admin role lacks the required permission"),
+            Self::InvalidUserToken(reason) => write!(f, "Invalid user token: {reason}"),
+            Self::CrossTenant => write!(f, "Ids can't contain `/` or reach into another tenant"),
+            Self::UserNotFound => write!(f, "User not found"),
+            Self::ConsentExpired => write!(
+                f,
+                "The user's consent to notifications expired and has to be renewed"
+            ),
+            Self::InvalidRegistration { field, reason } => {
+                write!(f, "Invalid registration field `{field}`: {reason}")
+            }
+            Self::InvalidVapidKey(reason) => write!(f, "Invalid VAPID key: {reason}"),
+            Self::InvalidSchedule(reason) => write!(f, "Invalid schedule: {reason}"),
+            Self::InvalidPushOptions(reason) => write!(f, "Invalid push options: {reason}"),
+            Self::ScheduleNotFound => write!(f, "Scheduled job not found"),
+            Self::MessageNotFound => write!(f, "Message not found"),
+            Self::InvalidTemplate(reason) => write!(f, "Invalid template: {reason}"),
+            Self::TemplateNotFound => write!(f, "Template not found"),
+            Self::InvalidSchema(reason) => write!(f, "Invalid JSON Schema: {reason}"),
+            Self::SchemaNotFound => write!(f, "Schema not found"),
+            Self::SchemaViolation(violations) => write!(
+                f,
+                "Notification doesn't conform to its schema: {}",
+                violations
+                    .iter()
+                    .map(|violation| format!("`{}` {}", violation.pointer, violation.message))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::GroupNotFound => write!(f, "Group not found"),
+            Self::BroadcastNotFound => write!(f, "Broadcast not found"),
+            Self::CallbackNotFound => write!(f, "No callback registered for this API key"),
+            Self::InvalidCallback(reason) => write!(f, "Invalid callback URL: {reason}"),
+            Self::InvalidPreferences(reason) => write!(f, "Invalid preferences: {reason}"),
+            Self::InvalidIdempotencyKey(reason) => write!(f, "Invalid idempotency key: {reason}"),
+            Self::InvalidBody(reason) => write!(f, "Invalid request body: {reason}"),
+            Self::ExportKeyMissing => write!(f, "Encrypted exports require EXPORT_SECRET"),
+            Self::ArchiveDisabled => write!(f, "The message archive requires HISTORY_DATABASE_URL"),
+            Self::InvalidCursor(reason) => write!(f, "Invalid cursor: {reason}"),
+            Self::PayloadTooLarge { size, limit } => write!(
+                f,
+                "Payload of {size} bytes exceeds the Web Push limit of {limit}, send it with `indirect` instead"
+            ),
+            Self::TooManyConnections { per_user: true } => {
+                write!(f, "Too many open SSE connections for this user")
+            }
+            Self::TooManyConnections { per_user: false } => {
+                write!(f, "The server accepts no more SSE connections")
+            }
+            Self::RateLimited(retry_after) => write!(
+                f,
+                "Rate limit exceeded, retry in {}s",
+                retry_after_secs(*retry_after)
+            ),
+            Self::SendTimedOut => write!(
+                f,
+                "No worker reported on the send in time, it may still be delivered"
+            ),
+            Self::WorkerFailed(reason) => write!(f, "Delivery failed on another instance: {reason}"),
+            Self::Store(error) => write!(f, "{error}"),
+            Self::AuditLog(error) => write!(f, "Audit log could not be accessed: {error}"),
+        }
+    }
+}
+
+impl From<StoreError> for AppError {
+    fn from(value: StoreError) -> Self {
+        Self::Store(value)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        match &self {
+            Self::InvalidRegistration { field, .. } => body["field"] = json!(field),
+            Self::SchemaViolation(violations) => body["violations"] = json!(violations),
+            _ => {}
+        }
+        let mut response = (self.status(), Json(body)).into_response();
+        match self {
+            Self::RateLimited(retry_after) => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, retry_after_secs(retry_after).into());
+            }
+            Self::AdminUnauthorized => {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Basic realm=\"admin\""),
+                );
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// `Retry-After` only takes whole seconds, so round up to avoid retrying too early.
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
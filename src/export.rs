@@ -0,0 +1,115 @@
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::{IntoParams, ToSchema};
+
+const NONCE_LEN: usize = 12;
+
+/// Layouts of `/admin/export`, both read back by `/admin/import`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One array of registrations.
+    #[default]
+    Json,
+    /// A registration per line, for exports too large to handle in one piece.
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, records: &[T]) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec(records).expect("Registrations serialize"),
+            Self::Ndjson => {
+                let mut bytes = Vec::new();
+                for record in records {
+                    serde_json::to_writer(&mut bytes, record).expect("Registrations serialize");
+                    bytes.push(b'\n');
+                }
+                bytes
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportOptions {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Seals the export with `EXPORT_SECRET`, since it holds push credentials.
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+/// Reads registrations in either export format, told apart by the opening `[`.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<Vec<T>, String> {
+    if bytes.trim_ascii_start().starts_with(b"[") {
+        return serde_json::from_slice(bytes).map_err(|error| error.to_string());
+    }
+    bytes
+        .split(|byte| *byte == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.trim_ascii().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_slice(line).map_err(|error| format!("line {}: {error}", index + 1))
+        })
+        .collect()
+}
+
+/// Encrypts exports with AES-256-GCM so they can be handed around without
+/// leaking endpoints and keys. Sealed exports are the nonce followed by the
+/// ciphertext.
+pub struct ExportKey(Aes256Gcm);
+
+impl ExportKey {
+    /// Derived from `EXPORT_SECRET`, which has to match between the exporting and
+    /// the importing server.
+    pub fn from_env() -> Option<Self> {
+        let secret = std::env::var("EXPORT_SECRET").ok()?;
+        let key = Sha256::digest(secret.as_bytes());
+        Some(Self(Aes256Gcm::new(&key)))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("Exports fit AES-GCM");
+        [&nonce[..], &ciphertext].concat()
+    }
+
+    /// The plaintext, or `None` when `sealed` was tampered with or sealed with
+    /// another secret.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+/// What `/admin/import` made of the registrations it was given.
+#[derive(Serialize, Default, ToSchema)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub rejected: Vec<ImportRejection>,
+}
+
+/// A registration left out of an import, the others still go through.
+#[derive(Serialize, ToSchema)]
+pub struct ImportRejection {
+    /// Position of the registration in the import, from 0.
+    pub index: usize,
+    pub user_id: String,
+    pub error: String,
+}
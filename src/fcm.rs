@@ -0,0 +1,246 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use hyper::{header, Body, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::sync::Mutex;
+use web_push_native::jwt_simple::{
+    self,
+    prelude::{Claims, RS256KeyPair, RSAKeyPairLike},
+};
+
+use crate::{
+    error::AppError,
+    notification::{Notification, PushOptions, Urgency},
+    push::{self, DeliveryError, ProviderKind, PushAttempt, PushClient, PushProvider},
+    store::Subscription,
+};
+
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+
+/// The fields used from a Google service account key file.
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_owned()
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScopeClaim {
+    scope: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Delivers to Android, iOS and web apps through the FCM HTTP v1 API.
+pub struct FcmProvider {
+    client: PushClient,
+    account: ServiceAccount,
+    key_pair: RS256KeyPair,
+    /// OAuth access token and the moment it should be replaced.
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl FcmProvider {
+    /// Reads the service account key file at `FCM_SERVICE_ACCOUNT`, if set.
+    pub async fn from_env(client: PushClient) -> std::io::Result<Option<Self>> {
+        let Ok(path) = std::env::var("FCM_SERVICE_ACCOUNT") else {
+            return Ok(None);
+        };
+        let content = tokio::fs::read_to_string(path).await?;
+        let account = serde_json::from_str::<ServiceAccount>(&content)?;
+        let key_pair = RS256KeyPair::from_pem(&account.private_key).map_err(|error| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+        })?;
+        Ok(Some(Self {
+            client,
+            account,
+            key_pair,
+            token: Mutex::new(None),
+        }))
+    }
+
+    fn request(
+        &self,
+        fcm_token: &str,
+        access_token: &str,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<Request<Body>, AppError> {
+        let body = message(fcm_token, data, options);
+        Request::post(format!(
+            "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+            self.account.project_id
+        ))
+        .header(header::AUTHORIZATION, format!("Bearer {access_token}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(|error| AppError::invalid_registration("fcm_token", error))
+    }
+
+    /// Returns a cached access token, exchanging a freshly signed JWT for a new one
+    /// shortly before the old one expires.
+    async fn access_token(&self) -> Result<String, PushAttempt> {
+        let mut token = self.token.lock().await;
+        if let Some((token, refresh_at)) = &*token {
+            if Instant::now() < *refresh_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let claims = Claims::with_custom_claims(
+            ScopeClaim {
+                scope: SCOPE.to_owned(),
+            },
+            jwt_simple::prelude::Duration::from_hours(1),
+        )
+        .with_issuer(&self.account.client_email)
+        .with_audience(&self.account.token_uri);
+        let assertion = self
+            .key_pair
+            .sign(claims)
+            .map_err(|error| PushAttempt::rejected(format!("FCM token signing failed: {error}")))?;
+        let request = Request::post(&self.account.token_uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!(
+                "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={assertion}"
+            )))
+            .map_err(|error| PushAttempt::rejected(error.to_string()))?;
+
+        let network = |error: hyper::Error| {
+            PushAttempt::failed(DeliveryError::Network, error.to_string(), None, None)
+        };
+        let response = self.client.request(request).await.map_err(network)?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(network)?;
+        if !status.is_success() {
+            // Anything but an outage means the service account was refused.
+            let reason = if status.is_server_error() {
+                DeliveryError::ServerError
+            } else {
+                DeliveryError::Unauthorized
+            };
+            let error = format!("FCM token endpoint responded with {status}");
+            return Err(PushAttempt::failed(reason, error, None, None));
+        }
+        let response = serde_json::from_slice::<TokenResponse>(&body).map_err(|error| {
+            let error = format!("Invalid FCM token response: {error}");
+            PushAttempt::failed(DeliveryError::ServerError, error, None, None)
+        })?;
+        let lifetime = Duration::from_secs(response.expires_in.saturating_sub(60));
+        *token = Some((response.access_token.clone(), Instant::now() + lifetime));
+        Ok(response.access_token)
+    }
+}
+
+#[async_trait]
+impl PushProvider for FcmProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Fcm
+    }
+
+    fn origin(&self, _subscription: &Subscription) -> Option<String> {
+        Some("https://fcm.googleapis.com".to_owned())
+    }
+
+    async fn send(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<PushAttempt, AppError> {
+        let Some(fcm_token) = &subscription.fcm_token else {
+            return Err(AppError::invalid_registration("fcm_token", "missing"));
+        };
+        let access_token = match self.access_token().await {
+            Ok(access_token) => access_token,
+            Err(attempt) => return Ok(attempt),
+        };
+        let request = self.request(fcm_token, &access_token, data, options)?;
+        let response = push::dispatch(&self.client, self.kind(), request).await;
+        if matches!(&response, Ok(response) if response.status() == StatusCode::UNAUTHORIZED) {
+            // The access token was revoked or expired early, fetch a new one next time.
+            *self.token.lock().await = None;
+            return Ok(PushAttempt::Retryable {
+                reason: DeliveryError::Unauthorized,
+                error: "FCM rejected the access token".to_owned(),
+                retry_after: None,
+                status: Some(StatusCode::UNAUTHORIZED),
+            });
+        }
+        Ok(PushAttempt::from_response(response))
+    }
+
+    async fn preview(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<Request<Body>, String> {
+        let Some(fcm_token) = &subscription.fcm_token else {
+            return Err("No FCM registration token".to_owned());
+        };
+        let access_token = self.access_token().await.map_err(PushAttempt::into_error)?;
+        self.request(fcm_token, &access_token, data, options)
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Builds the HTTP v1 message. The full notification JSON travels in the data
+/// payload, with title and body also set as a display notification for apps that
+/// don't handle data messages themselves.
+fn message(token: &str, data: &str, options: &PushOptions) -> Value {
+    let mut message = Map::new();
+    message.insert("token".to_owned(), json!(token));
+    message.insert("data".to_owned(), json!({ "payload": data }));
+    if let Ok(notification) = serde_json::from_str::<Notification>(data) {
+        if !notification.title.is_empty() {
+            message.insert(
+                "notification".to_owned(),
+                json!({ "title": notification.title, "body": notification.body }),
+            );
+        }
+    }
+
+    let mut android = Map::new();
+    let mut webpush_headers = Map::new();
+    if let Some(ttl) = options.push_ttl() {
+        android.insert("ttl".to_owned(), json!(format!("{ttl}s")));
+        webpush_headers.insert("TTL".to_owned(), json!(ttl.to_string()));
+    }
+    if let Some(urgency) = options.urgency {
+        let priority = if urgency == Urgency::High {
+            "high"
+        } else {
+            "normal"
+        };
+        android.insert("priority".to_owned(), json!(priority));
+        webpush_headers.insert("Urgency".to_owned(), json!(urgency.as_str()));
+    }
+    if let Some(topic) = options.push_topic() {
+        android.insert("collapse_key".to_owned(), json!(topic));
+        webpush_headers.insert("Topic".to_owned(), json!(topic));
+    }
+    if !android.is_empty() {
+        message.insert("android".to_owned(), Value::Object(android));
+    }
+    if !webpush_headers.is_empty() {
+        message.insert("webpush".to_owned(), json!({ "headers": webpush_headers }));
+    }
+    json!({ "message": message })
+}
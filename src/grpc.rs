@@ -0,0 +1,258 @@
+use std::net::SocketAddr;
+
+use chrono::DateTime;
+use tonic::{metadata::MetadataMap, transport::Server, Request, Response, Status};
+use tracing::info;
+
+use crate::{
+    auth::{Principal, Scope},
+    error::AppError,
+    notification::{self, PushOptions},
+    schedule::Target,
+    send_one, shutdown_requested, subscribe_topic, AppState, DeliveryReport, DeliveryStatus,
+    SendData,
+};
+
+// Generated by `build.rs`.
+#[allow(clippy::all, clippy::nursery, clippy::pedantic)]
+mod proto {
+    tonic::include_proto!("notification.v1");
+}
+
+use proto::notifications_server::{Notifications, NotificationsServer};
+
+/// Serves `proto/notification.proto` on `addr` until shutdown is requested.
+///
+/// # Errors
+///
+/// When `addr` can't be bound.
+pub async fn serve(state: AppState, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    info!("Serving gRPC on {addr}");
+    let shutdown = shutdown_requested(&state);
+    Server::builder()
+        .add_service(NotificationsServer::new(GrpcService { state }))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+struct GrpcService {
+    state: AppState,
+}
+
+impl GrpcService {
+    /// Checks the call's credentials like `require_publisher` does for HTTP.
+    async fn authorize(&self, metadata: &MetadataMap) -> Result<Principal, AppError> {
+        self.state
+            .authenticator
+            .authorize(request_key(metadata), Scope::Publisher)
+            .await
+    }
+}
+
+fn request_key(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+#[tonic::async_trait]
+impl Notifications for GrpcService {
+    async fn send(
+        &self,
+        request: Request<proto::SendRequest>,
+    ) -> Result<Response<proto::SendReply>, Status> {
+        let Principal { tenant, sender, .. } = self.authorize(request.metadata()).await?;
+        if let Some(limiter) = &self.state.rate_limits.sender {
+            let key = request_key(request.metadata()).unwrap_or_default();
+            limiter.check(key).map_err(AppError::RateLimited)?;
+        }
+        let request = request.into_inner();
+        let send = SendData {
+            user_id: tenant.scope(&request.user_id)?,
+            data: request.data.unwrap_or_default().into(),
+            push: request.push.map(PushOptions::from).unwrap_or_default(),
+            message_id: Some(request.idempotency_key).filter(|key| !key.is_empty()),
+            sender,
+            dry_run: false,
+            digest: false,
+        };
+        let (status, message_id, message) = send_one(&self.state, send).await?;
+        if status.is_server_error() {
+            return Err(Status::internal(message));
+        }
+        Ok(Response::new(proto::SendReply {
+            message_id: message_id.to_string(),
+            message,
+        }))
+    }
+
+    async fn broadcast(
+        &self,
+        request: Request<proto::BroadcastRequest>,
+    ) -> Result<Response<proto::DeliveryReports>, Status> {
+        let Principal { tenant, sender, .. } = self.authorize(request.metadata()).await?;
+        let data = notification::Notification::from(request.into_inner().data.unwrap_or_default());
+        let reader = self.state.channels.read().await;
+        let recipients = crate::recipients(
+            &self.state,
+            reader.iter().filter(|(user_id, _)| tenant.owns(user_id)),
+            &data.to_json(),
+            &data.push_options(),
+            &sender,
+        );
+        drop(reader);
+        let reports = crate::fan_out(&self.state, recipients).await;
+        Ok(Response::new(reports.into()))
+    }
+
+    async fn send_topic(
+        &self,
+        request: Request<proto::TopicRequest>,
+    ) -> Result<Response<proto::DeliveryReports>, Status> {
+        let Principal { tenant, sender, .. } = self.authorize(request.metadata()).await?;
+        let request = request.into_inner();
+        let data = notification::Notification::from(request.data.unwrap_or_default());
+        let reports = crate::deliver(
+            &self.state,
+            &Target::Topic(tenant.scope(&request.topic)?),
+            &data.to_json(),
+            &data.push_options(),
+            &sender,
+        )
+        .await;
+        Ok(Response::new(reports.into()))
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<proto::SubscribeReply>, Status> {
+        let tenant = self.authorize(request.metadata()).await?.tenant;
+        let request = request.into_inner();
+        subscribe_topic(
+            &self.state,
+            tenant.scope(&request.user_id)?,
+            tenant.scope(&request.topic)?,
+        )
+        .await?;
+        Ok(Response::new(proto::SubscribeReply {}))
+    }
+}
+
+impl From<AppError> for Status {
+    fn from(error: AppError) -> Self {
+        let code = match error.status().as_u16() {
+            400 | 413 => tonic::Code::InvalidArgument,
+            401 => tonic::Code::Unauthenticated,
+            403 => tonic::Code::PermissionDenied,
+            404 => tonic::Code::NotFound,
+            429 => tonic::Code::ResourceExhausted,
+            503 => tonic::Code::Unavailable,
+            _ => tonic::Code::Internal,
+        };
+        let mut status = Self::new(code, error.to_string());
+        status
+            .metadata_mut()
+            .insert("error-code", error.code().parse().expect("Codes are ASCII"));
+        status
+    }
+}
+
+fn urgency(urgency: i32) -> Option<notification::Urgency> {
+    match proto::Urgency::try_from(urgency).ok()? {
+        proto::Urgency::Unspecified => None,
+        proto::Urgency::VeryLow => Some(notification::Urgency::VeryLow),
+        proto::Urgency::Low => Some(notification::Urgency::Low),
+        proto::Urgency::Normal => Some(notification::Urgency::Normal),
+        proto::Urgency::High => Some(notification::Urgency::High),
+    }
+}
+
+impl From<proto::Notification> for notification::Notification {
+    fn from(data: proto::Notification) -> Self {
+        Self {
+            title: data.title,
+            body: data.body,
+            icon: data.icon,
+            badge: data.badge,
+            url: data.url,
+            actions: data
+                .actions
+                .into_iter()
+                .map(|action| notification::NotificationAction {
+                    action: action.action,
+                    title: action.title,
+                    icon: action.icon,
+                })
+                .collect(),
+            tag: data.tag,
+            ttl: data.ttl,
+            urgency: urgency(data.urgency),
+        }
+    }
+}
+
+impl From<proto::PushOptions> for PushOptions {
+    fn from(options: proto::PushOptions) -> Self {
+        Self {
+            ttl: options.ttl,
+            expires_at: options
+                .expires_at
+                .and_then(|at| DateTime::from_timestamp(at, 0)),
+            urgency: urgency(options.urgency),
+            topic: options.topic,
+            indirect: options.indirect,
+            mode: match proto::PushMode::try_from(options.mode) {
+                Ok(proto::PushMode::Payload) => Some(notification::PushMode::Payload),
+                Ok(proto::PushMode::Tickle) => Some(notification::PushMode::Tickle),
+                Ok(proto::PushMode::Unspecified) | Err(_) => None,
+            },
+            collapse_key: options.collapse_key,
+            priority: match proto::Priority::try_from(options.priority) {
+                Ok(proto::Priority::High) => Some(notification::Priority::High),
+                Ok(proto::Priority::Normal) => Some(notification::Priority::Normal),
+                Ok(proto::Priority::Low) => Some(notification::Priority::Low),
+                Ok(proto::Priority::Unspecified) | Err(_) => None,
+            },
+            track_clicks: options.track_clicks,
+        }
+    }
+}
+
+impl From<DeliveryStatus> for proto::DeliveryStatus {
+    fn from(status: DeliveryStatus) -> Self {
+        let (status, error, until) = match status {
+            DeliveryStatus::Sent => ("sent", None, None),
+            DeliveryStatus::Routed => ("routed", None, None),
+            DeliveryStatus::Skipped => ("skipped", None, None),
+            DeliveryStatus::Failed { error } => ("failed", Some(error), None),
+            DeliveryStatus::Retrying { error } => ("retrying", Some(error), None),
+            DeliveryStatus::Deferred { until } => ("deferred", None, Some(until.to_rfc3339())),
+        };
+        Self {
+            status: status.to_owned(),
+            error,
+            until,
+        }
+    }
+}
+
+impl From<Vec<DeliveryReport>> for proto::DeliveryReports {
+    fn from(reports: Vec<DeliveryReport>) -> Self {
+        Self {
+            reports: reports
+                .into_iter()
+                .map(|report| proto::DeliveryReport {
+                    user_id: report.user_id,
+                    message_id: report.message_id.to_string(),
+                    push: Some(report.push.into()),
+                    sse: Some(report.sse.into()),
+                    websocket: Some(report.websocket.into()),
+                    queued: report.queued,
+                    email: Some(report.email.into()),
+                })
+                .collect(),
+        }
+    }
+}
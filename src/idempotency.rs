@@ -0,0 +1,124 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+type Key = (String, String);
+
+struct Entries<T> {
+    results: HashMap<Key, (Instant, Arc<OnceCell<T>>)>,
+    /// Keys in the order they were first seen, for expiring them.
+    order: VecDeque<(Instant, Key)>,
+}
+
+/// Results of sends made with an idempotency key, remembered for `window` so a
+/// retried request gets the original result instead of a second notification.
+/// Keys are per recipient and kept in memory only.
+pub struct IdempotencyStore<T> {
+    window: Duration,
+    entries: Mutex<Entries<T>>,
+}
+
+impl<T: Clone + Send + Sync> IdempotencyStore<T> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(Entries {
+                results: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Runs `send` unless `key` was already used for `user_id` within the window,
+    /// returning the result and whether it is a replay. A request arriving while
+    /// the first one is still running waits for it. Errors are not remembered,
+    /// the next request with the key tries again.
+    pub async fn run<E, F>(&self, user_id: &str, key: String, send: F) -> Result<(T, bool), E>
+    where
+        F: Future<Output = Result<T, E>> + Send,
+        E: Send,
+    {
+        let cell = self.cell((user_id.to_owned(), key));
+        let mut ran = false;
+        let result = cell
+            .get_or_try_init(|| {
+                ran = true;
+                send
+            })
+            .await?;
+        Ok((result.clone(), !ran))
+    }
+
+    fn cell(&self, key: Key) -> Arc<OnceCell<T>> {
+        let mut entries = self.entries.lock().expect("Idempotency keys were poisoned");
+        let now = Instant::now();
+        while let Some((seen, _)) = entries.order.front() {
+            if now.duration_since(*seen) < self.window {
+                break;
+            }
+            let (seen, key) = entries.order.pop_front().expect("Checked above");
+            if entries
+                .results
+                .get(&key)
+                .is_some_and(|(first_seen, _)| *first_seen == seen)
+            {
+                entries.results.remove(&key);
+            }
+        }
+        if let Some((_, cell)) = entries.results.get(&key) {
+            return cell.clone();
+        }
+        let cell = Arc::new(OnceCell::new());
+        entries.order.push_back((now, key.clone()));
+        entries.results.insert(key, (now, cell.clone()));
+        cell
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(store: &IdempotencyStore<u32>, user_id: &str, result: u32) -> (u32, bool) {
+        store
+            .run(user_id, "key".to_owned(), async { Ok::<_, ()>(result) })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_within_the_window() {
+        let store = IdempotencyStore::new(Duration::from_mins(1));
+        assert_eq!(run(&store, "alice", 1).await, (1, false));
+        assert_eq!(run(&store, "alice", 2).await, (1, true));
+        // Keys are per recipient.
+        assert_eq!(run(&store, "bob", 3).await, (3, false));
+    }
+
+    #[tokio::test]
+    async fn forgets_errors() {
+        let store = IdempotencyStore::new(Duration::from_mins(1));
+        let failed = store
+            .run("alice", "key".to_owned(), async { Err::<u32, _>(()) })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(run(&store, "alice", 1).await, (1, false));
+    }
+
+    #[tokio::test]
+    async fn expires_after_the_window() {
+        let store = IdempotencyStore::new(Duration::from_millis(50));
+        assert_eq!(run(&store, "alice", 1).await, (1, false));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(run(&store, "alice", 2).await, (2, false));
+        assert_eq!(run(&store, "alice", 3).await, (2, true));
+        let entries = store.entries.lock().unwrap();
+        assert_eq!(entries.order.len(), 1);
+        assert_eq!(entries.results.len(), 1);
+    }
+}
@@ -1,8 +1,13 @@
 const details = document.getElementById("details");
 const state = document.getElementById("state");
+// Handed over by the application backend when the server requires user tokens.
+const userToken = new URLSearchParams(location.search).get("user_token");
+// Set by the server on the built-in page, asked for when serving from a directory.
+const assetVersion = new URL(import.meta.url).searchParams.get("v");
 
 document.getElementById("initPushBtn").addEventListener("click", main);
 document.getElementById("initSseBtn").addEventListener("click", serverSentEvent);
+document.getElementById("seedDemoBtn").addEventListener("click", seedDemo);
 
 function urlBase64ToUint8Array(base64String) {
     var padding = "=".repeat((4 - (base64String.length % 4)) % 4);
@@ -21,8 +26,18 @@ async function fetchVapidKeys() {
     return fetch("/vapid.json").then((resp) => resp.json());
 }
 
+async function fetchVersion() {
+    return assetVersion ?? (await fetch("/version").then((resp) => resp.json())).version;
+}
+
 async function subscribeUserToPush(vapidKeys) {
-    const registration = await navigator.serviceWorker.register("service_worker.js");
+    const query = new URLSearchParams({ user_id: document.getElementById("userId").value });
+    if (userToken) {
+        query.set("user_token", userToken);
+    }
+    // A new version registers under a new URL, and the worker compares it with `/version`.
+    query.set("v", await fetchVersion());
+    const registration = await navigator.serviceWorker.register(`service_worker.js?${query}`);
     registration.update();
     const pushSubscription = await registration.pushManager.subscribe({
         userVisibleOnly: true,
@@ -32,8 +47,9 @@ async function subscribeUserToPush(vapidKeys) {
 }
 
 async function main() {
+    let keys;
     try {
-        let keys = await fetchVapidKeys();
+        keys = await fetchVapidKeys();
         await Notification.requestPermission();
         state.subscription = await subscribeUserToPush(keys);
         details.textContent = JSON.stringify(state.subscription, null, 4);
@@ -47,10 +63,12 @@ async function main() {
         await fetch("/register", {
             method: "POST",
             headers: {
-                "Content-Type": "application/json"
+                "Content-Type": "application/json",
+                ...(userToken ? { "X-User-Token": userToken } : {})
             },
             body: JSON.stringify({
                 user_id: document.getElementById("userId").value,
+                vapid_key: keys?.publicKey,
                 ...JSON.parse(JSON.stringify(state.subscription))
             })
         });
@@ -62,8 +80,32 @@ async function main() {
 }
 
 function serverSentEvent() {
-    const eventSource = new EventSource(`/sse?user_id=${document.getElementById("userId").value}`);
+    const query = new URLSearchParams({ user_id: document.getElementById("userId").value });
+    if (userToken) {
+        query.set("user_token", userToken);
+    }
+    const eventSource = new EventSource(`/sse?${query}`);
     eventSource.onmessage = (event) => {
         state.textContent = (state.textContent ?? "") + "\n" + event.data;
     };
 }
+
+// Only answered by servers started with `--demo`.
+async function seedDemo() {
+    const userId = document.getElementById("userId").value;
+    try {
+        const resp = await fetch("/demo/seed", {
+            method: "POST",
+            headers: { "Content-Type": "application/json" },
+            body: JSON.stringify(userId ? { user_id: userId } : {})
+        });
+        if (!resp.ok) {
+            throw new Error(`/demo/seed answered ${resp.status}, is the server running with --demo?`);
+        }
+        details.textContent = JSON.stringify(await resp.json(), null, 4);
+    } catch (error) {
+        if (error instanceof Error) {
+            state.innerText = error.message;
+        }
+    }
+}
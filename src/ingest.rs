@@ -0,0 +1,226 @@
+use metrics::increment_counter;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::{
+    audit::Sender, deliver, schedule::Target, send_one, AppState, BroadcastData, SendData,
+};
+
+/// Where notification events are consumed from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    #[cfg(feature = "nats")]
+    Nats { url: String, subject: String },
+    #[cfg(feature = "kafka")]
+    Kafka { brokers: Vec<String>, topic: String },
+}
+
+/// JSON pointers (RFC 6901) locating the parts of a `/send` request in an event.
+#[derive(Debug, Clone)]
+pub struct PayloadMapping {
+    pub user_id: String,
+    /// Used when the event has no user id, sending to the topic's subscribers.
+    pub topic: String,
+    /// The notification, an object or a plain string as `/send` accepts. An empty
+    /// pointer takes the whole event.
+    pub data: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IngestConfig {
+    pub source: Source,
+    pub mapping: PayloadMapping,
+}
+
+impl IngestConfig {
+    /// Reads `INGEST_BROKER` (`nats` or `kafka`), `INGEST_URL` (comma-separated
+    /// brokers for Kafka) and `INGEST_SUBJECT`, the subject or topic consumed.
+    /// `INGEST_USER_ID_POINTER`, `INGEST_TOPIC_POINTER` and `INGEST_DATA_POINTER`
+    /// map events, defaulting to the fields of a `/send` request.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(broker) = std::env::var("INGEST_BROKER") else {
+            return Ok(None);
+        };
+        let (Ok(url), Ok(subject)) = (std::env::var("INGEST_URL"), std::env::var("INGEST_SUBJECT"))
+        else {
+            return Err("INGEST_BROKER requires INGEST_URL and INGEST_SUBJECT".to_owned());
+        };
+        let source = match broker.as_str() {
+            #[cfg(feature = "nats")]
+            "nats" => Source::Nats { url, subject },
+            #[cfg(feature = "kafka")]
+            "kafka" => Source::Kafka {
+                brokers: url
+                    .split(',')
+                    .map(|broker| broker.trim().to_owned())
+                    .collect(),
+                topic: subject,
+            },
+            #[cfg(not(feature = "nats"))]
+            "nats" => return Err("Built without the `nats` feature".to_owned()),
+            #[cfg(not(feature = "kafka"))]
+            "kafka" => return Err("Built without the `kafka` feature".to_owned()),
+            _ => return Err(format!("Unknown INGEST_BROKER `{broker}`")),
+        };
+        let pointer = |name: &str, default: &str| {
+            let pointer = std::env::var(name).unwrap_or_else(|_| default.to_owned());
+            if pointer.is_empty() || pointer.starts_with('/') {
+                Ok(pointer)
+            } else {
+                Err(format!("{name} must be empty or start with `/`"))
+            }
+        };
+        Ok(Some(Self {
+            source,
+            mapping: PayloadMapping {
+                user_id: pointer("INGEST_USER_ID_POINTER", "/user_id")?,
+                topic: pointer("INGEST_TOPIC_POINTER", "/topic")?,
+                data: pointer("INGEST_DATA_POINTER", "/data")?,
+            },
+        }))
+    }
+}
+
+/// Consumes events until shutdown, delivering each like `/send` or `/send/topic`.
+/// Events are for the default tenant.
+pub async fn run(state: AppState, config: IngestConfig) {
+    let result = match &config.source {
+        #[cfg(feature = "nats")]
+        Source::Nats { url, subject } => consume_nats(&state, &config.mapping, url, subject).await,
+        #[cfg(feature = "kafka")]
+        Source::Kafka { brokers, topic } => {
+            consume_kafka(&state, &config.mapping, brokers, topic).await
+        }
+    };
+    if let Err(error) = result {
+        error!("Ingestion stopped: {error}");
+    }
+}
+
+#[cfg(feature = "nats")]
+async fn consume_nats(
+    state: &AppState,
+    mapping: &PayloadMapping,
+    url: &str,
+    subject: &str,
+) -> Result<(), String> {
+    let client = async_nats::connect(url)
+        .await
+        .map_err(|error| error.to_string())?;
+    let subscriber = client
+        .subscribe(subject.to_owned())
+        .await
+        .map_err(|error| error.to_string())?;
+    info!("Ingesting notification events from NATS subject {subject}");
+    let mut messages = std::pin::pin!(futures::StreamExt::take_until(
+        subscriber,
+        crate::shutdown_requested(state)
+    ));
+    while let Some(message) = futures::StreamExt::next(&mut messages).await {
+        ingest(state, mapping, &message.payload).await;
+    }
+    Ok(())
+}
+
+/// Reads every partition of `topic` from its latest offset. There is no consumer
+/// group, so events produced while the server is down are not seen.
+#[cfg(feature = "kafka")]
+async fn consume_kafka(
+    state: &AppState,
+    mapping: &PayloadMapping,
+    brokers: &[String],
+    topic: &str,
+) -> Result<(), String> {
+    use std::sync::Arc;
+
+    use rskafka::client::{
+        consumer::{StartOffset, StreamConsumerBuilder},
+        partition::UnknownTopicHandling,
+        ClientBuilder,
+    };
+
+    let client = ClientBuilder::new(brokers.to_vec())
+        .build()
+        .await
+        .map_err(|error| error.to_string())?;
+    let partitions = client
+        .list_topics()
+        .await
+        .map_err(|error| error.to_string())?
+        .into_iter()
+        .find(|metadata| metadata.name == topic)
+        .ok_or_else(|| format!("Kafka topic {topic} does not exist"))?
+        .partitions;
+    let mut consumers = Vec::new();
+    for partition in partitions {
+        let partition = client
+            .partition_client(topic, partition, UnknownTopicHandling::Error)
+            .await
+            .map_err(|error| error.to_string())?;
+        consumers
+            .push(StreamConsumerBuilder::new(Arc::new(partition), StartOffset::Latest).build());
+    }
+    info!("Ingesting notification events from Kafka topic {topic}");
+    let mut records = std::pin::pin!(futures::StreamExt::take_until(
+        futures::stream::select_all(consumers),
+        crate::shutdown_requested(state),
+    ));
+    while let Some(record) = futures::StreamExt::next(&mut records).await {
+        match record {
+            Ok((record, _)) => {
+                if let Some(value) = record.record.value {
+                    ingest(state, mapping, &value).await;
+                }
+            }
+            Err(error) => warn!("Kafka consumer error: {error}"),
+        }
+    }
+    Ok(())
+}
+
+async fn ingest(state: &AppState, mapping: &PayloadMapping, payload: &[u8]) {
+    let outcome = match deliver_event(state, mapping, payload).await {
+        Ok(()) => "delivered",
+        Err(error) => {
+            warn!("Ingested event was not delivered: {error}");
+            "rejected"
+        }
+    };
+    increment_counter!("ingested_events_total", "outcome" => outcome);
+}
+
+async fn deliver_event(
+    state: &AppState,
+    mapping: &PayloadMapping,
+    payload: &[u8],
+) -> Result<(), String> {
+    let event = serde_json::from_slice::<Value>(payload).map_err(|error| error.to_string())?;
+    let data = event
+        .pointer(&mapping.data)
+        .cloned()
+        .ok_or("the event has no notification")?;
+    let field = |pointer: &str| event.pointer(pointer).and_then(Value::as_str);
+    if let Some(user_id) = field(&mapping.user_id) {
+        let mut send =
+            serde_json::from_value::<SendData>(json!({ "user_id": user_id, "data": data }))
+                .map_err(|error| error.to_string())?;
+        send.sender = Sender::internal("ingest");
+        send_one(state, send)
+            .await
+            .map_err(|error| error.to_string())?;
+    } else if let Some(topic) = field(&mapping.topic) {
+        let send = serde_json::from_value::<BroadcastData>(json!({ "data": data }))
+            .map_err(|error| error.to_string())?;
+        deliver(
+            state,
+            &Target::Topic(topic.to_owned()),
+            &send.data.to_json(),
+            &send.data.push_options(),
+            &Sender::internal("ingest"),
+        )
+        .await;
+    } else {
+        return Err("the event names neither a user nor a topic".to_owned());
+    }
+    Ok(())
+}
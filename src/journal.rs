@@ -0,0 +1,519 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use chrono::Utc;
+use metrics::increment_counter;
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Row, SqlitePool,
+};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    at_rest::{self, PayloadCipher},
+    audit::Sender,
+    cluster::{Cluster, ClusterEvent},
+    delivery_queue::{DeliveryQueue, Job, MemoryQueue, RedisQueue, ReplyTo},
+    error::AppError,
+    notification::{Notification, PushOptions},
+    send_now,
+    store::StoreError,
+    AppState, SendData, Sent,
+};
+
+/// How old a Redis entry has to be before a starting instance replays it, so
+/// it leaves alone what the other instances are still delivering.
+const REDIS_REPLAY_GRACE: Duration = Duration::from_mins(1);
+/// How long a request waits for a worker to report on its send, which only
+/// runs out when the instance delivering it went away.
+const REPLY_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// Where accepted sends are written before they are delivered and removed once
+/// they were. Whatever is left on startup was interrupted by a crash and is
+/// delivered again.
+#[async_trait]
+pub trait SendJournal: Send + Sync {
+    /// Records `payload`, returning the id to complete it by.
+    async fn append(&self, payload: &str) -> Result<String, StoreError>;
+    async fn complete(&self, id: &str) -> Result<(), StoreError>;
+    /// The entries to replay, oldest first.
+    async fn pending(&self) -> Result<Vec<(String, String)>, StoreError>;
+}
+
+/// Keeps nothing, for servers without a database where nothing survives a
+/// crash anyway.
+pub struct MemoryJournal;
+
+#[async_trait]
+impl SendJournal for MemoryJournal {
+    async fn append(&self, _payload: &str) -> Result<String, StoreError> {
+        Ok(String::new())
+    }
+
+    async fn complete(&self, _id: &str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<(String, String)>, StoreError> {
+        Ok(Vec::new())
+    }
+}
+
+pub struct SqliteJournal {
+    pool: SqlitePool,
+}
+
+impl SqliteJournal {
+    /// Opens the database at `url`, the one subscriptions are kept in.
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS send_journal (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                payload TEXT NOT NULL,
+                accepted_at TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl SendJournal for SqliteJournal {
+    async fn append(&self, payload: &str) -> Result<String, StoreError> {
+        let id = sqlx::query("INSERT INTO send_journal (payload, accepted_at) VALUES (?, ?)")
+            .bind(payload)
+            .bind(Utc::now().to_rfc3339())
+            .execute(&self.pool)
+            .await?
+            .last_insert_rowid();
+        Ok(id.to_string())
+    }
+
+    async fn complete(&self, id: &str) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM send_journal WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<(String, String)>, StoreError> {
+        let rows = sqlx::query("SELECT id, payload FROM send_journal ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let id: i64 = row.try_get("id")?;
+                Ok((id.to_string(), row.try_get("payload")?))
+            })
+            .collect()
+    }
+}
+
+/// A Redis stream shared by every instance on the same server.
+pub struct RedisJournal {
+    connection: MultiplexedConnection,
+}
+
+impl RedisJournal {
+    const KEY: &'static str = "notifications:send-journal";
+
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            connection: client.get_multiplexed_tokio_connection().await?,
+        })
+    }
+}
+
+#[async_trait]
+impl SendJournal for RedisJournal {
+    async fn append(&self, payload: &str) -> Result<String, StoreError> {
+        Ok(redis::cmd("XADD")
+            .arg(Self::KEY)
+            .arg("*")
+            .arg("payload")
+            .arg(payload)
+            .query_async(&mut self.connection.clone())
+            .await?)
+    }
+
+    async fn complete(&self, id: &str) -> Result<(), StoreError> {
+        redis::cmd("XDEL")
+            .arg(Self::KEY)
+            .arg(id)
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<(String, String)>, StoreError> {
+        // Stream ids start with the millisecond they were added at.
+        let cutoff = Utc::now().timestamp_millis()
+            - i64::try_from(REDIS_REPLAY_GRACE.as_millis()).unwrap_or(i64::MAX);
+        let entries: Vec<(String, Vec<(String, String)>)> = redis::cmd("XRANGE")
+            .arg(Self::KEY)
+            .arg("-")
+            .arg(cutoff)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        entries
+            .into_iter()
+            .map(|(id, fields)| {
+                fields
+                    .into_iter()
+                    .find(|(name, _)| name == "payload")
+                    .map(|(_, payload)| (id.clone(), payload))
+                    .ok_or_else(|| {
+                        StoreError::Corrupt(format!("journal entry {id} has no payload"))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// A `/send` request as it is journaled.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    user_id: String,
+    data: Notification,
+    push: PushOptions,
+    sender: Option<String>,
+}
+
+impl From<Entry> for SendData {
+    fn from(entry: Entry) -> Self {
+        Self {
+            user_id: entry.user_id,
+            data: entry.data,
+            push: entry.push,
+            message_id: None,
+            sender: Sender(entry.sender),
+            dry_run: false,
+            digest: false,
+        }
+    }
+}
+
+/// How a send went, as a worker on another instance reports it back.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SendOutcome {
+    Sent {
+        status: u16,
+        message_id: Uuid,
+        text: String,
+    },
+    UserNotFound,
+    ConsentExpired,
+    RateLimited {
+        retry_after_ms: u64,
+    },
+    InvalidPushOptions {
+        reason: String,
+    },
+    PayloadTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+impl From<&Result<Sent, AppError>> for SendOutcome {
+    fn from(sent: &Result<Sent, AppError>) -> Self {
+        match sent {
+            Ok((status, message_id, text)) => Self::Sent {
+                status: status.as_u16(),
+                message_id: *message_id,
+                text: text.clone(),
+            },
+            Err(AppError::UserNotFound) => Self::UserNotFound,
+            Err(AppError::ConsentExpired) => Self::ConsentExpired,
+            Err(AppError::RateLimited(retry_after)) => Self::RateLimited {
+                retry_after_ms: u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX),
+            },
+            Err(AppError::InvalidPushOptions(reason)) => Self::InvalidPushOptions {
+                reason: reason.clone(),
+            },
+            Err(AppError::PayloadTooLarge { size, limit }) => Self::PayloadTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            Err(error) => Self::Failed {
+                reason: error.to_string(),
+            },
+        }
+    }
+}
+
+impl From<SendOutcome> for Result<Sent, AppError> {
+    fn from(outcome: SendOutcome) -> Self {
+        match outcome {
+            SendOutcome::Sent {
+                status,
+                message_id,
+                text,
+            } => Ok((
+                StatusCode::from_u16(status).unwrap_or(StatusCode::OK),
+                message_id,
+                text,
+            )),
+            SendOutcome::UserNotFound => Err(AppError::UserNotFound),
+            SendOutcome::ConsentExpired => Err(AppError::ConsentExpired),
+            SendOutcome::RateLimited { retry_after_ms } => {
+                Err(AppError::RateLimited(Duration::from_millis(retry_after_ms)))
+            }
+            SendOutcome::InvalidPushOptions { reason } => Err(AppError::InvalidPushOptions(reason)),
+            SendOutcome::PayloadTooLarge { size, limit } => {
+                Err(AppError::PayloadTooLarge { size, limit })
+            }
+            SendOutcome::Failed { reason } => Err(AppError::WorkerFailed(reason)),
+        }
+    }
+}
+
+/// Journals sends and hands them to the workers started by [`run`], through
+/// whichever [`DeliveryQueue`] is configured.
+pub struct SendQueue {
+    journal: Box<dyn SendJournal>,
+    queue: Box<dyn DeliveryQueue>,
+    /// Seals entries before they are journaled, they hold the message content.
+    cipher: Box<dyn PayloadCipher>,
+    /// Addresses results to this instance when a worker elsewhere delivers.
+    instance: Uuid,
+    /// Requests waiting for their send to be delivered.
+    waiting: std::sync::Mutex<HashMap<Uuid, oneshot::Sender<Result<Sent, AppError>>>>,
+    workers: usize,
+}
+
+impl SendQueue {
+    /// Journals next to the subscriptions: in the database at `DATABASE_URL`,
+    /// else in a stream on the Redis server at `REDIS_URL`, else nowhere. Entries are
+    /// encrypted when `PAYLOAD_ENCRYPTION_SECRET` is set. `SEND_WORKERS` sends are
+    /// delivered at the same time, 64 by default.
+    ///
+    /// With `SEND_QUEUE=redis`, sends are queued in a Redis stream the workers of
+    /// every instance in the `cluster` take them from, which also journals them.
+    pub async fn from_env(cluster: Option<&Cluster>) -> Result<Self, StoreError> {
+        let workers = std::env::var("SEND_WORKERS")
+            .ok()
+            .and_then(|workers| workers.parse().ok())
+            .unwrap_or(64_usize)
+            .max(1);
+        let instance = cluster.map_or_else(Uuid::new_v4, Cluster::instance);
+        let shared = std::env::var("SEND_QUEUE").is_ok_and(|queue| queue == "redis");
+        let (journal, queue): (Box<dyn SendJournal>, Box<dyn DeliveryQueue>) = if shared {
+            let (Some(_), Ok(url)) = (cluster, std::env::var("REDIS_URL")) else {
+                return Err(StoreError::Redis(redis::RedisError::from((
+                    redis::ErrorKind::InvalidClientConfig,
+                    "SEND_QUEUE=redis needs the subscriptions kept at REDIS_URL",
+                ))));
+            };
+            (
+                Box::new(MemoryJournal),
+                Box::new(RedisQueue::connect(&url, instance, workers).await?),
+            )
+        } else if let Ok(url) = std::env::var("DATABASE_URL") {
+            (
+                Box::new(SqliteJournal::connect(&url).await?),
+                Box::new(MemoryQueue::default()),
+            )
+        } else if let Ok(url) = std::env::var("REDIS_URL") {
+            (
+                Box::new(RedisJournal::connect(&url).await?),
+                Box::new(MemoryQueue::default()),
+            )
+        } else {
+            (Box::new(MemoryJournal), Box::new(MemoryQueue::default()))
+        };
+        Ok(Self {
+            journal,
+            queue,
+            cipher: at_rest::cipher_from_env(),
+            instance,
+            waiting: std::sync::Mutex::default(),
+            workers,
+        })
+    }
+
+    /// Drops the journaled and queued sends to `user_id`, returning how many
+    /// there were. Entries that can't be opened are left alone.
+    pub async fn erase(&self, user_id: &str) -> Result<usize, StoreError> {
+        let mut erased = 0;
+        for (entry, sealed) in self.journal.pending().await? {
+            if self.is_for(&sealed, user_id) {
+                self.journal.complete(&entry).await?;
+                erased += 1;
+            }
+        }
+        for (id, sealed) in self.queue.waiting().await? {
+            if self.is_for(&sealed, user_id) {
+                self.queue.ack(&id).await?;
+                erased += 1;
+            }
+        }
+        Ok(erased)
+    }
+
+    fn is_for(&self, sealed: &str, user_id: &str) -> bool {
+        self.cipher.open(sealed).is_ok_and(|payload| {
+            serde_json::from_str::<Entry>(&payload).is_ok_and(|parsed| parsed.user_id == user_id)
+        })
+    }
+
+    /// Journals `send` and waits for a worker to deliver it.
+    pub async fn submit(&self, send: SendData) -> Result<Sent, AppError> {
+        let entry = Entry {
+            user_id: send.user_id,
+            data: send.data,
+            push: send.push,
+            sender: send.sender.0,
+        };
+        let payload = self
+            .cipher
+            .seal(&serde_json::to_string(&entry).expect("Journal entries serialize"));
+        let entry = self.journal.append(&payload).await?;
+        let request = Uuid::new_v4();
+        let (reply, result) = oneshot::channel();
+        self.waiting().insert(request, reply);
+        let job = Job {
+            id: String::new(),
+            entry,
+            payload,
+            reply_to: Some(ReplyTo {
+                instance: self.instance,
+                request,
+            }),
+        };
+        if let Err(error) = self.queue.push(job).await {
+            self.waiting().remove(&request);
+            return Err(error.into());
+        }
+        if let Ok(Ok(sent)) = tokio::time::timeout(REPLY_TIMEOUT, result).await {
+            sent
+        } else {
+            self.waiting().remove(&request);
+            Err(AppError::SendTimedOut)
+        }
+    }
+
+    /// Hands the result of a send to the request waiting for it, if it still is.
+    pub fn resolve(&self, request: Uuid, sent: Result<Sent, AppError>) {
+        let reply = self.waiting().remove(&request);
+        if let Some(reply) = reply {
+            let _ = reply.send(sent);
+        }
+    }
+
+    fn waiting(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<Uuid, oneshot::Sender<Result<Sent, AppError>>>> {
+        self.waiting.lock().expect("Send replies were poisoned")
+    }
+}
+
+/// Starts the workers, then replays what the journal kept from before a crash.
+pub async fn run(state: AppState) {
+    for _ in 0..state.send_queue.workers {
+        tokio::spawn(work(state.clone()));
+    }
+    // Replayed before the recipients are back, the sends would find nobody.
+    state.recovery().await;
+    let pending = match state.send_queue.journal.pending().await {
+        Ok(pending) => pending,
+        Err(error) => {
+            error!("Journaled sends could not be read: {error}");
+            return;
+        }
+    };
+    if !pending.is_empty() {
+        info!("Replaying {} interrupted send(s)", pending.len());
+    }
+    for (entry, payload) in pending {
+        increment_counter!("send_replays_total");
+        let job = Job {
+            id: String::new(),
+            entry,
+            payload,
+            reply_to: None,
+        };
+        if let Err(error) = state.send_queue.queue.push(job).await {
+            error!("Journaled send could not be queued: {error}");
+        }
+    }
+}
+
+async fn work(state: AppState) {
+    while let Some(job) = state.send_queue.queue.pop().await {
+        let payload = match state.send_queue.cipher.open(&job.payload) {
+            Ok(payload) => payload,
+            Err(error) => {
+                // Kept, it may well open once the right secret is configured.
+                error!("Journal entry {} could not be opened: {error}", job.entry);
+                if let Err(error) = state.send_queue.queue.bury(&job).await {
+                    error!("Queued send {} could not be set aside: {error}", job.id);
+                }
+                reply(&state, &job, Err(error.into())).await;
+                continue;
+            }
+        };
+        let sent = match serde_json::from_str::<Entry>(&payload) {
+            Ok(parsed) => send_now(&state, parsed.into()).await,
+            Err(error) => {
+                warn!("Dropping corrupt journal entry {}: {error}", job.entry);
+                Err(StoreError::Corrupt(error.to_string()).into())
+            }
+        };
+        complete(&state, &job).await;
+        match (&job.reply_to, &sent) {
+            (None, Err(error)) => warn!("Replayed send failed: {error}"),
+            (None, Ok(_)) => {}
+            (Some(_), _) => reply(&state, &job, sent).await,
+        }
+    }
+}
+
+/// Tells the request waiting for `job` how it went, wherever it waits.
+async fn reply(state: &AppState, job: &Job, sent: Result<Sent, AppError>) {
+    let Some(reply_to) = job.reply_to else {
+        return;
+    };
+    if reply_to.instance == state.send_queue.instance {
+        state.send_queue.resolve(reply_to.request, sent);
+        return;
+    }
+    let Some(cluster) = &state.cluster else {
+        return;
+    };
+    let event = ClusterEvent::SendResult {
+        request: reply_to.request,
+        outcome: (&sent).into(),
+    };
+    // Nobody listening means the request went away with its instance.
+    if let Err(error) = cluster.tell(reply_to.instance, event).await {
+        error!("Send result could not be reported: {error}");
+    }
+}
+
+async fn complete(state: &AppState, job: &Job) {
+    if let Err(error) = state.send_queue.journal.complete(&job.entry).await {
+        error!(
+            "Journal entry {} could not be completed: {error}",
+            job.entry
+        );
+    }
+    if let Err(error) = state.send_queue.queue.ack(&job.id).await {
+        error!("Queued send {} could not be acknowledged: {error}", job.id);
+    }
+}
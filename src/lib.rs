@@ -0,0 +1,5088 @@
+#![warn(clippy::all, clippy::nursery, clippy::pedantic, clippy::perf)]
+#![allow(clippy::significant_drop_tightening)]
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    future::Future,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    middleware,
+    response::{sse::Event, Html, IntoResponse, Response, Sse},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{BoxStream, FuturesOrdered, FuturesUnordered},
+    Stream,
+};
+use hyper::{header, header::HeaderValue, Client, HeaderMap};
+use hyper_rustls::HttpsConnectorBuilder;
+use metrics::{counter, increment_counter};
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, json, Value};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch, RwLock},
+    time::MissedTickBehavior,
+};
+use tokio_stream::{
+    wrappers::{BroadcastStream, IntervalStream},
+    StreamExt,
+};
+use tower_http::{
+    compression::{predicate::SizeAbove, CompressionLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    services::ServeDir,
+    set_header::SetResponseHeader,
+    trace::TraceLayer,
+};
+use tracing::{error, info, info_span, instrument, warn, Instrument, Span};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use uuid::Uuid;
+
+use crate::apns::ApnsProvider;
+use crate::archive::{ArchivePage, MessageArchive};
+use crate::assets::BuiltIn;
+use crate::audit::{AuditEntry, AuditLog, AuditQuery, Sender, TailQuery};
+use crate::auth::{AdminAuth, Authenticator, Principal, Scope, Tenant};
+use crate::broadcast_job::{BroadcastJobs, BroadcastOptions, BroadcastProgress};
+use crate::callback::{CallbackRegistration, CallbackRequest, Callbacks};
+use crate::circuit::{CircuitBreakers, CircuitConfig};
+use crate::click::ClickTracking;
+use crate::cluster::{Cluster, ClusterEvent};
+use crate::codec::{Encoding, Payload};
+use crate::consent::ConsentConfig;
+use crate::demo::{DemoSeed, DemoSeedRequest};
+use crate::digest::{Digest, DigestConfig};
+use crate::email::EmailChannel;
+use crate::erasure::ErasureReceipt;
+use crate::error::AppError;
+use crate::export::{ExportKey, ExportOptions, ImportRejection, ImportReport};
+use crate::fcm::FcmProvider;
+use crate::idempotency::IdempotencyStore;
+use crate::journal::SendQueue;
+use crate::notification::{Notification, PushOptions};
+use crate::openapi::ApiDoc;
+use crate::outbox::{outbox, Closed, OutboxReceiver, OutboxSender, Pushed, Received};
+use crate::preferences::{Preferences, PushPlan};
+use crate::presence::{Presence, PresenceChange, PresenceEvent, PresenceHooks};
+use crate::push::{DeliveryError, ProviderKind, PushAttempt, PushPreview, PushProvider};
+use crate::push_queue::{OriginLimits, PushQueue};
+use crate::queue::{EventHistory, OfflineQueue, QueueConfig, RealtimeMessage};
+use crate::rate_limit::RateLimits;
+use crate::reaper::{AddressHealth, ReaperConfig};
+use crate::retry::RetryConfig;
+use crate::schedule::{ScheduleRequest, ScheduledJob, Target};
+use crate::schema::{PayloadSchema, Schemas};
+use crate::status::{
+    AckAction, AckEvent, MessageStatus, PushState, RealtimeState, StatusStore, Transition,
+};
+use crate::store::{
+    MemoryStore, RawWebPushSubscription, RedisStore, SqliteStore, StoreError, Subscription,
+    SubscriptionStore, WebPushSubscription,
+};
+use crate::telemetry::ConnectionGauge;
+use crate::template::{NotificationTemplate, TemplateSendData, Templates};
+use crate::throttle::{Admission, RecipientCaps};
+use crate::transform::Transformers;
+use crate::user_token::{UserToken, UserTokens};
+use crate::web_push::{VapidKeys, WebPushProvider};
+use crate::webhook::WebhookProvider;
+
+mod apns;
+mod archive;
+mod assets;
+mod at_rest;
+mod audit;
+mod auth;
+mod bench;
+mod broadcast_job;
+mod callback;
+mod circuit;
+mod click;
+mod cluster;
+mod codec;
+mod config;
+mod consent;
+mod delivery_queue;
+mod demo;
+mod digest;
+mod email;
+mod erasure;
+mod error;
+mod export;
+mod fcm;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod idempotency;
+#[cfg(any(feature = "nats", feature = "kafka"))]
+mod ingest;
+mod journal;
+mod notification;
+mod openapi;
+mod outbox;
+mod preferences;
+mod presence;
+mod push;
+mod push_queue;
+mod queue;
+mod rate_limit;
+mod reaper;
+mod recovery;
+mod retry;
+mod schedule;
+mod schema;
+mod status;
+mod store;
+mod telemetry;
+mod template;
+#[cfg(test)]
+mod tests;
+mod throttle;
+mod transform;
+mod user_token;
+mod web_push;
+mod webhook;
+
+pub use crate::bench::{run as bench, BenchError, Report};
+pub use crate::config::{
+    Bench, Command, Config, ConfigError, GenVapid, LogFormat, OverflowPolicy, SseDelivery, Tls,
+};
+#[cfg(feature = "grpc")]
+pub use crate::grpc::serve as serve_grpc;
+pub use crate::web_push::VapidKey;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UserInfo {
+    user_id: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct Ack {
+    message_id: Uuid,
+    action: AckAction,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SendData {
+    user_id: String,
+    #[serde(deserialize_with = "notification::deserialize")]
+    data: Notification,
+    #[serde(flatten)]
+    push: PushOptions,
+    /// Client-chosen key making retries safe, as the `Idempotency-Key` header does.
+    /// Not the id of the message, which the response carries.
+    #[serde(default)]
+    message_id: Option<String>,
+    /// Set from the request's API key, for the audit log.
+    #[serde(skip)]
+    sender: Sender,
+    /// Validate and build the push requests, reporting them instead of sending
+    /// anything.
+    #[serde(default)]
+    dry_run: bool,
+    /// Keep the message for the recipient's next digest instead of sending it
+    /// now, answering `202 Accepted`.
+    #[serde(default)]
+    digest: bool,
+}
+
+/// What `send_one` reports: the response status, the message id and how the
+/// message went out.
+type Sent = (StatusCode, Uuid, String);
+
+/// Longest `Idempotency-Key` accepted.
+const MAX_IDEMPOTENCY_KEY: usize = 255;
+/// Most messages `/history/archive` returns at once.
+const MAX_ARCHIVE_PAGE: usize = 500;
+/// Most metadata attributes a registration may carry.
+const MAX_METADATA_ENTRIES: usize = 32;
+/// Longest metadata key or value accepted.
+const MAX_METADATA_LENGTH: usize = 256;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SseOptions {
+    /// `msgpack` or `cbor` send each event's data in that format, base64-encoded.
+    #[serde(default)]
+    #[param(inline)]
+    encoding: Encoding,
+    /// Overrides the server's heartbeat interval for this connection, clamped to
+    /// 1-300 seconds.
+    keep_alive_secs: Option<u64>,
+    /// Overrides the server's reconnect delay hint for this connection.
+    retry_ms: Option<u64>,
+    /// Comma-separated topics to subscribe the user to. The message retained for
+    /// each of them is sent before any other.
+    topic: Option<String>,
+}
+
+/// Most seconds a connection may ask to go without a heartbeat.
+const MAX_KEEP_ALIVE_SECS: u64 = 300;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PollOptions {
+    /// Seconds to wait for a message, 30 by default and at most 120.
+    timeout: Option<u64>,
+    /// The `event_id` of the last message received, to first get whatever was
+    /// missed since.
+    last_event_id: Option<u64>,
+}
+
+const DEFAULT_POLL_SECS: u64 = 30;
+const MAX_POLL_SECS: u64 = 120;
+
+/// A message as `/poll` returns it.
+#[derive(Serialize, ToSchema)]
+struct PolledMessage {
+    /// The SSE event id it was delivered with, to pass back as `last_event_id`.
+    event_id: u64,
+    #[schema(value_type = Notification)]
+    data: Value,
+}
+
+impl From<RealtimeMessage> for PolledMessage {
+    fn from(message: RealtimeMessage) -> Self {
+        Self {
+            event_id: message.event_id,
+            data: from_str(&message.data).unwrap_or(Value::String(message.data)),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryQuery {
+    user_id: String,
+    /// At most this many messages, 50 by default.
+    limit: Option<usize>,
+    /// Only messages with an event id below this one, for paging backwards.
+    before: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ArchiveQuery {
+    user_id: String,
+    /// At most this many messages, 50 by default and at most 500.
+    limit: Option<usize>,
+    /// The `next_cursor` of the page before.
+    cursor: Option<String>,
+}
+
+/// A message the user was sent, as listed by `/history`.
+#[derive(Serialize, ToSchema)]
+struct HistoryItem {
+    /// The SSE event id it was delivered with.
+    event_id: u64,
+    message_id: Uuid,
+    sent_at: DateTime<Utc>,
+    #[schema(value_type = Notification)]
+    data: Value,
+}
+
+/// A message waiting for the client to fetch it, as listed by `/messages/pending`.
+#[derive(Serialize, ToSchema)]
+struct PendingMessage {
+    message_id: Uuid,
+    sent_at: DateTime<Utc>,
+    #[schema(value_type = Notification)]
+    data: Value,
+}
+
+/// The new end of a user's consent period.
+#[derive(Serialize, ToSchema)]
+struct ConsentRenewal {
+    /// Unset when consent doesn't expire.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UnregisterOptions {
+    #[serde(default)]
+    notify: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct TopicSubscription {
+    user_id: String,
+    topic: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct TopicSendData {
+    topic: String,
+    #[serde(deserialize_with = "notification::deserialize")]
+    data: Notification,
+    /// Keeps the message as the topic's last value, replacing the one kept
+    /// before. Clients connecting to `/sse` with the topic receive it first.
+    #[serde(default)]
+    retain: bool,
+}
+
+/// Users to add to and remove from a group, removals applied last.
+#[derive(Deserialize, ToSchema)]
+struct GroupMembersUpdate {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Group {
+    name: String,
+    members: Vec<String>,
+}
+
+/// Outcome of a `/send/group` request.
+#[derive(Serialize, ToSchema)]
+struct GroupDelivery {
+    group: String,
+    /// Members with a registration, each of which got a report.
+    recipients: usize,
+    /// Recipients the message got through to, or still may, on some channel.
+    reached: usize,
+    reports: Vec<DeliveryReport>,
+}
+
+/// What a metadata attribute has to be for a user to match.
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum AttributeFilter {
+    Equals(String),
+    /// Any of these values.
+    OneOf(Vec<String>),
+}
+
+impl AttributeFilter {
+    fn matches(&self, value: Option<&String>) -> bool {
+        match (self, value) {
+            (_, None) => false,
+            (Self::Equals(expected), Some(value)) => expected == value,
+            (Self::OneOf(expected), Some(value)) => expected.contains(value),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct QuerySendData {
+    /// Metadata attributes every recipient has to match, such as
+    /// `{"locale": "de", "plan": ["pro", "team"]}`. Empty matches every user.
+    #[serde(default)]
+    filter: BTreeMap<String, AttributeFilter>,
+    #[serde(deserialize_with = "notification::deserialize")]
+    data: Notification,
+}
+
+/// Outcome of a `/send/query` request.
+#[derive(Serialize, ToSchema)]
+struct QueryDelivery {
+    /// Users matching the filter, each of which got a report.
+    recipients: usize,
+    /// Recipients the message got through to, or still may, on some channel.
+    reached: usize,
+    reports: Vec<DeliveryReport>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BroadcastData {
+    #[serde(deserialize_with = "notification::deserialize")]
+    data: Notification,
+    /// Report what each user would be sent instead of sending it.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// What a send would have done, reported by `dry_run` instead of sending.
+#[derive(Serialize, ToSchema)]
+struct DryRun {
+    user_id: String,
+    /// The id the message would have had.
+    message_id: Uuid,
+    /// What SSE and WebSocket connections would have been sent.
+    #[schema(value_type = Object)]
+    event: Value,
+    /// What the push providers would have been sent, before encryption.
+    #[schema(value_type = Object)]
+    push_payload: Value,
+    /// The requests to the push providers, none when the user's preferences
+    /// skip push.
+    push: Vec<PushPreview>,
+    /// How long quiet hours would have held the push back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    push_deferred_until: Option<DateTime<Utc>>,
+    sse_connections: usize,
+    websocket_connections: usize,
+    /// Whether the message would have waited in the offline queue for the next
+    /// connection.
+    queued: bool,
+}
+
+impl DryRun {
+    async fn new(
+        state: &AppState,
+        user_id: &str,
+        reg: &UserRegistration,
+        data: String,
+        options: PushOptions,
+    ) -> Self {
+        let message = OutboundMessage {
+            id: Uuid::new_v4(),
+            data,
+            options,
+            sender: Sender::default(),
+        };
+        let push_payload = message.push_payload();
+        let (push, push_deferred_until) = match reg.preferences.push_plan(Utc::now()) {
+            PushPlan::Skip => (Vec::new(), None),
+            plan => {
+                let previews = state
+                    .providers
+                    .iter()
+                    .filter(|provider| reg.subscription.address(provider.kind()).is_some())
+                    .map(|provider| async {
+                        let request = provider
+                            .preview(&reg.subscription, &push_payload, &message.options)
+                            .await;
+                        PushPreview::new(provider.kind(), request).await
+                    })
+                    .collect::<FuturesOrdered<_>>()
+                    .collect::<Vec<_>>()
+                    .await;
+                let until = match plan {
+                    PushPlan::Defer(until) => Some(until),
+                    PushPlan::Now | PushPlan::Skip => None,
+                };
+                (previews, until)
+            }
+        };
+        let realtime = reg.preferences.wants_realtime();
+        let json = |data: &str| from_str::<Value>(data).unwrap_or_else(|_| json!(data));
+        Self {
+            user_id: Tenant::local_part(user_id).to_owned(),
+            message_id: message.id,
+            event: json(&message.data),
+            push_payload: json(&push_payload),
+            push,
+            push_deferred_until,
+            sse_connections: if realtime {
+                reg.connection_count(Transport::Sse)
+            } else {
+                0
+            },
+            websocket_connections: if realtime {
+                reg.connection_count(Transport::WebSocket)
+            } else {
+                0
+            },
+            queued: realtime && reg.connections.is_empty(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum DeliveryStatus {
+    Sent,
+    /// Forwarded to the instance that holds the user's connection.
+    Routed,
+    Skipped,
+    Failed {
+        error: String,
+    },
+    /// The first attempt failed and further attempts continue in the background.
+    Retrying {
+        error: String,
+    },
+    /// Held back until the user's quiet hours end.
+    Deferred {
+        until: DateTime<Utc>,
+    },
+}
+
+/// What the message id adds to a direct push payload.
+const MESSAGE_ID_OVERHEAD: usize = r#","message_id":"00000000-0000-0000-0000-000000000000""#.len();
+
+/// A message accepted for one recipient, tracked under `id` in the status store.
+#[derive(Debug, Clone)]
+struct OutboundMessage {
+    id: Uuid,
+    data: String,
+    options: PushOptions,
+    sender: Sender,
+}
+
+impl OutboundMessage {
+    fn accept(
+        state: &AppState,
+        user_id: &str,
+        data: String,
+        options: PushOptions,
+        sender: &Sender,
+    ) -> Self {
+        let id = Uuid::new_v4();
+        let mut data = state.transformers.apply(sender, user_id, id, data);
+        state
+            .statuses
+            .accept(id, user_id, sender, options.expires_at);
+        if options.track_clicks.unwrap_or(false) {
+            if let Some((tracked, target)) = state.clicks.rewrite(id, &data) {
+                data = tracked;
+                state.statuses.set_target_url(&id, target);
+            }
+        }
+        state
+            .audit
+            .record(AuditEntry::new(user_id, id, sender, "server", "accepted"));
+        if options.stored() {
+            state.statuses.set_body(&id, data.clone());
+        }
+        if let Some(archive) = &state.archive {
+            archive.record(user_id, id, &data);
+        }
+        Self {
+            id,
+            data,
+            options,
+            sender: sender.clone(),
+        }
+    }
+
+    /// Notes in the audit log what happened to the message on `channel`.
+    fn audit(&self, user_id: &str, channel: &str, outcome: &str) -> AuditEntry {
+        AuditEntry::new(user_id, self.id, &self.sender, channel, outcome)
+    }
+
+    /// What push providers send: the content with the message id the client
+    /// acknowledges it by, or only the id of an indirect or tickle message.
+    fn push_payload(&self) -> String {
+        if self.options.stored() {
+            return json!({ "message_id": self.id }).to_string();
+        }
+        match from_str::<Value>(&self.data) {
+            Ok(Value::Object(mut fields)) => {
+                fields.insert("message_id".to_owned(), json!(self.id));
+                Value::Object(fields).to_string()
+            }
+            _ => self.data.clone(),
+        }
+    }
+}
+
+/// Outcome of one item of a `/send/batch` request.
+#[derive(Serialize, ToSchema)]
+struct BatchResult {
+    user_id: String,
+    /// The status `/send` would have responded with.
+    status: u16,
+    message_id: Option<Uuid>,
+    message: String,
+    /// Machine-readable error code, for failed items.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeliveryReport {
+    user_id: String,
+    message_id: Uuid,
+    push: DeliveryStatus,
+    sse: DeliveryStatus,
+    websocket: DeliveryStatus,
+    queued: bool,
+    email: DeliveryStatus,
+}
+
+impl DeliveryReport {
+    fn reached(&self) -> bool {
+        [&self.push, &self.sse, &self.websocket, &self.email]
+            .iter()
+            .any(|status| status.reached())
+            || self.queued
+    }
+}
+
+impl DeliveryStatus {
+    /// Whether the message got through or may still get through.
+    const fn reached(&self) -> bool {
+        matches!(
+            self,
+            Self::Sent | Self::Routed | Self::Retrying { .. } | Self::Deferred { .. }
+        )
+    }
+
+    /// Combines the outcomes of two transports, preferring success over failure over skipping.
+    fn or(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Sent, _) | (_, Self::Sent) => Self::Sent,
+            (Self::Routed, _) | (_, Self::Routed) => Self::Routed,
+            (deferred @ Self::Deferred { .. }, _) | (_, deferred @ Self::Deferred { .. }) => {
+                deferred
+            }
+            (Self::Failed { error } | Self::Retrying { error }, _)
+            | (_, Self::Failed { error } | Self::Retrying { error }) => Self::Failed { error },
+            (Self::Skipped, Self::Skipped) => Self::Skipped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Transport {
+    Sse,
+    WebSocket,
+}
+
+impl Transport {
+    const fn label(self) -> &'static str {
+        match self {
+            Self::Sse => "sse",
+            Self::WebSocket => "websocket",
+        }
+    }
+
+    /// The buffer of a new connection.
+    fn outbox(self, config: &Config) -> (OutboxSender, OutboxReceiver) {
+        outbox(
+            config.channel_buffer,
+            config.overflow_policy,
+            config.lag_threshold,
+            self.label(),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    transport: Transport,
+    sender: OutboxSender,
+    connected_at: DateTime<Utc>,
+}
+
+/// Held by an open stream; detaches its connection from the user once dropped.
+struct ConnectionHandle {
+    state: AppState,
+    user_id: String,
+    id: u64,
+    _gauge: ConnectionGauge,
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let (state, user_id, id) = (
+            self.state.clone(),
+            std::mem::take(&mut self.user_id),
+            self.id,
+        );
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { detach_connections(&state, &user_id, &[id]).await });
+        }
+    }
+}
+
+/// Any combination of a Web Push subscription, an FCM registration token, an
+/// APNs device token and a webhook URL, plus an optional email address to fall
+/// back to.
+/// Also the shape of registrations in `/admin/export`.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+struct UserRegistrationRequest {
+    user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keys: Option<UserRegistrationKey>,
+    /// The `applicationServerKey` the subscription was created with, the key in
+    /// `/vapid.json` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vapid_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fcm_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apns_token: Option<String>,
+    /// Only accepted from credentials with the publisher scope.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// Attributes to segment users by with `/send/query`, such as
+    /// `{"locale": "de", "plan": "pro"}`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    /// When the user's consent expires. Only kept by `/admin/import`, `/register`
+    /// starts a new consent period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl UserRegistrationRequest {
+    /// The request that registers `subscription` again.
+    fn export(user_id: &str, subscription: &Subscription) -> Self {
+        let web_push = subscription
+            .web_push
+            .clone()
+            .map(RawWebPushSubscription::from);
+        Self {
+            user_id: user_id.to_owned(),
+            endpoint: web_push.as_ref().map(|raw| raw.endpoint.clone()),
+            vapid_key: web_push.as_ref().and_then(|raw| raw.vapid_key.clone()),
+            keys: web_push.map(|raw| UserRegistrationKey {
+                p256dh: raw.p256dh,
+                auth: raw.auth,
+            }),
+            fcm_token: subscription.fcm_token.clone(),
+            apns_token: subscription.apns_token.clone(),
+            webhook_url: subscription.webhook_url.clone(),
+            email: subscription.email.clone(),
+            metadata: subscription.metadata.clone(),
+            expires_at: subscription.expires_at,
+        }
+    }
+}
+
+/// The Web Push subscription a service worker's `pushsubscriptionchange`
+/// handler received in place of the old one.
+#[derive(Deserialize, Debug, ToSchema)]
+struct RotationRequest {
+    user_id: String,
+    /// The endpoint being replaced. When given, the rotation is refused if the
+    /// user has registered a different one since.
+    #[serde(default)]
+    old_endpoint: Option<String>,
+    endpoint: String,
+    keys: UserRegistrationKey,
+    /// The `applicationServerKey` the new subscription was created with, the
+    /// key in `/vapid.json` when unset.
+    #[serde(default)]
+    vapid_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+struct UserRegistrationKey {
+    p256dh: String,
+    auth: String,
+}
+
+#[derive(Debug)]
+struct UserRegistration {
+    connections: HashMap<u64, Connection>,
+    topics: HashSet<String>,
+    queue: OfflineQueue,
+    history: EventHistory,
+    digest: Digest,
+    subscription: Subscription,
+    preferences: Preferences,
+    /// When the last open connection closed.
+    last_seen: Option<DateTime<Utc>>,
+    /// Whether the user was asked to renew the consent expiring at
+    /// `subscription.expires_at`.
+    renewal_requested: bool,
+}
+
+impl UserRegistration {
+    fn connection_count(&self, transport: Transport) -> usize {
+        self.connections
+            .values()
+            .filter(|connection| connection.transport == transport)
+            .count()
+    }
+
+    /// Adds a live connection for this user, keeping any other devices connected.
+    fn attach(
+        &mut self,
+        state: &AppState,
+        user_id: &str,
+        transport: Transport,
+        sender: OutboxSender,
+    ) -> ConnectionHandle {
+        let id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.insert(
+            id,
+            Connection {
+                transport,
+                sender,
+                connected_at: Utc::now(),
+            },
+        );
+        self.last_seen = None;
+        state.announce_presence(user_id, PresenceChange::Connected, transport, self);
+        if state.cluster.is_some() {
+            let (state, user_id) = (state.clone(), user_id.to_owned());
+            tokio::spawn(async move {
+                let Some(cluster) = &state.cluster else {
+                    return;
+                };
+                if let Err(error) = cluster.join(&user_id).await {
+                    error!("Presence of {user_id} could not be recorded: {error}");
+                }
+            });
+        }
+        ConnectionHandle {
+            state: state.clone(),
+            user_id: user_id.to_owned(),
+            id,
+            _gauge: ConnectionGauge::new(transport.label()),
+        }
+    }
+}
+
+/// An open SSE or WebSocket connection and how well it keeps up with its
+/// messages, as listed by `/admin/connections`.
+#[derive(Serialize, ToSchema)]
+struct ConnectionSummary {
+    id: u64,
+    user_id: String,
+    /// `sse` or `websocket`.
+    transport: &'static str,
+    connected_at: DateTime<Utc>,
+    /// Messages waiting to be written to the connection.
+    queued: usize,
+    /// Messages it buffers before the overflow policy applies.
+    capacity: usize,
+    delivered: u64,
+    /// Messages lost to the overflow policy.
+    dropped: u64,
+}
+
+impl ConnectionSummary {
+    fn new(id: u64, user_id: &str, connection: &Connection) -> Self {
+        let stats = connection.sender.stats();
+        Self {
+            id,
+            user_id: Tenant::local_part(user_id).to_owned(),
+            transport: connection.transport.label(),
+            connected_at: connection.connected_at,
+            queued: stats.queued,
+            capacity: stats.capacity,
+            delivered: stats.delivered,
+            dropped: stats.dropped,
+        }
+    }
+}
+
+/// What `/admin/users` exposes about a registration. Only the host of the push
+/// endpoint is shown since the full URL acts as a credential.
+#[derive(Serialize, ToSchema)]
+struct UserSummary {
+    user_id: String,
+    endpoint_host: Option<String>,
+    push_providers: Vec<&'static str>,
+    email_fallback: bool,
+    connected: bool,
+    sse_connections: usize,
+    websocket_connections: usize,
+    queue_depth: usize,
+    topics: Vec<String>,
+    metadata: BTreeMap<String, String>,
+    consent_expires_at: Option<DateTime<Utc>>,
+}
+
+impl UserSummary {
+    fn new(user_id: &str, reg: &UserRegistration) -> Self {
+        let mut topics = reg
+            .topics
+            .iter()
+            .map(|topic| Tenant::local_part(topic).to_owned())
+            .collect::<Vec<_>>();
+        topics.sort();
+        Self {
+            user_id: Tenant::local_part(user_id).to_owned(),
+            endpoint_host: reg
+                .subscription
+                .web_push
+                .as_ref()
+                .and_then(|web_push| web_push.endpoint().host().map(ToOwned::to_owned)),
+            push_providers: ProviderKind::ALL
+                .into_iter()
+                .filter(|kind| reg.subscription.address(*kind).is_some())
+                .map(ProviderKind::label)
+                .collect(),
+            email_fallback: reg.subscription.email.is_some(),
+            connected: !reg.connections.is_empty(),
+            sse_connections: reg.connection_count(Transport::Sse),
+            websocket_connections: reg.connection_count(Transport::WebSocket),
+            queue_depth: reg.queue.len(),
+            topics,
+            metadata: reg.subscription.metadata.clone(),
+            consent_expires_at: reg.subscription.expires_at,
+        }
+    }
+}
+
+/// Outcome of one `/readyz` check.
+#[derive(Serialize, ToSchema)]
+struct ReadinessCheck {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ReadinessCheck {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        let error = result.err();
+        Self {
+            name,
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct Readiness {
+    /// Whether every check passed and the server isn't shutting down.
+    ready: bool,
+    checks: Vec<ReadinessCheck>,
+    /// Labels of the push providers that were set up.
+    push_providers: Vec<&'static str>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Stats {
+    users: usize,
+    connected_users: usize,
+    sse_connections: usize,
+    websocket_connections: usize,
+    queued_messages: usize,
+    topics: usize,
+    groups: usize,
+    schedules: usize,
+}
+
+impl TryFrom<UserRegistrationRequest> for Subscription {
+    type Error = AppError;
+
+    fn try_from(value: UserRegistrationRequest) -> Result<Self, Self::Error> {
+        let web_push = match (value.endpoint, value.keys) {
+            (Some(endpoint), Some(keys)) => {
+                Some(WebPushSubscription::try_from(RawWebPushSubscription {
+                    endpoint,
+                    p256dh: keys.p256dh,
+                    auth: keys.auth,
+                    vapid_key: value.vapid_key,
+                })?)
+            }
+            (Some(_), None) => {
+                return Err(AppError::invalid_registration(
+                    "keys",
+                    "required together with `endpoint`",
+                ))
+            }
+            (None, _) => None,
+        };
+        let subscription = Self {
+            web_push,
+            fcm_token: value.fcm_token,
+            apns_token: value.apns_token,
+            webhook_url: value.webhook_url,
+            email: value.email,
+            metadata: value.metadata,
+            expires_at: value.expires_at,
+        };
+        if let Some(url) = &subscription.webhook_url {
+            webhook::validate_url(url)
+                .map_err(|error| AppError::invalid_registration("webhook_url", error))?;
+        }
+        if let Some(email) = &subscription.email {
+            email
+                .parse::<lettre::Address>()
+                .map_err(|error| AppError::invalid_registration("email", error))?;
+        }
+        if subscription.metadata.len() > MAX_METADATA_ENTRIES {
+            return Err(AppError::invalid_registration(
+                "metadata",
+                format!("at most {MAX_METADATA_ENTRIES} attributes are allowed"),
+            ));
+        }
+        if subscription.metadata.iter().any(|(key, value)| {
+            key.is_empty() || key.len() > MAX_METADATA_LENGTH || value.len() > MAX_METADATA_LENGTH
+        }) {
+            return Err(AppError::invalid_registration(
+                "metadata",
+                format!("keys must be non-empty and keys and values at most {MAX_METADATA_LENGTH} bytes"),
+            ));
+        }
+        if subscription.is_empty() {
+            return Err(AppError::invalid_registration(
+                "endpoint",
+                "one of `endpoint`, `fcm_token`, `apns_token`, `webhook_url` or `email` is required",
+            ));
+        }
+        Ok(subscription)
+    }
+}
+
+impl From<Subscription> for UserRegistration {
+    fn from(value: Subscription) -> Self {
+        Self {
+            connections: HashMap::new(),
+            topics: HashSet::new(),
+            queue: OfflineQueue::default(),
+            history: EventHistory::default(),
+            digest: Digest::default(),
+            subscription: value,
+            preferences: Preferences::default(),
+            last_seen: None,
+            renewal_requested: false,
+        }
+    }
+}
+
+/// State shared by every handler and background task.
+#[derive(Clone)]
+pub struct AppState(Arc<SharedState>);
+
+impl Deref for AppState {
+    type Target = SharedState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+pub struct SharedState {
+    config: Config,
+    /// Every configured push backend, each delivering to the address a
+    /// subscription holds for it.
+    providers: Vec<Arc<dyn PushProvider>>,
+    email: Option<EmailChannel>,
+    channels: RwLock<HashMap<String, UserRegistration>>,
+    topics: RwLock<HashMap<String, HashSet<String>>>,
+    /// The last message sent to each topic with `retain`.
+    retained: RwLock<HashMap<String, String>>,
+    /// Cohorts managed by publishers. Members need not be registered, those who
+    /// aren't are passed over when sending.
+    groups: RwLock<HashMap<String, HashSet<String>>>,
+    vapid: Arc<RwLock<Arc<VapidKeys>>>,
+    queue_config: QueueConfig,
+    digest_config: DigestConfig,
+    retry_config: RetryConfig,
+    reaper_config: ReaperConfig,
+    consent_config: ConsentConfig,
+    /// Push addresses currently failing, watched by the reaper.
+    push_health: AddressHealth,
+    statuses: StatusStore,
+    schedules: RwLock<HashMap<Uuid, ScheduledJob>>,
+    templates: Templates,
+    schemas: Schemas,
+    /// Rewrite outbound notifications, by tenant and API key.
+    transformers: Transformers,
+    clicks: ClickTracking,
+    store: Box<dyn SubscriptionStore>,
+    cluster: Option<Cluster>,
+    authenticator: Box<dyn Authenticator>,
+    /// Guards `/admin/*` instead of publisher keys when set.
+    admin_auth: Option<AdminAuth>,
+    /// Subscriber requests have to prove the user id with a token when set.
+    user_tokens: Option<UserTokens>,
+    rate_limits: RateLimits,
+    recipient_caps: RecipientCaps,
+    metrics: PrometheusHandle,
+    shutdown: watch::Sender<bool>,
+    /// Set once the stored registrations are all loaded.
+    recovered: watch::Sender<bool>,
+    presence: broadcast::Sender<PresenceEvent>,
+    acks: broadcast::Sender<AckEvent>,
+    /// Broadcasts delivered in the background with `?background=true`.
+    broadcasts: BroadcastJobs,
+    push_queue: PushQueue,
+    /// Circuits of the push services, by origin.
+    circuits: CircuitBreakers,
+    /// Results of `/send` requests by idempotency key.
+    idempotency: IdempotencyStore<Sent>,
+    /// Encrypts `/admin/export` and decrypts `/admin/import` bodies when set.
+    export_key: Option<ExportKey>,
+    audit: AuditLog,
+    /// Every message sent, beyond what `history` remembers, when enabled.
+    archive: Option<MessageArchive>,
+    /// Where publishers want to hear about their messages, by API key.
+    callbacks: Callbacks,
+    /// Journals `/send` requests until they are delivered.
+    send_queue: SendQueue,
+    next_connection_id: AtomicU64,
+    /// Set once `/demo/seed` started sending synthetic notifications.
+    demo_started: AtomicBool,
+}
+
+impl AppState {
+    /// Opens the subscription store, loads the VAPID key and API keys and sets up
+    /// every push provider configured through the environment. Also installs the
+    /// global Prometheus recorder, shared by every state built in the process.
+    /// The stored registrations are loaded in the background, `/readyz` fails
+    /// until they are.
+    ///
+    /// # Panics
+    ///
+    /// When any of those can't be loaded, or another recorder is already installed.
+    pub async fn new(config: Config) -> Self {
+        let metrics = telemetry::install().expect("Prometheus recorder could not be installed.");
+
+        let (store, cluster) = open_store().await;
+        let vapid = VapidKeys::load(&config.vapid_file, config.vapid_secondary_file.as_deref())
+            .await
+            .expect("VAPID key could not be loaded.");
+        info!("Loaded VAPID key from {}", config.vapid_file.display());
+        if let Some(path) = &config.vapid_secondary_file {
+            info!("Loaded secondary VAPID key from {}", path.display());
+        }
+
+        let authenticator = auth::authenticator_from_env()
+            .await
+            .expect("Authentication could not be configured.");
+        let admin_auth = AdminAuth::from_env().expect("Admin credentials could not be loaded.");
+        let user_tokens = UserTokens::from_env()
+            .await
+            .expect("User token key could not be loaded.");
+        if user_tokens.is_some() {
+            info!("User tokens required");
+        }
+
+        let vapid = Arc::new(RwLock::new(Arc::new(vapid)));
+        let providers = push_providers(&vapid).await;
+        let email = EmailChannel::from_env().expect("Email fallback could not be configured.");
+        if email.is_some() {
+            info!("Email fallback enabled");
+        }
+        let push_queue = PushQueue::new(
+            config.push_concurrency,
+            OriginLimits {
+                default: config.push_origin_concurrency,
+                hosts: config.push_origin_limits.clone(),
+            },
+        );
+        let idempotency = IdempotencyStore::new(config.idempotency_window);
+        let (statuses, transitions) = StatusStore::from_env();
+        let archive = MessageArchive::from_env()
+            .await
+            .expect("Message archive could not be opened.");
+        let send_queue = SendQueue::from_env(cluster.as_ref())
+            .await
+            .expect("Send queue could not be opened.");
+        let state = Self(Arc::new(SharedState {
+            config,
+            providers,
+            email,
+            channels: RwLock::new(HashMap::new()),
+            topics: RwLock::new(HashMap::new()),
+            retained: RwLock::new(HashMap::new()),
+            groups: RwLock::new(HashMap::new()),
+            vapid,
+            queue_config: QueueConfig::from_env(),
+            digest_config: DigestConfig::from_env(),
+            retry_config: RetryConfig::from_env(),
+            reaper_config: ReaperConfig::from_env(),
+            consent_config: ConsentConfig::from_env(),
+            push_health: AddressHealth::default(),
+            statuses,
+            schedules: RwLock::new(HashMap::new()),
+            templates: Templates::from_env(),
+            schemas: Schemas::default(),
+            transformers: Transformers::from_env()
+                .expect("Message transformers could not be configured."),
+            clicks: ClickTracking::from_env(),
+            store,
+            cluster,
+            authenticator,
+            admin_auth,
+            user_tokens,
+            rate_limits: RateLimits::from_env(),
+            recipient_caps: RecipientCaps::from_env()
+                .expect("Recipient caps could not be configured."),
+            metrics,
+            shutdown: watch::channel(false).0,
+            recovered: watch::channel(false).0,
+            presence: broadcast::channel(256).0,
+            acks: broadcast::channel(256).0,
+            broadcasts: BroadcastJobs::from_env(),
+            push_queue,
+            circuits: CircuitBreakers::new(CircuitConfig::from_env()),
+            idempotency,
+            export_key: ExportKey::from_env(),
+            audit: AuditLog::from_env(),
+            archive,
+            callbacks: Callbacks::from_env(),
+            send_queue,
+            next_connection_id: AtomicU64::new(0),
+            demo_started: AtomicBool::new(false),
+        }));
+
+        if let Some(cluster) = &state.cluster {
+            let events = cluster
+                .events()
+                .await
+                .expect("Cluster events could not be subscribed to.");
+            tokio::spawn(handle_cluster_events(state.clone(), events));
+        }
+        state.spawn_tasks(transitions);
+        state
+    }
+
+    /// Waits until the stored registrations are all loaded.
+    async fn recovery(&self) {
+        // The sender lives as long as the state does.
+        let _ = self
+            .recovered
+            .subscribe()
+            .wait_for(|recovered| *recovered)
+            .await;
+    }
+
+    /// Starts the background tasks that don't depend on the cluster.
+    fn spawn_tasks(&self, transitions: mpsc::UnboundedReceiver<(Transition, MessageStatus)>) {
+        if let Some(age) = self.reaper_config.unreachable_after {
+            tokio::spawn(reap_unreachable(self.clone(), age));
+        }
+        if self.consent_config.ttl.is_some() {
+            tokio::spawn(consent::run(self.clone()));
+        }
+        tokio::spawn(recovery::run(self.clone()));
+        tokio::spawn(digest::run(self.clone()));
+        tokio::spawn(archive::run(self.clone()));
+        tokio::spawn(throttle::run(self.clone()));
+        if let Some(hooks) =
+            PresenceHooks::from_env().expect("Presence hooks could not be configured.")
+        {
+            tokio::spawn(presence::run_hooks(hooks, self.presence.subscribe()));
+        }
+        tokio::spawn(callback::run(self.clone(), transitions));
+        tokio::spawn(journal::run(self.clone()));
+        #[cfg(any(feature = "nats", feature = "kafka"))]
+        if let Some(config) =
+            ingest::IngestConfig::from_env().expect("Ingestion could not be configured.")
+        {
+            tokio::spawn(ingest::run(self.clone(), config));
+        }
+        #[cfg(not(any(feature = "nats", feature = "kafka")))]
+        if std::env::var_os("INGEST_BROKER").is_some() {
+            tracing::warn!(
+                "INGEST_BROKER is ignored, the server was built without `nats` and `kafka`."
+            );
+        }
+    }
+
+    /// Lets `/admin/presence` listeners and the presence hooks know a connection
+    /// of `user_id` opened or closed.
+    fn announce_presence(
+        &self,
+        user_id: &str,
+        change: PresenceChange,
+        transport: Transport,
+        reg: &UserRegistration,
+    ) {
+        self.announce_lifecycle(user_id, change, Some(transport), reg.connections.len());
+    }
+
+    /// Lets them know `user_id` registered or was unregistered.
+    fn announce_lifecycle(
+        &self,
+        user_id: &str,
+        change: PresenceChange,
+        transport: Option<Transport>,
+        connections: usize,
+    ) {
+        // Fails only while nobody is listening.
+        let _ = self.presence.send(PresenceEvent {
+            user_id: user_id.to_owned(),
+            change,
+            transport: transport.map(Transport::label),
+            connections,
+            at: Utc::now(),
+        });
+    }
+
+    /// Tells every open stream to wind down, for servers embedding the router that
+    /// handle shutdown themselves.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+/// How long an idle push service connection is kept for the next push.
+const PUSH_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// How often idle h2 connections to push services are pinged to keep them open.
+const PUSH_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Web Push, plus whichever other providers the environment configures.
+async fn push_providers(vapid: &Arc<RwLock<Arc<VapidKeys>>>) -> Vec<Arc<dyn PushProvider>> {
+    let https = HttpsConnectorBuilder::new().with_native_roots();
+    #[cfg(not(test))]
+    let https = https.https_only();
+    // The mock push service of the tests speaks plain HTTP.
+    #[cfg(test)]
+    let https = https.https_or_http();
+    // Offers h2 through ALPN, so a burst of pushes to one push service shares a
+    // single multiplexed connection where the service supports it.
+    let https = https.enable_http1().enable_http2().build();
+    let push_client = Client::builder()
+        .pool_idle_timeout(PUSH_POOL_IDLE_TIMEOUT)
+        .http2_keep_alive_interval(PUSH_KEEP_ALIVE_INTERVAL)
+        .http2_keep_alive_while_idle(true)
+        .build(https);
+    let mut providers: Vec<Arc<dyn PushProvider>> = vec![Arc::new(WebPushProvider::new(
+        push_client.clone(),
+        vapid.clone(),
+    ))];
+    if let Some(fcm) = FcmProvider::from_env(push_client)
+        .await
+        .expect("FCM service account could not be loaded.")
+    {
+        info!("FCM delivery enabled");
+        providers.push(Arc::new(fcm));
+    }
+    if let Some(apns) = ApnsProvider::from_env()
+        .await
+        .expect("APNs key could not be loaded.")
+    {
+        info!("APNs delivery enabled");
+        providers.push(Arc::new(apns));
+    }
+    if let Some(webhook) = WebhookProvider::from_env() {
+        info!("Webhook delivery enabled");
+        providers.push(Arc::new(webhook));
+    }
+    providers
+}
+
+/// Picks the subscription store: a database for `DATABASE_URL`, Redis for `REDIS_URL`
+/// (which also joins the cluster of instances sharing it), in-memory otherwise.
+async fn open_store() -> (Box<dyn SubscriptionStore>, Option<Cluster>) {
+    if let Ok(url) = std::env::var("DATABASE_URL") {
+        let store = SqliteStore::connect(&url)
+            .await
+            .expect("SQLite store could not be opened.");
+        return (Box::new(store), None);
+    }
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        return (Box::new(MemoryStore::default()), None);
+    };
+    let cluster = Cluster::connect(&url)
+        .await
+        .expect("Redis cluster connection could not be opened.");
+    let store = RedisStore::connect(&url)
+        .await
+        .expect("Redis store could not be opened.");
+    (Box::new(store), Some(cluster))
+}
+
+/// Applies registry changes announced by other instances and delivers messages
+/// they forwarded to connections held here.
+async fn handle_cluster_events(state: AppState, events: impl Stream<Item = ClusterEvent>) {
+    tokio::pin!(events);
+    while let Some(event) = events.next().await {
+        match event {
+            ClusterEvent::Registered {
+                user_id,
+                subscription,
+            } => upsert_registration(&state, user_id, *subscription).await,
+            ClusterEvent::Removed { user_id } => {
+                let mut channel = state.channels.write().await;
+                forget_registration(&state, &mut channel, &user_id).await;
+            }
+            ClusterEvent::Erased { user_id } => {
+                if let Err(error) = erasure::erase_local(&state, &user_id).await {
+                    error!("Erasure announced by another instance failed: {error}");
+                }
+            }
+            ClusterEvent::Retained { topic, data } => {
+                state.retained.write().await.insert(topic, data);
+            }
+            ClusterEvent::SendResult { request, outcome } => {
+                state.send_queue.resolve(request, outcome.into());
+            }
+            ClusterEvent::Deliver {
+                user_id,
+                message_id,
+                data,
+                collapse_key,
+                expires_at,
+                delivered,
+            } => {
+                let reader = state.channels.read().await;
+                let Some(reg) = reader.get(&user_id) else {
+                    continue;
+                };
+                let message = reg
+                    .history
+                    .record(&state.queue_config, message_id, expires_at, data);
+                let sse = realtime_push(&state, &user_id, reg, Transport::Sse, &message);
+                let websocket =
+                    realtime_push(&state, &user_id, reg, Transport::WebSocket, &message);
+                if !delivered && !matches!(sse.or(websocket), DeliveryStatus::Sent) {
+                    // The connection closed since the message was routed here.
+                    reg.queue.push(
+                        &state.queue_config,
+                        message_id,
+                        collapse_key.as_deref(),
+                        expires_at,
+                        message,
+                    );
+                }
+            }
+        }
+    }
+    error!("Cluster event stream ended.");
+}
+
+/// Resolves on SIGINT/SIGTERM and tells every open stream to wind down.
+///
+/// # Panics
+///
+/// When the signal handlers can't be installed.
+pub async fn shutdown_signal(state: AppState) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("SIGINT handler could not be installed.");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler could not be installed.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+    info!("Shutdown requested, draining connections.");
+    state.shutdown();
+}
+
+/// Resolves once a shutdown has been requested.
+fn shutdown_requested(state: &AppState) -> impl Future<Output = ()> {
+    let mut shutdown = state.shutdown.subscribe();
+    async move {
+        let _ = shutdown.wait_for(|requested| *requested).await;
+    }
+}
+
+/// The notification endpoints, ready to be served or nested into another router.
+pub struct NotificationService;
+
+impl NotificationService {
+    pub fn router(state: AppState) -> Router {
+        let router = Router::new()
+            .route("/vapid.json", get(vapid_key))
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .route("/c/:message_id", get(click))
+            .route(
+                "/api-docs/openapi.json",
+                get(|| async { Json(ApiDoc::openapi()) }),
+            )
+            .route(
+                "/docs",
+                get(|| async { Html::from(include_str!("docs.html")) }),
+            )
+            .route(
+                "/manifest.json",
+                get(|| async {
+                    let json = from_str::<Value>(include_str!("manifest.json")).unwrap_or_default();
+                    Json(json)
+                }),
+            )
+            .route(
+                "/metrics",
+                get(|State(state): State<AppState>| async move { state.metrics.render() }),
+            )
+            .merge(Self::demo_routes(&state.config))
+            .merge(Self::subscriber_routes(&state))
+            .merge(Self::publisher_routes(&state))
+            .merge(Self::admin_routes(&state))
+            .merge(Self::frontend_routes(&state.config))
+            .route_layer(middleware::from_fn(telemetry::track_http));
+        let router = match state.config.cors.clone() {
+            Some(cors) => router.layer(cors),
+            None => router,
+        };
+        // Outermost last: an `x-request-id` is assigned unless the client sent one,
+        // then logged with every line of the request and echoed in the response.
+        router
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(TraceLayer::new_for_http().make_span_with(telemetry::request_span))
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+            .with_state(state)
+    }
+
+    /// Open to anyone, so only served with `--demo`.
+    fn demo_routes(config: &Config) -> Router<AppState> {
+        if config.demo {
+            Router::new().route("/demo/seed", post(seed_demo))
+        } else {
+            Router::new()
+        }
+    }
+
+    /// The demo page and its scripts, compressed unless `--asset-compression false`.
+    fn frontend_routes(config: &Config) -> Router<AppState> {
+        let router = Self::frontend_files(config);
+        if config.asset_compression {
+            router.layer(CompressionLayer::new())
+        } else {
+            router
+        }
+    }
+
+    /// Built in and versioned unless `--static-dir` is set.
+    fn frontend_files(config: &Config) -> Router<AppState> {
+        let router = Router::new().route("/version", get(assets::version));
+        let Some(dir) = &config.static_dir else {
+            return router
+                .route("/", assets::serve(BuiltIn::index))
+                .route("/service_worker.js", assets::serve(BuiltIn::service_worker))
+                .route("/index.js", assets::serve(BuiltIn::index_js));
+        };
+        // Browsers revalidate on every load, so edits show up on the next refresh
+        // and unchanged files are answered with a 304.
+        router.fallback_service(SetResponseHeader::overriding(
+            ServeDir::new(dir),
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-cache"),
+        ))
+    }
+
+    fn subscriber_routes(state: &AppState) -> Router<AppState> {
+        let sse_route = if state.config.sse_compression {
+            // The default predicate leaves `text/event-stream` alone. Events still go
+            // out one by one, the encoder flushes whenever the stream waits.
+            get(sse).layer(CompressionLayer::new().compress_when(SizeAbove::new(32)))
+        } else {
+            get(sse)
+        };
+        Router::new()
+            .route("/sse", sse_route)
+            .route("/ws", get(websocket))
+            .route("/poll", get(poll))
+            .route("/register", post(register))
+            .route("/register/rotate", post(rotate_registration))
+            .route("/register/:user_id", delete(unregister))
+            .route("/register/:user_id/renew", post(renew_consent))
+            .route("/subscribe", post(subscribe))
+            .route("/history", get(history))
+            .route("/history/archive", get(archived_history))
+            .route("/messages/pending", get(pending_messages))
+            .route("/messages/:id", get(message_body))
+            .route("/ack", post(ack))
+            .route(
+                "/preferences/:user_id",
+                get(get_preferences).put(set_preferences),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::require_subscriber,
+            ))
+    }
+
+    fn publisher_routes(state: &AppState) -> Router<AppState> {
+        Router::new()
+            .route(
+                "/send",
+                post(send).layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    rate_limit::limit_sender,
+                )),
+            )
+            .route(
+                "/send/batch",
+                post(send_batch).layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    rate_limit::limit_sender,
+                )),
+            )
+            .route(
+                "/send/template",
+                post(send_template).layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    rate_limit::limit_sender,
+                )),
+            )
+            .route("/templates", post(create_template).get(list_templates))
+            .route(
+                "/schemas",
+                post(create_schema).get(list_schemas).delete(remove_schema),
+            )
+            .route("/broadcast", post(broadcast))
+            .route(
+                "/broadcast/:job_id",
+                get(broadcast_progress).delete(cancel_broadcast),
+            )
+            .route("/send/topic", post(send_topic))
+            .route("/send/group/:name", post(send_group))
+            .route("/send/query", post(send_query))
+            .route(
+                "/groups/:name/members",
+                post(update_group).get(group_members),
+            )
+            .route("/schedule", post(create_schedule).get(list_schedules))
+            .route("/schedule/:id", delete(cancel_schedule))
+            .route("/messages/:id/status", get(message_status))
+            .route("/acks", get(ack_events))
+            .route(
+                "/callbacks",
+                put(set_callback).get(get_callback).delete(remove_callback),
+            )
+            .route("/presence/:user_id", get(presence))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::require_publisher,
+            ))
+    }
+
+    /// Reading needs the viewer role, anything exposing credentials or changing
+    /// the server the operator one.
+    fn admin_routes(state: &AppState) -> Router<AppState> {
+        let viewer = Router::new()
+            .route("/admin/users", get(admin_users))
+            .route("/admin/users/:user_id", get(admin_user))
+            .route("/admin/connections", get(admin_connections))
+            .route("/admin/audit", get(audit_log))
+            .route("/admin/presence", get(presence_events))
+            .route("/admin/tail", get(tail))
+            .route("/admin/stats", get(admin_stats))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::require_admin_viewer,
+            ));
+        let operator = Router::new()
+            .route("/admin/export", get(export_registrations))
+            .route("/admin/import", post(import_registrations))
+            .route("/admin/vapid/reload", post(reload_vapid))
+            .route("/users/:user_id/data", delete(erase_user_data))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::require_admin_operator,
+            ));
+        viewer.merge(operator)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    responses((status = 200, description = "Every registered user", body = [UserSummary]))
+)]
+async fn admin_users(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> impl IntoResponse {
+    let reader = state.channels.read().await;
+    let mut users = reader
+        .iter()
+        .filter(|(user_id, _)| tenant.owns(user_id))
+        .map(|(user_id, reg)| UserSummary::new(user_id, reg))
+        .collect::<Vec<_>>();
+    users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+    Json(users)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users/{user_id}",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    params(("user_id" = String, Path, description = "The registered user id")),
+    responses(
+        (status = 200, description = "The user", body = UserSummary),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn admin_user(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(user_id): Path<String>,
+) -> Result<Json<UserSummary>, AppError> {
+    let user_id = tenant.scope(&user_id)?;
+    let reader = state.channels.read().await;
+    let reg = reader.get(&user_id).ok_or(AppError::UserNotFound)?;
+    Ok(Json(UserSummary::new(&user_id, reg)))
+}
+
+/// Open connections on this instance, the fullest buffers first.
+#[utoipa::path(
+    get,
+    path = "/admin/connections",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    responses((status = 200, description = "Every open connection", body = [ConnectionSummary]))
+)]
+async fn admin_connections(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> impl IntoResponse {
+    let reader = state.channels.read().await;
+    let mut connections = reader
+        .iter()
+        .filter(|(user_id, _)| tenant.owns(user_id))
+        .flat_map(|(user_id, reg)| {
+            reg.connections
+                .iter()
+                .map(|(id, connection)| ConnectionSummary::new(*id, user_id, connection))
+        })
+        .collect::<Vec<_>>();
+    drop(reader);
+    connections.sort_by(|a, b| b.queued.cmp(&a.queued).then(a.id.cmp(&b.id)));
+    Json(connections)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "The latest send attempts for the user, oldest first", body = [AuditEntry]),
+        (status = 500, description = "The audit log file couldn't be read", body = ErrorResponse),
+    )
+)]
+async fn audit_log(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    let user_id = tenant.scope(&query.user_id)?;
+    let limit = query.limit.unwrap_or(100).min(audit::MAX_QUERY_LIMIT);
+    let mut entries = state
+        .audit
+        .query(&user_id, query.message_id, limit)
+        .await
+        .map_err(AppError::AuditLog)?;
+    for entry in &mut entries {
+        entry.user_id.clone_from(&query.user_id);
+    }
+    Ok(Json(entries))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/export",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    params(ExportOptions),
+    responses(
+        (status = 200, description = "Every registration, as `/register` takes them. `application/octet-stream` when encrypted", body = [UserRegistrationRequest]),
+        (status = 400, description = "Encryption was asked for without `EXPORT_SECRET`", body = ErrorResponse),
+    )
+)]
+async fn export_registrations(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(options): Query<ExportOptions>,
+) -> Result<impl IntoResponse, AppError> {
+    let key = match (options.encrypt, &state.export_key) {
+        (false, _) => None,
+        (true, Some(key)) => Some(key),
+        (true, None) => return Err(AppError::ExportKeyMissing),
+    };
+    let mut registrations = {
+        let reader = state.channels.read().await;
+        reader
+            .iter()
+            .filter(|(user_id, _)| tenant.owns(user_id))
+            .map(|(user_id, reg)| {
+                UserRegistrationRequest::export(Tenant::local_part(user_id), &reg.subscription)
+            })
+            .collect::<Vec<_>>()
+    };
+    registrations.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+    let body = options.format.encode(&registrations);
+    info!("Exported {} registration(s)", registrations.len());
+    Ok(match key {
+        Some(key) => (
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            key.seal(&body),
+        ),
+        None => (
+            [(header::CONTENT_TYPE, options.format.content_type())],
+            body,
+        ),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/import",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    request_body(content = [UserRegistrationRequest], description = "An export, as a JSON array or NDJSON, or encrypted as `application/octet-stream`"),
+    responses(
+        (status = 200, description = "How many registrations were imported and which were rejected", body = ImportReport),
+        (status = 400, description = "An encrypted import without `EXPORT_SECRET`", body = ErrorResponse),
+        (status = 422, description = "The body doesn't decode or decrypt", body = ErrorResponse),
+    )
+)]
+async fn import_registrations(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Json<ImportReport>, AppError> {
+    let encrypted = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/octet-stream"));
+    let body = if encrypted {
+        let key = state
+            .export_key
+            .as_ref()
+            .ok_or(AppError::ExportKeyMissing)?;
+        key.open(&body).ok_or_else(|| {
+            AppError::InvalidBody("not encrypted with this EXPORT_SECRET".to_owned())
+        })?
+    } else {
+        body.to_vec()
+    };
+    let registrations =
+        export::decode::<UserRegistrationRequest>(&body).map_err(AppError::InvalidBody)?;
+
+    let mut report = ImportReport::default();
+    for (index, user_reg) in registrations.into_iter().enumerate() {
+        let user_id = user_reg.user_id.clone();
+        match save_registration(&state, &tenant, user_reg).await {
+            Ok(()) => {
+                increment_counter!("registrations_total");
+                report.imported += 1;
+            }
+            Err(error) => report.rejected.push(ImportRejection {
+                index,
+                user_id,
+                error: error.to_string(),
+            }),
+        }
+    }
+    info!(
+        "Imported {} registration(s), rejected {}",
+        report.imported,
+        report.rejected.len()
+    );
+    Ok(Json(report))
+}
+
+#[utoipa::path(
+    get,
+    path = "/presence/{user_id}",
+    tag = "publisher",
+    params(("user_id" = String, Path, description = "The registered user id")),
+    responses(
+        (status = 200, description = "Whether the user is connected", body = Presence),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn presence(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(user_id): Path<String>,
+) -> Result<Json<Presence>, AppError> {
+    let scoped = tenant.scope(&user_id)?;
+    let (sse_connections, websocket_connections, last_seen) = {
+        let reader = state.channels.read().await;
+        let reg = reader.get(&scoped).ok_or(AppError::UserNotFound)?;
+        (
+            reg.connection_count(Transport::Sse),
+            reg.connection_count(Transport::WebSocket),
+            reg.last_seen,
+        )
+    };
+    let remote_connections = match &state.cluster {
+        Some(cluster) => cluster.remote_connections(&scoped).await?,
+        None => 0,
+    };
+    Ok(Json(Presence {
+        online: sse_connections + websocket_connections + remote_connections > 0,
+        user_id,
+        sse_connections,
+        websocket_connections,
+        remote_connections,
+        last_seen,
+    }))
+}
+
+/// Streams `presence` events as users connect to and disconnect from this
+/// instance, and as they register and unregister through it.
+#[utoipa::path(
+    get,
+    path = "/admin/presence",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    responses((status = 200, description = "Event stream of presence changes", content_type = "text/event-stream", body = PresenceEvent))
+)]
+async fn presence_events(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(state.presence.subscribe()).filter_map(move |event| {
+        // A listener too slow to keep up just misses the events it lagged behind on.
+        let mut event = event.ok()?;
+        event.user_id = tenant.unscope(&event.user_id)?.to_owned();
+        Some(Ok(Event::default()
+            .event("presence")
+            .data(serde_json::to_string(&event).unwrap_or_default())))
+    });
+    Sse::new(with_heartbeat(
+        futures::StreamExt::take_until(events, shutdown_requested(&state)),
+        state.config.keep_alive,
+    ))
+}
+
+/// Streams `delivery` events for every message on this instance as it is
+/// accepted, pushed, delivered in real time or fails, the entries of the audit
+/// log as they are recorded.
+#[utoipa::path(
+    get,
+    path = "/admin/tail",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    params(TailQuery),
+    responses((status = 200, description = "Event stream of delivery events", content_type = "text/event-stream", body = AuditEntry))
+)]
+async fn tail(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<TailQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let user_id = query.user_id.map(|id| tenant.scope(&id)).transpose()?;
+    let topic = query.topic.map(|topic| tenant.scope(&topic)).transpose()?;
+    let listener = state.clone();
+    let events = futures::StreamExt::filter_map(
+        BroadcastStream::new(state.audit.subscribe()),
+        move |entry| {
+            let (state, tenant, user_id, topic) = (
+                listener.clone(),
+                tenant.clone(),
+                user_id.clone(),
+                topic.clone(),
+            );
+            async move {
+                // A listener too slow to keep up just misses the events it lagged behind on.
+                let mut entry = entry.ok()?;
+                let local = tenant.unscope(&entry.user_id)?.to_owned();
+                if user_id.is_some_and(|user_id| user_id != entry.user_id) {
+                    return None;
+                }
+                if let Some(topic) = topic {
+                    let topics = state.topics.read().await;
+                    if !topics
+                        .get(&topic)
+                        .is_some_and(|subscribers| subscribers.contains(&entry.user_id))
+                    {
+                        return None;
+                    }
+                }
+                entry.user_id = local;
+                Some(Ok(Event::default()
+                    .event("delivery")
+                    .data(serde_json::to_string(&entry).unwrap_or_default())))
+            }
+        },
+    );
+    Ok(Sse::new(with_heartbeat(
+        futures::StreamExt::take_until(events, shutdown_requested(&state)),
+        state.config.keep_alive,
+    )))
+}
+
+/// Sets up the demo data for a demo page, on servers started with `--demo`.
+#[utoipa::path(
+    post,
+    path = "/demo/seed",
+    tag = "demo",
+    security(()),
+    request_body = Option<DemoSeedRequest>,
+    responses(
+        (status = 200, description = "What was set up, notifications follow every `interval_secs`", body = DemoSeed),
+        (status = 404, description = "The server wasn't started with `--demo`"),
+    )
+)]
+async fn seed_demo(
+    State(state): State<AppState>,
+    request: Option<Json<DemoSeedRequest>>,
+) -> Result<Json<DemoSeed>, AppError> {
+    let Json(request) = request.unwrap_or_default();
+    let seeded = demo::seed(&state, request.user_id).await?;
+    info!("Seeded demo data for {} user(s)", seeded.users.len());
+    Ok(Json(seeded))
+}
+
+/// Liveness probe, answering as long as the process serves requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    security(()),
+    responses((status = 200, description = "The process is alive", body = Object))
+)]
+async fn healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness probe. Fails while the stored subscriptions are still being loaded,
+/// while the subscription store or the cluster's Redis server doesn't answer
+/// within two seconds, or once shutdown has begun.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    security(()),
+    responses(
+        (status = 200, description = "Ready to serve", body = Readiness),
+        (status = 503, description = "Not ready", body = Readiness),
+    )
+)]
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    async fn ping(check: impl Future<Output = Result<(), StoreError>>) -> Result<(), String> {
+        tokio::time::timeout(Duration::from_secs(2), check)
+            .await
+            .map_err(|_| "timed out".to_owned())?
+            .map_err(|error| error.to_string())
+    }
+
+    let mut checks = vec![
+        // No state is built without a key and failed reloads keep the old one.
+        ReadinessCheck::new("vapid", Ok(())),
+        ReadinessCheck::new("store", ping(state.store.ping()).await),
+    ];
+    if let Some(cluster) = &state.cluster {
+        checks.push(ReadinessCheck::new("cluster", ping(cluster.ping()).await));
+    }
+    checks.push(ReadinessCheck::new(
+        "recovery",
+        if *state.recovered.borrow() {
+            Ok(())
+        } else {
+            Err("loading stored subscriptions".to_owned())
+        },
+    ));
+    checks.push(ReadinessCheck::new(
+        "shutdown",
+        if *state.shutdown.borrow() {
+            Err("shutting down".to_owned())
+        } else {
+            Ok(())
+        },
+    ));
+    let ready = checks.iter().all(|check| check.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            checks,
+            push_providers: state
+                .providers
+                .iter()
+                .map(|provider| provider.kind().label())
+                .collect(),
+        }),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/stats",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    responses((status = 200, description = "Server totals", body = Stats))
+)]
+async fn admin_stats(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> impl IntoResponse {
+    let reader = state.channels.read().await;
+    let summaries = reader
+        .iter()
+        .filter(|(user_id, _)| tenant.owns(user_id))
+        .map(|(user_id, reg)| UserSummary::new(user_id, reg))
+        .collect::<Vec<_>>();
+    Json(Stats {
+        users: summaries.len(),
+        connected_users: summaries.iter().filter(|user| user.connected).count(),
+        sse_connections: summaries.iter().map(|user| user.sse_connections).sum(),
+        websocket_connections: summaries
+            .iter()
+            .map(|user| user.websocket_connections)
+            .sum(),
+        queued_messages: summaries.iter().map(|user| user.queue_depth).sum(),
+        topics: count_owned(
+            &tenant,
+            state.topics.read().await.keys().map(String::as_str),
+        ),
+        groups: count_owned(
+            &tenant,
+            state.groups.read().await.keys().map(String::as_str),
+        ),
+        schedules: count_owned(
+            &tenant,
+            state
+                .schedules
+                .read()
+                .await
+                .values()
+                .map(|job| job.target.id()),
+        ),
+    })
+}
+
+fn count_owned<'a>(tenant: &Tenant, ids: impl Iterator<Item = &'a str>) -> usize {
+    ids.filter(|id| tenant.owns(id)).count()
+}
+
+async fn vapid_key(State(state): State<AppState>) -> impl IntoResponse {
+    let vapid = state.vapid.read().await.clone();
+    // Only the primary key, which new subscriptions are created with.
+    Json(&vapid.primary).into_response()
+}
+
+/// Erases everything kept about a user, for right-to-be-forgotten requests:
+/// the registration with its queued messages and history, delivery statuses,
+/// audit entries, journaled sends, schedules and group memberships. Erasing a
+/// user nothing is known about succeeds with nothing erased.
+#[utoipa::path(
+    delete,
+    path = "/users/{user_id}/data",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    params(("user_id" = String, Path, description = "The user to erase")),
+    responses((status = 200, description = "Erased", body = ErasureReceipt))
+)]
+async fn erase_user_data(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(user_id): Path<String>,
+) -> Result<Json<ErasureReceipt>, AppError> {
+    let user_id = tenant.scope(&user_id)?;
+    let erased = erasure::erase(&state, &user_id).await?;
+    let vapid = state.vapid.read().await.clone();
+    let receipt = ErasureReceipt::signed(&vapid.primary, &user_id, erased)?;
+    // The user id stays out of the log, the receipt id is enough to trace it.
+    info!("Erased a user's data, receipt {}", receipt.receipt_id);
+    Ok(Json(receipt))
+}
+
+/// Reloads both VAPID key files, so keys can be rotated without a restart: the
+/// current key moves to the secondary file and a new one takes its place.
+#[utoipa::path(
+    post,
+    path = "/admin/vapid/reload",
+    tag = "admin",
+    security(("api_key" = []), ("admin_basic" = [])),
+    responses(
+        (status = 200, description = "Reloaded", body = String),
+        (status = 500, description = "A key file could not be read", body = String),
+    )
+)]
+async fn reload_vapid(State(state): State<AppState>) -> impl IntoResponse {
+    let config = &state.config;
+    match VapidKeys::load(&config.vapid_file, config.vapid_secondary_file.as_deref()).await {
+        Ok(keys) => {
+            *state.vapid.write().await = Arc::new(keys);
+            info!("Reloaded VAPID keys");
+            (StatusCode::OK, "Reloaded".to_owned())
+        }
+        Err(error) => {
+            error!("VAPID key could not be reloaded: {error}");
+            (StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/register",
+    tag = "subscriber",
+    request_body(content = UserRegistrationRequest, description = "JSON, or MessagePack or CBOR sent as `application/msgpack` or `application/cbor`"),
+    responses(
+        (status = 200, description = "Registered", body = String),
+        (status = 400, description = "Invalid registration, `field` names the culprit", body = ErrorResponse),
+        (status = 401, description = "User tokens are required and `X-User-Token` doesn't name the user", body = ErrorResponse),
+        (status = 403, description = "A `webhook_url` was given without the publisher scope", body = ErrorResponse),
+    )
+)]
+async fn register(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    UserToken(token): UserToken,
+    Payload(mut user_reg): Payload<UserRegistrationRequest>,
+) -> Result<(StatusCode, String), AppError> {
+    // The server POSTs to webhooks from inside the network it runs in, so only
+    // trusted backends may name one, not the browsers holding subscriber keys.
+    if user_reg.webhook_url.is_some() && !principal.scopes.contains(&Scope::Publisher) {
+        return Err(AppError::Forbidden);
+    }
+    verify_user(&state, token.as_deref(), &user_reg.user_id)?;
+    user_reg.expires_at = None;
+    save_registration(&state, &tenant, user_reg).await?;
+    increment_counter!("registrations_total");
+    Ok((StatusCode::OK, "Success".to_owned()))
+}
+
+/// Checks the request's user token names `user_id`, if tokens are required.
+fn verify_user(state: &AppState, token: Option<&str>, user_id: &str) -> Result<(), AppError> {
+    state
+        .user_tokens
+        .as_ref()
+        .map_or(Ok(()), |tokens| tokens.verify(token, user_id))
+}
+
+/// Validates, stores and applies a registration request, starting a consent
+/// period unless it carries one.
+async fn save_registration(
+    state: &AppState,
+    tenant: &Tenant,
+    user_reg: UserRegistrationRequest,
+) -> Result<(), AppError> {
+    let user_id = tenant.scope(&user_reg.user_id)?;
+    let mut subscription = Subscription::try_from(user_reg)?;
+    if let Some(web_push) = &mut subscription.web_push {
+        assign_vapid_key(state, web_push).await?;
+    }
+    if subscription.expires_at.is_none() {
+        subscription.expires_at = state.consent_config.expiry(Utc::now());
+    }
+    persist_registration(state, &user_id, &subscription).await?;
+    upsert_registration(state, user_id.clone(), subscription).await;
+    let connections = state
+        .channels
+        .read()
+        .await
+        .get(&user_id)
+        .map_or(0, |reg| reg.connections.len());
+    state.announce_lifecycle(&user_id, PresenceChange::Registered, None, connections);
+    Ok(())
+}
+
+/// Records the VAPID key a new Web Push subscription was created under, the
+/// primary one unless the client named another configured key.
+async fn assign_vapid_key(
+    state: &AppState,
+    web_push: &mut WebPushSubscription,
+) -> Result<(), AppError> {
+    let vapid = state.vapid.read().await.clone();
+    match web_push.vapid_key() {
+        Some(key) if vapid.find(key).is_none() => Err(AppError::invalid_registration(
+            "vapid_key",
+            "not one of the configured VAPID keys",
+        )),
+        Some(_) => Ok(()),
+        None => {
+            web_push.set_vapid_key(vapid.primary.application_server_key().to_owned());
+            Ok(())
+        }
+    }
+}
+
+/// Saves a subscription and tells the other instances about it.
+async fn persist_registration(
+    state: &AppState,
+    user_id: &str,
+    subscription: &Subscription,
+) -> Result<(), AppError> {
+    if let Err(error) = state.store.save(user_id, subscription).await {
+        error!("{error}");
+        return Err(error.into());
+    }
+
+    if let Some(cluster) = &state.cluster {
+        let event = ClusterEvent::Registered {
+            user_id: user_id.to_owned(),
+            subscription: Box::new(subscription.clone()),
+        };
+        if let Err(error) = cluster.announce(event).await {
+            error!("Registration of {user_id} could not be announced: {error}");
+        }
+    }
+    Ok(())
+}
+
+/// Inserts or replaces a registration, carrying over the live state of the previous one.
+async fn upsert_registration(state: &AppState, user_id: String, subscription: Subscription) {
+    let mut channel = state.channels.write().await;
+    let mut registration = UserRegistration::from(subscription);
+    if let Some(previous) = channel.remove(&user_id) {
+        registration.topics = previous.topics;
+        registration.queue = previous.queue;
+        registration.history = previous.history;
+        registration.digest = previous.digest;
+        registration.connections = previous.connections;
+        registration.preferences = previous.preferences;
+        registration.last_seen = previous.last_seen;
+        if previous.subscription.expires_at == registration.subscription.expires_at {
+            registration.renewal_requested = previous.renewal_requested;
+        }
+    }
+    channel.insert(user_id, registration);
+}
+
+#[utoipa::path(
+    delete,
+    path = "/register/{user_id}",
+    tag = "subscriber",
+    params(("user_id" = String, Path, description = "The registered user id"), UnregisterOptions),
+    responses(
+        (status = 200, description = "Unregistered", body = String),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn unregister(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Path(user_id): Path<String>,
+    Query(options): Query<UnregisterOptions>,
+) -> Result<(StatusCode, String), AppError> {
+    verify_user(&state, token.as_deref(), &user_id)?;
+    let user_id = tenant.scope(&user_id)?;
+    let Some(reg) = remove_registration(&state, &user_id).await? else {
+        return Err(AppError::UserNotFound);
+    };
+
+    if options.notify {
+        let data = Notification::new("Unsubscribed", "You will no longer receive notifications.")
+            .to_json();
+        let message = OutboundMessage::accept(
+            &state,
+            &user_id,
+            data,
+            PushOptions::default(),
+            &Sender::internal("unregister"),
+        );
+        if let DeliveryStatus::Failed { error } = deliver_push(
+            &state,
+            &user_id,
+            &reg.subscription,
+            &reg.preferences,
+            &message,
+        )
+        .await?
+        {
+            error!("{error}");
+        }
+    }
+    Ok((StatusCode::OK, "Unregistered".to_owned()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/register/{user_id}/renew",
+    tag = "subscriber",
+    params(("user_id" = String, Path, description = "The registered user id")),
+    responses(
+        (status = 200, description = "Renewed", body = ConsentRenewal),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn renew_consent(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Path(user_id): Path<String>,
+) -> Result<Json<ConsentRenewal>, AppError> {
+    verify_user(&state, token.as_deref(), &user_id)?;
+    let user_id = tenant.scope(&user_id)?;
+    let Some(mut subscription) = state
+        .channels
+        .read()
+        .await
+        .get(&user_id)
+        .map(|reg| reg.subscription.clone())
+    else {
+        return Err(AppError::UserNotFound);
+    };
+    subscription.expires_at = state.consent_config.expiry(Utc::now());
+    persist_registration(&state, &user_id, &subscription).await?;
+    let expires_at = subscription.expires_at;
+    upsert_registration(&state, user_id, subscription).await;
+    Ok(Json(ConsentRenewal { expires_at }))
+}
+
+/// Swaps a user's Web Push subscription for the one the browser replaced it
+/// with. Everything tied to the user, such as queued messages, history, topics
+/// and preferences, stays as it is.
+#[utoipa::path(
+    post,
+    path = "/register/rotate",
+    tag = "subscriber",
+    request_body(content = RotationRequest, description = "JSON, or MessagePack or CBOR sent as `application/msgpack` or `application/cbor`"),
+    responses(
+        (status = 200, description = "Rotated", body = String),
+        (status = 400, description = "Invalid subscription, or `old_endpoint` is no longer registered", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn rotate_registration(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Payload(rotation): Payload<RotationRequest>,
+) -> Result<(StatusCode, String), AppError> {
+    verify_user(&state, token.as_deref(), &rotation.user_id)?;
+    let user_id = tenant.scope(&rotation.user_id)?;
+    let mut web_push = WebPushSubscription::try_from(RawWebPushSubscription {
+        endpoint: rotation.endpoint,
+        p256dh: rotation.keys.p256dh,
+        auth: rotation.keys.auth,
+        vapid_key: rotation.vapid_key,
+    })?;
+    assign_vapid_key(&state, &mut web_push).await?;
+    // Held across the store write so a concurrent rotation or eviction can't interleave.
+    let mut channel = state.channels.write().await;
+    let Some(reg) = channel.get_mut(&user_id) else {
+        return Err(AppError::UserNotFound);
+    };
+    if let Some(old_endpoint) = &rotation.old_endpoint {
+        if reg.subscription.address(ProviderKind::WebPush) != Some(old_endpoint) {
+            return Err(AppError::invalid_registration(
+                "old_endpoint",
+                "not the endpoint registered for this user",
+            ));
+        }
+    }
+    let mut subscription = reg.subscription.clone();
+    subscription.web_push = Some(web_push);
+    persist_registration(&state, &user_id, &subscription).await?;
+    reg.subscription = subscription;
+    increment_counter!("registrations_rotated_total");
+    info!("Rotated the Web Push subscription of {user_id}");
+    Ok((StatusCode::OK, "Rotated".to_owned()))
+}
+
+/// Drops a user from the store, the registry and the topic index. Dropping the
+/// registration also drops its transport senders, which ends any open streams.
+async fn remove_registration(
+    state: &AppState,
+    user_id: &str,
+) -> Result<Option<UserRegistration>, AppError> {
+    let mut channel = state.channels.write().await;
+    purge_registration(state, &mut channel, user_id).await
+}
+
+/// Drops the address a provider reported as gone. The user is only removed
+/// entirely once no provider can reach them any more.
+///
+/// Nothing happens if the address was replaced in the meantime, so a fresh
+/// re-registration isn't evicted by a stale failure.
+async fn evict_address(
+    state: &AppState,
+    user_id: &str,
+    kind: ProviderKind,
+    address: &str,
+    reason: &'static str,
+) -> Result<bool, AppError> {
+    let mut channel = state.channels.write().await;
+    let Some(reg) = channel.get_mut(user_id) else {
+        return Ok(false);
+    };
+    if reg.subscription.address(kind) != Some(address) {
+        return Ok(false);
+    }
+    reg.subscription.clear(kind);
+    if reg.subscription.is_empty() {
+        purge_registration(state, &mut channel, user_id).await?;
+    } else {
+        persist_registration(state, user_id, &reg.subscription).await?;
+    }
+    increment_counter!("subscriptions_reaped_total", "provider" => kind.label(), "reason" => reason);
+    Ok(true)
+}
+
+async fn purge_registration(
+    state: &AppState,
+    channel: &mut HashMap<String, UserRegistration>,
+    user_id: &str,
+) -> Result<Option<UserRegistration>, AppError> {
+    state.store.remove(user_id).await?;
+    if let Some(cluster) = &state.cluster {
+        let event = ClusterEvent::Removed {
+            user_id: user_id.to_owned(),
+        };
+        if let Err(error) = cluster.announce(event).await {
+            error!("Removal of {user_id} could not be announced: {error}");
+        }
+    }
+    let reg = forget_registration(state, channel, user_id).await;
+    if reg.is_some() {
+        state.announce_lifecycle(user_id, PresenceChange::Unregistered, None, 0);
+    }
+    Ok(reg)
+}
+
+/// Drops a user from this instance's registry and topic index, leaving the store untouched.
+async fn forget_registration(
+    state: &AppState,
+    channel: &mut HashMap<String, UserRegistration>,
+    user_id: &str,
+) -> Option<UserRegistration> {
+    let reg = channel.remove(user_id)?;
+    if let (Some(cluster), count @ 1..) = (&state.cluster, reg.connections.len()) {
+        if let Err(error) = cluster.leave(user_id, count).await {
+            error!("Presence of {user_id} could not be updated: {error}");
+        }
+    }
+
+    let mut topics = state.topics.write().await;
+    for topic in &reg.topics {
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.remove(user_id);
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+    Some(reg)
+}
+
+#[utoipa::path(
+    post,
+    path = "/subscribe",
+    tag = "subscriber",
+    request_body = TopicSubscription,
+    responses(
+        (status = 200, description = "Subscribed", body = String),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn subscribe(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Json(subscription): Json<TopicSubscription>,
+) -> Result<(StatusCode, String), AppError> {
+    verify_user(&state, token.as_deref(), &subscription.user_id)?;
+    subscribe_topic(
+        &state,
+        tenant.scope(&subscription.user_id)?,
+        tenant.scope(&subscription.topic)?,
+    )
+    .await?;
+    Ok((StatusCode::OK, "Subscribed".to_owned()))
+}
+
+async fn subscribe_topic(state: &AppState, user_id: String, topic: String) -> Result<(), AppError> {
+    let mut channel = state.channels.write().await;
+    let Some(user) = channel.get_mut(&user_id) else {
+        return Err(AppError::UserNotFound);
+    };
+    user.topics.insert(topic.clone());
+    state
+        .topics
+        .write()
+        .await
+        .entry(topic)
+        .or_default()
+        .insert(user_id);
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/preferences/{user_id}",
+    tag = "subscriber",
+    params(("user_id" = String, Path, description = "The registered user id")),
+    responses(
+        (status = 200, description = "The user's preferences", body = Preferences),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn get_preferences(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Path(user_id): Path<String>,
+) -> Result<Json<Preferences>, AppError> {
+    verify_user(&state, token.as_deref(), &user_id)?;
+    let user_id = tenant.scope(&user_id)?;
+    let channel = state.channels.read().await;
+    let Some(user) = channel.get(&user_id) else {
+        return Err(AppError::UserNotFound);
+    };
+    Ok(Json(user.preferences.clone()))
+}
+
+/// Replaces a user's preferences. They're kept alongside the user's topics and
+/// last until the user unregisters or the server restarts.
+#[utoipa::path(
+    put,
+    path = "/preferences/{user_id}",
+    tag = "subscriber",
+    params(("user_id" = String, Path, description = "The registered user id")),
+    request_body = Preferences,
+    responses(
+        (status = 200, description = "Preferences saved", body = Preferences),
+        (status = 400, description = "Invalid preferences", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn set_preferences(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Path(user_id): Path<String>,
+    Json(preferences): Json<Preferences>,
+) -> Result<Json<Preferences>, AppError> {
+    verify_user(&state, token.as_deref(), &user_id)?;
+    let user_id = tenant.scope(&user_id)?;
+    preferences.validate()?;
+    let mut channel = state.channels.write().await;
+    let Some(user) = channel.get_mut(&user_id) else {
+        return Err(AppError::UserNotFound);
+    };
+    user.preferences = preferences.clone();
+    Ok(Json(preferences))
+}
+
+/// Streams a user's messages as SSE events numbered with per-user ids. A client
+/// reconnecting with `Last-Event-ID` first gets whatever it missed since then.
+/// In between come `heartbeat` events carrying the server's clock and a
+/// sequence number, which clients can tell a stalled connection by.
+#[utoipa::path(
+    get,
+    path = "/sse",
+    tag = "subscriber",
+    params(
+        UserInfo,
+        SseOptions,
+        ("Last-Event-ID" = Option<u64>, Header, description = "Replays events after this id"),
+        ("user_token" = Option<String>, Query, description = "The user's token, if the server requires them"),
+    ),
+    responses(
+        (status = 200, description = "Event stream of notification JSON", content_type = "text/event-stream"),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn sse(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Query(mut user_info): Query<UserInfo>,
+    Query(options): Query<SseOptions>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    verify_user(&state, token.as_deref(), &user_info.user_id)?;
+    user_info.user_id = tenant.scope(&user_info.user_id)?;
+    let retained = subscribe_retained(
+        &state,
+        &tenant,
+        &user_info.user_id,
+        options.topic.as_deref(),
+    )
+    .await?
+    .into_iter()
+    // Without an id, so `Last-Event-ID` keeps naming the last message.
+    .map(|data| Ok(Event::default().data(options.encoding.sse_data(data))))
+    .collect::<Vec<_>>();
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    let (tx, rx) = Transport::Sse.outbox(&state.config);
+    let mut channel = state.channels.write().await;
+    check_sse_limits(&state, &channel, &user_info.user_id)?;
+    let Some(user) = channel.get_mut(&user_info.user_id) else {
+        error!("User {} not found.", user_info.user_id);
+        return Err(AppError::UserNotFound);
+    };
+    let handle = user.attach(&state, &user_info.user_id, Transport::Sse, tx);
+    let pending = catch_up(&state, user, last_event_id);
+
+    // Under the disconnect policy the stream ends early, closing with an
+    // `overflow` event instead of the `shutdown` one.
+    let (overflow_tx, overflow_rx) = oneshot::channel();
+    let received =
+        futures::stream::unfold((rx, Some(overflow_tx)), |(rx, mut overflow)| async move {
+            match rx.recv().await? {
+                Received::Message(message) => Some((message, (rx, overflow))),
+                Received::Overflowed => {
+                    if let Some(overflow) = overflow.take() {
+                        let _ = overflow.send(());
+                    }
+                    None
+                }
+            }
+        });
+    let messages = futures::stream::iter(pending).chain(received);
+    let events = paced(&state.config, messages);
+    let stream = events.map(move |message| {
+        let _ = &handle;
+        Ok(Event::default()
+            .id(message.event_id.to_string())
+            .data(options.encoding.sse_data(message.data)))
+    });
+    let stream = futures::StreamExt::take_until(stream, shutdown_requested(&state)).chain(
+        futures::stream::once(async {
+            Ok(if overflow_rx.await.is_ok() {
+                Event::default()
+                    .event("overflow")
+                    .data("Connection fell behind, reconnect with Last-Event-ID to catch up.")
+            } else {
+                Event::default()
+                    .event("shutdown")
+                    .data("Server is shutting down.")
+            })
+        }),
+    );
+
+    let interval = options
+        .keep_alive_secs
+        .map_or(state.config.keep_alive, |secs| {
+            Duration::from_secs(secs.clamp(1, MAX_KEEP_ALIVE_SECS))
+        });
+    let retry = options
+        .retry_ms
+        .map(Duration::from_millis)
+        .or(state.config.sse_retry);
+    let stream = futures::stream::iter(retry.map(|retry| Ok(Event::default().retry(retry))))
+        .chain(futures::stream::iter(retained))
+        .chain(stream);
+    Ok(Sse::new(with_heartbeat(stream, interval)))
+}
+
+/// Refuses another SSE connection for `user_id` once `--max-sse-connections`
+/// are open in all, or `--max-sse-connections-per-user` for the user.
+fn check_sse_limits(
+    state: &AppState,
+    channels: &HashMap<String, UserRegistration>,
+    user_id: &str,
+) -> Result<(), AppError> {
+    if let Some(max) = state.config.max_sse_connections {
+        let open = channels
+            .values()
+            .map(|user| user.connection_count(Transport::Sse))
+            .sum::<usize>();
+        if open >= max {
+            return Err(AppError::TooManyConnections { per_user: false });
+        }
+    }
+    if let (Some(max), Some(user)) = (
+        state.config.max_sse_connections_per_user,
+        channels.get(user_id),
+    ) {
+        if user.connection_count(Transport::Sse) >= max {
+            return Err(AppError::TooManyConnections { per_user: true });
+        }
+    }
+    Ok(())
+}
+
+/// Spaces out or batches the messages of an SSE stream as `--sse-delivery` asks.
+fn paced(
+    config: &Config,
+    messages: impl Stream<Item = RealtimeMessage> + Send + 'static,
+) -> BoxStream<'static, RealtimeMessage> {
+    match config.sse_delivery {
+        SseDelivery::Immediate => futures::StreamExt::boxed(messages),
+        SseDelivery::Throttle(interval) => futures::StreamExt::boxed(messages.throttle(interval)),
+        SseDelivery::Batch(window) => futures::StreamExt::boxed(
+            messages
+                .chunks_timeout(config.channel_buffer.max(1), window)
+                .map(|batch| {
+                    let data = batch
+                        .iter()
+                        .map(|message| message.data.as_str())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    RealtimeMessage {
+                        event_id: batch.last().map_or(0, |message| message.event_id),
+                        data: format!("[{data}]"),
+                    }
+                }),
+        ),
+    }
+}
+
+/// Subscribes `user_id` to the comma-separated `topics` an `/sse` request names,
+/// returning the message retained for each of them.
+async fn subscribe_retained(
+    state: &AppState,
+    tenant: &Tenant,
+    user_id: &str,
+    topics: Option<&str>,
+) -> Result<Vec<String>, AppError> {
+    let topics = topics
+        .iter()
+        .flat_map(|topics| topics.split(','))
+        .map(str::trim)
+        .filter(|topic| !topic.is_empty())
+        .map(|topic| tenant.scope(topic))
+        .collect::<Result<HashSet<_>, _>>()?;
+    for topic in &topics {
+        subscribe_topic(state, user_id.to_owned(), topic.clone()).await?;
+    }
+    let retained = state.retained.read().await;
+    Ok(topics
+        .iter()
+        .filter_map(|topic| retained.get(topic).cloned())
+        .collect())
+}
+
+/// Long-polls for a user's messages, for networks whose proxies break SSE.
+/// Returns whatever is waiting right away, else the first message to arrive
+/// within `timeout` along with any sent at the same time, else an empty array.
+/// Meanwhile the request counts as one of the user's SSE connections, limits
+/// included, and is sent to like one, so messages arrive as they would over a
+/// stream.
+#[utoipa::path(
+    get,
+    path = "/poll",
+    tag = "subscriber",
+    params(
+        UserInfo,
+        PollOptions,
+        ("user_token" = Option<String>, Query, description = "The user's token, if the server requires them"),
+    ),
+    responses(
+        (status = 200, description = "The messages in the order they were sent, none on timeout", body = [PolledMessage]),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+        (status = 429, description = "The user has as many SSE connections open as allowed", body = ErrorResponse),
+        (status = 503, description = "The server has as many SSE connections open as allowed", body = ErrorResponse),
+    )
+)]
+async fn poll(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Query(mut user_info): Query<UserInfo>,
+    Query(options): Query<PollOptions>,
+) -> Result<Json<Vec<PolledMessage>>, AppError> {
+    verify_user(&state, token.as_deref(), &user_info.user_id)?;
+    user_info.user_id = tenant.scope(&user_info.user_id)?;
+    let wait = Duration::from_secs(
+        options
+            .timeout
+            .unwrap_or(DEFAULT_POLL_SECS)
+            .clamp(1, MAX_POLL_SECS),
+    );
+    let (tx, rx) = Transport::Sse.outbox(&state.config);
+    let handle = {
+        let mut channel = state.channels.write().await;
+        let Some(user) = channel.get_mut(&user_info.user_id) else {
+            return Err(AppError::UserNotFound);
+        };
+        let pending = catch_up(&state, user, options.last_event_id);
+        if !pending.is_empty() {
+            return Ok(Json(pending.into_iter().map(PolledMessage::from).collect()));
+        }
+        check_sse_limits(&state, &channel, &user_info.user_id)?;
+        let Some(user) = channel.get_mut(&user_info.user_id) else {
+            return Err(AppError::UserNotFound);
+        };
+        user.attach(&state, &user_info.user_id, Transport::Sse, tx)
+    };
+    let mut messages = Vec::new();
+    tokio::select! {
+        received = rx.recv() => {
+            if let Some(Received::Message(message)) = received {
+                messages.push(message);
+            }
+        }
+        () = tokio::time::sleep(wait) => {}
+        () = shutdown_requested(&state) => {}
+    }
+    // Detached before the rest is read, so nothing is handed to the outbox after.
+    detach_connections(&state, &handle.user_id, &[handle.id]).await;
+    while let Some(Received::Message(message)) = rx.recv().await {
+        messages.push(message);
+    }
+    drop(handle);
+    Ok(Json(
+        messages.into_iter().map(PolledMessage::from).collect(),
+    ))
+}
+
+/// Data of the `heartbeat` events SSE streams send every keep-alive interval.
+#[derive(Serialize, ToSchema)]
+struct Heartbeat {
+    /// When the event was sent, telling clients how far behind they are.
+    server_time: DateTime<Utc>,
+    /// Counts the connection's heartbeats from 1.
+    seq: u64,
+}
+
+/// Interleaves `stream` with a `heartbeat` event every `interval`, ending with it.
+fn with_heartbeat(
+    stream: impl Stream<Item = Result<Event, Infallible>>,
+    interval: Duration,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut seq = 0;
+    let heartbeats = IntervalStream::new(ticks).map(move |_| {
+        seq += 1;
+        let heartbeat = Heartbeat {
+            server_time: Utc::now(),
+            seq,
+        };
+        Some(Ok(Event::default()
+            .event("heartbeat")
+            .data(serde_json::to_string(&heartbeat).unwrap_or_default())))
+    });
+    // `None` marks the end of `stream`, the heartbeats would go on forever.
+    stream
+        .map(Some)
+        .chain(futures::stream::once(async { None }))
+        .merge(heartbeats)
+        .map_while(std::convert::identity)
+}
+
+/// Lists the most recent messages sent to a user, newest first, so a client that was
+/// offline can catch up without an open stream.
+#[utoipa::path(
+    get,
+    path = "/history",
+    tag = "subscriber",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Remembered messages, newest first", body = [HistoryItem]),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn history(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryItem>>, AppError> {
+    verify_user(&state, token.as_deref(), &query.user_id)?;
+    let user_id = tenant.scope(&query.user_id)?;
+    let reader = state.channels.read().await;
+    let reg = reader.get(&user_id).ok_or(AppError::UserNotFound)?;
+    let items = reg
+        .history
+        .page(query.before, query.limit.unwrap_or(50))
+        .into_iter()
+        .map(|entry| HistoryItem {
+            event_id: entry.message.event_id,
+            message_id: entry.message_id,
+            sent_at: entry.sent_at,
+            data: from_str(&entry.message.data).unwrap_or(Value::String(entry.message.data)),
+        })
+        .collect();
+    Ok(Json(items))
+}
+
+/// Pages through every message sent to a user that is still within the
+/// archive's retention period, newest first.
+#[utoipa::path(
+    get,
+    path = "/history/archive",
+    tag = "subscriber",
+    params(ArchiveQuery),
+    responses(
+        (status = 200, description = "Archived messages, newest first", body = ArchivePage),
+        (status = 400, description = "The archive isn't enabled or `cursor` is invalid", body = ErrorResponse),
+    )
+)]
+async fn archived_history(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<Json<ArchivePage>, AppError> {
+    verify_user(&state, token.as_deref(), &query.user_id)?;
+    let user_id = tenant.scope(&query.user_id)?;
+    let archive = state.archive.as_ref().ok_or(AppError::ArchiveDisabled)?;
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_ARCHIVE_PAGE);
+    let page = archive
+        .page(&user_id, query.cursor.as_deref(), limit)
+        .await?;
+    Ok(Json(page))
+}
+
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "subscriber",
+    params(UserInfo),
+    responses(
+        (status = 101, description = "WebSocket carrying notification JSON text frames"),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+async fn websocket(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Query(mut user_info): Query<UserInfo>,
+    upgrade: WebSocketUpgrade,
+) -> Result<impl IntoResponse, AppError> {
+    verify_user(&state, token.as_deref(), &user_info.user_id)?;
+    user_info.user_id = tenant.scope(&user_info.user_id)?;
+    let (tx, rx) = Transport::WebSocket.outbox(&state.config);
+    let mut channel = state.channels.write().await;
+    let Some(user) = channel.get_mut(&user_info.user_id) else {
+        error!("User {} not found.", user_info.user_id);
+        return Err(AppError::UserNotFound);
+    };
+    let handle = user.attach(&state, &user_info.user_id, Transport::WebSocket, tx);
+    let pending = drain_queue(&state, user);
+    let shutdown = shutdown_requested(&state);
+
+    Ok(upgrade.on_upgrade(move |socket| async move {
+        forward_to_websocket(socket, pending, rx, shutdown).await;
+        drop(handle);
+    }))
+}
+
+/// The queued messages for a fresh connection and, after a reconnect, whatever
+/// else it missed since `last_event_id`, in order.
+fn catch_up(
+    state: &AppState,
+    user: &UserRegistration,
+    last_event_id: Option<u64>,
+) -> Vec<RealtimeMessage> {
+    let mut pending = drain_queue(state, user);
+    if let Some(last_event_id) = last_event_id {
+        // Queued messages are in the history too unless they've been pushed out of it.
+        let (replay, expired) = user.history.since(last_event_id);
+        // Their status stays, it tells how they went out the first time.
+        let expired = u64::try_from(expired).unwrap_or(u64::MAX);
+        counter!("messages_expired_total", expired, "channel" => "replay");
+        pending.retain(|queued| !replay.iter().any(|sent| sent.event_id == queued.event_id));
+        pending.extend(replay);
+        pending.sort_by_key(|message| message.event_id);
+    }
+    pending
+}
+
+/// Records messages past their expiry as such, `channel` naming where they were
+/// dropped from.
+fn expire_realtime(state: &AppState, expired: &[Uuid], channel: &'static str) {
+    for id in expired {
+        state.statuses.set_realtime(id, RealtimeState::Expired);
+        increment_counter!("messages_expired_total", "channel" => channel);
+    }
+}
+
+/// Takes the user's queued messages for a fresh connection and records them as delivered.
+fn drain_queue(state: &AppState, user: &UserRegistration) -> Vec<RealtimeMessage> {
+    let (pending, expired) = user.queue.drain(&state.queue_config);
+    expire_realtime(state, &expired, "queue");
+    pending
+        .into_iter()
+        .map(|(id, message)| {
+            state
+                .statuses
+                .set_realtime(&id, RealtimeState::SseDelivered);
+            message
+        })
+        .collect()
+}
+
+async fn detach_connections(state: &AppState, user_id: &str, ids: &[u64]) {
+    let mut detached = 0;
+    if let Some(user) = state.channels.write().await.get_mut(user_id) {
+        for id in ids {
+            if let Some(connection) = user.connections.remove(id) {
+                detached += 1;
+                if user.connections.is_empty() {
+                    user.last_seen = Some(Utc::now());
+                }
+                state.announce_presence(
+                    user_id,
+                    PresenceChange::Disconnected,
+                    connection.transport,
+                    user,
+                );
+            }
+        }
+    }
+    if let (Some(cluster), 1..) = (&state.cluster, detached) {
+        if let Err(error) = cluster.leave(user_id, detached).await {
+            error!("Presence of {user_id} could not be updated: {error}");
+        }
+    }
+}
+
+/// Pumps queued messages into the socket until either side goes away.
+async fn forward_to_websocket(
+    mut socket: WebSocket,
+    pending: Vec<RealtimeMessage>,
+    rx: OutboxReceiver,
+    shutdown: impl Future<Output = ()>,
+) {
+    for message in pending {
+        if socket.send(Message::Text(message.data)).await.is_err() {
+            return;
+        }
+    }
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            () = &mut shutdown => {
+                let frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down.".into(),
+                };
+                let _ = socket.send(Message::Close(Some(frame))).await;
+                break;
+            }
+            received = rx.recv() => match received {
+                Some(Received::Message(message)) => {
+                    if socket.send(Message::Text(message.data)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Received::Overflowed) => {
+                    let frame = CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "Connection fell behind.".into(),
+                    };
+                    let _ = socket.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+                None => break,
+            },
+            incoming = socket.recv() => {
+                if matches!(incoming, None | Some(Ok(Message::Close(_)) | Err(_))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/send",
+    tag = "publisher",
+    request_body(content = SendData, description = "JSON, or MessagePack or CBOR sent as `application/msgpack` or `application/cbor`"),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key get the first result instead of sending again, takes precedence over `message_id`"),
+    ),
+    responses(
+        (status = 200, description = "Accepted, with `Idempotent-Replayed: true` when repeating an earlier result. What would have been sent with `dry_run`", body = SendResponse),
+        (status = 202, description = "Collected for the recipient's next digest, when sent with `digest`", body = SendResponse),
+        (status = 400, description = "Invalid push options or idempotency key", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+        (status = 413, description = "Too large for Web Push, unless sent `indirect` or as a tickle", body = ErrorResponse),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = ErrorResponse),
+    )
+)]
+async fn send(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    headers: HeaderMap,
+    Payload(mut send): Payload<SendData>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), AppError> {
+    send.user_id = tenant.scope(&send.user_id)?;
+    state.schemas.check(&sender, None, &send.data).await?;
+    send.sender = sender;
+    if send.dry_run {
+        let reader = state.channels.read().await;
+        let Some(reg) = reader.get(&send.user_id) else {
+            return Err(AppError::UserNotFound);
+        };
+        let (data, options) = check_send(reg, &send)?;
+        let dry_run = DryRun::new(&state, &send.user_id, reg, data, options).await;
+        return Ok((
+            StatusCode::OK,
+            HeaderMap::new(),
+            Json(serde_json::to_value(dry_run).expect("Dry runs serialize")),
+        ));
+    }
+    if let Some(key) = headers.get("idempotency-key") {
+        let key = key
+            .to_str()
+            .map_err(|_| AppError::InvalidIdempotencyKey("not visible ASCII".to_owned()))?;
+        send.message_id = Some(key.to_owned());
+    }
+    let ((status, message_id, text), replayed) = send_once(&state, send).await?;
+    let mut headers = HeaderMap::new();
+    if replayed {
+        headers.insert("idempotent-replayed", HeaderValue::from_static("true"));
+    }
+    Ok((
+        status,
+        headers,
+        Json(json!({ "message_id": message_id, "message": text })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/send/batch",
+    tag = "publisher",
+    request_body = [SendData],
+    responses(
+        (status = 200, description = "One result per item, in request order", body = [BatchResult]),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = ErrorResponse),
+    )
+)]
+async fn send_batch(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Json(items): Json<Vec<SendData>>,
+) -> impl IntoResponse {
+    let (state, tenant, sender) = (&state, &tenant, &sender);
+    let deliveries = items.into_iter().map(|mut send| async move {
+        let user_id = send.user_id.clone();
+        let sent = match tenant.scope(&send.user_id) {
+            Ok(scoped) => match state.schemas.check(sender, None, &send.data).await {
+                Ok(()) => {
+                    send.user_id = scoped;
+                    send.sender = sender.clone();
+                    send_one(state, send).await
+                }
+                Err(error) => Err(error),
+            },
+            Err(error) => Err(error),
+        };
+        match sent {
+            Ok((status, message_id, text)) => BatchResult {
+                user_id,
+                status: status.as_u16(),
+                message_id: Some(message_id),
+                message: text,
+                error: None,
+            },
+            Err(error) => BatchResult {
+                user_id,
+                status: error.status().as_u16(),
+                message_id: None,
+                message: error.to_string(),
+                error: Some(error.code()),
+            },
+        }
+    });
+    // `tokio_stream::StreamExt` has no `buffered`.
+    let results = futures::StreamExt::buffered(
+        futures::stream::iter(deliveries),
+        state.config.batch_concurrency,
+    )
+    .collect::<Vec<_>>()
+    .await;
+    Json(results)
+}
+
+#[utoipa::path(
+    post,
+    path = "/send/template",
+    tag = "publisher",
+    request_body = TemplateSendData,
+    responses(
+        (status = 200, description = "Accepted", body = SendResponse),
+        (status = 400, description = "Missing variables or invalid push options", body = ErrorResponse),
+        (status = 404, description = "Unknown user or template", body = ErrorResponse),
+        (status = 429, description = "Rate limited, see `Retry-After`", body = ErrorResponse),
+    )
+)]
+async fn send_template(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Json(send): Json<TemplateSendData>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let user_id = tenant.scope(&send.user_id)?;
+    let locale = match send.locale {
+        Some(locale) => Some(locale),
+        None => state
+            .channels
+            .read()
+            .await
+            .get(&user_id)
+            .and_then(|reg| reg.subscription.metadata.get("locale").cloned()),
+    };
+    let data = state
+        .templates
+        .render(
+            &tenant.scope(&send.template)?,
+            locale.as_deref(),
+            &send.variables,
+        )
+        .await?;
+    let send = SendData {
+        user_id,
+        data,
+        push: send.push,
+        message_id: None,
+        sender,
+        dry_run: false,
+        digest: false,
+    };
+    let (status, message_id, text) = send_one(&state, send).await?;
+    Ok((
+        status,
+        Json(json!({ "message_id": message_id, "message": text })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/templates",
+    tag = "publisher",
+    request_body = NotificationTemplate,
+    responses(
+        (status = 200, description = "Saved, replacing any template of the same name", body = String),
+        (status = 400, description = "A field doesn't compile", body = ErrorResponse),
+    )
+)]
+async fn create_template(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(mut template): Json<NotificationTemplate>,
+) -> Result<(StatusCode, String), AppError> {
+    let name = template.name.clone();
+    template.name = tenant.scope(&name)?;
+    state.templates.insert(template).await?;
+    info!("Saved template {name}.");
+    Ok((StatusCode::OK, "Saved".to_owned()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/templates",
+    tag = "publisher",
+    responses((status = 200, description = "Every registered template", body = [NotificationTemplate]))
+)]
+async fn list_templates(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> impl IntoResponse {
+    let templates = state
+        .templates
+        .list()
+        .await
+        .into_iter()
+        .filter_map(|mut template| {
+            template.name = tenant.unscope(&template.name)?.to_owned();
+            Some(template)
+        })
+        .collect::<Vec<_>>();
+    Json(templates)
+}
+
+/// Registers a JSON Schema for a topic, or for the credentials registering it.
+/// Notifications that don't conform are rejected with the JSON pointers of the
+/// offending parts.
+#[utoipa::path(
+    post,
+    path = "/schemas",
+    tag = "publisher",
+    request_body = PayloadSchema,
+    responses(
+        (status = 200, description = "Saved, replacing any schema registered there before", body = String),
+        (status = 400, description = "Not a valid JSON Schema", body = ErrorResponse),
+    )
+)]
+async fn create_schema(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Json(mut schema): Json<PayloadSchema>,
+) -> Result<(StatusCode, String), AppError> {
+    schema.topic = schema.topic.map(|topic| tenant.scope(&topic)).transpose()?;
+    state.schemas.insert(&sender, schema).await?;
+    Ok((StatusCode::OK, "Saved".to_owned()))
+}
+
+/// The schemas of the tenant's topics, and the one of the credentials asking
+/// first if they registered one.
+#[utoipa::path(
+    get,
+    path = "/schemas",
+    tag = "publisher",
+    responses((status = 200, description = "Every schema that applies", body = [PayloadSchema]))
+)]
+async fn list_schemas(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+) -> impl IntoResponse {
+    let schemas = state
+        .schemas
+        .list(&sender)
+        .await
+        .into_iter()
+        .filter_map(|mut schema| {
+            schema.topic = match schema.topic {
+                Some(topic) => Some(tenant.unscope(&topic)?.to_owned()),
+                None => None,
+            };
+            Some(schema)
+        })
+        .collect::<Vec<_>>();
+    Json(schemas)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SchemaQuery {
+    /// The topic whose schema to remove, the credentials' own without it.
+    topic: Option<String>,
+}
+
+#[utoipa::path(
+    delete,
+    path = "/schemas",
+    tag = "publisher",
+    params(SchemaQuery),
+    responses(
+        (status = 200, description = "Removed", body = String),
+        (status = 404, description = "No schema registered there", body = ErrorResponse),
+    )
+)]
+async fn remove_schema(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Query(query): Query<SchemaQuery>,
+) -> Result<(StatusCode, String), AppError> {
+    let topic = query.topic.map(|topic| tenant.scope(&topic)).transpose()?;
+    if state.schemas.remove(&sender, topic.as_deref()).await {
+        Ok((StatusCode::OK, "Removed".to_owned()))
+    } else {
+        Err(AppError::SchemaNotFound)
+    }
+}
+
+/// Delivers a single message the way `/send` does, returning the response status,
+/// the message id and a description of the real-time delivery.
+async fn send_one(state: &AppState, send: SendData) -> Result<Sent, AppError> {
+    Ok(send_once(state, send).await?.0)
+}
+
+/// Sends unless `send.message_id` was already used for the recipient within the
+/// idempotency window, and tells whether the result is such a replay.
+async fn send_once(state: &AppState, mut send: SendData) -> Result<(Sent, bool), AppError> {
+    let Some(key) = send.message_id.take() else {
+        return Ok((dispatch(state, send).await?, false));
+    };
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY {
+        return Err(AppError::InvalidIdempotencyKey(format!(
+            "must be 1 to {MAX_IDEMPOTENCY_KEY} characters"
+        )));
+    }
+    let user_id = send.user_id.clone();
+    let result = state
+        .idempotency
+        .run(&user_id, key, dispatch(state, send))
+        .await?;
+    if result.1 {
+        increment_counter!("idempotent_replays_total");
+    }
+    Ok(result)
+}
+
+/// Journals `send` for delivery, or collects it for a digest.
+async fn dispatch(state: &AppState, send: SendData) -> Result<Sent, AppError> {
+    if send.digest {
+        digest::collect(state, send).await
+    } else {
+        state.send_queue.submit(send).await
+    }
+}
+
+/// Checks `send` can go out to `reg`, returning its content and push options.
+fn check_send(reg: &UserRegistration, send: &SendData) -> Result<(String, PushOptions), AppError> {
+    if reg.subscription.is_expired(Utc::now()) {
+        return Err(AppError::ConsentExpired);
+    }
+    let options = send.push.clone().or(send.data.push_options());
+    options.validate().map_err(AppError::InvalidPushOptions)?;
+    let data = send.data.to_json();
+    if reg.subscription.web_push.is_some()
+        && !options.stored()
+        && data.len() + MESSAGE_ID_OVERHEAD > web_push::MAX_PAYLOAD
+    {
+        return Err(AppError::PayloadTooLarge {
+            size: data.len() + MESSAGE_ID_OVERHEAD,
+            limit: web_push::MAX_PAYLOAD,
+        });
+    }
+    Ok((data, options))
+}
+
+async fn send_now(state: &AppState, send: SendData) -> Result<Sent, AppError> {
+    if let Some(limiter) = &state.rate_limits.target {
+        limiter
+            .check(&send.user_id)
+            .map_err(AppError::RateLimited)?;
+    }
+    let send = if state.recipient_caps.applies(&send.user_id) {
+        // Checked first, so nothing is held back that couldn't go out later.
+        let reader = state.channels.read().await;
+        let reg = reader.get(&send.user_id).ok_or(AppError::UserNotFound)?;
+        let (data, options) = check_send(reg, &send)?;
+        drop(reader);
+        match throttle::admit(state, send, data, options)? {
+            Admission::Send(send) => *send,
+            Admission::Held(held) => return Ok(held),
+        }
+    } else {
+        send
+    };
+    send_admitted(state, send).await
+}
+
+/// Delivers `send` now, its recipient's cap already taken into account.
+async fn send_admitted(state: &AppState, send: SendData) -> Result<Sent, AppError> {
+    let recipient = {
+        let reader = state.channels.read().await;
+        let Some(reg) = reader.get(&send.user_id) else {
+            return Err(AppError::UserNotFound);
+        };
+        let (data, options) = check_send(reg, &send)?;
+        let message = OutboundMessage::accept(state, &send.user_id, data, options, &send.sender);
+        Recipient::new(state, &send.user_id, reg, message)
+    };
+    send_to(state, recipient).await
+}
+
+/// Delivers a message accepted earlier, one held back by its recipient's cap.
+async fn send_accepted(
+    state: &AppState,
+    user_id: &str,
+    message: OutboundMessage,
+) -> Result<Sent, AppError> {
+    let recipient = {
+        let reader = state.channels.read().await;
+        let Some(reg) = reader.get(user_id) else {
+            return Err(AppError::UserNotFound);
+        };
+        if reg.subscription.is_expired(Utc::now()) {
+            return Err(AppError::ConsentExpired);
+        }
+        Recipient::new(state, user_id, reg, message)
+    };
+    send_to(state, recipient).await
+}
+
+#[instrument(skip_all, fields(user_id = %recipient.user_id, message_id = %recipient.message.id))]
+async fn send_to(state: &AppState, recipient: Recipient) -> Result<Sent, AppError> {
+    let message = &recipient.message;
+    let push = deliver_push(
+        state,
+        &recipient.user_id,
+        &recipient.subscription,
+        &recipient.preferences,
+        message,
+    )
+    .await?;
+    match &push {
+        DeliveryStatus::Failed { error } => error!("{error}"),
+        DeliveryStatus::Retrying { error } => info!("Push failed, retrying: {error}"),
+        DeliveryStatus::Deferred { until } => info!("Push deferred until {until}"),
+        DeliveryStatus::Sent | DeliveryStatus::Routed | DeliveryStatus::Skipped => {}
+    }
+
+    let (sse, websocket, queued) = realtime_deliver(state, &recipient).await;
+    let realtime = sse.or(websocket);
+    let email = email_fallback(
+        state,
+        &recipient.user_id,
+        &recipient.subscription,
+        message,
+        push.reached() || realtime.reached(),
+    )
+    .await;
+    let (status, text) = match realtime {
+        DeliveryStatus::Sent => (StatusCode::OK, "Sent".to_owned()),
+        DeliveryStatus::Routed => (
+            StatusCode::OK,
+            "Sent with event forwarded to the instance holding the channel.".to_owned(),
+        ),
+        _ if matches!(email, DeliveryStatus::Sent) => (
+            StatusCode::OK,
+            "Sent by email since no channel or push subscription was available.".to_owned(),
+        ),
+        _ if queued => (
+            StatusCode::OK,
+            "Sent with event queued until a channel becomes available.".to_owned(),
+        ),
+        DeliveryStatus::Skipped | DeliveryStatus::Deferred { .. } => (
+            StatusCode::OK,
+            "Sent without sending event due to no channel available.".to_owned(),
+        ),
+        DeliveryStatus::Failed { error } | DeliveryStatus::Retrying { error } => {
+            (StatusCode::INTERNAL_SERVER_ERROR, error)
+        }
+    };
+    Ok((status, message.id, text))
+}
+
+#[utoipa::path(
+    post,
+    path = "/broadcast",
+    tag = "publisher",
+    params(BroadcastOptions),
+    request_body = BroadcastData,
+    responses(
+        (status = 200, description = "One report per user, or what each would be sent with `dry_run`", body = [DeliveryReport]),
+        (status = 202, description = "Delivering in the background", body = BroadcastProgress),
+    )
+)]
+async fn broadcast(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Query(options): Query<BroadcastOptions>,
+    Json(broadcast): Json<BroadcastData>,
+) -> Response {
+    if let Err(error) = state.schemas.check(&sender, None, &broadcast.data).await {
+        return error.into_response();
+    }
+    let reader = state.channels.read().await;
+    if broadcast.dry_run {
+        let now = Utc::now();
+        let (data, options) = (broadcast.data.to_json(), broadcast.data.push_options());
+        let dry_runs = reader
+            .iter()
+            .filter(|(user_id, reg)| tenant.owns(user_id) && !reg.subscription.is_expired(now))
+            .map(|(user_id, reg)| DryRun::new(&state, user_id, reg, data.clone(), options.clone()))
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+        return Json(dry_runs).into_response();
+    }
+    if options.background {
+        let user_ids = reader
+            .keys()
+            .filter(|user_id| tenant.owns(user_id))
+            .cloned()
+            .collect();
+        drop(reader);
+        let progress = broadcast_job::start(
+            &state,
+            tenant,
+            user_ids,
+            broadcast.data.to_json(),
+            broadcast.data.push_options(),
+            sender,
+        );
+        return (StatusCode::ACCEPTED, Json(progress)).into_response();
+    }
+
+    let recipients = recipients(
+        &state,
+        reader.iter().filter(|(user_id, _)| tenant.owns(user_id)),
+        &broadcast.data.to_json(),
+        &broadcast.data.push_options(),
+        &sender,
+    );
+    drop(reader);
+    Json(fan_out(&state, recipients).await).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/broadcast/{job_id}",
+    tag = "publisher",
+    params(("job_id" = Uuid, Path, description = "The id `/broadcast?background=true` returned")),
+    responses(
+        (status = 200, description = "How far the broadcast got", body = BroadcastProgress),
+        (status = 404, description = "Unknown or long finished broadcast", body = ErrorResponse),
+    )
+)]
+async fn broadcast_progress(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<BroadcastProgress>, AppError> {
+    Ok(Json(state.broadcasts.progress(&tenant, job_id)?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/broadcast/{job_id}",
+    tag = "publisher",
+    params(("job_id" = Uuid, Path, description = "The id `/broadcast?background=true` returned")),
+    responses(
+        (status = 200, description = "Cancelled, deliveries in flight still finish", body = BroadcastProgress),
+        (status = 404, description = "Unknown or long finished broadcast", body = ErrorResponse),
+    )
+)]
+async fn cancel_broadcast(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<BroadcastProgress>, AppError> {
+    Ok(Json(state.broadcasts.cancel(&tenant, job_id)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/send/topic",
+    tag = "publisher",
+    request_body = TopicSendData,
+    responses((status = 200, description = "One report per subscriber", body = [DeliveryReport]))
+)]
+async fn send_topic(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Json(send): Json<TopicSendData>,
+) -> Result<Json<Vec<DeliveryReport>>, AppError> {
+    let topic = tenant.scope(&send.topic)?;
+    state
+        .schemas
+        .check(&sender, Some(&topic), &send.data)
+        .await?;
+    let data = send.data.to_json();
+    if send.retain {
+        retain(&state, &topic, &data).await;
+    }
+    Ok(Json(
+        deliver(
+            &state,
+            &Target::Topic(topic),
+            &data,
+            &send.data.push_options(),
+            &sender,
+        )
+        .await,
+    ))
+}
+
+/// Keeps `data` as the last value of `topic` here and on the other instances.
+async fn retain(state: &AppState, topic: &str, data: &str) {
+    state
+        .retained
+        .write()
+        .await
+        .insert(topic.to_owned(), data.to_owned());
+    if let Some(cluster) = &state.cluster {
+        let event = ClusterEvent::Retained {
+            topic: topic.to_owned(),
+            data: data.to_owned(),
+        };
+        if let Err(error) = cluster.announce(event).await {
+            error!("Retained message of {topic} could not be announced: {error}");
+        }
+    }
+}
+
+/// Adds and removes members, creating the group on its first member and
+/// dropping it with its last.
+#[utoipa::path(
+    post,
+    path = "/groups/{name}/members",
+    tag = "publisher",
+    params(("name" = String, Path, description = "The group to change")),
+    request_body = GroupMembersUpdate,
+    responses((status = 200, description = "The group's members afterwards", body = Group))
+)]
+async fn update_group(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+    Json(update): Json<GroupMembersUpdate>,
+) -> Result<Json<Group>, AppError> {
+    let scoped = tenant.scope(&name)?;
+    let add = scope_all(&tenant, &update.add)?;
+    let remove = scope_all(&tenant, &update.remove)?;
+    let mut groups = state.groups.write().await;
+    let members = groups.entry(scoped.clone()).or_default();
+    members.extend(add);
+    for user_id in &remove {
+        members.remove(user_id);
+    }
+    let members = local_members(members);
+    if members.is_empty() {
+        groups.remove(&scoped);
+    }
+    Ok(Json(Group { name, members }))
+}
+
+fn scope_all(tenant: &Tenant, ids: &[String]) -> Result<Vec<String>, AppError> {
+    ids.iter().map(|id| tenant.scope(id)).collect()
+}
+
+/// Sorted member ids without the namespace.
+fn local_members(members: &HashSet<String>) -> Vec<String> {
+    let mut members = members
+        .iter()
+        .map(|user_id| Tenant::local_part(user_id).to_owned())
+        .collect::<Vec<_>>();
+    members.sort();
+    members
+}
+
+#[utoipa::path(
+    get,
+    path = "/groups/{name}/members",
+    tag = "publisher",
+    params(("name" = String, Path, description = "The group to list")),
+    responses(
+        (status = 200, description = "The group's members", body = Group),
+        (status = 404, description = "Unknown group", body = ErrorResponse),
+    )
+)]
+async fn group_members(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+) -> Result<Json<Group>, AppError> {
+    let scoped = tenant.scope(&name)?;
+    let Some(members) = state.groups.read().await.get(&scoped).map(local_members) else {
+        return Err(AppError::GroupNotFound);
+    };
+    Ok(Json(Group { name, members }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/send/group/{name}",
+    tag = "publisher",
+    params(("name" = String, Path, description = "The group to send to")),
+    request_body = BroadcastData,
+    responses(
+        (status = 200, description = "One report per registered member", body = GroupDelivery),
+        (status = 404, description = "Unknown group", body = ErrorResponse),
+    )
+)]
+async fn send_group(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Path(name): Path<String>,
+    Json(send): Json<BroadcastData>,
+) -> Result<Json<GroupDelivery>, AppError> {
+    let scoped = tenant.scope(&name)?;
+    if !state.groups.read().await.contains_key(&scoped) {
+        return Err(AppError::GroupNotFound);
+    }
+    state.schemas.check(&sender, None, &send.data).await?;
+    let reports = deliver(
+        &state,
+        &Target::Group(scoped),
+        &send.data.to_json(),
+        &send.data.push_options(),
+        &sender,
+    )
+    .await;
+    Ok(Json(GroupDelivery {
+        group: name,
+        recipients: reports.len(),
+        reached: reports.iter().filter(|report| report.reached()).count(),
+        reports,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/send/query",
+    tag = "publisher",
+    request_body = QuerySendData,
+    responses((status = 200, description = "One report per matching user", body = QueryDelivery))
+)]
+async fn send_query(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(sender): Extension<Sender>,
+    Json(send): Json<QuerySendData>,
+) -> Result<Json<QueryDelivery>, AppError> {
+    state.schemas.check(&sender, None, &send.data).await?;
+    let reader = state.channels.read().await;
+    let targets = reader.iter().filter(|(user_id, reg)| {
+        tenant.owns(user_id)
+            && send
+                .filter
+                .iter()
+                .all(|(key, filter)| filter.matches(reg.subscription.metadata.get(key)))
+    });
+    let recipients = recipients(
+        &state,
+        targets,
+        &send.data.to_json(),
+        &send.data.push_options(),
+        &sender,
+    );
+    drop(reader);
+    let reports = fan_out(&state, recipients).await;
+    Ok(Json(QueryDelivery {
+        recipients: reports.len(),
+        reached: reports.iter().filter(|report| report.reached()).count(),
+        reports,
+    }))
+}
+
+/// Resolves a target to its registrations and fans `data` out to all of them,
+/// leaving out users who muted the topic.
+async fn deliver(
+    state: &AppState,
+    target: &Target,
+    data: &str,
+    options: &PushOptions,
+    sender: &Sender,
+) -> Vec<DeliveryReport> {
+    let user_ids = match target {
+        Target::User(user_id) => HashSet::from([user_id.clone()]),
+        Target::Topic(topic) => state
+            .topics
+            .read()
+            .await
+            .get(topic)
+            .cloned()
+            .unwrap_or_default(),
+        Target::Group(group) => state
+            .groups
+            .read()
+            .await
+            .get(group)
+            .cloned()
+            .unwrap_or_default(),
+    };
+    let reader = state.channels.read().await;
+
+    let topic = match target {
+        Target::User(_) | Target::Group(_) => None,
+        // Preferences name topics the way the user's tenant does.
+        Target::Topic(topic) => Some(Tenant::local_part(topic)),
+    };
+    let targets = user_ids
+        .iter()
+        .filter_map(|user_id| reader.get_key_value(user_id))
+        .filter(|(_, reg)| !topic.is_some_and(|topic| reg.preferences.mutes(topic)));
+    let recipients = recipients(state, targets, data, options, sender);
+    drop(reader);
+    fan_out(state, recipients).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedule",
+    tag = "publisher",
+    request_body = ScheduleRequest,
+    responses(
+        (status = 201, description = "Scheduled", body = ScheduledJob),
+        (status = 400, description = "Invalid schedule", body = ErrorResponse),
+    )
+)]
+async fn create_schedule(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(request): Json<ScheduleRequest>,
+) -> Result<(StatusCode, Json<ScheduledJob>), AppError> {
+    let (target, trigger, data) = request.into_parts()?;
+    let target = target.scoped(&tenant)?;
+    let id = Uuid::new_v4();
+    let next_run = trigger.next_after(Utc::now());
+    if next_run.is_none() {
+        return Err(AppError::InvalidSchedule(
+            "the trigger never fires".to_owned(),
+        ));
+    }
+
+    let mut schedules = state.schedules.write().await;
+    let task = tokio::spawn(run_schedule(state.clone(), id));
+    let job = ScheduledJob {
+        id,
+        target,
+        trigger,
+        data,
+        next_run,
+        task: Some(task),
+    };
+    let view = job.view();
+    schedules.insert(id, job);
+    info!("Scheduled job {id}, next run at {next_run:?}.");
+    Ok((StatusCode::CREATED, Json(view)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule",
+    tag = "publisher",
+    responses((status = 200, description = "Pending jobs by next run", body = [ScheduledJob]))
+)]
+async fn list_schedules(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> impl IntoResponse {
+    let schedules = state.schedules.read().await;
+    let mut jobs = schedules
+        .values()
+        .filter(|job| tenant.owns(job.target.id()))
+        .map(ScheduledJob::view)
+        .collect::<Vec<_>>();
+    jobs.sort_by_key(|job| job.next_run);
+    Json(json!(jobs))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/schedule/{id}",
+    tag = "publisher",
+    params(("id" = Uuid, Path, description = "The message or job id")),
+    responses(
+        (status = 200, description = "Cancelled", body = String),
+        (status = 404, description = "Unknown job", body = ErrorResponse),
+    )
+)]
+async fn cancel_schedule(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> Result<(StatusCode, String), AppError> {
+    let mut schedules = state.schedules.write().await;
+    if !schedules
+        .get(&id)
+        .is_some_and(|job| tenant.owns(job.target.id()))
+    {
+        return Err(AppError::ScheduleNotFound);
+    }
+    let job = schedules.remove(&id).ok_or(AppError::ScheduleNotFound)?;
+    drop(schedules);
+    if let Some(task) = job.task {
+        task.abort();
+    }
+    Ok((StatusCode::OK, "Cancelled".to_owned()))
+}
+
+/// Content of an indirect message, fetched by the recipient's client after the
+/// push carrying its id arrives. The random id is all a client needs to know.
+#[utoipa::path(
+    get,
+    path = "/messages/{id}",
+    tag = "subscriber",
+    params(("id" = Uuid, Path, description = "The message id from the push")),
+    responses(
+        (status = 200, description = "The notification", body = Notification),
+        (status = 404, description = "Unknown, expired or direct message", body = ErrorResponse),
+    )
+)]
+async fn message_body(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let body = state
+        .statuses
+        .get(&id)
+        .filter(|status| tenant.owns(&status.user_id))
+        .and_then(|status| status.body)
+        .ok_or(AppError::MessageNotFound)?;
+    Ok(([(header::CONTENT_TYPE, "application/json")], body))
+}
+
+/// Messages the user's client hasn't displayed yet, fetched by the service
+/// worker when a tickle wakes it. Acknowledging one as displayed takes it off
+/// the list.
+#[utoipa::path(
+    get,
+    path = "/messages/pending",
+    tag = "subscriber",
+    params(UserInfo),
+    responses(
+        (status = 200, description = "Waiting messages, oldest first", body = [PendingMessage]),
+    )
+)]
+async fn pending_messages(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    UserToken(token): UserToken,
+    Query(query): Query<UserInfo>,
+) -> Result<Json<Vec<PendingMessage>>, AppError> {
+    verify_user(&state, token.as_deref(), &query.user_id)?;
+    let user_id = tenant.scope(&query.user_id)?;
+    let messages = state
+        .statuses
+        .pending(&user_id)
+        .into_iter()
+        .filter_map(|status| {
+            let body = status.body?;
+            Some(PendingMessage {
+                message_id: status.id,
+                sent_at: status.created_at,
+                data: from_str(&body).unwrap_or(Value::String(body)),
+            })
+        })
+        .collect();
+    Ok(Json(messages))
+}
+
+/// Confirms the recipient's client displayed or clicked a notification.
+#[utoipa::path(
+    post,
+    path = "/ack",
+    tag = "subscriber",
+    request_body = Ack,
+    responses(
+        (status = 200, description = "Recorded", body = String),
+        (status = 404, description = "Unknown or expired message", body = ErrorResponse),
+    )
+)]
+async fn ack(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(ack): Json<Ack>,
+) -> Result<(StatusCode, String), AppError> {
+    let owned = state
+        .statuses
+        .get(&ack.message_id)
+        .is_some_and(|status| tenant.owns(&status.user_id));
+    let at = Utc::now();
+    let Some(status) = owned
+        .then(|| state.statuses.ack(&ack.message_id, ack.action, at))
+        .flatten()
+    else {
+        return Err(AppError::MessageNotFound);
+    };
+    increment_counter!("acks_total", "action" => match ack.action {
+        AckAction::Displayed => "displayed",
+        AckAction::Clicked => "clicked",
+    });
+    // Fails only while nobody is listening.
+    let _ = state.acks.send(AckEvent {
+        message_id: ack.message_id,
+        user_id: status.user_id,
+        action: ack.action,
+        at,
+    });
+    Ok((StatusCode::OK, "Acknowledged".to_owned()))
+}
+
+/// Records a click on a notification sent with `track_clicks`, then redirects
+/// to the URL it was sent with. Needs no credentials, browsers follow it as is.
+#[utoipa::path(
+    get,
+    path = "/c/{message_id}",
+    tag = "subscriber",
+    params(("message_id" = Uuid, Path, description = "The clicked message")),
+    responses(
+        (status = 302, description = "Redirects to the notification's URL"),
+        (status = 404, description = "Unknown or expired message, or sent without `track_clicks`", body = ErrorResponse),
+    )
+)]
+async fn click(
+    State(state): State<AppState>,
+    Path(message_id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let at = Utc::now();
+    let Some(status) = state
+        .statuses
+        .get(&message_id)
+        .filter(|status| status.target_url.is_some())
+        .and_then(|_| state.statuses.ack(&message_id, AckAction::Clicked, at))
+    else {
+        return Err(AppError::MessageNotFound);
+    };
+    let target = status.target_url.clone().unwrap_or_default();
+    state.audit.record(AuditEntry::new(
+        &status.user_id,
+        message_id,
+        &status.sender,
+        "click",
+        "clicked",
+    ));
+    increment_counter!("clicks_total");
+    // Fails only while nobody is listening.
+    let _ = state.acks.send(AckEvent {
+        message_id,
+        user_id: status.user_id,
+        action: AckAction::Clicked,
+        at,
+    });
+    Ok((StatusCode::FOUND, [(header::LOCATION, target)]))
+}
+
+/// Streams `ack` events as recipients acknowledge the tenant's messages.
+#[utoipa::path(
+    get,
+    path = "/acks",
+    tag = "publisher",
+    responses((status = 200, description = "Event stream of acknowledgements", content_type = "text/event-stream", body = AckEvent))
+)]
+async fn ack_events(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(state.acks.subscribe()).filter_map(move |event| {
+        // A listener too slow to keep up just misses the events it lagged behind on.
+        let mut event = event.ok()?;
+        event.user_id = tenant.unscope(&event.user_id)?.to_owned();
+        Some(Ok(Event::default()
+            .event("ack")
+            .data(serde_json::to_string(&event).unwrap_or_default())))
+    });
+    Sse::new(with_heartbeat(
+        futures::StreamExt::take_until(events, shutdown_requested(&state)),
+        state.config.keep_alive,
+    ))
+}
+
+/// Registers where the calling API key's messages are reported as they are
+/// delivered, fail or expire, see [`callback::CallbackEvent`].
+#[utoipa::path(
+    put,
+    path = "/callbacks",
+    tag = "publisher",
+    request_body = CallbackRequest,
+    responses(
+        (status = 200, description = "Registered, with the secret signing the events", body = CallbackRegistration),
+        (status = 400, description = "Not an absolute http or https URL", body = ErrorResponse),
+    )
+)]
+async fn set_callback(
+    State(state): State<AppState>,
+    Extension(sender): Extension<Sender>,
+    Json(request): Json<CallbackRequest>,
+) -> Result<Json<CallbackRegistration>, AppError> {
+    let registration = state.callbacks.set(sender, request.url).await?;
+    info!("Registered callback {}", registration.url);
+    Ok(Json(registration))
+}
+
+#[utoipa::path(
+    get,
+    path = "/callbacks",
+    tag = "publisher",
+    responses(
+        (status = 200, description = "The calling API key's callback", body = CallbackRegistration),
+        (status = 404, description = "No callback registered", body = ErrorResponse),
+    )
+)]
+async fn get_callback(
+    State(state): State<AppState>,
+    Extension(sender): Extension<Sender>,
+) -> Result<Json<CallbackRegistration>, AppError> {
+    state
+        .callbacks
+        .get(&sender)
+        .await
+        .map(Json)
+        .ok_or(AppError::CallbackNotFound)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/callbacks",
+    tag = "publisher",
+    responses(
+        (status = 200, description = "Removed", body = String),
+        (status = 404, description = "No callback registered", body = ErrorResponse),
+    )
+)]
+async fn remove_callback(
+    State(state): State<AppState>,
+    Extension(sender): Extension<Sender>,
+) -> Result<(StatusCode, String), AppError> {
+    if state.callbacks.remove(&sender).await {
+        Ok((StatusCode::OK, "Removed".to_owned()))
+    } else {
+        Err(AppError::CallbackNotFound)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/messages/{id}/status",
+    tag = "publisher",
+    params(("id" = Uuid, Path, description = "The message or job id")),
+    responses(
+        (status = 200, description = "Delivery status", body = MessageStatus),
+        (status = 404, description = "Unknown or expired message", body = ErrorResponse),
+    )
+)]
+async fn message_status(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<MessageStatus>, AppError> {
+    let mut status = state.statuses.get(&id).ok_or(AppError::MessageNotFound)?;
+    status.user_id = tenant
+        .unscope(&status.user_id)
+        .ok_or(AppError::MessageNotFound)?
+        .to_owned();
+    Ok(Json(status))
+}
+
+/// Background task for one scheduled job: sleeps until each run, delivers, and
+/// removes the job once its trigger won't fire again.
+async fn run_schedule(state: AppState, id: Uuid) {
+    let schedules = &state.schedules;
+    loop {
+        let Some(next_run) = schedules.read().await.get(&id).and_then(|job| job.next_run) else {
+            break;
+        };
+        let wait = (next_run - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let Some((target, data)) = schedules
+            .read()
+            .await
+            .get(&id)
+            .map(|job| (job.target.clone(), job.data.clone()))
+        else {
+            break;
+        };
+        let sender = Sender::internal(&format!("schedule:{id}"));
+        let reports = deliver(
+            &state,
+            &target,
+            &data.to_json(),
+            &data.push_options(),
+            &sender,
+        )
+        .await;
+        info!("Scheduled job {id} delivered to {} user(s).", reports.len());
+
+        let mut schedules = schedules.write().await;
+        let Some(job) = schedules.get_mut(&id) else {
+            break;
+        };
+        job.next_run = job.trigger.next_after(Utc::now());
+        if job.next_run.is_none() {
+            schedules.remove(&id);
+            break;
+        }
+    }
+}
+
+/// A message accepted for a user, with what delivering it needs once the
+/// registry lock is released and how handing it to the user's connections on
+/// this instance went.
+struct Recipient {
+    user_id: String,
+    subscription: Subscription,
+    preferences: Preferences,
+    message: OutboundMessage,
+    span: Span,
+    sse: DeliveryStatus,
+    websocket: DeliveryStatus,
+    /// The event recorded for the real-time transports, unset for users who
+    /// opted out of them.
+    event: Option<RealtimeMessage>,
+}
+
+impl Recipient {
+    /// Hands `message` to the connections `reg` has on this instance. Called with
+    /// the registry lock held, which is released before the rest of the delivery.
+    fn new(
+        state: &AppState,
+        user_id: &str,
+        reg: &UserRegistration,
+        message: OutboundMessage,
+    ) -> Self {
+        let span = info_span!("deliver", %user_id, message_id = %message.id);
+        let (sse, websocket, event) =
+            span.in_scope(|| realtime_local(state, user_id, reg, &message));
+        Self {
+            user_id: user_id.to_owned(),
+            subscription: reg.subscription.clone(),
+            preferences: reg.preferences.clone(),
+            message,
+            span,
+            sse,
+            websocket,
+            event,
+        }
+    }
+}
+
+/// Accepts `data` for every given registration whose consent hasn't expired.
+/// Called with the registry lock held, [`fan_out`] delivers to the recipients
+/// once it is released.
+fn recipients<'a>(
+    state: &AppState,
+    targets: impl Iterator<Item = (&'a String, &'a UserRegistration)>,
+    data: &str,
+    options: &PushOptions,
+    sender: &Sender,
+) -> Vec<Recipient> {
+    let now = Utc::now();
+    targets
+        .filter(|(_, reg)| !reg.subscription.is_expired(now))
+        .map(|(user_id, reg)| {
+            let message =
+                OutboundMessage::accept(state, user_id, data.to_owned(), options.clone(), sender);
+            Recipient::new(state, user_id, reg, message)
+        })
+        .collect()
+}
+
+/// Delivers to every recipient over push and SSE concurrently.
+async fn fan_out(state: &AppState, recipients: Vec<Recipient>) -> Vec<DeliveryReport> {
+    recipients
+        .into_iter()
+        .map(|recipient| {
+            let span = recipient.span.clone();
+            async move {
+                let (push, (sse, websocket, queued)) = futures::join!(
+                    deliver_push(
+                        state,
+                        &recipient.user_id,
+                        &recipient.subscription,
+                        &recipient.preferences,
+                        &recipient.message
+                    ),
+                    realtime_deliver(state, &recipient)
+                );
+                let push = push.unwrap_or_else(|error| DeliveryStatus::Failed {
+                    error: error.to_string(),
+                });
+                let reached = push.reached() || sse.reached() || websocket.reached();
+                let email = email_fallback(
+                    state,
+                    &recipient.user_id,
+                    &recipient.subscription,
+                    &recipient.message,
+                    reached,
+                )
+                .await;
+                DeliveryReport {
+                    // Reports go back to the tenant, which knows its users without the namespace.
+                    user_id: Tenant::local_part(&recipient.user_id).to_owned(),
+                    message_id: recipient.message.id,
+                    push,
+                    sse,
+                    websocket,
+                    queued,
+                    email,
+                }
+            }
+            .instrument(span)
+        })
+        .collect::<FuturesUnordered<_>>()
+        .collect::<Vec<_>>()
+        .await
+}
+
+/// Emails the message to users that `reached` no connection or push provider.
+async fn email_fallback(
+    state: &AppState,
+    user_id: &str,
+    subscription: &Subscription,
+    message: &OutboundMessage,
+    reached: bool,
+) -> DeliveryStatus {
+    let (Some(channel), Some(address), false) = (&state.email, &subscription.email, reached) else {
+        return DeliveryStatus::Skipped;
+    };
+    match channel.send(address, &message.data).await {
+        Ok(()) => {
+            state
+                .audit
+                .record(message.audit(user_id, "email", "delivered"));
+            DeliveryStatus::Sent
+        }
+        Err(error) => {
+            error!("Email fallback failed: {error}");
+            state.audit.record(
+                message
+                    .audit(user_id, "email", "failed")
+                    .with_error(Some(&error)),
+            );
+            DeliveryStatus::Failed { error }
+        }
+    }
+}
+
+/// Sends the message through every provider the user has an address for and
+/// records the combined outcome in the status store. Users who opted out of push
+/// are skipped, during their quiet hours the push is dropped or deferred.
+async fn deliver_push(
+    state: &AppState,
+    user_id: &str,
+    subscription: &Subscription,
+    preferences: &Preferences,
+    message: &OutboundMessage,
+) -> Result<DeliveryStatus, AppError> {
+    let addressed = state
+        .providers
+        .iter()
+        .any(|provider| subscription.address(provider.kind()).is_some());
+    if addressed && message.options.expired() {
+        expire_push(state, user_id, message);
+        return Ok(DeliveryStatus::Failed {
+            error: "Message expired before it could be pushed".to_owned(),
+        });
+    }
+    match preferences.push_plan(Utc::now()) {
+        PushPlan::Defer(until) if addressed => {
+            state
+                .statuses
+                .set_push(&message.id, PushState::Deferred, None);
+            state
+                .audit
+                .record(message.audit(user_id, "push", "deferred"));
+            spawn_deferred_push(state.clone(), user_id, message, until);
+            return Ok(DeliveryStatus::Deferred { until });
+        }
+        PushPlan::Skip => {
+            state
+                .statuses
+                .set_push(&message.id, PushState::Skipped, None);
+            if addressed {
+                state
+                    .audit
+                    .record(message.audit(user_id, "push", "opted_out"));
+            }
+            return Ok(DeliveryStatus::Skipped);
+        }
+        PushPlan::Now | PushPlan::Defer(_) => {}
+    }
+    let results = state
+        .providers
+        .iter()
+        .filter(|provider| subscription.address(provider.kind()).is_some())
+        .map(|provider| try_push(state, provider, user_id, subscription, message))
+        .collect::<FuturesUnordered<_>>()
+        .collect::<Vec<_>>()
+        .await;
+    let status = combine_push(results);
+    let (push, error) = match &status {
+        Ok(DeliveryStatus::Sent | DeliveryStatus::Routed) => (PushState::Pushed, None),
+        Ok(DeliveryStatus::Skipped) => (PushState::Skipped, None),
+        Ok(DeliveryStatus::Retrying { error }) => (PushState::Retrying, Some(error.clone())),
+        Ok(DeliveryStatus::Deferred { .. }) => (PushState::Deferred, None),
+        Ok(DeliveryStatus::Failed { error }) => (PushState::Failed, Some(error.clone())),
+        Err(error) => (PushState::Failed, Some(error.to_string())),
+    };
+    state.statuses.set_push(&message.id, push, error);
+    status
+}
+
+fn expire_push(state: &AppState, user_id: &str, message: &OutboundMessage) {
+    state
+        .statuses
+        .set_push(&message.id, PushState::Expired, None);
+    state
+        .audit
+        .record(message.audit(user_id, "push", "expired"));
+    increment_counter!("messages_expired_total", "channel" => "push");
+}
+
+/// Waits for the user's quiet hours to end and tries the push again, which defers
+/// it once more if the preferences changed in the meantime. Deferred pushes are
+/// kept in memory only and don't survive a restart.
+fn spawn_deferred_push(
+    state: AppState,
+    user_id: &str,
+    message: &OutboundMessage,
+    until: DateTime<Utc>,
+) {
+    let (user_id, message) = (user_id.to_owned(), message.clone());
+    tokio::spawn(
+        async move {
+            tokio::time::sleep((until - Utc::now()).to_std().unwrap_or_default()).await;
+            let reader = state.channels.read().await;
+            let Some(reg) = reader.get(&user_id) else {
+                state
+                    .statuses
+                    .set_push(&message.id, PushState::Skipped, None);
+                return;
+            };
+            let (subscription, preferences) = (reg.subscription.clone(), reg.preferences.clone());
+            drop(reader);
+            if let Err(error) =
+                deliver_push(&state, &user_id, &subscription, &preferences, &message).await
+            {
+                error!("Deferred push to {user_id} failed: {error}");
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Merges per-provider outcomes. An error is only returned when every provider
+/// failed to build its request, otherwise it counts as a failed delivery.
+fn combine_push(
+    results: Vec<Result<DeliveryStatus, AppError>>,
+) -> Result<DeliveryStatus, AppError> {
+    if results.iter().all(Result::is_err) {
+        return results
+            .into_iter()
+            .next()
+            .unwrap_or(Ok(DeliveryStatus::Skipped));
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|error| DeliveryStatus::Failed {
+                error: error.to_string(),
+            })
+        })
+        .reduce(DeliveryStatus::or)
+        .unwrap_or(DeliveryStatus::Skipped))
+}
+
+async fn try_push(
+    state: &AppState,
+    provider: &Arc<dyn PushProvider>,
+    user_id: &str,
+    subscription: &Subscription,
+    message: &OutboundMessage,
+) -> Result<DeliveryStatus, AppError> {
+    let attempt = attempt_push(state, provider, subscription, message).await;
+    audit_push(state, provider.kind(), user_id, message, &attempt);
+    record_failure(state, provider.kind(), message, &attempt);
+    let attempt = attempt?;
+    record_health(state, user_id, provider.kind(), subscription, &attempt);
+    Ok(match attempt {
+        PushAttempt::Delivered(_) => DeliveryStatus::Sent,
+        PushAttempt::Gone(status) => {
+            // Evicted in its own task, so the outcome isn't held up waiting for the registry lock.
+            spawn_eviction(state.clone(), user_id, provider.kind(), subscription);
+            DeliveryStatus::Failed {
+                error: format!("Push service responded with {status}"),
+            }
+        }
+        PushAttempt::Retryable {
+            error, retry_after, ..
+        } if state.retry_config.max_attempts > 1 => {
+            tokio::spawn(
+                retry_push(
+                    state.clone(),
+                    provider.clone(),
+                    user_id.to_owned(),
+                    subscription.clone(),
+                    message.clone(),
+                    retry_after,
+                )
+                .in_current_span(),
+            );
+            DeliveryStatus::Retrying { error }
+        }
+        PushAttempt::Retryable { error, .. } | PushAttempt::Rejected { error, .. } => {
+            DeliveryStatus::Failed { error }
+        }
+    })
+}
+
+/// Keeps re-sending a push in the background until it is delivered, rejected,
+/// expired or the configured number of attempts is used up.
+async fn retry_push(
+    state: AppState,
+    provider: Arc<dyn PushProvider>,
+    user_id: String,
+    subscription: Subscription,
+    message: OutboundMessage,
+    mut retry_after: Option<Duration>,
+) {
+    let config = state.retry_config;
+    let kind = provider.kind().label();
+    let mut attempt = 2;
+    let outcome = loop {
+        tokio::time::sleep(config.delay(attempt, retry_after)).await;
+        if message.options.expired() {
+            expire_push(&state, &user_id, &message);
+            break "expired";
+        }
+        let result = attempt_push(&state, &provider, &subscription, &message).await;
+        audit_push(&state, provider.kind(), &user_id, &message, &result);
+        record_failure(&state, provider.kind(), &message, &result);
+        if let Ok(attempt) = &result {
+            record_health(&state, &user_id, provider.kind(), &subscription, attempt);
+        }
+        let (outcome, push, error) = match result {
+            Ok(PushAttempt::Delivered(_)) => ("delivered", PushState::Pushed, None),
+            Ok(PushAttempt::Gone(status)) => {
+                spawn_eviction(state.clone(), &user_id, provider.kind(), &subscription);
+                let error = format!("Push service responded with {status}");
+                ("gone", PushState::Failed, Some(error))
+            }
+            Ok(PushAttempt::Retryable { error, .. }) if attempt >= config.max_attempts => {
+                error!("Push ({kind}) to {user_id} failed after {attempt} attempts: {error}");
+                ("exhausted", PushState::Failed, Some(error))
+            }
+            Ok(PushAttempt::Retryable {
+                retry_after: next,
+                error,
+                ..
+            }) => {
+                retry_after = next;
+                state
+                    .statuses
+                    .set_push(&message.id, PushState::Retrying, Some(error));
+                attempt += 1;
+                continue;
+            }
+            Ok(PushAttempt::Rejected { error, .. }) => {
+                error!("Push ({kind}) to {user_id} was rejected: {error}");
+                ("rejected", PushState::Failed, Some(error))
+            }
+            Err(error) => {
+                error!("Push ({kind}) to {user_id} could not be built: {error}");
+                ("rejected", PushState::Failed, Some(error.to_string()))
+            }
+        };
+        state.statuses.set_push(&message.id, push, error);
+        break outcome;
+    };
+    info!("Push ({kind}) retry to {user_id} finished as {outcome} after {attempt} attempts.");
+    increment_counter!("push_retry_outcomes_total", "provider" => kind, "outcome" => outcome);
+}
+
+/// Sends once through `provider`, unless the circuit of its push service is open.
+/// Then the attempt fails right away, without waiting for a push slot.
+async fn attempt_push(
+    state: &AppState,
+    provider: &Arc<dyn PushProvider>,
+    subscription: &Subscription,
+    message: &OutboundMessage,
+) -> Result<PushAttempt, AppError> {
+    let origin = provider.origin(subscription);
+    if let Some(origin) = &origin {
+        if let Err(retry_after) = state.circuits.check(origin) {
+            increment_counter!("push_short_circuited_total", "provider" => provider.kind().label());
+            return Ok(PushAttempt::Retryable {
+                reason: DeliveryError::ServerError,
+                error: format!("Push service {origin} is failing, its circuit is open"),
+                retry_after: Some(retry_after),
+                status: None,
+            });
+        }
+    }
+    let slot = state
+        .push_queue
+        .slot(origin.as_deref(), message.options.priority())
+        .await;
+    let attempt = provider
+        .send(subscription, &message.push_payload(), &message.options)
+        .await;
+    drop(slot);
+    if let Some(origin) = &origin {
+        let healthy = attempt
+            .as_ref()
+            .ok()
+            .map(|attempt| !matches!(attempt, PushAttempt::Retryable { .. }));
+        state.circuits.record(origin, healthy);
+    }
+    attempt
+}
+
+fn audit_push(
+    state: &AppState,
+    kind: ProviderKind,
+    user_id: &str,
+    message: &OutboundMessage,
+    attempt: &Result<PushAttempt, AppError>,
+) {
+    let entry = match attempt {
+        Ok(attempt) => {
+            let (outcome, error) = match attempt {
+                PushAttempt::Delivered(_) => ("delivered", None),
+                PushAttempt::Gone(_) => ("gone", None),
+                PushAttempt::Retryable { error, .. } => ("failed_transiently", Some(error)),
+                PushAttempt::Rejected { error, .. } => ("rejected", Some(error)),
+            };
+            message
+                .audit(user_id, kind.label(), outcome)
+                .with_status(attempt.status())
+                .with_reason(attempt.reason())
+                .with_error(error.map(String::as_str))
+        }
+        Err(error) => message
+            .audit(user_id, kind.label(), "invalid")
+            .with_reason(Some(DeliveryError::InvalidRequest))
+            .with_error(Some(&error.to_string())),
+    };
+    state.audit.record(entry);
+}
+
+/// Notes why a push attempt failed on the message's status and in metrics.
+fn record_failure(
+    state: &AppState,
+    kind: ProviderKind,
+    message: &OutboundMessage,
+    attempt: &Result<PushAttempt, AppError>,
+) {
+    let reason = attempt
+        .as_ref()
+        .map_or(Some(DeliveryError::InvalidRequest), PushAttempt::reason);
+    if let Some(reason) = reason {
+        state.statuses.set_delivery_error(&message.id, reason);
+        increment_counter!("push_errors_total", "provider" => kind.label(), "reason" => reason.label());
+    }
+}
+
+fn record_health(
+    state: &AppState,
+    user_id: &str,
+    kind: ProviderKind,
+    subscription: &Subscription,
+    attempt: &PushAttempt,
+) {
+    if let Some(address) = subscription.address(kind) {
+        state.push_health.record(user_id, kind, address, attempt);
+    }
+}
+
+fn spawn_eviction(state: AppState, user_id: &str, kind: ProviderKind, subscription: &Subscription) {
+    let Some(address) = subscription.address(kind).map(ToOwned::to_owned) else {
+        return;
+    };
+    let user_id = user_id.to_owned();
+    tokio::spawn(
+        async move {
+            info!("{} address of {user_id} is gone, evicting.", kind.label());
+            if let Err(error) = evict_address(&state, &user_id, kind, &address, "gone").await {
+                error!("{error}");
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Periodically drops push addresses that kept failing for longer than `age`.
+async fn reap_unreachable(state: AppState, age: Duration) {
+    let mut interval = tokio::time::interval(state.reaper_config.interval);
+    loop {
+        interval.tick().await;
+        let mut reaped = 0;
+        for (user_id, kind, address) in state.push_health.take_unreachable(age) {
+            match evict_address(&state, &user_id, kind, &address, "unreachable").await {
+                Ok(true) => reaped += 1,
+                Ok(false) => {}
+                Err(error) => {
+                    error!("Unreachable address of {user_id} could not be reaped: {error}");
+                }
+            }
+        }
+        if reaped > 0 {
+            info!("Reaped {reaped} unreachable push address(es).");
+        }
+    }
+}
+
+/// Records the message as an event and hands it to the user's SSE and WebSocket
+/// connections on this instance, returning both outcomes and the event. Called
+/// with the registry lock held.
+fn realtime_local(
+    state: &AppState,
+    user_id: &str,
+    reg: &UserRegistration,
+    message: &OutboundMessage,
+) -> (DeliveryStatus, DeliveryStatus, Option<RealtimeMessage>) {
+    if !reg.preferences.wants_realtime() {
+        state
+            .audit
+            .record(message.audit(user_id, "realtime", "opted_out"));
+        return (DeliveryStatus::Skipped, DeliveryStatus::Skipped, None);
+    }
+    let event = reg.history.record(
+        &state.queue_config,
+        message.id,
+        message.options.expires_at,
+        message.data.clone(),
+    );
+    let (sse, websocket) = realtime_connections(state, user_id, reg, message, &event);
+    (sse, websocket, Some(event))
+}
+
+/// Hands `event` to every SSE and WebSocket connection of the user.
+fn realtime_connections(
+    state: &AppState,
+    user_id: &str,
+    reg: &UserRegistration,
+    message: &OutboundMessage,
+    event: &RealtimeMessage,
+) -> (DeliveryStatus, DeliveryStatus) {
+    let sse = realtime_push(state, user_id, reg, Transport::Sse, event);
+    let websocket = realtime_push(state, user_id, reg, Transport::WebSocket, event);
+    for (channel, status) in [("sse", &sse), ("websocket", &websocket)] {
+        if matches!(status, DeliveryStatus::Sent) {
+            state
+                .audit
+                .record(message.audit(user_id, channel, "delivered"));
+        }
+    }
+    (sse, websocket)
+}
+
+/// Forwards the event to the other instances the user is connected to, and
+/// queues it for the next connection when no connection anywhere took it.
+/// Returns the SSE and WebSocket outcomes and whether it was queued.
+async fn realtime_deliver(
+    state: &AppState,
+    recipient: &Recipient,
+) -> (DeliveryStatus, DeliveryStatus, bool) {
+    let (user_id, message) = (recipient.user_id.as_str(), &recipient.message);
+    let Some(event) = &recipient.event else {
+        return (DeliveryStatus::Skipped, DeliveryStatus::Skipped, false);
+    };
+    let (sse, websocket) = (recipient.sse.clone(), recipient.websocket.clone());
+    let delivered = |sse: &DeliveryStatus, websocket: &DeliveryStatus| {
+        matches!(sse, DeliveryStatus::Sent) || matches!(websocket, DeliveryStatus::Sent)
+    };
+    let delivered_here = delivered(&sse, &websocket);
+    // The user's other devices may be connected to other instances, which get it
+    // whatever happened here.
+    if route_to_cluster(state, user_id, message, delivered_here).await {
+        state
+            .audit
+            .record(message.audit(user_id, "cluster", "routed"));
+        if !delivered_here {
+            state
+                .statuses
+                .set_realtime(&message.id, RealtimeState::Routed);
+            return (DeliveryStatus::Routed, websocket, false);
+        }
+    }
+    if delivered_here {
+        state
+            .statuses
+            .set_realtime(&message.id, RealtimeState::SseDelivered);
+        return (sse, websocket, false);
+    }
+    let reader = state.channels.read().await;
+    let Some(reg) = reader.get(user_id) else {
+        // Unregistered since, there is nobody to queue for.
+        return (sse, websocket, false);
+    };
+    // A connection opened since the first attempt gets it rather than the queue.
+    let (sse, websocket) = if reg.connections.is_empty() {
+        (sse, websocket)
+    } else {
+        let (retried_sse, retried_websocket) =
+            realtime_connections(state, user_id, reg, message, event);
+        (sse.or(retried_sse), websocket.or(retried_websocket))
+    };
+    if delivered(&sse, &websocket) {
+        state
+            .statuses
+            .set_realtime(&message.id, RealtimeState::SseDelivered);
+        return (sse, websocket, false);
+    }
+    state
+        .statuses
+        .set_realtime(&message.id, RealtimeState::Queued);
+    state
+        .audit
+        .record(message.audit(user_id, "queue", "queued"));
+    let displaced = reg.queue.push(
+        &state.queue_config,
+        message.id,
+        message.options.collapse_key.as_deref(),
+        message.options.expires_at,
+        event.clone(),
+    );
+    expire_realtime(state, &displaced.expired, "queue");
+    for dropped in &displaced.dropped {
+        state.statuses.set_realtime(dropped, RealtimeState::Expired);
+    }
+    for replaced in &displaced.replaced {
+        state
+            .statuses
+            .set_realtime(replaced, RealtimeState::Replaced);
+    }
+    (sse, websocket, true)
+}
+
+/// Hands the message to every other instance holding a connection for the user.
+async fn route_to_cluster(
+    state: &AppState,
+    user_id: &str,
+    message: &OutboundMessage,
+    delivered: bool,
+) -> bool {
+    let Some(cluster) = &state.cluster else {
+        return false;
+    };
+    let collapse_key = message.options.collapse_key.as_deref();
+    match cluster
+        .route(
+            user_id,
+            message.id,
+            &message.data,
+            collapse_key,
+            message.options.expires_at,
+            delivered,
+        )
+        .await
+    {
+        Ok(routed) => routed,
+        Err(error) => {
+            error!("Message for {user_id} could not be routed: {error}");
+            false
+        }
+    }
+}
+
+/// Hands the message to every open connection of `transport`, pruning the ones
+/// that turn out dead or get disconnected for falling behind.
+fn realtime_push(
+    state: &AppState,
+    user_id: &str,
+    reg: &UserRegistration,
+    transport: Transport,
+    message: &RealtimeMessage,
+) -> DeliveryStatus {
+    let mut status = DeliveryStatus::Skipped;
+    let mut dead = Vec::new();
+    for (id, connection) in reg
+        .connections
+        .iter()
+        .filter(|(_, connection)| connection.transport == transport)
+    {
+        let closed = || DeliveryStatus::Failed {
+            error: "Connection is closed".to_owned(),
+        };
+        let (outcome, overflow) = match connection.sender.send(message.clone()) {
+            Ok(Pushed::Queued) => {
+                status = status.or(DeliveryStatus::Sent);
+                ("sent", None)
+            }
+            Ok(Pushed::Lagging) => {
+                let buffer = connection.sender.stats();
+                warn!(
+                    "Connection {id} of {user_id} over {} is lagging, {} of {} messages are queued.",
+                    transport.label(),
+                    buffer.queued,
+                    buffer.capacity
+                );
+                increment_counter!("realtime_lagging_connections_total", "transport" => transport.label());
+                status = status.or(DeliveryStatus::Sent);
+                ("sent", None)
+            }
+            Ok(Pushed::DroppedOldest) => {
+                status = status.or(DeliveryStatus::Sent);
+                ("sent", Some("drop_oldest"))
+            }
+            Ok(Pushed::DroppedNewest) => {
+                status = status.or(DeliveryStatus::Failed {
+                    error: "Connection buffer is full".to_owned(),
+                });
+                ("dropped", Some("drop_newest"))
+            }
+            Ok(Pushed::Disconnected) => {
+                dead.push(*id);
+                status = status.or(closed());
+                ("failed", Some("disconnect"))
+            }
+            Err(Closed) => {
+                dead.push(*id);
+                status = status.or(closed());
+                ("failed", None)
+            }
+        };
+        if let Some(action) = overflow {
+            increment_counter!("realtime_overflows_total", "transport" => transport.label(), "action" => action);
+        }
+        increment_counter!("realtime_sends_total", "transport" => transport.label(), "outcome" => outcome);
+    }
+    if !dead.is_empty() {
+        // The caller still holds the registry lock, so prune once it has been released.
+        let (state, user_id) = (state.clone(), user_id.to_owned());
+        tokio::spawn(async move { detach_connections(&state, &user_id, &dead).await });
+    }
+    status
+}
@@ -1,248 +1,178 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic, clippy::perf)]
-#![allow(clippy::significant_drop_tightening)]
-use std::{
-    collections::HashMap, convert::Infallible, net::SocketAddr, process::exit, str::FromStr,
-    sync::OnceLock, time::Duration,
-};
+use std::{fs::OpenOptions, io::Write, net::SocketAddr, process::exit};
 
-use axum::{
-    extract::Query,
-    http::StatusCode,
-    response::{
-        sse::{Event, KeepAlive},
-        Html, IntoResponse, Sse,
-    },
-    routing::{get, post},
-    Json, Router, Server,
+use axum::{routing::IntoMakeService, Router, Server};
+use axum_notification_test::{
+    bench, shutdown_signal, AppState, Command, GenVapid, LogFormat, NotificationService, Tls,
+    VapidKey,
 };
-use base64ct::{Base64UrlUnpadded, Encoding};
-use futures::Stream;
-use hyper::{header, Body, Client};
-use hyper_rustls::HttpsConnectorBuilder;
-use serde::{Deserialize, Serialize};
-use serde_json::{from_str, Value};
-use tokio::sync::{mpsc::Sender, RwLock};
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tracing::{error, info, Level};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use tracing::{info, Level};
 use tracing_subscriber::{
     filter::{LevelFilter, Targets},
     prelude::*,
 };
-use web_push_native::{jwt_simple::prelude::ES256KeyPair, p256::PublicKey, Auth, WebPushBuilder};
-
-#[derive(Deserialize)]
-struct UserInfo {
-    user_id: String,
-}
-
-#[derive(Deserialize)]
-struct SendData {
-    user_id: String,
-    data: String
-}
-
-#[derive(Deserialize, Debug)]
-struct UserRegistrationRequest {
-    user_id: String,
-    endpoint: String,
-    keys: UserRegistrationKey,
-}
-
-#[derive(Deserialize, Debug)]
-struct UserRegistrationKey {
-    p256dh: String,
-    auth: String,
-}
-
-#[derive(Debug)]
-struct UserRegistration {
-    sse_sender: Option<Sender<String>>,
-    endpoint: String,
-    p256dh: String,
-    auth: String,
-}
-
-impl From<UserRegistrationRequest> for UserRegistration {
-    fn from(value: UserRegistrationRequest) -> Self {
-        Self {
-            sse_sender: None,
-            endpoint: value.endpoint,
-            p256dh: value.keys.p256dh,
-            auth: value.keys.auth,
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct VapidKey {
-    subject: String,
-    public_key: String,
-    private_key: String,
-}
-
-impl FromStr for VapidKey {
-    type Err = serde_json::error::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        from_str::<Self>(s)
-    }
-}
-
-static CHANNELS: OnceLock<RwLock<HashMap<String, UserRegistration>>> = OnceLock::new();
-static VAPID: OnceLock<VapidKey> = OnceLock::new();
 
 #[tokio::main]
 async fn main() {
+    let config = match Command::from_args() {
+        Ok(Command::Serve(config)) => *config,
+        Ok(Command::GenVapid(options)) => {
+            if let Err(error) = gen_vapid(&options) {
+                eprintln!("{error}");
+                exit(1)
+            }
+            return;
+        }
+        Ok(Command::Bench(options)) => {
+            match bench(options).await {
+                Ok(report) => print!("{report}"),
+                Err(error) => {
+                    eprintln!("{error}");
+                    exit(1)
+                }
+            }
+            return;
+        }
+        Err(error) => {
+            eprintln!("{error}");
+            exit(2)
+        }
+    };
     let tracing_filter = Targets::new()
         .with_target("tower_http::trace::on_response", Level::DEBUG)
         .with_target("tower_http::trace::on_request", Level::DEBUG)
         .with_target("tower_http::trace::make_span", Level::DEBUG)
         .with_target("rustls::*", LevelFilter::OFF)
-        .with_default(Level::INFO);
-
+        .with_default(config.log_level);
+
+    let fmt_layer = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .boxed(),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt_layer)
         .with(tracing_filter)
         .init();
 
-    CHANNELS.get_or_init(|| RwLock::new(HashMap::new()));
-    VAPID.get_or_init(|| {
-        VapidKey::from_str(include_str!("vapid.json"))
-            .expect("VAPID key could not be deserialized.")
-    });
-
-    let router = Router::new()
-        .route(
-            "/",
-            get(|| async { Html::from(include_str!("index.html")) }),
-        )
-        .route("/vapid.json", get(|| async { Json(VAPID.get().unwrap()) }))
-        .route(
-            "/manifest.json",
-            get(|| async {
-                let json = from_str::<Value>(include_str!("manifest.json")).unwrap_or_default();
-                Json(json)
-            }),
-        )
-        .route(
-            "/service_worker.js",
-            get(|| async {
-                (
-                    [(header::CONTENT_TYPE, "application/javascript")],
-                    include_bytes!("service_worker.js"),
-                )
-            }),
-        )
-        .route(
-            "/index.js",
-            get(|| async {
-                (
-                    [(header::CONTENT_TYPE, "application/javascript")],
-                    include_bytes!("index.js"),
-                )
-            }),
-        )
-        .route("/sse", get(sse))
-        .route("/register", post(register))
-        .route("/send", post(send))
-        .into_make_service();
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 13700));
-    info!("Listening on {addr}");
-
-    Server::bind(&addr)
-        .serve(router)
-        .await
-        .expect("Server startup failed.");
-}
-
-async fn register(Json(user_reg): Json<UserRegistrationRequest>) -> impl IntoResponse {
-    let Some(channel) = CHANNELS.get() else {
-        error!("CACHE not found.");
-        exit(1)
-    };
+    let addr = SocketAddr::from((config.bind, config.port));
+    let tls = config.tls.clone();
+    #[cfg(feature = "grpc")]
+    let grpc_addr = config
+        .grpc_port
+        .map(|port| SocketAddr::from((config.bind, port)));
+    let state = AppState::new(config).await;
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = grpc_addr {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(error) = axum_notification_test::serve_grpc(state, grpc_addr).await {
+                tracing::error!("gRPC server failed: {error}");
+            }
+        });
+    }
+    let router = NotificationService::router(state.clone()).into_make_service();
 
-    channel
-        .write()
-        .await
-        .insert(user_reg.user_id.clone(), UserRegistration::from(user_reg));
-    (StatusCode::OK, "Success".to_owned())
+    if let Some(tls) = tls {
+        info!("Listening on {addr} with TLS");
+        serve_tls(addr, tls, router, state)
+            .await
+            .expect("Server startup failed.");
+    } else {
+        info!("Listening on {addr}");
+        Server::bind(&addr)
+            .serve(router)
+            .with_graceful_shutdown(shutdown_signal(state))
+            .await
+            .expect("Server startup failed.");
+    }
 }
 
-async fn sse(
-    Query(user_info): Query<UserInfo>,
-) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
-    let Some(channel) = CHANNELS.get() else {
-        error!("CACHE not found.");
-        exit(1)
-    };
-    let (tx, rx) = tokio::sync::mpsc::channel(100);
-    let mut channel = channel.write().await;
-    let Some(user) = channel.get_mut(&user_info.user_id) else {
-        error!("User {} not found.", user_info.user_id);
-        return Err(StatusCode::NOT_FOUND);
-    };
-    user.sse_sender = Some(tx);
-
-    let stream = ReceiverStream::new(rx)
-        .map(|data| Ok(Event::default().data(data)))
-        .throttle(Duration::from_secs(10));
+async fn serve_tls(
+    addr: SocketAddr,
+    tls: Tls,
+    router: IntoMakeService<Router>,
+    state: AppState,
+) -> std::io::Result<()> {
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown_signal(state).await;
+            handle.graceful_shutdown(None);
+        }
+    });
 
-    Ok(Sse::new(stream).keep_alive(
-        KeepAlive::new()
-            .interval(Duration::from_secs(10))
-            .text("keep-alive-text"),
-    ))
+    match tls {
+        Tls::Files { cert, key } => {
+            let config = RustlsConfig::from_pem_file(cert, key).await?;
+            axum_server::bind_rustls(addr, config)
+                .handle(handle)
+                .serve(router)
+                .await
+        }
+        #[cfg(feature = "acme")]
+        Tls::Acme {
+            domains,
+            email,
+            cache,
+            production,
+        } => {
+            use futures::StreamExt;
+            use rustls_acme::{caches::DirCache, AcmeConfig};
+
+            let mut acme = AcmeConfig::new(domains)
+                .contact(email.map(|email| format!("mailto:{email}")))
+                .cache_option(cache.map(DirCache::new))
+                .directory_lets_encrypt(production)
+                .state();
+            let acceptor = acme.axum_acceptor(acme.default_rustls_config());
+            tokio::spawn(async move {
+                while let Some(event) = acme.next().await {
+                    match event {
+                        Ok(event) => info!("ACME: {event:?}"),
+                        Err(error) => tracing::error!("ACME: {error:?}"),
+                    }
+                }
+            });
+            axum_server::bind(addr)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(router)
+                .await
+        }
+    }
 }
 
-async fn send(Json(send): Json<SendData>) -> impl IntoResponse {
-    if let Some(channel) = CHANNELS.get() {
-        let reader = channel.read().await;
-        let Some(reg) = reader.get(&send.user_id) else {
-            return (StatusCode::NOT_FOUND, "User not found".to_owned());
-        };
-        if let Some(vapid) = VAPID.get() {
-            let key_pair = ES256KeyPair::from_bytes(
-                &Base64UrlUnpadded::decode_vec(&vapid.private_key).unwrap(),
-            )
-            .unwrap();
-            let builder = WebPushBuilder::new(
-                reg.endpoint.parse().unwrap(),
-                PublicKey::from_sec1_bytes(&Base64UrlUnpadded::decode_vec(&reg.p256dh).unwrap())
-                    .unwrap(),
-                Auth::clone_from_slice(&Base64UrlUnpadded::decode_vec(&reg.auth).unwrap()),
-            )
-            .with_vapid(&key_pair, &vapid.subject);
-            if let Ok(request) = builder
-                .build(send.data.clone())
-                .map(|req| req.map(std::convert::Into::into))
-            {
-                let https = HttpsConnectorBuilder::new()
-                    .with_native_roots()
-                    .https_only()
-                    .enable_http1()
-                    .build();
-                let client: Client<_, Body> = Client::builder().build(https);
-                if let Err(error) = client.request(request).await {
-                    error!("{error}");
-                };
-            }
-        };
-
-        if let Some(sender) = &reg.sse_sender {
-            match sender.send(send.data).await {
-                Ok(_) => (StatusCode::OK, "Sent".to_owned()),
-                Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{error:?}")),
+/// Writes a fresh VAPID key file, readable by its owner only, or prints it when
+/// no `--out` is given.
+fn gen_vapid(options: &GenVapid) -> std::io::Result<()> {
+    let key = VapidKey::generate(options.subject.clone());
+    let json = serde_json::to_string_pretty(&key)?;
+    match &options.out {
+        Some(path) => {
+            let mut file = OpenOptions::new();
+            file.write(true);
+            if options.force {
+                file.create(true).truncate(true);
+            } else {
+                file.create_new(true);
             }
-        } else {
-            (
-                StatusCode::OK,
-                "Sent without sending event due to no channel available.".to_owned(),
-            )
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+            let mut file = file.open(path).map_err(|error| {
+                std::io::Error::new(error.kind(), format!("{}: {error}", path.display()))
+            })?;
+            writeln!(file, "{json}")?;
+            eprintln!("Wrote {}", path.display());
         }
-    } else {
-        error!("CACHE not found.");
-        exit(1)
+        None => println!("{json}"),
+    }
+    if options.print_public_key {
+        println!("{}", key.application_server_key());
     }
+    Ok(())
 }
@@ -0,0 +1,264 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+
+/// Notification content shared by every transport. The bundled service worker
+/// turns it into `showNotification(title, options)`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct Notification {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub badge: Option<String>,
+    /// Page opened when the notification is clicked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<NotificationAction>,
+    /// Notifications sharing a tag replace each other on the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Seconds the push service should keep the message while the device is offline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<Urgency>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct NotificationAction {
+    pub action: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Urgency {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::VeryLow => "very-low",
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+/// Order in which waiting pushes get a delivery slot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Normal => "normal",
+            Self::Low => "low",
+        }
+    }
+}
+
+/// What a push carries.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PushMode {
+    /// The notification, or only its id when `indirect`.
+    #[default]
+    Payload,
+    /// An empty Web Push that only wakes the service worker, which then fetches
+    /// the notification from `/messages/pending`. Other providers get the id.
+    Tickle,
+}
+
+/// How pushes are delivered, mostly Web Push headers (RFC 8030 section 5).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct PushOptions {
+    /// `TTL`: seconds the push service keeps the message for an offline device.
+    pub ttl: Option<u32>,
+    /// When the message goes stale. Past it the message is dropped from the
+    /// offline queue, isn't replayed or pushed anymore, and it caps the `TTL`.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// `Urgency`: lets the device save battery on low-priority messages.
+    pub urgency: Option<Urgency>,
+    /// `Topic`: a pending message with the same topic is replaced by the push service.
+    pub topic: Option<String>,
+    /// Pushes only `{"message_id": ...}` and lets the client fetch the content from
+    /// `/messages/{id}`, for payloads beyond what Web Push carries.
+    pub indirect: Option<bool>,
+    /// `payload` unless set.
+    pub mode: Option<PushMode>,
+    /// A newer message with the same key replaces this one while it waits in the
+    /// user's offline queue. Also sent as the `Topic` unless `topic` is set, so the
+    /// push service collapses them too.
+    pub collapse_key: Option<String>,
+    /// Defaults to `high` for `high` urgency, `low` below `normal` urgency and
+    /// `normal` otherwise.
+    pub priority: Option<Priority>,
+    /// Points the notification's `url` at `/c/{message_id}`, which records the
+    /// click and redirects to the original URL.
+    pub track_clicks: Option<bool>,
+}
+
+impl PushOptions {
+    /// Fills in whatever this leaves unset from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            ttl: self.ttl.or(fallback.ttl),
+            expires_at: self.expires_at.or(fallback.expires_at),
+            urgency: self.urgency.or(fallback.urgency),
+            topic: self.topic.or(fallback.topic),
+            indirect: self.indirect.or(fallback.indirect),
+            mode: self.mode.or(fallback.mode),
+            collapse_key: self.collapse_key.or(fallback.collapse_key),
+            priority: self.priority.or(fallback.priority),
+            track_clicks: self.track_clicks.or(fallback.track_clicks),
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority.unwrap_or(match self.urgency {
+            Some(Urgency::High) => Priority::High,
+            Some(Urgency::Low | Urgency::VeryLow) => Priority::Low,
+            Some(Urgency::Normal) | None => Priority::Normal,
+        })
+    }
+
+    /// Whether `expires_at` has passed.
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    /// The `TTL` to push with now: `ttl`, but no longer than until `expires_at`.
+    pub fn push_ttl(&self) -> Option<u32> {
+        let remaining = self
+            .expires_at
+            .map(|at| u32::try_from((at - Utc::now()).num_seconds().max(0)).unwrap_or(u32::MAX));
+        match (self.ttl, remaining) {
+            (Some(ttl), Some(remaining)) => Some(ttl.min(remaining)),
+            (ttl, remaining) => ttl.or(remaining),
+        }
+    }
+
+    pub const fn tickle(&self) -> bool {
+        matches!(self.mode, Some(PushMode::Tickle))
+    }
+
+    /// Whether the content stays on the server for the client to fetch.
+    pub fn stored(&self) -> bool {
+        self.indirect.unwrap_or(false) || self.tickle()
+    }
+
+    /// The `Topic` header value, `topic` or else the collapse key.
+    pub fn push_topic(&self) -> Option<&str> {
+        self.topic.as_deref().or(self.collapse_key.as_deref())
+    }
+
+    /// The `Topic` header is limited to 32 characters of the URL-safe base64 alphabet.
+    pub fn validate(&self) -> Result<(), String> {
+        for (field, value) in [("topic", &self.topic), ("collapse_key", &self.collapse_key)] {
+            match value {
+                Some(value)
+                    if value.is_empty()
+                        || value.len() > 32
+                        || !value.bytes().all(|byte| {
+                            byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'
+                        }) =>
+                {
+                    return Err(format!(
+                        "`{field}` must be 1-32 characters of [A-Za-z0-9_-]"
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Notification {
+    /// Push headers implied by the notification itself.
+    pub const fn push_options(&self) -> PushOptions {
+        PushOptions {
+            ttl: self.ttl,
+            expires_at: None,
+            urgency: self.urgency,
+            topic: None,
+            indirect: None,
+            mode: None,
+            collapse_key: None,
+            priority: None,
+            track_clicks: None,
+        }
+    }
+
+    pub fn new(title: impl Into<String>, body: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            body: Some(body.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Accepts the legacy opaque string payload, which older clients filled with
+    /// JSON-encoded notifications and everyone else with plain text.
+    fn from_text(text: String) -> Self {
+        serde_json::from_str(&text).unwrap_or_else(|_| Self {
+            body: Some(text),
+            ..Self::default()
+        })
+    }
+
+    /// Replaces every piece of text shown to the user with `f` applied to it.
+    pub fn try_map_text<E>(mut self, f: impl Fn(&str) -> Result<String, E>) -> Result<Self, E> {
+        let map = |text: Option<String>| text.as_deref().map(&f).transpose();
+        self.title = f(&self.title)?;
+        self.body = map(self.body)?;
+        self.icon = map(self.icon)?;
+        self.badge = map(self.badge)?;
+        self.url = map(self.url)?;
+        self.tag = map(self.tag)?;
+        for action in &mut self.actions {
+            action.title = f(&action.title)?;
+            action.icon = map(action.icon.take())?;
+        }
+        Ok(self)
+    }
+
+    /// The JSON delivered over SSE, WebSocket and Web Push.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Deserializes either a structured notification or a legacy string payload.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Notification, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Payload {
+        Text(String),
+        Structured(Notification),
+    }
+
+    Ok(match Payload::deserialize(deserializer)? {
+        Payload::Text(text) => Notification::from_text(text),
+        Payload::Structured(notification) => notification,
+    })
+}
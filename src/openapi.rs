@@ -0,0 +1,223 @@
+// The `OpenApi` derive expands to a `for_each` over the modifiers.
+#![allow(clippy::needless_for_each)]
+
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+use uuid::Uuid;
+
+use crate::{
+    archive::{ArchivePage, ArchivedMessage},
+    assets::AssetVersion,
+    audit::AuditEntry,
+    broadcast_job::{BroadcastProgress, BroadcastState},
+    callback::{CallbackEvent, CallbackRegistration, CallbackRequest},
+    demo::{DemoSeed, DemoSeedRequest},
+    erasure::{ErasedData, ErasureReceipt},
+    export::{ExportFormat, ImportRejection, ImportReport},
+    notification::{Notification, NotificationAction, Priority, PushMode, PushOptions, Urgency},
+    preferences::{ChannelSelection, Preferences, QuietAction, QuietHours},
+    presence::{Presence, PresenceChange, PresenceEvent},
+    push::{DeliveryError, PushPreview},
+    schedule::{ScheduleRequest, ScheduledJob, Target, Trigger},
+    schema::{PayloadSchema, Violation},
+    status::{
+        AckAction, AckEvent, MessageState, MessageStatus, PushState, RealtimeState, Transition,
+    },
+    template::{NotificationTemplate, TemplateSendData},
+    Ack, AttributeFilter, BatchResult, BroadcastData, ConnectionSummary, ConsentRenewal,
+    DeliveryReport, DeliveryStatus, DryRun, Group, GroupDelivery, GroupMembersUpdate, Heartbeat,
+    HistoryItem, PendingMessage, PolledMessage, QueryDelivery, QuerySendData, Readiness,
+    ReadinessCheck, RotationRequest, SendData, Stats, TopicSendData, TopicSubscription,
+    UserRegistrationKey, UserRegistrationRequest, UserSummary,
+};
+
+/// The document served at `/api-docs/openapi.json` and rendered by `/docs`.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Notification server", description = "Web Push, SSE and WebSocket notifications."),
+    paths(
+        crate::register,
+        crate::unregister,
+        crate::renew_consent,
+        crate::rotate_registration,
+        crate::subscribe,
+        crate::sse,
+        crate::websocket,
+        crate::poll,
+        crate::history,
+        crate::archived_history,
+        crate::get_preferences,
+        crate::set_preferences,
+        crate::send,
+        crate::send_batch,
+        crate::send_template,
+        crate::create_template,
+        crate::list_templates,
+        crate::create_schema,
+        crate::list_schemas,
+        crate::remove_schema,
+        crate::broadcast,
+        crate::broadcast_progress,
+        crate::cancel_broadcast,
+        crate::send_topic,
+        crate::update_group,
+        crate::group_members,
+        crate::send_group,
+        crate::send_query,
+        crate::create_schedule,
+        crate::list_schedules,
+        crate::cancel_schedule,
+        crate::pending_messages,
+        crate::message_body,
+        crate::ack,
+        crate::click,
+        crate::message_status,
+        crate::ack_events,
+        crate::set_callback,
+        crate::get_callback,
+        crate::remove_callback,
+        crate::admin_users,
+        crate::admin_connections,
+        crate::admin_user,
+        crate::audit_log,
+        crate::export_registrations,
+        crate::import_registrations,
+        crate::presence,
+        crate::presence_events,
+        crate::tail,
+        crate::admin_stats,
+        crate::seed_demo,
+        crate::healthz,
+        crate::readyz,
+        crate::reload_vapid,
+        crate::erase_user_data,
+        crate::assets::version,
+    ),
+    components(schemas(
+        UserRegistrationRequest,
+        ConsentRenewal,
+        RotationRequest,
+        UserRegistrationKey,
+        ExportFormat,
+        ImportReport,
+        ImportRejection,
+        ErasureReceipt,
+        DemoSeedRequest,
+        DemoSeed,
+        ErasedData,
+        AuditEntry,
+        TopicSubscription,
+        HistoryItem,
+        ArchivePage,
+        ArchivedMessage,
+        PolledMessage,
+        PendingMessage,
+        Preferences,
+        QuietHours,
+        QuietAction,
+        ChannelSelection,
+        SendData,
+        SendResponse,
+        BatchResult,
+        NotificationTemplate,
+        TemplateSendData,
+        PayloadSchema,
+        Violation,
+        BroadcastData,
+        TopicSendData,
+        GroupMembersUpdate,
+        Group,
+        GroupDelivery,
+        BroadcastProgress,
+        BroadcastState,
+        QuerySendData,
+        AttributeFilter,
+        QueryDelivery,
+        DeliveryReport,
+        DryRun,
+        PushPreview,
+        DeliveryStatus,
+        DeliveryError,
+        Notification,
+        NotificationAction,
+        Urgency,
+        Priority,
+        PushMode,
+        PushOptions,
+        ScheduleRequest,
+        ScheduledJob,
+        Target,
+        Trigger,
+        MessageStatus,
+        MessageState,
+        Ack,
+        AckAction,
+        AckEvent,
+        CallbackRequest,
+        CallbackRegistration,
+        CallbackEvent,
+        Transition,
+        PushState,
+        RealtimeState,
+        UserSummary,
+        ConnectionSummary,
+        Presence,
+        PresenceEvent,
+        PresenceChange,
+        Heartbeat,
+        Stats,
+        Readiness,
+        AssetVersion,
+        ReadinessCheck,
+        ErrorResponse,
+    )),
+    tags(
+        (name = "subscriber", description = "Registration and real-time streams, needs a subscriber key."),
+        (name = "publisher", description = "Sending notifications, needs a publisher key."),
+        (name = "admin", description = "Inspection and maintenance, needs admin credentials when configured and a publisher key otherwise."),
+        (name = "health", description = "Probes for orchestrators and load balancers, open to anyone."),
+        (name = "demo", description = "Fixtures for the demo page, open to anyone and only served with `--demo`."),
+    ),
+    modifiers(&ApiKeyScheme),
+    security(("api_key" = []))
+)]
+pub struct ApiDoc;
+
+struct ApiKeyScheme;
+
+impl Modify for ApiKeyScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+            // `/admin` also takes an admin JWT as the bearer token.
+            components.add_security_scheme(
+                "admin_basic",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+            );
+        }
+    }
+}
+
+/// Body of every error response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Machine-readable code such as `user_not_found`.
+    error: String,
+    message: String,
+    /// The offending field, for `invalid_registration`.
+    field: Option<String>,
+}
+
+/// Body of a `/send` response.
+#[derive(Serialize, ToSchema)]
+pub struct SendResponse {
+    message_id: Uuid,
+    /// Describes how the real-time part of the delivery went.
+    message: String,
+}
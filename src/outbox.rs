@@ -0,0 +1,194 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use metrics::{decrement_gauge, increment_counter, increment_gauge};
+use tokio::sync::Notify;
+
+use crate::{config::OverflowPolicy, queue::RealtimeMessage};
+
+/// What became of a message handed to a connection's outbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// Queued, filling the outbox up to its lag threshold.
+    Lagging,
+    /// Queued after dropping the oldest waiting message to make room.
+    DroppedOldest,
+    /// Dropped because the outbox was full.
+    DroppedNewest,
+    /// The outbox was full, so the connection is being closed with an error.
+    Disconnected,
+}
+
+/// The receiving end is gone or the connection was already disconnected.
+#[derive(Debug)]
+pub struct Closed;
+
+/// What the receiving end of an outbox yields.
+#[derive(Debug)]
+pub enum Received {
+    Message(RealtimeMessage),
+    /// Sent once, last, when the connection fell too far behind under
+    /// [`OverflowPolicy::Disconnect`].
+    Overflowed,
+}
+
+/// How a connection's outbox has been keeping up, for `/admin/connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxStats {
+    pub queued: usize,
+    pub capacity: usize,
+    /// Messages the connection took out of the outbox.
+    pub delivered: u64,
+    /// Messages lost to the overflow policy.
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    queue: VecDeque<RealtimeMessage>,
+    sender_gone: bool,
+    receiver_gone: bool,
+    overflowed: bool,
+    delivered: u64,
+    dropped: u64,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    lag_threshold: Option<usize>,
+    /// Label of the `realtime_buffered_messages` gauge and
+    /// `realtime_dropped_messages_total` counter.
+    transport: &'static str,
+}
+
+/// Bounded message buffer of one connection. Unlike an `mpsc` channel a full
+/// outbox never makes the sender wait, `policy` decides what gives instead.
+pub fn outbox(
+    capacity: usize,
+    policy: OverflowPolicy,
+    lag_threshold: Option<usize>,
+    transport: &'static str,
+) -> (OutboxSender, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner::default()),
+        notify: Notify::new(),
+        capacity: capacity.max(1),
+        policy,
+        lag_threshold,
+        transport,
+    });
+    (OutboxSender(shared.clone()), OutboxReceiver(shared))
+}
+
+pub struct OutboxSender(Arc<Shared>);
+
+impl std::fmt::Debug for OutboxSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboxSender")
+            .field("capacity", &self.0.capacity)
+            .field("policy", &self.0.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OutboxSender {
+    pub fn send(&self, message: RealtimeMessage) -> Result<Pushed, Closed> {
+        let mut inner = self.0.inner.lock().expect("Outbox was poisoned");
+        if inner.receiver_gone || inner.overflowed {
+            return Err(Closed);
+        }
+        let transport = self.0.transport;
+        let pushed = if inner.queue.len() < self.0.capacity {
+            inner.queue.push_back(message);
+            increment_gauge!("realtime_buffered_messages", 1.0, "transport" => transport);
+            if self.0.lag_threshold == Some(inner.queue.len()) {
+                Pushed::Lagging
+            } else {
+                Pushed::Queued
+            }
+        } else {
+            inner.dropped += 1;
+            increment_counter!("realtime_dropped_messages_total", "transport" => transport);
+            match self.0.policy {
+                OverflowPolicy::DropOldest => {
+                    inner.queue.pop_front();
+                    inner.queue.push_back(message);
+                    Pushed::DroppedOldest
+                }
+                OverflowPolicy::DropNewest => return Ok(Pushed::DroppedNewest),
+                OverflowPolicy::Disconnect => {
+                    inner.overflowed = true;
+                    Pushed::Disconnected
+                }
+            }
+        };
+        drop(inner);
+        self.0.notify.notify_one();
+        Ok(pushed)
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        let inner = self.0.inner.lock().expect("Outbox was poisoned");
+        OutboxStats {
+            queued: inner.queue.len(),
+            capacity: self.0.capacity,
+            delivered: inner.delivered,
+            dropped: inner.dropped,
+        }
+    }
+}
+
+impl Drop for OutboxSender {
+    fn drop(&mut self) {
+        self.0
+            .inner
+            .lock()
+            .expect("Outbox was poisoned")
+            .sender_gone = true;
+        self.0.notify.notify_one();
+    }
+}
+
+pub struct OutboxReceiver(Arc<Shared>);
+
+impl OutboxReceiver {
+    /// Waits for the next message, or `None` once the sender is gone and the
+    /// outbox is drained.
+    pub async fn recv(&self) -> Option<Received> {
+        loop {
+            {
+                let mut inner = self.0.inner.lock().expect("Outbox was poisoned");
+                if let Some(message) = inner.queue.pop_front() {
+                    inner.delivered += 1;
+                    decrement_gauge!("realtime_buffered_messages", 1.0, "transport" => self.0.transport);
+                    return Some(Received::Message(message));
+                }
+                if inner.overflowed && !inner.receiver_gone {
+                    inner.receiver_gone = true;
+                    return Some(Received::Overflowed);
+                }
+                if inner.sender_gone || inner.receiver_gone {
+                    return None;
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        let mut inner = self.0.inner.lock().expect("Outbox was poisoned");
+        inner.receiver_gone = true;
+        // Nobody is going to read what's left, which never exceeds the capacity.
+        let left = u32::try_from(inner.queue.len()).unwrap_or(u32::MAX);
+        inner.queue.clear();
+        decrement_gauge!("realtime_buffered_messages", f64::from(left), "transport" => self.0.transport);
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+
+/// Which of the user's channels messages go out on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelSelection {
+    #[default]
+    Both,
+    /// Push providers only, nothing over SSE or WebSocket.
+    PushOnly,
+    /// SSE and WebSocket connections only, no push.
+    SseOnly,
+}
+
+/// What happens to pushes sent during quiet hours.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuietAction {
+    /// Hold the push back until the quiet hours end.
+    #[default]
+    Defer,
+    Drop,
+}
+
+/// A daily window without pushes, ending the next day when `end` is before `start`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ToSchema)]
+pub struct QuietHours {
+    #[schema(value_type = String, example = "22:00")]
+    pub start: NaiveTime,
+    #[schema(value_type = String, example = "07:00")]
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// How much of the window is left at the local time `time`, if it falls inside.
+    fn remaining(&self, time: NaiveTime) -> Option<TimeDelta> {
+        let inside = if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        };
+        if !inside {
+            return None;
+        }
+        let remaining = self.end.signed_duration_since(time);
+        Some(if remaining < TimeDelta::zero() {
+            remaining + TimeDelta::days(1)
+        } else {
+            remaining
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Preferences {
+    /// Topics whose messages the user doesn't want.
+    #[serde(default)]
+    pub muted_topics: HashSet<String>,
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHours>,
+    /// IANA time zone the quiet hours are in, UTC when unset.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, example = "Europe/Berlin")]
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub during_quiet_hours: QuietAction,
+    /// Local time the user's digest goes out at, `DIGEST_TIME` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, example = "18:30")]
+    pub digest_at: Option<NaiveTime>,
+    #[serde(default)]
+    pub channels: ChannelSelection,
+}
+
+/// When a push may go out under the user's preferences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushPlan {
+    Now,
+    Defer(DateTime<Utc>),
+    Skip,
+}
+
+impl Preferences {
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self
+            .quiet_hours
+            .iter()
+            .any(|hours| hours.start == hours.end)
+        {
+            return Err(AppError::InvalidPreferences(
+                "quiet hours must not start and end at the same time".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn mutes(&self, topic: &str) -> bool {
+        self.muted_topics.contains(topic)
+    }
+
+    pub fn wants_realtime(&self) -> bool {
+        self.channels != ChannelSelection::PushOnly
+    }
+
+    /// The end of the quiet hours `now` falls into, the latest one if windows overlap.
+    pub fn quiet_until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = now.with_timezone(&self.timezone.unwrap_or(Tz::UTC)).time();
+        self.quiet_hours
+            .iter()
+            .filter_map(|hours| hours.remaining(local))
+            .max()
+            .map(|remaining| now + remaining)
+    }
+
+    pub fn push_plan(&self, now: DateTime<Utc>) -> PushPlan {
+        if self.channels == ChannelSelection::SseOnly {
+            return PushPlan::Skip;
+        }
+        match (self.quiet_until(now), self.during_quiet_hours) {
+            (None, _) => PushPlan::Now,
+            (Some(until), QuietAction::Defer) => PushPlan::Defer(until),
+            (Some(_), QuietAction::Drop) => PushPlan::Skip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn remaining_within_one_day() {
+        let hours = QuietHours {
+            start: at(13, 0),
+            end: at(14, 0),
+        };
+        assert_eq!(hours.remaining(at(13, 0)), Some(TimeDelta::hours(1)));
+        assert_eq!(hours.remaining(at(13, 45)), Some(TimeDelta::minutes(15)));
+        assert_eq!(hours.remaining(at(14, 0)), None);
+        assert_eq!(hours.remaining(at(12, 59)), None);
+    }
+
+    #[test]
+    fn remaining_wraps_past_midnight() {
+        let hours = QuietHours {
+            start: at(22, 0),
+            end: at(7, 0),
+        };
+        assert_eq!(hours.remaining(at(22, 0)), Some(TimeDelta::hours(9)));
+        assert_eq!(hours.remaining(at(23, 30)), Some(TimeDelta::minutes(450)));
+        assert_eq!(hours.remaining(at(0, 0)), Some(TimeDelta::hours(7)));
+        assert_eq!(hours.remaining(at(6, 59)), Some(TimeDelta::minutes(1)));
+        assert_eq!(hours.remaining(at(7, 0)), None);
+        assert_eq!(hours.remaining(at(12, 0)), None);
+        assert_eq!(hours.remaining(at(21, 59)), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use metrics::increment_counter;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::webhook;
+
+#[derive(Serialize, Debug, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceChange {
+    Connected,
+    Disconnected,
+    Registered,
+    /// The registration was removed, by the user or because no address was left.
+    Unregistered,
+}
+
+/// One of a user's streams opening or closing on this instance, or the user
+/// registering or going, as sent to `/admin/presence` and the presence hooks.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct PresenceEvent {
+    pub user_id: String,
+    pub change: PresenceChange,
+    /// `sse` or `websocket`, for connections only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<&'static str>,
+    /// Connections the user has open on this instance after the change.
+    pub connections: usize,
+    pub at: DateTime<Utc>,
+}
+
+/// Whether a user can currently be reached in real time.
+#[derive(Serialize, ToSchema)]
+pub struct Presence {
+    pub user_id: String,
+    pub online: bool,
+    pub sse_connections: usize,
+    pub websocket_connections: usize,
+    /// Connections held by other instances of the cluster.
+    pub remote_connections: usize,
+    /// When the last connection to this instance closed, unset while connected
+    /// or if the user never was.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// URLs every presence event is posted to, signed like subscriber webhooks
+/// with `X-Webhook-Timestamp` and `X-Webhook-Signature`.
+pub struct PresenceHooks {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    urls: Vec<String>,
+    secret: Vec<u8>,
+    timeout: Duration,
+}
+
+impl PresenceHooks {
+    /// Enabled by the comma-separated `PRESENCE_HOOK_URLS`, signed with
+    /// `PRESENCE_HOOK_SECRET`. `PRESENCE_HOOK_TIMEOUT_SECS` bounds each
+    /// request, 10 seconds by default.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(urls) = std::env::var("PRESENCE_HOOK_URLS") else {
+            return Ok(None);
+        };
+        let urls = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| {
+                webhook::validate_url(url)
+                    .map(|()| url.to_owned())
+                    .map_err(|error| format!("PRESENCE_HOOK_URLS: `{url}` {error}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if urls.is_empty() {
+            return Ok(None);
+        }
+        let secret = std::env::var("PRESENCE_HOOK_SECRET")
+            .map_err(|_| "PRESENCE_HOOK_URLS is set without PRESENCE_HOOK_SECRET".to_owned())?;
+        let timeout = std::env::var("PRESENCE_HOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(10);
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Some(Self {
+            client: Client::builder().build(https),
+            urls,
+            secret: secret.into_bytes(),
+            timeout: Duration::from_secs(timeout),
+        }))
+    }
+}
+
+/// Posts every presence event to the hooks, one attempt each. User ids keep
+/// their tenant namespace.
+pub async fn run_hooks(hooks: PresenceHooks, mut events: broadcast::Receiver<PresenceEvent>) {
+    info!("Posting presence events to {} hook(s)", hooks.urls.len());
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Presence hooks fell behind, {missed} event(s) were not posted");
+                increment_counter!("presence_hooks_total", "outcome" => "missed");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let body = serde_json::to_string(&event).expect("Presence events serialize");
+        for url in &hooks.urls {
+            let (client, timeout) = (hooks.client.clone(), hooks.timeout);
+            let (url, secret, body) = (url.clone(), hooks.secret.clone(), body.clone());
+            tokio::spawn(async move {
+                let outcome = match webhook::post(&client, timeout, &url, &secret, body).await {
+                    Ok(()) => "delivered",
+                    Err(error) => {
+                        warn!("Presence hook {url} failed: {error}");
+                        "failed"
+                    }
+                };
+                increment_counter!("presence_hooks_total", "outcome" => outcome);
+            });
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use hyper::{client::HttpConnector, Body, Client, Request, Response, Version};
+use hyper_rustls::HttpsConnector;
+use metrics::{histogram, increment_counter};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{error::AppError, notification::PushOptions, retry, store::Subscription};
+
+pub type PushClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// The push services a subscription can hold an address for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProviderKind {
+    WebPush,
+    Fcm,
+    Apns,
+    Webhook,
+}
+
+impl ProviderKind {
+    pub const ALL: [Self; 4] = [Self::WebPush, Self::Fcm, Self::Apns, Self::Webhook];
+
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::WebPush => "web_push",
+            Self::Fcm => "fcm",
+            Self::Apns => "apns",
+            Self::Webhook => "webhook",
+        }
+    }
+}
+
+/// Why a push wasn't delivered, the same for every push service.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryError {
+    /// 400, the push service couldn't make sense of the request.
+    BadRequest,
+    /// 401 or 403, the VAPID signature or provider credentials were refused.
+    Unauthorized,
+    /// 404 or 410, the subscription no longer exists.
+    Gone,
+    /// 413, the payload is too large for the push service.
+    PayloadTooLarge,
+    /// 429, the push service asks to slow down.
+    RateLimited,
+    /// 5xx, the push service failed.
+    ServerError,
+    /// No response, the connection failed or timed out.
+    Network,
+    /// The request couldn't be built or signed.
+    InvalidRequest,
+    /// Any other status the push service refused the request with.
+    Rejected,
+}
+
+impl DeliveryError {
+    /// What a response with `status` that isn't a success means.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Unauthorized,
+            StatusCode::NOT_FOUND | StatusCode::GONE => Self::Gone,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited,
+            status if status.is_server_error() => Self::ServerError,
+            _ => Self::Rejected,
+        }
+    }
+
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::BadRequest => "bad_request",
+            Self::Unauthorized => "unauthorized",
+            Self::Gone => "gone",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::RateLimited => "rate_limited",
+            Self::ServerError => "server_error",
+            Self::Network => "network",
+            Self::InvalidRequest => "invalid_request",
+            Self::Rejected => "rejected",
+        }
+    }
+
+    /// Whether the same push may go through when tried again later.
+    pub const fn retryable(self) -> bool {
+        matches!(self, Self::RateLimited | Self::ServerError | Self::Network)
+    }
+}
+
+/// Outcome of a single request to the push service, with the status it responded
+/// with when it got that far.
+pub enum PushAttempt {
+    Delivered(StatusCode),
+    /// The subscription no longer exists and should be dropped.
+    Gone(StatusCode),
+    /// A transient failure worth trying again.
+    Retryable {
+        reason: DeliveryError,
+        error: String,
+        retry_after: Option<Duration>,
+        status: Option<StatusCode>,
+    },
+    Rejected {
+        reason: DeliveryError,
+        error: String,
+        status: Option<StatusCode>,
+    },
+}
+
+impl PushAttempt {
+    /// Classifies a push service response by its status code.
+    pub fn from_response(response: Result<Response<Body>, hyper::Error>) -> Self {
+        match response {
+            Ok(response) if response.status().is_success() => Self::Delivered(response.status()),
+            Ok(response) => {
+                let status = response.status();
+                match DeliveryError::from_status(status) {
+                    DeliveryError::Gone => Self::Gone(status),
+                    reason => Self::failed(
+                        reason,
+                        format!("Push service responded with {status}"),
+                        Some(status),
+                        retry::retry_after(response.headers()),
+                    ),
+                }
+            }
+            Err(error) => Self::failed(DeliveryError::Network, error.to_string(), None, None),
+        }
+    }
+
+    /// A failure, retryable or not depending on `reason`.
+    pub const fn failed(
+        reason: DeliveryError,
+        error: String,
+        status: Option<StatusCode>,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        if reason.retryable() {
+            Self::Retryable {
+                reason,
+                error,
+                retry_after,
+                status,
+            }
+        } else {
+            Self::Rejected {
+                reason,
+                error,
+                status,
+            }
+        }
+    }
+
+    /// A failure before any response, such as a request that couldn't be signed.
+    pub const fn rejected(error: String) -> Self {
+        Self::Rejected {
+            reason: DeliveryError::InvalidRequest,
+            error,
+            status: None,
+        }
+    }
+
+    /// Why the attempt didn't deliver, unless it did.
+    pub const fn reason(&self) -> Option<DeliveryError> {
+        match self {
+            Self::Delivered(_) => None,
+            Self::Gone(_) => Some(DeliveryError::Gone),
+            Self::Retryable { reason, .. } | Self::Rejected { reason, .. } => Some(*reason),
+        }
+    }
+
+    /// Why the attempt didn't deliver, for attempts made before any request.
+    pub fn into_error(self) -> String {
+        match self {
+            Self::Retryable { error, .. } | Self::Rejected { error, .. } => error,
+            Self::Delivered(status) | Self::Gone(status) => {
+                format!("Push service responded with {status}")
+            }
+        }
+    }
+
+    pub const fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Delivered(status) | Self::Gone(status) => Some(*status),
+            Self::Retryable { status, .. } | Self::Rejected { status, .. } => *status,
+        }
+    }
+}
+
+/// A backend delivering notifications to one kind of device address.
+#[async_trait]
+pub trait PushProvider: Send + Sync {
+    fn kind(&self) -> ProviderKind;
+
+    /// The push service a send to `subscription` goes to, by default the origin of
+    /// its address URL.
+    fn origin(&self, subscription: &Subscription) -> Option<String> {
+        subscription.address(self.kind()).and_then(origin)
+    }
+
+    /// Sends `data` to the address `subscription` holds for this provider.
+    async fn send(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<PushAttempt, AppError>;
+
+    /// Builds the request `send` would make, signed and encrypted, without
+    /// making it. Access tokens are still fetched when none is cached.
+    async fn preview(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<Request<Body>, String>;
+}
+
+/// A push request a dry run built instead of sending.
+#[derive(Serialize, ToSchema)]
+pub struct PushPreview {
+    pub provider: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// `Authorization` is left out, it holds a live credential.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    pub body_size: usize,
+    /// The body unless it is encrypted or no text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Why the request couldn't be built.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PushPreview {
+    pub async fn new(kind: ProviderKind, request: Result<Request<Body>, String>) -> Self {
+        let mut preview = Self {
+            provider: kind.label(),
+            method: None,
+            url: None,
+            headers: BTreeMap::new(),
+            body_size: 0,
+            body: None,
+            error: None,
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(error) => {
+                preview.error = Some(error);
+                return preview;
+            }
+        };
+        let (parts, body) = request.into_parts();
+        preview.method = Some(parts.method.to_string());
+        preview.url = Some(parts.uri.to_string());
+        preview.headers = parts
+            .headers
+            .iter()
+            .filter(|(name, _)| *name != hyper::header::AUTHORIZATION)
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.to_string(), value)
+            })
+            .collect();
+        match hyper::body::to_bytes(body).await {
+            Ok(body) => {
+                preview.body_size = body.len();
+                let encrypted = parts.headers.contains_key(hyper::header::CONTENT_ENCODING);
+                if !encrypted {
+                    preview.body = String::from_utf8(body.to_vec()).ok();
+                }
+            }
+            Err(error) => preview.error = Some(error.to_string()),
+        }
+        preview
+    }
+}
+
+/// The scheme and authority of `url`.
+pub fn origin(url: &str) -> Option<String> {
+    let uri = url.parse::<hyper::Uri>().ok()?;
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+/// Sends a request to a push service, recording its latency and response status.
+pub async fn dispatch(
+    client: &PushClient,
+    kind: ProviderKind,
+    request: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let start = Instant::now();
+    let response = client.request(request).await;
+    let protocol = response
+        .as_ref()
+        .map_or("none", |response| protocol(response.version()));
+    histogram!(
+        "push_delivery_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        "provider" => kind.label(),
+        "protocol" => protocol
+    );
+    let status = response.as_ref().map_or_else(
+        |_| "error".to_owned(),
+        |response| response.status().as_u16().to_string(),
+    );
+    increment_counter!(
+        "push_sends_total",
+        "provider" => kind.label(),
+        "status" => status,
+        "protocol" => protocol
+    );
+    response
+}
+
+/// The metrics label of the HTTP version a push service responded with.
+const fn protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_2 => "h2",
+        Version::HTTP_3 => "h3",
+        Version::HTTP_10 => "http/1.0",
+        _ => "http/1.1",
+    }
+}
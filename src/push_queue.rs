@@ -0,0 +1,154 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use metrics::increment_counter;
+use tokio::sync::oneshot;
+
+use crate::notification::Priority;
+
+/// Bounds how many pushes are in flight. Pushes beyond that wait for a slot, and
+/// a freed slot goes to the longest waiting push of the highest priority, so a
+/// large low-priority broadcast can't hold up urgent messages queued behind it.
+///
+/// Push services with a limit of their own are queued for the same way before
+/// a push takes one of the shared slots, so a slow one can't tie them all up.
+pub struct PushQueue {
+    global: Arc<Gate>,
+    /// Gates of the push services seen so far that have a limit.
+    origins: Mutex<HashMap<String, Arc<Gate>>>,
+    limits: OriginLimits,
+}
+
+/// Pushes a single push service may have in flight.
+#[derive(Debug, Clone, Default)]
+pub struct OriginLimits {
+    /// For push services without a limit of their own, unlimited when unset.
+    pub default: Option<usize>,
+    /// By host, as in `fcm.googleapis.com`, with the port if the origin has one.
+    pub hosts: HashMap<String, usize>,
+}
+
+impl OriginLimits {
+    fn of(&self, origin: &str) -> Option<usize> {
+        let host = origin.split_once("://").map_or(origin, |(_, host)| host);
+        self.hosts.get(host).copied().or(self.default)
+    }
+}
+
+struct Gate {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    free: usize,
+    /// Waiting pushes by priority, highest first.
+    waiting: [VecDeque<oneshot::Sender<Slot>>; 3],
+}
+
+/// Permission to send one push, handed on to the next waiting push when dropped.
+pub struct Slot(Option<Arc<Gate>>);
+
+/// The slots a push holds while it is in flight: its push service's, if that
+/// has a limit, and a shared one.
+pub struct Permit {
+    _origin: Option<Slot>,
+    _global: Slot,
+}
+
+impl Gate {
+    fn new(slots: usize) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                free: slots.max(1),
+                waiting: Default::default(),
+            }),
+        })
+    }
+
+    /// A slot right away, or the receiver it will be handed to.
+    fn try_take(self: &Arc<Self>, priority: Priority) -> Result<Slot, oneshot::Receiver<Slot>> {
+        let mut inner = self.inner.lock().expect("Push queue was poisoned");
+        if inner.free > 0 {
+            inner.free -= 1;
+            return Ok(Slot(Some(self.clone())));
+        }
+        let (sender, receiver) = oneshot::channel();
+        inner.waiting[rank(priority)].push_back(sender);
+        Err(receiver)
+    }
+}
+
+impl PushQueue {
+    pub fn new(concurrency: usize, limits: OriginLimits) -> Self {
+        Self {
+            global: Gate::new(concurrency),
+            origins: Mutex::new(HashMap::new()),
+            limits,
+        }
+    }
+
+    /// Waits for a slot of the push service at `origin`, then for a shared one,
+    /// queueing behind every waiting push of the same or a higher priority.
+    pub async fn slot(&self, origin: Option<&str>, priority: Priority) -> Permit {
+        let origin_slot = match origin.and_then(|origin| Some((origin, self.gate(origin)?))) {
+            Some((origin, gate)) => match gate.try_take(priority) {
+                Ok(slot) => Some(slot),
+                Err(receiver) => {
+                    increment_counter!("push_origin_waits_total", "origin" => origin.to_owned());
+                    // Senders are only dropped by handing over a slot.
+                    Some(receiver.await.expect("Push queue was dropped"))
+                }
+            },
+            None => None,
+        };
+        let global = match self.global.try_take(priority) {
+            Ok(slot) => slot,
+            Err(receiver) => {
+                increment_counter!("push_queue_waits_total", "priority" => priority.label());
+                receiver.await.expect("Push queue was dropped")
+            }
+        };
+        Permit {
+            _origin: origin_slot,
+            _global: global,
+        }
+    }
+
+    fn gate(&self, origin: &str) -> Option<Arc<Gate>> {
+        let limit = self.limits.of(origin)?;
+        let mut origins = self.origins.lock().expect("Push queue was poisoned");
+        Some(
+            origins
+                .entry(origin.to_owned())
+                .or_insert_with(|| Gate::new(limit))
+                .clone(),
+        )
+    }
+}
+
+const fn rank(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(gate) = self.0.take() else {
+            return;
+        };
+        let mut inner = gate.inner.lock().expect("Push queue was poisoned");
+        while let Some(waiter) = inner.waiting.iter_mut().find_map(VecDeque::pop_front) {
+            match waiter.send(Self(Some(gate.clone()))) {
+                Ok(()) => return,
+                // The waiter gave up, the slot goes to the next one instead.
+                Err(mut slot) => slot.0 = None,
+            }
+        }
+        inner.free += 1;
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    pub max_depth: usize,
+    pub ttl: Duration,
+    /// Sent messages kept per user for `Last-Event-ID` replay and `/history`.
+    pub history_depth: usize,
+}
+
+impl QueueConfig {
+    /// Reads `QUEUE_MAX_DEPTH`, `QUEUE_TTL_SECS` and `SSE_HISTORY_DEPTH`, falling back to
+    /// 100 messages queued for an hour and 100 kept for replay.
+    pub fn from_env() -> Self {
+        let max_depth = std::env::var("QUEUE_MAX_DEPTH")
+            .ok()
+            .and_then(|depth| depth.parse().ok())
+            .unwrap_or(100);
+        let ttl = std::env::var("QUEUE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map_or(Duration::from_hours(1), Duration::from_secs);
+        let history_depth = std::env::var("SSE_HISTORY_DEPTH")
+            .ok()
+            .and_then(|depth| depth.parse().ok())
+            .unwrap_or(100);
+        Self {
+            max_depth,
+            ttl,
+            history_depth,
+        }
+    }
+}
+
+/// A real-time message tagged with its per-user SSE event id.
+#[derive(Debug, Clone)]
+pub struct RealtimeMessage {
+    pub event_id: u64,
+    pub data: String,
+}
+
+/// Messages held back for a user while none of their real-time transports is connected.
+#[derive(Debug, Default)]
+pub struct OfflineQueue {
+    messages: Mutex<VecDeque<QueuedMessage>>,
+}
+
+#[derive(Debug)]
+struct QueuedMessage {
+    queued_at: Instant,
+    id: Uuid,
+    collapse_key: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    message: RealtimeMessage,
+}
+
+impl QueuedMessage {
+    fn expired(&self, config: &QueueConfig, now: DateTime<Utc>) -> bool {
+        self.queued_at.elapsed() > config.ttl || self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Messages [`OfflineQueue::push`] took out to make room for a new one.
+#[derive(Debug, Default)]
+pub struct Displaced {
+    pub expired: Vec<Uuid>,
+    /// Dropped for the queue depth.
+    pub dropped: Vec<Uuid>,
+    /// Queued earlier with the same collapse key.
+    pub replaced: Vec<Uuid>,
+}
+
+impl OfflineQueue {
+    /// Queues a message in place of any queued with the same `collapse_key`,
+    /// dropping the oldest ones once `max_depth` is reached. It expires after the
+    /// queue's TTL or at `expires_at`, whichever comes first.
+    pub fn push(
+        &self,
+        config: &QueueConfig,
+        id: Uuid,
+        collapse_key: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        message: RealtimeMessage,
+    ) -> Displaced {
+        if config.max_depth == 0 {
+            return Displaced {
+                dropped: vec![id],
+                ..Displaced::default()
+            };
+        }
+        let mut messages = self.messages.lock().unwrap();
+        let mut displaced = Displaced {
+            expired: Self::prune(&mut messages, config),
+            ..Displaced::default()
+        };
+        if let Some(key) = collapse_key {
+            messages.retain(|queued| {
+                if queued.collapse_key.as_deref() != Some(key) {
+                    return true;
+                }
+                displaced.replaced.push(queued.id);
+                false
+            });
+        }
+        while messages.len() >= config.max_depth {
+            displaced
+                .dropped
+                .extend(messages.pop_front().map(|message| message.id));
+        }
+        messages.push_back(QueuedMessage {
+            queued_at: Instant::now(),
+            id,
+            collapse_key: collapse_key.map(ToOwned::to_owned),
+            expires_at,
+            message,
+        });
+        displaced
+    }
+
+    /// Takes every message that hasn't expired yet, oldest first, along with
+    /// the ids of the ones that had.
+    pub fn drain(&self, config: &QueueConfig) -> (Vec<(Uuid, RealtimeMessage)>, Vec<Uuid>) {
+        let mut messages = self.messages.lock().unwrap();
+        let expired = Self::prune(&mut messages, config);
+        let pending = messages
+            .drain(..)
+            .map(|queued| (queued.id, queued.message))
+            .collect();
+        (pending, expired)
+    }
+
+    /// Number of messages currently held, including ones past their TTL that
+    /// haven't been pruned yet.
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    fn prune(messages: &mut VecDeque<QueuedMessage>, config: &QueueConfig) -> Vec<Uuid> {
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        messages.retain(|message| {
+            if !message.expired(config, now) {
+                return true;
+            }
+            expired.push(message.id);
+            false
+        });
+        expired
+    }
+}
+
+/// A message as remembered by [`EventHistory`].
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub message_id: Uuid,
+    pub sent_at: DateTime<Utc>,
+    /// Not replayed anymore once passed.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub message: RealtimeMessage,
+}
+
+/// Recently sent real-time messages for one user, numbered so an SSE client
+/// reconnecting with `Last-Event-ID` can catch up on what it missed and offline
+/// clients can page through `/history`.
+#[derive(Debug, Default)]
+pub struct EventHistory {
+    inner: Mutex<(u64, VecDeque<HistoryEntry>)>,
+}
+
+impl EventHistory {
+    /// Assigns the next event id to `data` and remembers the message.
+    pub fn record(
+        &self,
+        config: &QueueConfig,
+        message_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        data: String,
+    ) -> RealtimeMessage {
+        let mut inner = self.inner.lock().unwrap();
+        let (last_id, entries) = &mut *inner;
+        *last_id += 1;
+        let message = RealtimeMessage {
+            event_id: *last_id,
+            data,
+        };
+        if config.history_depth > 0 {
+            while entries.len() >= config.history_depth {
+                entries.pop_front();
+            }
+            entries.push_back(HistoryEntry {
+                message_id,
+                sent_at: Utc::now(),
+                expires_at,
+                message: message.clone(),
+            });
+        }
+        message
+    }
+
+    /// Every remembered message newer than `last_event_id` that hasn't expired,
+    /// oldest first, along with how many had.
+    pub fn since(&self, last_event_id: u64) -> (Vec<RealtimeMessage>, usize) {
+        let now = Utc::now();
+        let inner = self.inner.lock().unwrap();
+        let (expired, replay): (Vec<_>, Vec<_>) = inner
+            .1
+            .iter()
+            .filter(|entry| entry.message.event_id > last_event_id)
+            .partition(|entry| entry.expires_at.is_some_and(|at| at <= now));
+        (
+            replay
+                .into_iter()
+                .map(|entry| entry.message.clone())
+                .collect(),
+            expired.len(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().1.len()
+    }
+
+    /// Up to `limit` remembered messages older than the `before` event id, newest first.
+    pub fn page(&self, before: Option<u64>, limit: usize) -> Vec<HistoryEntry> {
+        self.inner
+            .lock()
+            .unwrap()
+            .1
+            .iter()
+            .rev()
+            .filter(|entry| before.is_none_or(|before| entry.message.event_id < before))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
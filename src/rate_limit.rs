@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+
+use crate::{auth, error::AppError, AppState};
+
+/// Buckets are pruned once this many keys are tracked, dropping the ones that
+/// have refilled completely and so carry no state worth keeping.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per key: `capacity` requests at once, `limit` refilled evenly
+/// over a minute.
+pub struct RateLimiter {
+    capacity: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn per_minute(limit: u32) -> Self {
+        Self::with_burst(limit, limit)
+    }
+
+    /// Allows `burst` requests at once, smoothing the rest of `limit` over the minute.
+    pub fn with_burst(limit: u32, burst: u32) -> Self {
+        Self {
+            capacity: f64::from(burst.max(1).min(limit)),
+            per_second: f64::from(limit) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Requests allowed at once, 0 when nothing is.
+    pub const fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    /// Takes a token for `key`, or returns how long until one becomes available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        elapsed
+            .mul_add(self.per_second, bucket.tokens)
+            .min(self.capacity)
+    }
+}
+
+/// Limits applied to `/send`, each disabled unless configured.
+pub struct RateLimits {
+    /// Keyed on the API key, all requests share one bucket when auth is disabled.
+    pub sender: Option<RateLimiter>,
+    /// Keyed on the recipient's user id.
+    pub target: Option<RateLimiter>,
+}
+
+impl RateLimits {
+    /// Reads `SEND_LIMIT_PER_KEY` and `SEND_LIMIT_PER_USER`, both in requests per minute.
+    pub fn from_env() -> Self {
+        let limiter = |name| {
+            std::env::var(name)
+                .ok()
+                .and_then(|limit| limit.parse().ok())
+                .filter(|limit| *limit > 0)
+                .map(RateLimiter::per_minute)
+        };
+        Self {
+            sender: limiter("SEND_LIMIT_PER_KEY"),
+            target: limiter("SEND_LIMIT_PER_USER"),
+        }
+    }
+}
+
+/// Middleware enforcing the per-sender limit.
+pub async fn limit_sender<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    if let Some(limiter) = &state.rate_limits.sender {
+        let key = auth::request_key(&request).unwrap_or_default();
+        limiter.check(&key).map_err(AppError::RateLimited)?;
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_spreads_over_the_minute() {
+        let limiter = RateLimiter::with_burst(60, 3);
+        for _ in 0..3 {
+            assert!(limiter.check("alice").is_ok());
+        }
+        let wait = limiter.check("alice").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        // Every key has a bucket of its own.
+        assert!(limiter.check("bob").is_ok());
+    }
+
+    #[test]
+    fn burst_is_capped_by_the_limit() {
+        assert!((RateLimiter::with_burst(10, 50).capacity() - 10.0).abs() < f64::EPSILON);
+        assert!((RateLimiter::with_burst(10, 0).capacity() - 1.0).abs() < f64::EPSILON);
+        assert!(RateLimiter::per_minute(0).capacity() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::push::{ProviderKind, PushAttempt};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReaperConfig {
+    /// Time between two sweeps.
+    pub interval: Duration,
+    /// How long an address may keep failing before it is dropped, never when unset.
+    pub unreachable_after: Option<Duration>,
+}
+
+impl ReaperConfig {
+    /// Reads `REAPER_INTERVAL_SECS` and `REAP_UNREACHABLE_AFTER_SECS`, falling back to
+    /// hourly sweeps dropping addresses unreachable for a week. An age of `0` disables
+    /// reaping.
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<u64> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+        Self {
+            interval: Duration::from_secs(var("REAPER_INTERVAL_SECS").unwrap_or(3600).max(1)),
+            unreachable_after: match var("REAP_UNREACHABLE_AFTER_SECS") {
+                Some(0) => None,
+                secs => Some(Duration::from_secs(secs.unwrap_or(7 * 24 * 3600))),
+            },
+        }
+    }
+}
+
+/// Push addresses whose deliveries currently fail with transient errors, and since
+/// when. Kept in memory, so the clock restarts with the process.
+#[derive(Default)]
+pub struct AddressHealth {
+    failing: Mutex<HashMap<(String, ProviderKind), (String, Instant)>>,
+}
+
+impl AddressHealth {
+    /// Notes the outcome of a push to `address`: a delivery clears it, a transient
+    /// failure starts the clock unless it's already running.
+    pub fn record(&self, user_id: &str, kind: ProviderKind, address: &str, attempt: &PushAttempt) {
+        let mut failing = self.failing.lock().expect("Address health was poisoned");
+        let key = (user_id.to_owned(), kind);
+        match attempt {
+            PushAttempt::Delivered(_) => {
+                failing.remove(&key);
+            }
+            PushAttempt::Retryable { .. } => {
+                let entry = failing
+                    .entry(key)
+                    .or_insert_with(|| (address.to_owned(), Instant::now()));
+                if entry.0 != address {
+                    *entry = (address.to_owned(), Instant::now());
+                }
+            }
+            PushAttempt::Gone(_) | PushAttempt::Rejected { .. } => {}
+        }
+    }
+
+    /// Takes out every address that has been failing for longer than `age`.
+    pub fn take_unreachable(&self, age: Duration) -> Vec<(String, ProviderKind, String)> {
+        let mut failing = self.failing.lock().expect("Address health was poisoned");
+        let mut unreachable = Vec::new();
+        failing.retain(|(user_id, kind), (address, since)| {
+            if since.elapsed() < age {
+                return true;
+            }
+            unreachable.push((user_id.clone(), *kind, std::mem::take(address)));
+            false
+        });
+        unreachable
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+use tracing::{error, info};
+
+use crate::{store::Page, AppState, UserRegistration};
+
+/// Subscriptions read from the store per page.
+const PAGE_SIZE: usize = 500;
+/// Time between two progress reports while recovering.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Wait before reading a page again that the store failed to hand out.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Loads the stored registrations into memory page by page, then marks the
+/// state recovered so `/readyz` passes and interrupted sends are replayed.
+/// Users that registered while this ran keep their newer registration.
+pub async fn run(state: AppState) {
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut cursor = None::<String>;
+    let mut loaded = 0_usize;
+    loop {
+        let Page { entries, next } = match state.store.load_page(cursor.as_deref(), PAGE_SIZE).await
+        {
+            Ok(page) => page,
+            Err(error) => {
+                error!("Stored subscriptions could not be loaded, retrying: {error}");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        loaded += entries.len();
+        {
+            let mut channels = state.channels.write().await;
+            for (user_id, subscription) in entries {
+                channels
+                    .entry(user_id)
+                    .or_insert_with(|| UserRegistration::from(subscription));
+            }
+        }
+        if next.is_none() {
+            break;
+        }
+        cursor = next;
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            info!("Recovering subscriptions, {loaded} loaded so far");
+            last_report = Instant::now();
+        }
+    }
+    info!(
+        "Loaded {loaded} stored subscription(s) in {:.1?}",
+        started.elapsed()
+    );
+    state.recovered.send_replace(true);
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hyper::{header, HeaderMap};
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryConfig {
+    /// Reads `PUSH_RETRY_ATTEMPTS`, `PUSH_RETRY_BASE_MS` and `PUSH_RETRY_MAX_MS`,
+    /// falling back to 5 attempts starting at 500ms and capped at a minute.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+        Self {
+            max_attempts: var("PUSH_RETRY_ATTEMPTS").unwrap_or(5),
+            base_delay: var("PUSH_RETRY_BASE_MS")
+                .map_or(Duration::from_millis(500), Duration::from_millis),
+            max_delay: var("PUSH_RETRY_MAX_MS")
+                .map_or(Duration::from_mins(1), Duration::from_millis),
+        }
+    }
+
+    /// Delay before attempt number `attempt` (the first retry is attempt 2): exponential
+    /// backoff with jitter, but never shorter than what the push service asked for.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(2)))
+            .min(self.max_delay);
+        let jittered = exponential.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+        retry_after.map_or(jittered, |retry_after| jittered.max(retry_after))
+    }
+}
+
+/// Parses a `Retry-After` header given in delta-seconds or as an HTTP date,
+/// a date in the past asking for no wait at all.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn parse(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        retry_after(&headers)
+    }
+
+    #[test]
+    fn parses_seconds_and_dates() {
+        assert_eq!(parse("150"), Some(Duration::from_secs(150)));
+        let later = (Utc::now() + chrono::Duration::seconds(90))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let wait = parse(&later).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90));
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+        assert_eq!(parse("soon"), None);
+    }
+}
@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    auth::Tenant,
+    error::AppError,
+    notification::{self, Notification},
+};
+
+/// Who a notification is addressed to.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Target {
+    User(String),
+    Topic(String),
+    Group(String),
+}
+
+impl Target {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::User(id) | Self::Topic(id) | Self::Group(id) => id,
+        }
+    }
+
+    /// The same target inside `tenant`'s namespace.
+    pub fn scoped(self, tenant: &Tenant) -> Result<Self, AppError> {
+        Ok(match self {
+            Self::User(id) => Self::User(tenant.scope(&id)?),
+            Self::Topic(id) => Self::Topic(tenant.scope(&id)?),
+            Self::Group(id) => Self::Group(tenant.scope(&id)?),
+        })
+    }
+
+    /// The target as its tenant knows it, without the namespace.
+    fn local(&self) -> Self {
+        let id = Tenant::local_part(self.id()).to_owned();
+        match self {
+            Self::User(_) => Self::User(id),
+            Self::Topic(_) => Self::Topic(id),
+            Self::Group(_) => Self::Group(id),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    At(DateTime<Utc>),
+    /// A `cron` expression with a leading seconds field.
+    Cron(String),
+}
+
+impl Trigger {
+    /// The first time the trigger fires strictly after `after`, if any.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::At(at) => (*at > after).then_some(*at),
+            Self::Cron(expression) => cron::Schedule::from_str(expression)
+                .ok()
+                .and_then(|schedule| schedule.after(&after).next()),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ScheduleRequest {
+    user_id: Option<String>,
+    topic: Option<String>,
+    #[serde(deserialize_with = "notification::deserialize")]
+    data: Notification,
+    at: Option<DateTime<Utc>>,
+    cron: Option<String>,
+}
+
+impl ScheduleRequest {
+    /// Validates that exactly one target and one trigger were given.
+    pub fn into_parts(self) -> Result<(Target, Trigger, Notification), AppError> {
+        let target = match (self.user_id, self.topic) {
+            (Some(user_id), None) => Target::User(user_id),
+            (None, Some(topic)) => Target::Topic(topic),
+            _ => {
+                return Err(AppError::InvalidSchedule(
+                    "exactly one of `user_id` and `topic` is required".to_owned(),
+                ))
+            }
+        };
+        let trigger = match (self.at, self.cron) {
+            (Some(at), None) if at > Utc::now() => Trigger::At(at),
+            (Some(_), None) => {
+                return Err(AppError::InvalidSchedule(
+                    "`at` must lie in the future".to_owned(),
+                ))
+            }
+            (None, Some(expression)) => {
+                cron::Schedule::from_str(&expression).map_err(|error| {
+                    AppError::InvalidSchedule(format!("invalid `cron`: {error}"))
+                })?;
+                Trigger::Cron(expression)
+            }
+            _ => {
+                return Err(AppError::InvalidSchedule(
+                    "exactly one of `at` and `cron` is required".to_owned(),
+                ))
+            }
+        };
+        Ok((target, trigger, self.data))
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ScheduledJob {
+    pub id: Uuid,
+    pub target: Target,
+    pub trigger: Trigger,
+    pub data: Notification,
+    pub next_run: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub task: Option<JoinHandle<()>>,
+}
+
+impl ScheduledJob {
+    /// A copy to respond with, addressed the way the tenant that created it knows
+    /// the target.
+    pub fn view(&self) -> Self {
+        Self {
+            id: self.id,
+            target: self.target.local(),
+            trigger: self.trigger.clone(),
+            data: self.data.clone(),
+            next_run: self.next_run,
+            task: None,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::{collections::HashMap, sync::Arc};
+
+use jsonschema::Validator;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+
+use crate::{audit::Sender, error::AppError, notification::Notification};
+
+/// Most violations a rejected send lists.
+const MAX_VIOLATIONS: usize = 20;
+
+/// A JSON Schema the notifications sent have to conform to.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct PayloadSchema {
+    /// Checks the messages sent to this topic. Without a topic the schema checks
+    /// everything sent with the credentials registering it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[schema(value_type = Object)]
+    pub schema: Value,
+}
+
+/// Where and why a notification doesn't conform to a schema.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Violation {
+    /// JSON pointer into the notification, empty for the notification itself.
+    pub pointer: String,
+    pub message: String,
+}
+
+struct Compiled {
+    source: Value,
+    validator: Validator,
+}
+
+impl Compiled {
+    fn new(source: Value) -> Result<Self, AppError> {
+        let validator = jsonschema::validator_for(&source)
+            .map_err(|error| AppError::InvalidSchema(error.to_string()))?;
+        Ok(Self { source, validator })
+    }
+
+    fn check(&self, data: &Value) -> Result<(), AppError> {
+        let violations = self
+            .validator
+            .iter_errors(data)
+            .take(MAX_VIOLATIONS)
+            .map(|error| Violation {
+                pointer: error.instance_path().as_str().to_owned(),
+                message: error.to_string(),
+            })
+            .collect::<Vec<_>>();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::SchemaViolation(violations))
+        }
+    }
+}
+
+/// Schemas registered through `/schemas`, by topic and by sender.
+#[derive(Default)]
+pub struct Schemas {
+    topics: RwLock<HashMap<String, Arc<Compiled>>>,
+    senders: RwLock<HashMap<Sender, Arc<Compiled>>>,
+}
+
+impl Schemas {
+    /// Checks `schema` compiles before storing it under its topic, or else under
+    /// `sender`, replacing the one registered there before.
+    pub async fn insert(&self, sender: &Sender, schema: PayloadSchema) -> Result<(), AppError> {
+        let compiled = Arc::new(Compiled::new(schema.schema)?);
+        match schema.topic {
+            Some(topic) => self.topics.write().await.insert(topic, compiled),
+            None => self.senders.write().await.insert(sender.clone(), compiled),
+        };
+        Ok(())
+    }
+
+    /// The schemas of every topic and the one of `sender`, if it registered one.
+    pub async fn list(&self, sender: &Sender) -> Vec<PayloadSchema> {
+        let mut schemas = self
+            .topics
+            .read()
+            .await
+            .iter()
+            .map(|(topic, compiled)| PayloadSchema {
+                topic: Some(topic.clone()),
+                schema: compiled.source.clone(),
+            })
+            .collect::<Vec<_>>();
+        schemas.sort_by(|a, b| a.topic.cmp(&b.topic));
+        if let Some(compiled) = self.senders.read().await.get(sender) {
+            schemas.insert(
+                0,
+                PayloadSchema {
+                    topic: None,
+                    schema: compiled.source.clone(),
+                },
+            );
+        }
+        schemas
+    }
+
+    /// Removes the schema of `topic`, or else the one of `sender`. Whether
+    /// there was one.
+    pub async fn remove(&self, sender: &Sender, topic: Option<&str>) -> bool {
+        match topic {
+            Some(topic) => self.topics.write().await.remove(topic).is_some(),
+            None => self.senders.write().await.remove(sender).is_some(),
+        }
+    }
+
+    /// Checks `data` conforms to the schema of `sender` and, when sent to a
+    /// topic, to the topic's.
+    pub async fn check(
+        &self,
+        sender: &Sender,
+        topic: Option<&str>,
+        data: &Notification,
+    ) -> Result<(), AppError> {
+        let by_sender = self.senders.read().await.get(sender).cloned();
+        let by_topic = match topic {
+            Some(topic) => self.topics.read().await.get(topic).cloned(),
+            None => None,
+        };
+        if by_sender.is_none() && by_topic.is_none() {
+            return Ok(());
+        }
+        let data = serde_json::to_value(data).expect("Notifications serialize");
+        for compiled in by_sender.iter().chain(&by_topic) {
+            compiled.check(&data)?;
+        }
+        Ok(())
+    }
+}
@@ -2,18 +2,141 @@ self.addEventListener("activate", () => {
     clients.claim();
 });
 
-self.addEventListener("install", () => {
-    self.skipWaiting();
-});
+// The version this worker was registered with, see `GET /version`.
+const version = new URLSearchParams(self.location.search).get("v");
+// Looked up at most this often, as the worker only runs while handling events.
+const UPDATE_CHECK_INTERVAL = 60 * 60 * 1000;
+let lastUpdateCheck = 0;
 
-self.addEventListener("push", async (event) => {
+async function checkForUpdate() {
+    if (!version || Date.now() - lastUpdateCheck < UPDATE_CHECK_INTERVAL) {
+        return;
+    }
+    lastUpdateCheck = Date.now();
     try {
-        const data = event.data.json();
-        const options = {
-            body: data.body
-        };
-        await self.registration.showNotification(data.title, options);
+        const current = (await (await fetch("/version", { cache: "no-store" })).json()).version;
+        if (current !== version) {
+            await self.registration.update();
+        }
     } catch (error) {
         console.log(error);
     }
+}
+
+self.addEventListener("install", () => {
+    self.skipWaiting();
+});
+
+// Lets the server know what became of a notification, see `POST /ack`.
+function acknowledge(messageId, action) {
+    if (!messageId) {
+        return Promise.resolve();
+    }
+    return fetch("/ack", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ message_id: messageId, action })
+    }).catch((error) => console.log(error));
+}
+
+async function display(data, messageId) {
+    const options = {
+        body: data.body,
+        icon: data.icon,
+        badge: data.badge,
+        tag: data.tag,
+        actions: data.actions ?? [],
+        data: { url: data.url, messageId }
+    };
+    await self.registration.showNotification(data.title, options);
+    await acknowledge(messageId, "displayed");
+}
+
+// Registered as `service_worker.js?user_id=...&v=...`, which tickles need to know
+// whose messages to fetch.
+async function displayPending() {
+    const query = new URLSearchParams(self.location.search);
+    query.delete("v");
+    const messages = await (await fetch(`/messages/pending?${query}`)).json();
+    for (const message of messages) {
+        await display(message.data, message.message_id);
+    }
+}
+
+self.addEventListener("push", (event) => {
+    event.waitUntil(
+        (async () => {
+            try {
+                if (!event.data) {
+                    // Tickle, the messages wait on the server.
+                    await displayPending();
+                    return;
+                }
+                let data = event.data.json();
+                const messageId = data.message_id;
+                if (messageId && data.title === undefined) {
+                    // Indirect message, too large for the push itself.
+                    data = await (await fetch(`/messages/${data.message_id}`)).json();
+                }
+                await display(data, messageId);
+            } catch (error) {
+                console.log(error);
+            }
+            await checkForUpdate();
+        })()
+    );
+});
+
+self.addEventListener("notificationclick", (event) => {
+    event.notification.close();
+    const { url, messageId } = event.notification.data ?? {};
+    event.waitUntil(
+        Promise.all([
+            acknowledge(messageId, "clicked"),
+            url ? clients.openWindow(url) : Promise.resolve(),
+            checkForUpdate()
+        ])
+    );
+});
+
+// The `applicationServerKey` a subscription was created with, base64url encoded
+// as in `/vapid.json`.
+function vapidKeyOf(subscription) {
+    const key = subscription.options?.applicationServerKey;
+    if (!key) {
+        return undefined;
+    }
+    const binary = String.fromCharCode(...new Uint8Array(key));
+    return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+// The browser replaced the subscription, hand the new one to the server so the
+// user keeps receiving pushes, see `POST /register/rotate`.
+self.addEventListener("pushsubscriptionchange", (event) => {
+    event.waitUntil(
+        (async () => {
+            try {
+                const query = new URLSearchParams(self.location.search);
+                const subscription =
+                    event.newSubscription ??
+                    (await self.registration.pushManager.subscribe(event.oldSubscription.options));
+                const userToken = query.get("user_token");
+                await fetch("/register/rotate", {
+                    method: "POST",
+                    headers: {
+                        "Content-Type": "application/json",
+                        ...(userToken ? { "X-User-Token": userToken } : {})
+                    },
+                    body: JSON.stringify({
+                        user_id: query.get("user_id"),
+                        old_endpoint: event.oldSubscription?.endpoint,
+                        vapid_key: vapidKeyOf(subscription),
+                        ...subscription.toJSON()
+                    })
+                });
+            } catch (error) {
+                console.log(error);
+            }
+        })()
+    );
 });
@@ -0,0 +1,335 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{audit::Sender, push::DeliveryError};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum PushState {
+    Pending,
+    Pushed,
+    Retrying,
+    /// Held back until the user's quiet hours end.
+    Deferred,
+    /// Its `expires_at` passed before it could be pushed.
+    Expired,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RealtimeState {
+    Pending,
+    /// Waiting in the offline queue for the user to reconnect.
+    Queued,
+    SseDelivered,
+    /// Forwarded to the instance holding the user's connection.
+    Routed,
+    /// Dropped from the offline queue before the user reconnected, or because
+    /// its `expires_at` passed.
+    Expired,
+    /// Superseded in the offline queue by a newer message with the same collapse key.
+    Replaced,
+}
+
+/// Overall state of a message, derived from its per-channel states.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageState {
+    Accepted,
+    Queued,
+    Pushed,
+    SseDelivered,
+    Failed,
+}
+
+/// What the recipient's client confirmed about a notification.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AckAction {
+    Displayed,
+    Clicked,
+}
+
+/// A client's acknowledgement, as sent to `/acks` listeners.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct AckEvent {
+    pub message_id: Uuid,
+    pub user_id: String,
+    pub action: AckAction,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct MessageStatus {
+    pub id: Uuid,
+    pub user_id: String,
+    pub state: MessageState,
+    pub push: PushState,
+    pub realtime: RealtimeState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Why the last push attempt failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_error: Option<DeliveryError>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// When the message goes stale, if it was sent with an expiry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// When the client showed the notification, as acknowledged through `/ack`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub displayed_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clicked_at: Option<DateTime<Utc>>,
+    /// Where `/c/{message_id}` redirects, for messages sent with `track_clicks`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_url: Option<String>,
+    /// The summary the message went out as part of, for messages coalesced past
+    /// the recipient's cap.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coalesced_into: Option<Uuid>,
+    /// Content of an indirect or tickle message, kept for the client to fetch.
+    #[serde(skip)]
+    pub body: Option<String>,
+    #[serde(skip)]
+    pub sender: Sender,
+}
+
+/// A point in a message's life its sender may want to react to.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    /// Reached a connection or a push service.
+    Delivered,
+    /// Push failed for good before the message reached the user. It may still
+    /// arrive over a real-time connection, which is reported as delivered.
+    Failed,
+    /// Dropped or replaced in the offline queue before the user reconnected.
+    Expired,
+}
+
+impl MessageStatus {
+    const fn delivered(&self) -> bool {
+        matches!(
+            self.state,
+            MessageState::Pushed | MessageState::SseDelivered
+        )
+    }
+
+    fn transition_from(
+        &self,
+        push: PushState,
+        realtime: RealtimeState,
+        delivered: bool,
+    ) -> Option<Transition> {
+        if delivered {
+            return None;
+        }
+        if self.delivered() {
+            return Some(Transition::Delivered);
+        }
+        let expired = |state| matches!(state, RealtimeState::Expired | RealtimeState::Replaced);
+        if self.push == PushState::Failed && push != PushState::Failed {
+            Some(Transition::Failed)
+        } else if expired(self.realtime) && !expired(realtime) {
+            Some(Transition::Expired)
+        } else {
+            None
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.state = match (self.push, self.realtime) {
+            (_, RealtimeState::SseDelivered | RealtimeState::Routed) => MessageState::SseDelivered,
+            (PushState::Pushed, _) => MessageState::Pushed,
+            (_, RealtimeState::Queued) => MessageState::Queued,
+            (
+                PushState::Failed | PushState::Skipped | PushState::Expired,
+                RealtimeState::Expired | RealtimeState::Replaced,
+            ) => MessageState::Failed,
+            _ => MessageState::Accepted,
+        };
+        self.updated_at = Utc::now();
+    }
+}
+
+/// In-memory record of recent messages, dropping the oldest past `capacity`.
+pub struct StatusStore {
+    capacity: usize,
+    inner: Mutex<(HashMap<Uuid, MessageStatus>, VecDeque<Uuid>)>,
+    transitions: mpsc::UnboundedSender<(Transition, MessageStatus)>,
+}
+
+impl StatusStore {
+    /// The store and the transitions of its messages, as they happen.
+    pub fn new(capacity: usize) -> (Self, mpsc::UnboundedReceiver<(Transition, MessageStatus)>) {
+        let (transitions, receiver) = mpsc::unbounded_channel();
+        let store = Self {
+            capacity,
+            inner: Mutex::new((HashMap::new(), VecDeque::new())),
+            transitions,
+        };
+        (store, receiver)
+    }
+
+    /// Reads the capacity from `STATUS_RETENTION`, keeping 10000 messages by default.
+    pub fn from_env() -> (Self, mpsc::UnboundedReceiver<(Transition, MessageStatus)>) {
+        Self::new(
+            std::env::var("STATUS_RETENTION")
+                .ok()
+                .and_then(|capacity| capacity.parse().ok())
+                .unwrap_or(10_000),
+        )
+    }
+
+    pub fn accept(
+        &self,
+        id: Uuid,
+        user_id: &str,
+        sender: &Sender,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().unwrap();
+        let (statuses, order) = &mut *inner;
+        while order.len() >= self.capacity.max(1) {
+            if let Some(oldest) = order.pop_front() {
+                statuses.remove(&oldest);
+            }
+        }
+        order.push_back(id);
+        statuses.insert(
+            id,
+            MessageStatus {
+                id,
+                user_id: user_id.to_owned(),
+                state: MessageState::Accepted,
+                push: PushState::Pending,
+                realtime: RealtimeState::Pending,
+                error: None,
+                delivery_error: None,
+                created_at: now,
+                updated_at: now,
+                expires_at,
+                displayed_at: None,
+                clicked_at: None,
+                target_url: None,
+                coalesced_into: None,
+                body: None,
+                sender: sender.clone(),
+            },
+        );
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<MessageStatus> {
+        self.inner.lock().unwrap().0.get(id).cloned()
+    }
+
+    /// Once any provider has delivered the message it stays pushed, even if
+    /// another one fails afterwards.
+    pub fn set_push(&self, id: &Uuid, state: PushState, error: Option<String>) {
+        self.update(id, |status| {
+            if status.push == PushState::Pushed {
+                return;
+            }
+            status.push = state;
+            if error.is_some() {
+                status.error = error;
+            }
+        });
+    }
+
+    pub fn set_delivery_error(&self, id: &Uuid, reason: DeliveryError) {
+        if let Some(status) = self.inner.lock().unwrap().0.get_mut(id) {
+            status.delivery_error = Some(reason);
+        }
+    }
+
+    pub fn set_target_url(&self, id: &Uuid, url: String) {
+        if let Some(status) = self.inner.lock().unwrap().0.get_mut(id) {
+            status.target_url = Some(url);
+        }
+    }
+
+    pub fn set_coalesced_into(&self, id: &Uuid, summary: Uuid) {
+        if let Some(status) = self.inner.lock().unwrap().0.get_mut(id) {
+            status.coalesced_into = Some(summary);
+        }
+    }
+
+    pub fn set_body(&self, id: &Uuid, body: String) {
+        if let Some(status) = self.inner.lock().unwrap().0.get_mut(id) {
+            status.body = Some(body);
+        }
+    }
+
+    /// Stored messages of `user_id` its client hasn't displayed yet and that
+    /// haven't expired, oldest first.
+    pub fn pending(&self, user_id: &str) -> Vec<MessageStatus> {
+        let now = Utc::now();
+        let inner = self.inner.lock().unwrap();
+        let (statuses, order) = &*inner;
+        order
+            .iter()
+            .filter_map(|id| statuses.get(id))
+            .filter(|status| {
+                status.user_id == user_id
+                    && status.body.is_some()
+                    && status.displayed_at.is_none()
+                    && status.expires_at.is_none_or(|at| at > now)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets every message of `user_id`, returning how many there were.
+    pub fn erase(&self, user_id: &str) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let (statuses, order) = &mut *inner;
+        let before = statuses.len();
+        statuses.retain(|_, status| status.user_id != user_id);
+        order.retain(|id| statuses.contains_key(id));
+        before - statuses.len()
+    }
+
+    /// Records an acknowledgement, a click implying the notification was shown.
+    /// Returns the updated status, unless the message is unknown.
+    pub fn ack(&self, id: &Uuid, action: AckAction, at: DateTime<Utc>) -> Option<MessageStatus> {
+        let mut inner = self.inner.lock().unwrap();
+        let status = inner.0.get_mut(id)?;
+        if action == AckAction::Clicked {
+            status.clicked_at.get_or_insert(at);
+        }
+        status.displayed_at.get_or_insert(at);
+        status.updated_at = Utc::now();
+        Some(status.clone())
+    }
+
+    pub fn set_realtime(&self, id: &Uuid, state: RealtimeState) {
+        self.update(id, |status| status.realtime = state);
+    }
+
+    fn update(&self, id: &Uuid, apply: impl FnOnce(&mut MessageStatus)) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(status) = inner.0.get_mut(id) else {
+            return;
+        };
+        let (push, realtime, delivered) = (status.push, status.realtime, status.delivered());
+        apply(status);
+        status.refresh();
+        if let Some(transition) = status.transition_from(push, realtime, delivered) {
+            // Only fails when nothing listens anymore, on shutdown.
+            let _ = self.transitions.send((transition, status.clone()));
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    str::FromStr,
+};
+
+use async_trait::async_trait;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use hyper::Uri;
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    Row, SqlitePool,
+};
+use tokio::sync::RwLock;
+use tracing::warn;
+use web_push_native::{p256::PublicKey, Auth};
+
+use crate::{error::AppError, push::ProviderKind};
+
+/// A Web Push subscription as the browser hands it over, base64url keys included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RawWebPushSubscription {
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+    /// The `applicationServerKey` the browser subscribed with, which pushes are
+    /// signed with. Unset for subscriptions stored before keys were tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vapid_key: Option<String>,
+}
+
+/// A validated Web Push subscription. Keeps the raw form for storage next to the
+/// decoded one, so keys aren't decoded again for every push.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "RawWebPushSubscription", into = "RawWebPushSubscription")]
+pub struct WebPushSubscription {
+    raw: RawWebPushSubscription,
+    endpoint: Uri,
+    p256dh: PublicKey,
+    auth: Auth,
+}
+
+impl WebPushSubscription {
+    pub const fn endpoint(&self) -> &Uri {
+        &self.endpoint
+    }
+
+    pub const fn p256dh(&self) -> &PublicKey {
+        &self.p256dh
+    }
+
+    pub fn vapid_key(&self) -> Option<&str> {
+        self.raw.vapid_key.as_deref()
+    }
+
+    pub fn set_vapid_key(&mut self, key: String) {
+        self.raw.vapid_key = Some(key);
+    }
+
+    pub const fn auth(&self) -> &Auth {
+        &self.auth
+    }
+}
+
+impl TryFrom<RawWebPushSubscription> for WebPushSubscription {
+    type Error = AppError;
+
+    fn try_from(raw: RawWebPushSubscription) -> Result<Self, Self::Error> {
+        let endpoint = raw
+            .endpoint
+            .parse::<Uri>()
+            .map_err(|error| AppError::invalid_registration("endpoint", error))?;
+        // The mock push service of the tests speaks plain HTTP.
+        let secure = endpoint.scheme_str() == Some("https")
+            || (cfg!(test) && endpoint.scheme_str() == Some("http"));
+        if !secure || endpoint.host().is_none() {
+            return Err(AppError::invalid_registration(
+                "endpoint",
+                "must be an absolute https URL",
+            ));
+        }
+        let p256dh = Base64UrlUnpadded::decode_vec(&raw.p256dh)
+            .map_err(|error| AppError::invalid_registration("p256dh", error))
+            .and_then(|bytes| {
+                PublicKey::from_sec1_bytes(&bytes).map_err(|_| {
+                    AppError::invalid_registration("p256dh", "not a SEC1 encoded P-256 key")
+                })
+            })?;
+        let auth = Base64UrlUnpadded::decode_vec(&raw.auth)
+            .map_err(|error| AppError::invalid_registration("auth", error))?;
+        if auth.len() != 16 {
+            return Err(AppError::invalid_registration(
+                "auth",
+                format!("expected 16 bytes, got {}", auth.len()),
+            ));
+        }
+        Ok(Self {
+            endpoint,
+            p256dh,
+            auth: Auth::clone_from_slice(&auth),
+            raw,
+        })
+    }
+}
+
+impl From<WebPushSubscription> for RawWebPushSubscription {
+    fn from(value: WebPushSubscription) -> Self {
+        value.raw
+    }
+}
+
+/// Where a user can be reached by push, one address per provider.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subscription {
+    /// Flattened so subscriptions stored before FCM support still decode.
+    #[serde(flatten)]
+    pub web_push: Option<WebPushSubscription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fcm_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apns_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+    /// Fallback for messages that no connection or push provider could take.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Attributes the publisher registered the user with, matched by `/send/query`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// When the user's consent to notifications runs out, never when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Subscription {
+    /// The address held for `kind`, compared on eviction so a newer one is kept.
+    pub fn address(&self, kind: ProviderKind) -> Option<&str> {
+        match kind {
+            ProviderKind::WebPush => self
+                .web_push
+                .as_ref()
+                .map(|web_push| &*web_push.raw.endpoint),
+            ProviderKind::Fcm => self.fcm_token.as_deref(),
+            ProviderKind::Apns => self.apns_token.as_deref(),
+            ProviderKind::Webhook => self.webhook_url.as_deref(),
+        }
+    }
+
+    pub fn clear(&mut self, kind: ProviderKind) {
+        match kind {
+            ProviderKind::WebPush => self.web_push = None,
+            ProviderKind::Fcm => self.fcm_token = None,
+            ProviderKind::Apns => self.apns_token = None,
+            ProviderKind::Webhook => self.webhook_url = None,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether the user can't be reached by push or email any more.
+    pub const fn is_empty(&self) -> bool {
+        self.web_push.is_none()
+            && self.fcm_token.is_none()
+            && self.apns_token.is_none()
+            && self.webhook_url.is_none()
+            && self.email.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Database(sqlx::Error),
+    Redis(redis::RedisError),
+    /// A stored record could not be decoded.
+    Corrupt(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(error) => write!(f, "Database error: {error}"),
+            Self::Redis(error) => write!(f, "Redis error: {error}"),
+            Self::Corrupt(reason) => write!(f, "Corrupt stored record: {reason}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl From<redis::RedisError> for StoreError {
+    fn from(value: redis::RedisError) -> Self {
+        Self::Redis(value)
+    }
+}
+
+/// A batch of stored subscriptions, in whatever order the store keeps them.
+pub struct Page {
+    pub entries: Vec<(String, Subscription)>,
+    /// Where the next page starts, `None` after the last one.
+    pub next: Option<String>,
+}
+
+impl Page {
+    /// For stores paging in user id order: the next page starts after the last
+    /// user id, unless this one came up short.
+    fn after(entries: Vec<(String, Subscription)>, limit: usize) -> Self {
+        let next = if entries.len() < limit {
+            None
+        } else {
+            entries.last().map(|(user_id, _)| user_id.clone())
+        };
+        Self { entries, next }
+    }
+}
+
+/// Persistent storage for push subscriptions, keyed by user id.
+#[async_trait]
+pub trait SubscriptionStore: Send + Sync {
+    async fn save(&self, user_id: &str, subscription: &Subscription) -> Result<(), StoreError>;
+    async fn remove(&self, user_id: &str) -> Result<(), StoreError>;
+    /// Up to `limit` subscriptions, starting where the page before left off,
+    /// so a large store can be read without holding all of it at once.
+    async fn load_page(&self, cursor: Option<&str>, limit: usize) -> Result<Page, StoreError>;
+    /// Checks the backing database answers.
+    async fn ping(&self) -> Result<(), StoreError>;
+}
+
+/// Keeps subscriptions in memory only, everything is lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    subscriptions: RwLock<HashMap<String, Subscription>>,
+}
+
+#[async_trait]
+impl SubscriptionStore for MemoryStore {
+    async fn save(&self, user_id: &str, subscription: &Subscription) -> Result<(), StoreError> {
+        self.subscriptions
+            .write()
+            .await
+            .insert(user_id.to_owned(), subscription.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, user_id: &str) -> Result<(), StoreError> {
+        self.subscriptions.write().await.remove(user_id);
+        Ok(())
+    }
+
+    async fn load_page(&self, cursor: Option<&str>, limit: usize) -> Result<Page, StoreError> {
+        let subscriptions = self.subscriptions.read().await;
+        let mut user_ids = subscriptions
+            .keys()
+            .filter(|user_id| cursor.is_none_or(|cursor| user_id.as_str() > cursor))
+            .collect::<Vec<_>>();
+        user_ids.sort_unstable();
+        let entries = user_ids
+            .into_iter()
+            .take(limit)
+            .map(|user_id| (user_id.clone(), subscriptions[user_id].clone()))
+            .collect::<Vec<_>>();
+        Ok(Page::after(entries, limit))
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `url` and makes sure the table exists.
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS subscriptions (
+                user_id TEXT PRIMARY KEY NOT NULL,
+                endpoint TEXT NOT NULL,
+                p256dh TEXT NOT NULL,
+                auth TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        let store = Self { pool };
+        store.add_column("fcm_token").await?;
+        store.add_column("apns_token").await?;
+        store.add_column("email").await?;
+        store.add_column("webhook_url").await?;
+        // A JSON object, NULL without metadata.
+        store.add_column("metadata").await?;
+        // RFC 3339, NULL for consent that doesn't expire.
+        store.add_column("expires_at").await?;
+        // NULL for Web Push subscriptions stored before VAPID keys were tracked.
+        store.add_column("vapid_key").await?;
+        Ok(store)
+    }
+
+    /// Adds a nullable text column to tables created by an older version.
+    async fn add_column(&self, name: &str) -> Result<(), StoreError> {
+        let exists = sqlx::query("SELECT 1 FROM pragma_table_info('subscriptions') WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some();
+        if !exists {
+            sqlx::query(&format!("ALTER TABLE subscriptions ADD COLUMN {name} TEXT"))
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn subscription_of(row: &SqliteRow) -> Result<(String, Subscription), StoreError> {
+        let user_id: String = row.try_get("user_id")?;
+        let endpoint: String = row.try_get("endpoint")?;
+        let web_push = if endpoint.is_empty() {
+            None
+        } else {
+            let raw = RawWebPushSubscription {
+                endpoint,
+                p256dh: row.try_get("p256dh")?,
+                auth: row.try_get("auth")?,
+                vapid_key: row.try_get("vapid_key")?,
+            };
+            WebPushSubscription::try_from(raw)
+                .inspect_err(|error| {
+                    warn!("Dropping Web Push subscription of {user_id}: {error}");
+                })
+                .ok()
+        };
+        let metadata = row
+            .try_get::<Option<String>, _>("metadata")?
+            .map(|metadata| serde_json::from_str(&metadata))
+            .transpose()
+            .map_err(|error| StoreError::Corrupt(format!("metadata of {user_id}: {error}")))?
+            .unwrap_or_default();
+        let expires_at = row
+            .try_get::<Option<String>, _>("expires_at")?
+            .map(|expires_at| DateTime::parse_from_rfc3339(&expires_at))
+            .transpose()
+            .map_err(|error| StoreError::Corrupt(format!("consent expiry of {user_id}: {error}")))?
+            .map(|expires_at| expires_at.with_timezone(&Utc));
+        Ok((
+            user_id,
+            Subscription {
+                web_push,
+                fcm_token: row.try_get("fcm_token")?,
+                apns_token: row.try_get("apns_token")?,
+                email: row.try_get("email")?,
+                webhook_url: row.try_get("webhook_url")?,
+                metadata,
+                expires_at,
+            },
+        ))
+    }
+}
+
+#[async_trait]
+impl SubscriptionStore for SqliteStore {
+    async fn save(&self, user_id: &str, subscription: &Subscription) -> Result<(), StoreError> {
+        // The Web Push columns predate the other providers and are NOT NULL, so an
+        // absent Web Push subscription is stored as empty strings.
+        let web_push = subscription.web_push.clone().map_or_else(
+            || RawWebPushSubscription {
+                endpoint: String::new(),
+                p256dh: String::new(),
+                auth: String::new(),
+                vapid_key: None,
+            },
+            RawWebPushSubscription::from,
+        );
+        let metadata = (!subscription.metadata.is_empty())
+            .then(|| serde_json::to_string(&subscription.metadata).expect("Metadata serializes"));
+        sqlx::query(
+            "INSERT INTO subscriptions
+                (user_id, endpoint, p256dh, auth, fcm_token, apns_token, email, webhook_url,
+                 metadata, expires_at, vapid_key)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (user_id) DO UPDATE SET
+                endpoint = excluded.endpoint,
+                p256dh = excluded.p256dh,
+                auth = excluded.auth,
+                fcm_token = excluded.fcm_token,
+                apns_token = excluded.apns_token,
+                email = excluded.email,
+                webhook_url = excluded.webhook_url,
+                metadata = excluded.metadata,
+                expires_at = excluded.expires_at,
+                vapid_key = excluded.vapid_key",
+        )
+        .bind(user_id)
+        .bind(web_push.endpoint)
+        .bind(web_push.p256dh)
+        .bind(web_push.auth)
+        .bind(&subscription.fcm_token)
+        .bind(&subscription.apns_token)
+        .bind(&subscription.email)
+        .bind(&subscription.webhook_url)
+        .bind(metadata)
+        .bind(
+            subscription
+                .expires_at
+                .map(|expires_at| expires_at.to_rfc3339()),
+        )
+        .bind(web_push.vapid_key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, user_id: &str) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM subscriptions WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_page(&self, cursor: Option<&str>, limit: usize) -> Result<Page, StoreError> {
+        // Keyed on the primary key, so each page is an index range scan.
+        let rows = sqlx::query(
+            "SELECT user_id, endpoint, p256dh, auth, fcm_token, apns_token, email, webhook_url,
+                metadata, expires_at, vapid_key
+             FROM subscriptions WHERE user_id > ?1 ORDER BY user_id LIMIT ?2",
+        )
+        .bind(cursor.unwrap_or_default())
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&self.pool)
+        .await?;
+        let entries = rows
+            .iter()
+            .map(Self::subscription_of)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Page::after(entries, limit))
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+/// Shares subscriptions between every instance pointed at the same Redis server.
+pub struct RedisStore {
+    connection: MultiplexedConnection,
+}
+
+impl RedisStore {
+    const KEY: &'static str = "notifications:subscriptions";
+
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            connection: client.get_multiplexed_tokio_connection().await?,
+        })
+    }
+}
+
+#[async_trait]
+impl SubscriptionStore for RedisStore {
+    async fn save(&self, user_id: &str, subscription: &Subscription) -> Result<(), StoreError> {
+        let value = serde_json::to_string(subscription)
+            .map_err(|error| StoreError::Corrupt(error.to_string()))?;
+        self.connection
+            .clone()
+            .hset::<_, _, _, ()>(Self::KEY, user_id, value)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, user_id: &str) -> Result<(), StoreError> {
+        self.connection
+            .clone()
+            .hdel::<_, _, ()>(Self::KEY, user_id)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_page(&self, cursor: Option<&str>, limit: usize) -> Result<Page, StoreError> {
+        // HSCAN may hand out a few more or fewer than asked for, and its cursor
+        // is back to 0 once the whole hash was visited.
+        let (next, entries): (String, HashMap<String, String>) = redis::cmd("HSCAN")
+            .arg(Self::KEY)
+            .arg(cursor.unwrap_or("0"))
+            .arg("COUNT")
+            .arg(limit)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        let entries = entries
+            .into_iter()
+            .map(|(user_id, value)| {
+                serde_json::from_str(&value)
+                    .map(|subscription| (user_id, subscription))
+                    .map_err(|error| StoreError::Corrupt(error.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Page {
+            entries,
+            next: (next != "0").then_some(next),
+        })
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+}
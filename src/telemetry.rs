@@ -0,0 +1,77 @@
+use std::{sync::Mutex, time::Instant};
+
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use metrics::{decrement_gauge, histogram, increment_counter, increment_gauge};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use tracing::{info_span, Span};
+
+static HANDLE: Mutex<Option<PrometheusHandle>> = Mutex::new(None);
+
+/// Installs the global Prometheus recorder on first use and returns the handle
+/// used to render `/metrics`.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let mut handle = HANDLE.lock().expect("Prometheus handle was poisoned");
+    if let Some(handle) = &*handle {
+        return Ok(handle.clone());
+    }
+    Ok(handle
+        .insert(PrometheusBuilder::new().install_recorder()?)
+        .clone())
+}
+
+/// Middleware recording request counts and latency per matched route.
+pub async fn track_http<B>(request: Request<B>, next: Next<B>) -> Response {
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str)
+        .to_owned();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method), ("path", path), ("status", status)];
+    increment_counter!("http_requests_total", &labels);
+    histogram!(
+        "http_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        &labels
+    );
+    response
+}
+
+/// Span wrapping everything logged while handling a request. Only the path is
+/// recorded since the query may carry an `access_token`.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        request_id,
+    )
+}
+
+/// Keeps the `active_connections` gauge up to date for as long as it is alive.
+pub struct ConnectionGauge {
+    transport: &'static str,
+}
+
+impl ConnectionGauge {
+    pub fn new(transport: &'static str) -> Self {
+        increment_gauge!("active_connections", 1.0, "transport" => transport);
+        Self { transport }
+    }
+}
+
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        decrement_gauge!("active_connections", 1.0, "transport" => self.transport);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use handlebars::{Handlebars, Template};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+
+use crate::{
+    auth::Tenant,
+    error::AppError,
+    notification::{Notification, PushOptions},
+};
+
+/// A named notification whose text fields hold `{{handlebars}}` placeholders.
+/// Translations are saved as `<name>.<locale>`, such as `welcome.de`.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct NotificationTemplate {
+    pub name: String,
+    pub template: Notification,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TemplateSendData {
+    pub user_id: String,
+    /// Name of a template registered through `/templates`.
+    pub template: String,
+    /// Values for the placeholders, every one of them has to be given.
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub variables: Map<String, Value>,
+    /// Locale to pick the translation by instead of the recipient's `locale`
+    /// metadata.
+    pub locale: Option<String>,
+    #[serde(flatten)]
+    pub push: PushOptions,
+}
+
+/// Registered templates, rendered with plain text output and strict variables.
+pub struct Templates {
+    registry: Handlebars<'static>,
+    registered: RwLock<HashMap<String, Notification>>,
+    /// Translation used when there's none for the recipient's locale.
+    default_locale: Option<String>,
+}
+
+impl Templates {
+    /// Falls back to the translation for `DEFAULT_LOCALE` when set, then to
+    /// the template without a locale.
+    pub fn from_env() -> Self {
+        let mut registry = Handlebars::new();
+        registry.set_strict_mode(true);
+        registry.register_escape_fn(handlebars::no_escape);
+        Self {
+            registry,
+            registered: RwLock::new(HashMap::new()),
+            default_locale: std::env::var("DEFAULT_LOCALE")
+                .ok()
+                .filter(|locale| !locale.is_empty()),
+        }
+    }
+
+    /// Checks every field of the template compiles before storing it under its name.
+    pub async fn insert(&self, template: NotificationTemplate) -> Result<(), AppError> {
+        if Tenant::local_part(&template.name).is_empty() {
+            return Err(AppError::InvalidTemplate("`name` is required".to_owned()));
+        }
+        template.template.clone().try_map_text(|text| {
+            Template::compile(text)
+                .map(|_| text.to_owned())
+                .map_err(|error| AppError::InvalidTemplate(error.to_string()))
+        })?;
+        self.registered
+            .write()
+            .await
+            .insert(template.name, template.template);
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Vec<NotificationTemplate> {
+        let mut templates = self
+            .registered
+            .read()
+            .await
+            .iter()
+            .map(|(name, template)| NotificationTemplate {
+                name: name.clone(),
+                template: template.clone(),
+            })
+            .collect::<Vec<_>>();
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        templates
+    }
+
+    /// Fills in the translation of the template called `name` closest to
+    /// `locale` with `variables`.
+    pub async fn render(
+        &self,
+        name: &str,
+        locale: Option<&str>,
+        variables: &Map<String, Value>,
+    ) -> Result<Notification, AppError> {
+        let template = {
+            let registered = self.registered.read().await;
+            self.variants(name, locale)
+                .iter()
+                .find_map(|variant| registered.get(variant))
+                .cloned()
+        };
+        let Some(template) = template else {
+            return Err(AppError::TemplateNotFound);
+        };
+        template.try_map_text(|text| {
+            self.registry
+                .render_template(text, variables)
+                .map_err(|error| AppError::InvalidTemplate(error.to_string()))
+        })
+    }
+
+    /// The names to look `name` up by, best match first: `welcome.de-AT`,
+    /// `welcome.de`, the default locale's and then `welcome` itself.
+    fn variants(&self, name: &str, locale: Option<&str>) -> Vec<String> {
+        let mut variants = Vec::new();
+        if let Some(locale) = locale.filter(|locale| !locale.is_empty()) {
+            variants.push(format!("{name}.{locale}"));
+            if let Some((language, _)) = locale.split_once(['-', '_']) {
+                variants.push(format!("{name}.{language}"));
+            }
+        }
+        if let Some(locale) = &self.default_locale {
+            variants.push(format!("{name}.{locale}"));
+        }
+        variants.push(name.to_owned());
+        variants
+    }
+}
@@ -0,0 +1,540 @@
+//! End-to-end tests driving the router in process against a mock Web Push
+//! service.
+// Routers aren't `Sync`, so helpers borrowing one make futures that aren't `Send`,
+// which is fine on the test runtime.
+#![allow(clippy::future_not_send)]
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{BoxBody, Bytes, HttpBody},
+    extract::State,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router, Server,
+};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use hyper::{Body, HeaderMap};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::{
+    status::PushState,
+    store::{RawWebPushSubscription, WebPushSubscription},
+    web_push::VapidKeys,
+    AppState, Config, NotificationService, Transport, VapidKey,
+};
+
+/// A push request as the mock push service received it.
+struct PushRequest {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+#[derive(Default)]
+struct MockState {
+    requests: Mutex<Vec<PushRequest>>,
+    /// Statuses it answers with in turn, `201 Created` once they run out.
+    responses: Mutex<VecDeque<StatusCode>>,
+}
+
+/// Stands in for a Web Push endpoint, recording what it's sent.
+struct MockPushService {
+    endpoint: String,
+    state: Arc<MockState>,
+}
+
+impl MockPushService {
+    fn start(responses: impl IntoIterator<Item = StatusCode>) -> Self {
+        let state = Arc::new(MockState {
+            requests: Mutex::default(),
+            responses: Mutex::new(responses.into_iter().collect()),
+        });
+        let router = Router::new()
+            .route("/push/:device", post(Self::receive))
+            .with_state(state.clone());
+        let server =
+            Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(router.into_make_service());
+        let endpoint = format!("http://{}/push/device", server.local_addr());
+        tokio::spawn(server);
+        Self { endpoint, state }
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn receive(
+        State(state): State<Arc<MockState>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        state
+            .requests
+            .lock()
+            .unwrap()
+            .push(PushRequest { headers, body });
+        let status = state
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(StatusCode::CREATED);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return (status, [(header::RETRY_AFTER, "0")]).into_response();
+        }
+        status.into_response()
+    }
+
+    fn request_count(&self) -> usize {
+        self.state.requests.lock().unwrap().len()
+    }
+
+    /// The `k=` parameter of the `Authorization` header of request `index`,
+    /// the public key of the VAPID key it was signed with.
+    fn vapid_key(&self, index: usize) -> String {
+        let requests = self.state.requests.lock().unwrap();
+        let authorization = requests[index].headers[header::AUTHORIZATION]
+            .to_str()
+            .unwrap();
+        authorization.rsplit_once("k=").unwrap().1.to_owned()
+    }
+
+    /// A registration for `user_id` with a fresh device key pointing here.
+    fn registration(&self, user_id: &str) -> Value {
+        let device = VapidKey::generate("mailto:device@example.com".to_owned());
+        json!({
+            "user_id": user_id,
+            "endpoint": self.endpoint,
+            "keys": {
+                "p256dh": device.application_server_key(),
+                "auth": Base64UrlUnpadded::encode_string(&rand::random::<[u8; 16]>()),
+            },
+        })
+    }
+}
+
+/// Writes `key` where a key file is expected, returning its path.
+async fn key_file(key: &VapidKey) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("vapid-{}.json", Uuid::new_v4()));
+    tokio::fs::write(&path, serde_json::to_string(key).unwrap())
+        .await
+        .unwrap();
+    path
+}
+
+/// A fresh state with a generated VAPID key and the router serving it.
+async fn app() -> (AppState, Router) {
+    app_with(|_| {}).await
+}
+
+/// Like `app`, with `secondary` as the key being rotated out.
+async fn app_with_secondary(secondary: &VapidKey) -> (AppState, Router) {
+    let path = key_file(secondary).await;
+    app_with(|config| config.vapid_secondary_file = Some(path)).await
+}
+
+/// Like `app`, with the test configuration changed by `configure`.
+async fn app_with(configure: impl FnOnce(&mut Config)) -> (AppState, Router) {
+    let vapid = VapidKey::generate("mailto:test@example.com".to_owned());
+    let path = key_file(&vapid).await;
+    let mut config = Config::for_tests(path.clone());
+    configure(&mut config);
+    let secondary_path = config.vapid_secondary_file.clone();
+    let state = AppState::new(config).await;
+    for path in std::iter::once(path).chain(secondary_path) {
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+    let router = NotificationService::router(state.clone());
+    (state, router)
+}
+
+async fn call(router: &Router, request: Request<Body>) -> Response<BoxBody> {
+    // axum 0.6 routers are services themselves, there's no `into_service` yet.
+    router.clone().oneshot(request).await.unwrap()
+}
+
+async fn post_json(router: &Router, path: &str, body: &Value) -> (StatusCode, Bytes) {
+    let request = Request::post(path)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = call(router, request).await;
+    let status = response.status();
+    (status, hyper::body::to_bytes(response).await.unwrap())
+}
+
+async fn get_json(router: &Router, path: &str) -> (StatusCode, Value) {
+    let response = call(router, Request::get(path).body(Body::empty()).unwrap()).await;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+async fn send(router: &Router, body: &Value) -> Uuid {
+    let (status, body) = post_json(router, "/send", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+    let body = serde_json::from_slice::<Value>(&body).unwrap();
+    body["message_id"].as_str().unwrap().parse().unwrap()
+}
+
+/// The data of the next SSE event carrying any.
+async fn next_event(body: &mut BoxBody) -> String {
+    loop {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("No SSE event in time")
+            .expect("SSE stream ended")
+            .unwrap();
+        let chunk = String::from_utf8_lossy(&chunk).into_owned();
+        if let Some(data) = chunk.lines().find_map(|line| line.strip_prefix("data:")) {
+            return data.to_owned();
+        }
+    }
+}
+
+/// Polls `condition` until it holds, failing the test after five seconds.
+async fn eventually(mut condition: impl FnMut() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Condition not met in time");
+}
+
+fn push_state(state: &AppState, id: &Uuid) -> Option<PushState> {
+    state.statuses.get(id).map(|status| status.push)
+}
+
+#[tokio::test]
+async fn sent_message_arrives_over_sse_and_push() {
+    let (_, router) = app().await;
+    let push = MockPushService::start([]);
+    let (status, _) = post_json(&router, "/register", &push.registration("alice")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let events = call(
+        &router,
+        Request::get("/sse?user_id=alice")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(events.status(), StatusCode::OK);
+    let mut events = events.into_body();
+
+    send(
+        &router,
+        &json!({ "user_id": "alice", "data": { "title": "Hello", "body": "World" } }),
+    )
+    .await;
+    let event = serde_json::from_str::<Value>(&next_event(&mut events).await).unwrap();
+    assert_eq!(event["title"], "Hello");
+    assert_eq!(event["body"], "World");
+    eventually(|| push.request_count() == 1).await;
+}
+
+#[tokio::test]
+async fn push_request_follows_web_push() {
+    let (state, router) = app().await;
+    let push = MockPushService::start([]);
+    post_json(&router, "/register", &push.registration("bob")).await;
+
+    send(
+        &router,
+        &json!({
+            "user_id": "bob",
+            "data": "Hi",
+            "ttl": 60,
+            "urgency": "high",
+            "collapse_key": "score",
+        }),
+    )
+    .await;
+    eventually(|| push.request_count() == 1).await;
+
+    let vapid = state.vapid.read().await.clone();
+    let requests = push.state.requests.lock().unwrap();
+    let request = &requests[0];
+    let header = |name: &str| request.headers.get(name).unwrap().to_str().unwrap();
+    assert_eq!(header("content-encoding"), "aes128gcm");
+    assert_eq!(header("content-type"), "application/octet-stream");
+    assert_eq!(header("ttl"), "60");
+    assert_eq!(header("urgency"), "high");
+    assert_eq!(header("topic"), "score");
+    assert!(header("authorization").starts_with("vapid t="));
+    assert!(header("authorization")
+        .ends_with(&format!(", k={}", vapid.primary.application_server_key())));
+    // RFC 8188: salt, record size and the sender's uncompressed P-256 key as key id.
+    assert!(request.body.len() > 86);
+    assert_eq!(request.body[20], 65);
+    assert_eq!(request.body[21], 0x04);
+}
+
+#[tokio::test]
+async fn throttled_push_is_retried() {
+    let (state, router) = app().await;
+    let push = MockPushService::start([StatusCode::TOO_MANY_REQUESTS]);
+    post_json(&router, "/register", &push.registration("carol")).await;
+
+    let id = send(&router, &json!({ "user_id": "carol", "data": "Hi" })).await;
+    assert_eq!(push_state(&state, &id), Some(PushState::Retrying));
+    eventually(|| push_state(&state, &id) == Some(PushState::Pushed)).await;
+    assert_eq!(push.request_count(), 2);
+
+    let attempts = state.audit.query("carol", Some(id), 10).await.unwrap();
+    let attempts = attempts
+        .iter()
+        .filter(|entry| entry.channel == "web_push")
+        .map(|entry| (entry.outcome.as_str(), entry.status_code))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        attempts,
+        [("failed_transiently", Some(429)), ("delivered", Some(201))]
+    );
+}
+
+#[tokio::test]
+async fn gone_subscription_is_evicted() {
+    let (state, router) = app().await;
+    let push = MockPushService::start([StatusCode::GONE]);
+    post_json(&router, "/register", &push.registration("dave")).await;
+
+    let id = send(&router, &json!({ "user_id": "dave", "data": "Hi" })).await;
+    assert_eq!(push_state(&state, &id), Some(PushState::Failed));
+    for _ in 0..100 {
+        // Without any address left the whole registration goes.
+        let channels = state.channels.read().await;
+        if channels
+            .get("dave")
+            .is_none_or(|reg| reg.subscription.web_push.is_none())
+        {
+            return;
+        }
+        drop(channels);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Gone subscription was kept");
+}
+
+#[tokio::test]
+async fn tickle_is_empty_and_leaves_message_pending() {
+    let (_, router) = app().await;
+    let push = MockPushService::start([]);
+    post_json(&router, "/register", &push.registration("erin")).await;
+
+    let id = send(
+        &router,
+        &json!({ "user_id": "erin", "data": { "title": "Hi" }, "mode": "tickle" }),
+    )
+    .await;
+    eventually(|| push.request_count() == 1).await;
+    {
+        let requests = push.state.requests.lock().unwrap();
+        assert!(requests[0].body.is_empty());
+        assert!(!requests[0].headers.contains_key("content-encoding"));
+    }
+
+    let pending = |router| async move {
+        let request = Request::get("/messages/pending?user_id=erin")
+            .body(Body::empty())
+            .unwrap();
+        let body = hyper::body::to_bytes(call(router, request).await)
+            .await
+            .unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()
+    };
+    let messages = pending(&router).await;
+    assert_eq!(messages[0]["message_id"], id.to_string());
+    assert_eq!(messages[0]["data"]["title"], "Hi");
+
+    let ack = json!({ "message_id": id, "action": "displayed" });
+    assert_eq!(post_json(&router, "/ack", &ack).await.0, StatusCode::OK);
+    assert_eq!(pending(&router).await, json!([]));
+}
+
+#[tokio::test]
+async fn dry_run_builds_the_push_without_sending_it() {
+    let (state, router) = app().await;
+    let push = MockPushService::start([]);
+    post_json(&router, "/register", &push.registration("frank")).await;
+
+    let (status, body) = post_json(
+        &router,
+        "/send",
+        &json!({ "user_id": "frank", "data": { "title": "Hi" }, "dry_run": true }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let report = serde_json::from_slice::<Value>(&body).unwrap();
+    assert_eq!(report["event"]["title"], "Hi");
+    assert_eq!(report["push_payload"]["message_id"], report["message_id"]);
+    assert_eq!(report["queued"], true);
+    let preview = &report["push"][0];
+    assert_eq!(preview["provider"], "web_push");
+    assert_eq!(preview["url"], push.endpoint);
+    assert_eq!(preview["headers"]["content-encoding"], "aes128gcm");
+    assert!(preview["headers"].get("authorization").is_none());
+    assert!(preview["body_size"].as_u64().unwrap() > 86);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(push.request_count(), 0);
+    let id = report["message_id"].as_str().unwrap().parse().unwrap();
+    assert!(state.statuses.get(&id).is_none());
+}
+
+#[tokio::test]
+async fn pushes_are_signed_with_the_key_subscribed_under() {
+    let secondary = VapidKey::generate("mailto:old@example.com".to_owned());
+    let secondary_key = secondary.application_server_key().to_owned();
+    let (state, router) = app_with_secondary(&secondary).await;
+    let primary_key = state
+        .vapid
+        .read()
+        .await
+        .primary
+        .application_server_key()
+        .to_owned();
+    let push = MockPushService::start([]);
+
+    // Subscribed under the secondary key, which is tracked.
+    let mut registration = push.registration("grace");
+    registration["vapid_key"] = json!(secondary_key);
+    let (status, _) = post_json(&router, "/register", &registration).await;
+    assert_eq!(status, StatusCode::OK);
+    send(&router, &json!({ "user_id": "grace", "data": "Hi" })).await;
+    eventually(|| push.request_count() == 1).await;
+    assert_eq!(push.vapid_key(0), secondary_key);
+
+    // Stored before keys were tracked, so it predates the rotation.
+    {
+        let mut channels = state.channels.write().await;
+        let subscription = &mut channels.get_mut("grace").unwrap().subscription;
+        let raw = RawWebPushSubscription {
+            vapid_key: None,
+            ..subscription.web_push.clone().unwrap().into()
+        };
+        subscription.web_push = Some(WebPushSubscription::try_from(raw).unwrap());
+    }
+    send(&router, &json!({ "user_id": "grace", "data": "Hi" })).await;
+    eventually(|| push.request_count() == 2).await;
+    assert_eq!(push.vapid_key(1), secondary_key);
+
+    // Once the secondary key is retired, the primary one takes over.
+    post_json(&router, "/register", &registration).await;
+    let primary = serde_json::to_string(&state.vapid.read().await.primary).unwrap();
+    *state.vapid.write().await = Arc::new(VapidKeys {
+        primary: VapidKey::from_str(&primary).unwrap(),
+        secondary: None,
+    });
+    send(&router, &json!({ "user_id": "grace", "data": "Hi" })).await;
+    eventually(|| push.request_count() == 3).await;
+    assert_eq!(push.vapid_key(2), primary_key);
+}
+
+#[tokio::test]
+async fn unknown_vapid_key_is_rejected() {
+    let (state, router) = app().await;
+    let push = MockPushService::start([]);
+    let mut registration = push.registration("heidi");
+    registration["vapid_key"] =
+        json!(VapidKey::generate("mailto:x@example.com".to_owned()).application_server_key());
+    let (status, _) = post_json(&router, "/register", &registration).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(!state.channels.read().await.contains_key("heidi"));
+}
+
+/// Waits until `user_id` has `count` SSE connections open, polls included.
+async fn sse_connections(state: &AppState, user_id: &str, count: usize) {
+    for _ in 0..100 {
+        let channels = state.channels.read().await;
+        if channels[user_id].connection_count(Transport::Sse) == count {
+            return;
+        }
+        drop(channels);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("SSE connections not open in time");
+}
+
+#[tokio::test]
+async fn poll_catches_up_then_waits_for_messages() {
+    let (state, router) = app().await;
+    let push = MockPushService::start([]);
+    post_json(&router, "/register", &push.registration("ivan")).await;
+
+    // Queued while nothing was connected, so it comes back right away.
+    send(
+        &router,
+        &json!({ "user_id": "ivan", "data": { "title": "Missed" } }),
+    )
+    .await;
+    let (status, messages) = get_json(&router, "/poll?user_id=ivan").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(messages[0]["data"]["title"], "Missed");
+    let last_event_id = messages[0]["event_id"].as_u64().unwrap();
+
+    let path = format!("/poll?user_id=ivan&timeout=5&last_event_id={last_event_id}");
+    let (polled, ()) = tokio::join!(get_json(&router, &path), async {
+        sse_connections(&state, "ivan", 1).await;
+        send(
+            &router,
+            &json!({ "user_id": "ivan", "data": { "title": "Live" } }),
+        )
+        .await;
+    },);
+    let (status, messages) = polled;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(messages.as_array().unwrap().len(), 1);
+    assert_eq!(messages[0]["data"]["title"], "Live");
+    assert!(messages[0]["event_id"].as_u64().unwrap() > last_event_id);
+    sse_connections(&state, "ivan", 0).await;
+
+    let (status, messages) = get_json(&router, "/poll?user_id=ivan&timeout=1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(messages, json!([]));
+}
+
+#[tokio::test]
+async fn poll_counts_against_the_sse_limits() {
+    let (state, router) = app_with(|config| config.max_sse_connections_per_user = Some(1)).await;
+    let push = MockPushService::start([]);
+    post_json(&router, "/register", &push.registration("judy")).await;
+
+    let events = call(
+        &router,
+        Request::get("/sse?user_id=judy")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(events.status(), StatusCode::OK);
+    let (status, body) = get_json(&router, "/poll?user_id=judy&timeout=1").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error"], "too_many_connections");
+
+    // Once the stream is gone, polling takes its place.
+    drop(events);
+    sse_connections(&state, "judy", 0).await;
+    let (polled, ()) = tokio::join!(get_json(&router, "/poll?user_id=judy&timeout=5"), async {
+        sse_connections(&state, "judy", 1).await;
+        let events = call(
+            &router,
+            Request::get("/sse?user_id=judy")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(events.status(), StatusCode::TOO_MANY_REQUESTS);
+        send(&router, &json!({ "user_id": "judy", "data": "Hi" })).await;
+    },);
+    assert_eq!(polled.0, StatusCode::OK);
+}
@@ -0,0 +1,439 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use metrics::increment_counter;
+use serde::Deserialize;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditEntry, Sender},
+    auth::Tenant,
+    error::AppError,
+    fan_out,
+    notification::{Notification, PushOptions},
+    rate_limit::RateLimiter,
+    recipients, send_accepted, AppState, OutboundMessage, SendData, Sent,
+};
+
+/// Time between two looks for held messages the caps let through again.
+const RELEASE_INTERVAL: Duration = Duration::from_secs(1);
+/// Most messages deferred for one recipient, sends past it are rejected.
+const MAX_DEFERRED: usize = 100;
+/// Titles a coalesced summary lists before counting the rest.
+const SUMMARY_TITLES: usize = 3;
+
+/// What happens to a message sent past the recipient's cap.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Counted into one "N more notifications" summary, sent once the cap allows.
+    #[default]
+    Coalesce,
+    /// Held and sent as it is once the cap allows, in the order it was sent.
+    Defer,
+}
+
+/// A tenant's cap as written in `RECIPIENT_CAPS_FILE`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CapConfig {
+    per_minute: u32,
+    /// Sent back to back before the rest are spread over the minute, the
+    /// whole `per_minute` by default.
+    burst: Option<u32>,
+    #[serde(default)]
+    overflow: Overflow,
+}
+
+struct Cap {
+    limiter: RateLimiter,
+    overflow: Overflow,
+}
+
+/// Ids and titles of the messages coalesced for one recipient.
+type Coalesced = Vec<(Uuid, String)>;
+
+/// What is held back for one recipient.
+#[derive(Default)]
+struct Held {
+    /// Messages accepted when they were sent, so they go out under the id their
+    /// sender was answered with.
+    deferred: VecDeque<OutboundMessage>,
+    /// Empty titles included, so they count.
+    coalesced: Coalesced,
+}
+
+impl Held {
+    fn is_empty(&self) -> bool {
+        self.deferred.is_empty() && self.coalesced.is_empty()
+    }
+}
+
+/// A message held back, as its recipient's overflow keeps it.
+enum HeldMessage {
+    Deferred(OutboundMessage),
+    /// The id tracking it, and its title.
+    Coalesced(Uuid, String),
+}
+
+/// What the caps let through, by recipient.
+#[derive(Default)]
+struct Released {
+    deferred: Vec<(String, OutboundMessage)>,
+    summaries: Vec<(String, Coalesced)>,
+}
+
+/// The most messages each recipient is sent a minute, per tenant, protecting
+/// users from producers that send too much. Held messages are kept in memory.
+#[derive(Default)]
+pub struct RecipientCaps {
+    caps: HashMap<String, Cap>,
+    held: Mutex<HashMap<String, Held>>,
+}
+
+impl RecipientCaps {
+    /// Reads the JSON file at `RECIPIENT_CAPS_FILE`, such as `{"": {"per_minute":
+    /// 10, "burst": 3}, "acme": {"per_minute": 30, "overflow": "defer"}}`, keyed
+    /// by tenant, `""` covering the default tenant and every tenant not listed.
+    /// `overflow` is `coalesce` or `defer`, `coalesce` by default, and a
+    /// `per_minute` of 0 leaves a tenant's users uncapped.
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(path) = std::env::var("RECIPIENT_CAPS_FILE") else {
+            return Ok(Self::default());
+        };
+        let content = std::fs::read_to_string(&path).map_err(|error| format!("{path}: {error}"))?;
+        let file = serde_json::from_str::<HashMap<String, CapConfig>>(&content)
+            .map_err(|error| format!("{path}: {error}"))?;
+        let caps = file
+            .into_iter()
+            .map(|(tenant, config)| {
+                let burst = config.burst.unwrap_or(config.per_minute);
+                let cap = Cap {
+                    limiter: RateLimiter::with_burst(config.per_minute, burst),
+                    overflow: config.overflow,
+                };
+                (tenant, cap)
+            })
+            .collect();
+        Ok(Self {
+            caps,
+            held: Mutex::default(),
+        })
+    }
+
+    /// The cap of the tenant `user_id` belongs to, if it has one.
+    fn cap(&self, user_id: &str) -> Option<&Cap> {
+        let tenant = Tenant::name_of(user_id);
+        self.caps
+            .get(tenant)
+            .or_else(|| self.caps.get(""))
+            .filter(|cap| cap.limiter.capacity() > 0.0)
+    }
+
+    pub fn applies(&self, user_id: &str) -> bool {
+        self.cap(user_id).is_some()
+    }
+
+    /// Drops what is held back for `user_id`, returning how many messages that was.
+    pub fn erase(&self, user_id: &str) -> usize {
+        self.held
+            .lock()
+            .expect("Held messages were poisoned")
+            .remove(user_id)
+            .map_or(0, |held| held.deferred.len() + held.coalesced.len())
+    }
+
+    /// Takes a message to `user_id` through the recipient's cap: `None` when
+    /// it goes out now, else the overflow it was held for as `keep` makes it,
+    /// and its id.
+    fn admit(
+        &self,
+        user_id: &str,
+        keep: impl FnOnce(Overflow) -> HeldMessage,
+    ) -> Result<Option<(Overflow, Uuid)>, AppError> {
+        let Some(cap) = self.cap(user_id) else {
+            return Ok(None);
+        };
+        let mut held = self.held.lock().expect("Held messages were poisoned");
+        let waiting = held
+            .get(user_id)
+            .is_some_and(|held| !held.deferred.is_empty());
+        // Deferred messages keep their order, so nothing overtakes them.
+        if !waiting && cap.limiter.check(user_id).is_ok() {
+            return Ok(None);
+        }
+        let held = held.entry(user_id.to_owned()).or_default();
+        if cap.overflow == Overflow::Defer && held.deferred.len() >= MAX_DEFERRED {
+            return Err(AppError::RateLimited(RELEASE_INTERVAL));
+        }
+        let id = match keep(cap.overflow) {
+            HeldMessage::Deferred(message) => {
+                let id = message.id;
+                held.deferred.push_back(message);
+                id
+            }
+            HeldMessage::Coalesced(id, title) => {
+                held.coalesced.push((id, title));
+                id
+            }
+        };
+        Ok(Some((cap.overflow, id)))
+    }
+
+    /// Takes what the caps let through by now: deferred messages, and summaries
+    /// for recipients with coalesced messages.
+    fn release(&self) -> Released {
+        let mut released = Released::default();
+        let mut held = self.held.lock().expect("Held messages were poisoned");
+        for (user_id, held) in held.iter_mut() {
+            let Some(cap) = self.cap(user_id) else {
+                // The caps were lifted, so everything can go.
+                released.deferred.extend(
+                    held.deferred
+                        .drain(..)
+                        .map(|message| (user_id.clone(), message)),
+                );
+                held.coalesced.clear();
+                continue;
+            };
+            while !held.deferred.is_empty() && cap.limiter.check(user_id).is_ok() {
+                released.deferred.extend(
+                    held.deferred
+                        .pop_front()
+                        .map(|message| (user_id.clone(), message)),
+                );
+            }
+            if !held.coalesced.is_empty() && cap.limiter.check(user_id).is_ok() {
+                released
+                    .summaries
+                    .push((user_id.clone(), std::mem::take(&mut held.coalesced)));
+            }
+        }
+        held.retain(|_, held| !held.is_empty());
+        released
+    }
+}
+
+/// Whether a send goes out now.
+pub enum Admission {
+    Send(Box<SendData>),
+    /// Held back, with what the sender is told instead.
+    Held(Sent),
+}
+
+/// Lets `send` through, or holds it back when its recipient is over their cap,
+/// `data` and `options` being its checked content.
+pub fn admit(
+    state: &AppState,
+    send: SendData,
+    data: String,
+    options: PushOptions,
+) -> Result<Admission, AppError> {
+    let held = state
+        .recipient_caps
+        .admit(&send.user_id, |overflow| match overflow {
+            Overflow::Defer => HeldMessage::Deferred(OutboundMessage::accept(
+                state,
+                &send.user_id,
+                data,
+                options,
+                &send.sender,
+            )),
+            Overflow::Coalesce => {
+                let id = Uuid::new_v4();
+                state
+                    .statuses
+                    .accept(id, &send.user_id, &send.sender, options.expires_at);
+                HeldMessage::Coalesced(id, send.data.title.clone())
+            }
+        })?;
+    let Some((overflow, message_id)) = held else {
+        return Ok(Admission::Send(Box::new(send)));
+    };
+    let (outcome, text) = match overflow {
+        Overflow::Defer => ("deferred", "Deferred until the recipient's cap allows."),
+        Overflow::Coalesce => (
+            "coalesced",
+            "Coalesced into a summary sent once the recipient's cap allows.",
+        ),
+    };
+    state.audit.record(AuditEntry::new(
+        &send.user_id,
+        message_id,
+        &send.sender,
+        "cap",
+        outcome,
+    ));
+    increment_counter!("recipient_cap_overflows_total", "overflow" => outcome);
+    Ok(Admission::Held((
+        StatusCode::ACCEPTED,
+        message_id,
+        text.to_owned(),
+    )))
+}
+
+/// Stands in for the messages coalesced for a recipient.
+fn summary(coalesced: &[(Uuid, String)]) -> Notification {
+    let title = match coalesced.len() {
+        1 => "You have 1 more notification".to_owned(),
+        count => format!("You have {count} more notifications"),
+    };
+    let named = coalesced
+        .iter()
+        .map(|(_, title)| title)
+        .filter(|title| !title.is_empty())
+        .collect::<Vec<_>>();
+    let mut lines = named
+        .iter()
+        .take(SUMMARY_TITLES)
+        .map(|title| (*title).clone())
+        .collect::<Vec<_>>();
+    if named.len() > SUMMARY_TITLES {
+        lines.push(format!("and {} more", named.len() - SUMMARY_TITLES));
+    }
+    Notification::new(title, lines.join("\n"))
+}
+
+/// Sends what was held back as the recipients' caps allow it.
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(RELEASE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Released {
+            deferred,
+            summaries,
+        } = state.recipient_caps.release();
+        for (user_id, message) in deferred {
+            if let Err(error) = send_accepted(&state, &user_id, message).await {
+                warn!("Deferred send failed: {error}");
+            }
+        }
+        for (user_id, coalesced) in summaries {
+            let reader = state.channels.read().await;
+            let Some(target) = reader.get_key_value(&user_id) else {
+                continue;
+            };
+            let recipients = recipients(
+                &state,
+                std::iter::once(target),
+                &summary(&coalesced).to_json(),
+                &PushOptions::default(),
+                &Sender::internal("cap"),
+            );
+            drop(reader);
+            for recipient in &recipients {
+                for (id, _) in &coalesced {
+                    state.statuses.set_coalesced_into(id, recipient.message.id);
+                }
+            }
+            fan_out(&state, recipients).await;
+            increment_counter!("recipient_cap_summaries_total");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Caps of one message a minute for every tenant but `uncapped`.
+    fn caps(overflow: Overflow) -> RecipientCaps {
+        let cap = |per_minute| Cap {
+            limiter: RateLimiter::with_burst(per_minute, 1),
+            overflow,
+        };
+        RecipientCaps {
+            caps: HashMap::from([(String::new(), cap(1)), ("uncapped".to_owned(), cap(0))]),
+            held: Mutex::default(),
+        }
+    }
+
+    fn defer(caps: &RecipientCaps, user_id: &str) -> Result<Option<(Overflow, Uuid)>, AppError> {
+        caps.admit(user_id, |_| {
+            HeldMessage::Deferred(OutboundMessage {
+                id: Uuid::new_v4(),
+                data: String::new(),
+                options: PushOptions::default(),
+                sender: Sender::internal("test"),
+            })
+        })
+    }
+
+    #[test]
+    fn coalesces_past_the_cap() {
+        let caps = caps(Overflow::Coalesce);
+        let coalesce = |title: &str| {
+            caps.admit("alice", |_| {
+                HeldMessage::Coalesced(Uuid::new_v4(), title.to_owned())
+            })
+            .unwrap()
+        };
+        assert!(coalesce("one").is_none());
+        let (overflow, id) = coalesce("two").unwrap();
+        assert_eq!(overflow, Overflow::Coalesce);
+        assert!(coalesce("").is_some());
+        // Other recipients have caps of their own.
+        assert!(caps.admit("bob", |_| unreachable!()).unwrap().is_none());
+
+        let released = caps.release();
+        assert!(released.summaries.is_empty());
+        let held = caps.held.lock().unwrap();
+        let coalesced = &held["alice"].coalesced;
+        assert_eq!(coalesced[0], (id, "two".to_owned()));
+        assert_eq!(coalesced.len(), 2);
+        drop(held);
+        assert_eq!(caps.erase("alice"), 2);
+        assert_eq!(caps.erase("alice"), 0);
+    }
+
+    #[test]
+    fn defers_in_order_until_released() {
+        let mut caps = caps(Overflow::Defer);
+        assert!(defer(&caps, "alice").unwrap().is_none());
+        let ids = (0..3)
+            .map(|_| defer(&caps, "alice").unwrap().unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(caps.release().deferred.is_empty());
+
+        // Lifting the caps lets everything held go, in the order it was sent.
+        caps.caps.clear();
+        let released = caps.release();
+        let released = released
+            .deferred
+            .iter()
+            .map(|(user_id, message)| {
+                assert_eq!(user_id, "alice");
+                message.id
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(released, ids);
+        assert!(caps.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_past_the_most_deferred() {
+        let caps = caps(Overflow::Defer);
+        for _ in 0..=MAX_DEFERRED {
+            defer(&caps, "alice").unwrap();
+        }
+        assert!(matches!(
+            defer(&caps, "alice"),
+            Err(AppError::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn leaves_tenants_without_a_cap_alone() {
+        let caps = caps(Overflow::Defer);
+        assert!(!caps.applies("uncapped/alice"));
+        assert!(caps.applies("acme/alice"));
+        for _ in 0..3 {
+            assert!(defer(&caps, "uncapped/alice").unwrap().is_none());
+        }
+    }
+}
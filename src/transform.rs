@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{audit::Sender, auth::Tenant, notification::Notification};
+
+/// Stands in for whatever `Redact` removed.
+const REDACTED: &str = "[redacted]";
+/// Shortest digit run `Redact` takes for a phone or card number.
+const MIN_REDACTED_DIGITS: usize = 7;
+
+/// What a transformer knows about the message it is rewriting.
+pub struct TransformContext<'a> {
+    pub message_id: Uuid,
+    /// The recipient, without the tenant's namespace.
+    pub user_id: &'a str,
+}
+
+/// A stage outbound notifications pass through before any transport sees them.
+pub trait MessageTransformer: Send + Sync {
+    fn transform(&self, notification: &mut Notification, context: &TransformContext);
+}
+
+/// Replaces email addresses and long digit runs in the title and body.
+pub struct Redact;
+
+impl Redact {
+    fn redact(text: &str) -> String {
+        text.split_inclusive(char::is_whitespace)
+            .map(|word| {
+                let trimmed = word.trim_end();
+                let digits = trimmed.chars().filter(char::is_ascii_digit).count();
+                let email = trimmed
+                    .split_once('@')
+                    .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+                if email || digits >= MIN_REDACTED_DIGITS {
+                    format!("{REDACTED}{}", &word[trimmed.len()..])
+                } else {
+                    word.to_owned()
+                }
+            })
+            .collect()
+    }
+}
+
+impl MessageTransformer for Redact {
+    fn transform(&self, notification: &mut Notification, _: &TransformContext) {
+        notification.title = Self::redact(&notification.title);
+        if let Some(body) = &mut notification.body {
+            *body = Self::redact(body);
+        }
+    }
+}
+
+/// Cuts the title and body to at most the given number of characters.
+pub struct Truncate {
+    pub title: Option<usize>,
+    pub body: Option<usize>,
+}
+
+impl Truncate {
+    fn truncate(text: &mut String, max: usize) {
+        if let Some((end, _)) = text.char_indices().nth(max) {
+            // Keeps room for the ellipsis within `max`.
+            let end = text[..end]
+                .char_indices()
+                .last()
+                .map_or(0, |(index, _)| index);
+            text.truncate(end);
+            text.push('…');
+        }
+    }
+}
+
+impl MessageTransformer for Truncate {
+    fn transform(&self, notification: &mut Notification, _: &TransformContext) {
+        if let Some(max) = self.title {
+            Self::truncate(&mut notification.title, max);
+        }
+        if let (Some(max), Some(body)) = (self.body, &mut notification.body) {
+            Self::truncate(body, max);
+        }
+    }
+}
+
+/// Adds the message id to the notification's URL, so clicks can be attributed.
+pub struct TrackingId {
+    pub param: String,
+}
+
+impl MessageTransformer for TrackingId {
+    fn transform(&self, notification: &mut Notification, context: &TransformContext) {
+        if let Some(url) = &mut notification.url {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let (base, fragment) = url
+                .split_once('#')
+                .map_or((url.as_str(), None), |(base, fragment)| {
+                    (base, Some(fragment))
+                });
+            let mut tracked = format!("{base}{separator}{}={}", self.param, context.message_id);
+            if let Some(fragment) = fragment {
+                tracked = format!("{tracked}#{fragment}");
+            }
+            *url = tracked;
+        }
+    }
+}
+
+/// Appends an unsubscribe link to the body, `{user_id}` in `url` naming the recipient.
+pub struct UnsubscribeLink {
+    pub url: String,
+}
+
+impl MessageTransformer for UnsubscribeLink {
+    fn transform(&self, notification: &mut Notification, context: &TransformContext) {
+        let link = format!(
+            "Unsubscribe: {}",
+            self.url.replace("{user_id}", context.user_id)
+        );
+        notification.body = Some(match notification.body.take() {
+            Some(body) if !body.is_empty() => format!("{body}\n\n{link}"),
+            _ => link,
+        });
+    }
+}
+
+/// A stage as written in `TRANSFORMS_FILE`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum Stage {
+    Redact,
+    Truncate {
+        title: Option<usize>,
+        body: Option<usize>,
+    },
+    TrackingId {
+        #[serde(default = "default_tracking_param")]
+        param: String,
+    },
+    Unsubscribe {
+        url: String,
+    },
+}
+
+fn default_tracking_param() -> String {
+    "tracking_id".to_owned()
+}
+
+impl From<Stage> for Box<dyn MessageTransformer> {
+    fn from(stage: Stage) -> Self {
+        match stage {
+            Stage::Redact => Box::new(Redact),
+            Stage::Truncate { title, body } => Box::new(Truncate { title, body }),
+            Stage::TrackingId { param } => Box::new(TrackingId { param }),
+            Stage::Unsubscribe { url } => Box::new(UnsubscribeLink { url }),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TransformsFile {
+    #[serde(default)]
+    tenants: HashMap<String, Vec<Stage>>,
+    #[serde(default)]
+    senders: HashMap<String, Vec<Stage>>,
+}
+
+type Pipeline = Vec<Box<dyn MessageTransformer>>;
+
+/// The pipelines of each tenant and API key.
+#[derive(Default)]
+pub struct Transformers {
+    tenants: HashMap<String, Pipeline>,
+    senders: HashMap<String, Pipeline>,
+}
+
+impl Transformers {
+    /// Reads the JSON file at `TRANSFORMS_FILE`, such as
+    /// `{"tenants": {"acme": [{"type": "redact"}]}, "senders": {"key:0123456789ab":
+    /// [{"type": "truncate", "body": 120}, {"type": "tracking_id"}]}}`. Senders are
+    /// named as in the audit log, the default tenant is `""`. Stages are `redact`,
+    /// `truncate`, `tracking_id` and `unsubscribe`.
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(path) = std::env::var("TRANSFORMS_FILE") else {
+            return Ok(Self::default());
+        };
+        let content = std::fs::read_to_string(&path).map_err(|error| format!("{path}: {error}"))?;
+        let file = serde_json::from_str::<TransformsFile>(&content)
+            .map_err(|error| format!("{path}: {error}"))?;
+        let pipelines = |stages: HashMap<String, Vec<Stage>>| {
+            stages
+                .into_iter()
+                .map(|(name, stages)| (name, stages.into_iter().map(Into::into).collect()))
+                .collect()
+        };
+        Ok(Self {
+            tenants: pipelines(file.tenants),
+            senders: pipelines(file.senders),
+        })
+    }
+
+    /// Runs `data` through the recipient tenant's pipeline, then the sender's.
+    /// Data that isn't a notification passes through untouched.
+    pub fn apply(&self, sender: &Sender, user_id: &str, message_id: Uuid, data: String) -> String {
+        let tenant = Tenant::name_of(user_id);
+        let stages = self
+            .tenants
+            .get(tenant)
+            .into_iter()
+            .chain(
+                sender
+                    .0
+                    .as_ref()
+                    .and_then(|sender| self.senders.get(sender)),
+            )
+            .flatten()
+            .collect::<Vec<_>>();
+        if stages.is_empty() {
+            return data;
+        }
+        let Ok(mut notification) = serde_json::from_str::<Notification>(&data) else {
+            return data;
+        };
+        let context = TransformContext {
+            message_id,
+            user_id: Tenant::local_part(user_id),
+        };
+        for stage in stages {
+            stage.transform(&mut notification, &context);
+        }
+        notification.to_json()
+    }
+}
@@ -0,0 +1,156 @@
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderName},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::{
+    auth::{jwt_validation, query_param},
+    error::AppError,
+};
+
+static USER_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-user-token");
+
+/// Verifies the tokens an application backend mints for its users, so a client
+/// can only register and listen as the user its token names.
+pub struct UserTokens {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl UserTokens {
+    /// Enabled by `USER_TOKEN_SECRET` for HS256 or `USER_TOKEN_PUBLIC_KEY_FILE`, a PEM
+    /// file, for RS256. `exp` is required, and so are `iss` and `aud` if
+    /// `USER_TOKEN_ISSUER` or `USER_TOKEN_AUDIENCE` are set.
+    pub async fn from_env() -> std::io::Result<Option<Self>> {
+        let invalid = |error: jsonwebtoken::errors::Error| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+        };
+        let (key, algorithm) = if let Ok(secret) = std::env::var("USER_TOKEN_SECRET") {
+            (
+                DecodingKey::from_secret(secret.as_bytes()),
+                Algorithm::HS256,
+            )
+        } else if let Ok(path) = std::env::var("USER_TOKEN_PUBLIC_KEY_FILE") {
+            let pem = tokio::fs::read(path).await?;
+            (
+                DecodingKey::from_rsa_pem(&pem).map_err(invalid)?,
+                Algorithm::RS256,
+            )
+        } else {
+            return Ok(None);
+        };
+        let validation = jwt_validation(algorithm, "USER_TOKEN_ISSUER", "USER_TOKEN_AUDIENCE");
+        Ok(Some(Self { key, validation }))
+    }
+
+    /// Checks `token` is valid and was issued for `user_id`.
+    pub fn verify(&self, token: Option<&str>, user_id: &str) -> Result<(), AppError> {
+        let token = token.ok_or_else(|| AppError::InvalidUserToken("missing".to_owned()))?;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|error| AppError::InvalidUserToken(error.to_string()))?
+            .claims;
+        if claims.sub == user_id {
+            Ok(())
+        } else {
+            Err(AppError::InvalidUserToken(
+                "issued for another user".to_owned(),
+            ))
+        }
+    }
+}
+
+/// The token of the user a request acts for, from the `X-User-Token` header or,
+/// for `EventSource` and `WebSocket` connections, the `user_token` query parameter.
+pub struct UserToken(pub Option<String>);
+
+#[async_trait]
+impl<S: Sync> FromRequestParts<S> for UserToken {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(&USER_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        Ok(Self(
+            header.or_else(|| query_param(&parts.uri, "user_token")),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{get_current_timestamp, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::auth::claim_validation;
+
+    const SECRET: &[u8] = b"user-token-test-secret";
+
+    fn tokens(issuer: Option<&str>, audience: Option<&str>) -> UserTokens {
+        UserTokens {
+            key: DecodingKey::from_secret(SECRET),
+            validation: claim_validation(
+                Algorithm::HS256,
+                issuer.map(str::to_owned),
+                audience.map(str::to_owned),
+            ),
+        }
+    }
+
+    fn token(mut claims: Value) -> String {
+        claims["sub"] = json!("alice");
+        claims["exp"] = json!(get_current_timestamp() + 600);
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn checks_issuer_and_audience() {
+        let tokens = tokens(Some("backend"), Some("notifications"));
+        let valid = token(json!({"iss": "backend", "aud": "notifications"}));
+        assert!(tokens.verify(Some(&valid), "alice").is_ok());
+        assert!(tokens.verify(Some(&valid), "bob").is_err());
+
+        let wrong_audience = token(json!({"iss": "backend", "aud": "billing"}));
+        assert!(tokens.verify(Some(&wrong_audience), "alice").is_err());
+        let missing_audience = token(json!({"iss": "backend"}));
+        assert!(tokens.verify(Some(&missing_audience), "alice").is_err());
+        let missing_issuer = token(json!({"aud": "notifications"}));
+        assert!(tokens.verify(Some(&missing_issuer), "alice").is_err());
+    }
+
+    #[tokio::test]
+    async fn decodes_the_query_parameter() {
+        let (mut parts, ()) = axum::http::Request::get("/sse?user_id=a&user_token=a%2Bb%3D%3D")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let UserToken(token) = UserToken::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(token.as_deref(), Some("a+b=="));
+    }
+
+    #[test]
+    fn ignores_audience_when_unset() {
+        let tokens = tokens(None, None);
+        let with_audience = token(json!({"aud": "anything"}));
+        assert!(tokens.verify(Some(&with_audience), "alice").is_ok());
+        assert!(tokens.verify(Some(&token(json!({}))), "alice").is_ok());
+    }
+}
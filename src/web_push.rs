@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use hyper::{
+    header::{self, HeaderValue},
+    Body, Request, Uri,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::from_str;
+use tokio::sync::RwLock;
+use web_push_native::{
+    jwt_simple::{
+        self,
+        prelude::{Claims, ECDSAP256KeyPairLike, ECDSAP256PublicKeyLike, ES256KeyPair},
+    },
+    WebPushBuilder,
+};
+
+use crate::{
+    error::AppError,
+    notification::PushOptions,
+    push::{self, ProviderKind, PushAttempt, PushClient, PushProvider},
+    store::{Subscription, WebPushSubscription},
+};
+
+/// Contents of the VAPID key file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct VapidKeyFile {
+    subject: String,
+    public_key: String,
+    private_key: String,
+}
+
+/// Largest plaintext that fits the 4096 byte record push services accept, after
+/// the aes128gcm header (86 bytes), the tag (16) and the padding delimiter (1).
+pub const MAX_PAYLOAD: usize = 4096 - 86 - 16 - 1;
+
+/// VAPID tokens are signed for 12 hours and replaced an hour before they expire.
+const TOKEN_LIFETIME: Duration = Duration::from_hours(11);
+
+/// The VAPID key pair, decoded once when the key file is loaded.
+pub struct VapidKey {
+    file: VapidKeyFile,
+    key_pair: ES256KeyPair,
+    /// `k=` parameter of the `Authorization` header.
+    encoded_public_key: String,
+    /// Signed `Authorization` header per push service origin, and the moment it
+    /// should be replaced. Push services are few, so entries are never dropped.
+    tokens: Mutex<HashMap<String, (HeaderValue, Instant)>>,
+}
+
+impl FromStr for VapidKey {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file = from_str::<VapidKeyFile>(s)?;
+        let key_pair = Base64UrlUnpadded::decode_vec(&file.private_key)
+            .map_err(|error| error.to_string())
+            .and_then(|bytes| ES256KeyPair::from_bytes(&bytes).map_err(|error| error.to_string()))
+            .map_err(|error| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid VAPID private key: {error}"),
+                )
+            })?;
+        let encoded_public_key = Base64UrlUnpadded::encode_string(
+            &key_pair.public_key().public_key().to_bytes_uncompressed(),
+        );
+        Ok(Self {
+            file,
+            key_pair,
+            encoded_public_key,
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Serialize for VapidKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.file.serialize(serializer)
+    }
+}
+
+impl VapidKey {
+    /// Reads and decodes the key file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the file can't be read or doesn't hold a valid key.
+    pub async fn load(path: &std::path::Path) -> std::io::Result<Self> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::from_str(&content)
+    }
+
+    /// Creates a fresh key pair for `subject`, a `mailto:` or `https:` contact URL.
+    #[must_use]
+    pub fn generate(subject: String) -> Self {
+        let key_pair = ES256KeyPair::generate();
+        let encoded_public_key = Base64UrlUnpadded::encode_string(
+            &key_pair.public_key().public_key().to_bytes_uncompressed(),
+        );
+        Self {
+            file: VapidKeyFile {
+                subject,
+                public_key: encoded_public_key.clone(),
+                private_key: Base64UrlUnpadded::encode_string(&key_pair.to_bytes()),
+            },
+            key_pair,
+            encoded_public_key,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The public key as browsers expect it for `applicationServerKey`.
+    pub fn application_server_key(&self) -> &str {
+        &self.encoded_public_key
+    }
+
+    /// Signs `claims` into an ES256 JWT that doesn't expire, issued by the VAPID
+    /// subject and checkable against the public key in `/vapid.json`.
+    pub(crate) fn sign<T: Serialize + DeserializeOwned>(
+        &self,
+        claims: T,
+    ) -> Result<String, AppError> {
+        let mut claims =
+            Claims::with_custom_claims(claims, jwt_simple::prelude::Duration::from_secs(0))
+                .with_issuer(&self.file.subject);
+        claims.expires_at = None;
+        self.key_pair
+            .sign(claims)
+            .map_err(|error| AppError::InvalidVapidKey(error.to_string()))
+    }
+
+    /// Returns the `Authorization` header for a push to `endpoint`, reusing the
+    /// token signed for its origin until it gets close to expiring.
+    fn authorization(&self, endpoint: &Uri) -> Result<HeaderValue, AppError> {
+        let (Some(scheme), Some(host)) = (endpoint.scheme_str(), endpoint.host()) else {
+            return Err(AppError::invalid_registration(
+                "endpoint",
+                "must be an absolute URL",
+            ));
+        };
+        let origin = format!("{scheme}://{host}");
+        let mut tokens = self.tokens.lock().expect("VAPID token cache was poisoned");
+        if let Some((header, refresh_at)) = tokens.get(&origin) {
+            if Instant::now() < *refresh_at {
+                return Ok(header.clone());
+            }
+        }
+
+        let claims = Claims::create(jwt_simple::prelude::Duration::from_hours(12))
+            .with_audience(&origin)
+            .with_subject(&self.file.subject);
+        let token = self
+            .key_pair
+            .sign(claims)
+            .map_err(|error| AppError::InvalidVapidKey(error.to_string()))?;
+        let header =
+            HeaderValue::from_str(&format!("vapid t={token}, k={}", self.encoded_public_key))
+                .map_err(|error| AppError::InvalidVapidKey(error.to_string()))?;
+        tokens.insert(origin, (header.clone(), Instant::now() + TOKEN_LIFETIME));
+        Ok(header)
+    }
+}
+
+/// The VAPID key new subscriptions are created under, and the one being
+/// rotated out, which still signs pushes to the subscriptions created under it.
+pub struct VapidKeys {
+    pub primary: VapidKey,
+    pub secondary: Option<VapidKey>,
+}
+
+impl VapidKeys {
+    /// Reads the primary key file at `primary` and the secondary one, if any.
+    ///
+    /// # Errors
+    ///
+    /// Fails when either file can't be read or doesn't hold a valid key.
+    pub async fn load(
+        primary: &std::path::Path,
+        secondary: Option<&std::path::Path>,
+    ) -> std::io::Result<Self> {
+        let secondary = match secondary {
+            Some(path) => Some(VapidKey::load(path).await?),
+            None => None,
+        };
+        Ok(Self {
+            primary: VapidKey::load(primary).await?,
+            secondary,
+        })
+    }
+
+    /// The configured key with the `applicationServerKey` `public_key`.
+    pub fn find(&self, public_key: &str) -> Option<&VapidKey> {
+        std::iter::once(&self.primary)
+            .chain(&self.secondary)
+            .find(|key| key.application_server_key() == public_key)
+    }
+
+    /// The key pushes to `subscription` are signed with: the one it was created
+    /// under, or the primary one if that was retired. Subscriptions stored before
+    /// keys were tracked predate the rotation, so they get the secondary key.
+    fn for_subscription(&self, subscription: &WebPushSubscription) -> &VapidKey {
+        subscription
+            .vapid_key()
+            .map_or(self.secondary.as_ref(), |public_key| self.find(public_key))
+            .unwrap_or(&self.primary)
+    }
+}
+
+/// Delivers to browser push services with VAPID-signed, encrypted requests.
+pub struct WebPushProvider {
+    client: PushClient,
+    vapid: Arc<RwLock<Arc<VapidKeys>>>,
+}
+
+impl WebPushProvider {
+    pub const fn new(client: PushClient, vapid: Arc<RwLock<Arc<VapidKeys>>>) -> Self {
+        Self { client, vapid }
+    }
+}
+
+#[async_trait]
+impl PushProvider for WebPushProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::WebPush
+    }
+
+    async fn send(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<PushAttempt, AppError> {
+        let Some(subscription) = &subscription.web_push else {
+            return Err(AppError::invalid_registration("endpoint", "missing"));
+        };
+        let vapid = self.vapid.read().await.clone();
+        let request = push_request(
+            subscription,
+            vapid.for_subscription(subscription),
+            data.to_owned(),
+            options,
+        )?;
+        Ok(PushAttempt::from_response(
+            push::dispatch(&self.client, self.kind(), request).await,
+        ))
+    }
+
+    async fn preview(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        options: &PushOptions,
+    ) -> Result<Request<Body>, String> {
+        let Some(subscription) = &subscription.web_push else {
+            return Err("No Web Push subscription".to_owned());
+        };
+        let vapid = self.vapid.read().await.clone();
+        push_request(
+            subscription,
+            vapid.for_subscription(subscription),
+            data.to_owned(),
+            options,
+        )
+        .map_err(|error| error.to_string())
+    }
+}
+
+/// Seconds push services keep a tickle without `ttl`, as the builder does for
+/// encrypted pushes.
+const DEFAULT_TTL: u32 = 12 * 3600;
+
+/// Builds the encrypted, VAPID-signed Web Push request for a subscription, or
+/// one without a body when tickling.
+fn push_request(
+    reg: &WebPushSubscription,
+    vapid: &VapidKey,
+    data: String,
+    options: &PushOptions,
+) -> Result<Request<Body>, AppError> {
+    let mut request = if options.tickle() {
+        Request::post(reg.endpoint().clone())
+            .header("TTL", DEFAULT_TTL)
+            .header(header::CONTENT_LENGTH, 0)
+            .body(Body::empty())
+            .expect("Tickles are valid requests")
+    } else {
+        WebPushBuilder::new(reg.endpoint().clone(), *reg.p256dh(), *reg.auth())
+            .build(data)
+            .map(|req| req.map(std::convert::Into::into))
+            .map_err(|error| AppError::invalid_registration("keys", format!("{error:?}")))?
+    };
+    request
+        .headers_mut()
+        .insert(header::AUTHORIZATION, vapid.authorization(reg.endpoint())?);
+
+    // The builder ties TTL to the VAPID token lifetime, which is capped at 24 hours,
+    // so the delivery headers are set on the finished request instead.
+    let headers = request.headers_mut();
+    if let Some(ttl) = options.push_ttl() {
+        headers.insert("TTL", HeaderValue::from(ttl));
+    }
+    if let Some(urgency) = options.urgency {
+        headers.insert("Urgency", HeaderValue::from_static(urgency.as_str()));
+    }
+    if let Some(topic) = options
+        .push_topic()
+        .and_then(|topic| HeaderValue::from_str(topic).ok())
+    {
+        headers.insert("Topic", topic);
+    }
+    Ok(request)
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use hyper::{client::HttpConnector, header, Body, Client, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::Sha256;
+
+use crate::{
+    error::AppError,
+    notification::PushOptions,
+    push::{self, DeliveryError, ProviderKind, PushAttempt, PushProvider},
+    store::Subscription,
+};
+
+/// POSTs the notification JSON to the URL a registration provided, signed so the
+/// receiver can check it came from this server.
+///
+/// Each request carries `X-Webhook-Timestamp` (Unix seconds) and
+/// `X-Webhook-Signature: sha256=<hex>`, the HMAC-SHA256 of `<timestamp>.<body>`.
+pub struct WebhookProvider {
+    /// Unlike the push services, webhooks may be plain HTTP on an internal network.
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    secret: Vec<u8>,
+    timeout: Duration,
+}
+
+impl WebhookProvider {
+    /// Enabled by `WEBHOOK_SECRET`. `WEBHOOK_TIMEOUT_SECS` bounds each request,
+    /// 10 seconds by default.
+    pub fn from_env() -> Option<Self> {
+        let secret = std::env::var("WEBHOOK_SECRET").ok()?;
+        let timeout = std::env::var("WEBHOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(10);
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Some(Self {
+            client: Client::builder().build(https),
+            secret: secret.into_bytes(),
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+
+    fn request(&self, url: &str, data: &str) -> Result<Request<Body>, AppError> {
+        let timestamp = Utc::now().timestamp();
+        Request::post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Timestamp", timestamp)
+            .header("X-Webhook-Signature", sign(&self.secret, timestamp, data))
+            .body(Body::from(data.to_owned()))
+            .map_err(|error| AppError::invalid_registration("webhook_url", error))
+    }
+}
+
+/// The `X-Webhook-Signature` of `body` sent at `timestamp`.
+pub fn sign(secret: &[u8], timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POSTs the JSON `body` to `url`, signed with `secret` like the notifications
+/// sent to subscriber webhooks. Anything but a 2xx response is an error.
+pub async fn post(
+    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    timeout: Duration,
+    url: &str,
+    secret: &[u8],
+    body: String,
+) -> Result<(), String> {
+    let timestamp = Utc::now().timestamp();
+    let request = Request::post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Timestamp", timestamp)
+        .header("X-Webhook-Signature", sign(secret, timestamp, &body))
+        .body(Body::from(body))
+        .map_err(|error| error.to_string())?;
+    let response = tokio::time::timeout(timeout, client.request(request))
+        .await
+        .map_err(|_| format!("timed out after {}s", timeout.as_secs()))?
+        .map_err(|error| error.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("responded with {}", response.status()))
+    }
+}
+
+/// Checks `url` is an absolute http or https URL.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    let uri = url
+        .parse::<hyper::Uri>()
+        .map_err(|error| error.to_string())?;
+    if matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some() {
+        Ok(())
+    } else {
+        Err("must be an absolute http or https URL".to_owned())
+    }
+}
+
+#[async_trait]
+impl PushProvider for WebhookProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Webhook
+    }
+
+    async fn send(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        _options: &PushOptions,
+    ) -> Result<PushAttempt, AppError> {
+        let Some(url) = &subscription.webhook_url else {
+            return Err(AppError::invalid_registration("webhook_url", "missing"));
+        };
+        let request = self.request(url, data)?;
+        let response = tokio::time::timeout(
+            self.timeout,
+            push::dispatch(&self.client, self.kind(), request),
+        )
+        .await;
+        Ok(response.map_or_else(
+            |_| {
+                let error = format!("Webhook timed out after {}s", self.timeout.as_secs());
+                PushAttempt::failed(DeliveryError::Network, error, None, None)
+            },
+            PushAttempt::from_response,
+        ))
+    }
+
+    async fn preview(
+        &self,
+        subscription: &Subscription,
+        data: &str,
+        _options: &PushOptions,
+    ) -> Result<Request<Body>, String> {
+        let Some(url) = &subscription.webhook_url else {
+            return Err("No webhook URL".to_owned());
+        };
+        self.request(url, data).map_err(|error| error.to_string())
+    }
+}